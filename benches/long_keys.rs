@@ -0,0 +1,49 @@
+// Lookups of 1KB keys in an ART checking paths at the leaves, run with
+// `cargo bench --bench long_keys`. Misses changed in the last 8 bytes are
+// told apart by the leaf fingerprint, misses changed just before them
+// compare the key up to there, the gap between the two is what the
+// fingerprint saves. Every lookup also copies its key, as `find` takes it
+// by value
+use radix::art::{Art, PathCheck};
+use std::hint::black_box;
+use std::time::Instant;
+
+const KEYS: usize = 1000;
+const STEM: usize = 1000;
+const ROUNDS: usize = 1000;
+
+fn key(i: usize) -> String {
+    format!("{}{:03}\0", "a".repeat(STEM), i)
+}
+
+// Key `i` with the byte at `at` changed, a miss reaching key `i`'s leaf
+fn miss(i: usize, at: usize) -> String {
+    let key = key(i);
+    format!("{}b{}", &key[..at], &key[at + 1..])
+}
+
+fn bench(name: &str, art: &Art<String, usize>, keys: &[String], found: bool) {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for key in keys {
+            assert_eq!(found, black_box(art.find(black_box(key.clone()))).is_some());
+        }
+    }
+    let ns = start.elapsed().as_nanos() / (ROUNDS * keys.len()) as u128;
+    println!("{:<32} {:>6} ns/lookup", name, ns);
+}
+
+fn main() {
+    let keys: Vec<String> = (0..KEYS).map(key).collect();
+    let mut art = Art::new();
+    art.set_path_check(PathCheck::AtLeaf);
+    for (i, key) in keys.iter().enumerate() {
+        art.insert(key.clone(), i);
+    }
+    let len = keys[0].len();
+    let tail: Vec<String> = (0..KEYS).map(|i| miss(i, len - 8)).collect();
+    let body: Vec<String> = (0..KEYS).map(|i| miss(i, len - 9)).collect();
+    bench("hit", &art, &keys, true);
+    bench("miss in the last 8 bytes", &art, &tail, false);
+    bench("miss before the last 8 bytes", &art, &body, false);
+}
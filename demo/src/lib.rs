@@ -0,0 +1,2 @@
+// The wasm module of the demo, `ArtMap` comes with its JS bindings
+pub use radix::wasm::*;
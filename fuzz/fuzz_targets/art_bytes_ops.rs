@@ -0,0 +1,83 @@
+#![no_main]
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use radix::art::{Art, PathCheck};
+use radix::keys::Raw;
+use std::collections::BTreeMap;
+
+// Byte string keys cut from a few stems sharing up to 40 bytes, past the
+// partial a node stores, so inserts and deletes split and merge nodes
+// before, inside and after the stored bytes
+#[derive(Arbitrary, Debug)]
+struct Key {
+    stem: u8,
+    len: u8,
+    tail: Vec<u8>,
+}
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Insert(Key, u32),
+    Find(Key),
+    Remove(Key),
+    // Count the keys under the prefix
+    Prefix(Key),
+    // Switch between checking the paths at every node and at the leaves
+    Switch,
+    Check,
+}
+
+const STEMS: [&[u8]; 3] = [
+    b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+    b"aaaaaaaaaaaaaaaaaaaabaaaaaaaaaaaaaaa",
+    b"abababababababababababab",
+];
+
+fn key(k: &Key, terminated: bool) -> Vec<u8> {
+    let stem = STEMS[k.stem as usize % STEMS.len()];
+    let mut key = stem[..k.len as usize % (stem.len() + 1)].to_vec();
+    key.extend(k.tail.iter().take(3).map(|b| b'a' + b % 3));
+    // The tree holds no key that is a prefix of another
+    if terminated {
+        key.push(0);
+    }
+    key
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mut art = Art::<String, u32>::new();
+    let mut oracle = BTreeMap::new();
+    for op in ops {
+        match op {
+            Op::Insert(k, v) => {
+                let k = key(&k, true);
+                assert_eq!(art.insert_with(&Raw, &k[..], v), oracle.insert(k, v));
+            }
+            Op::Find(k) => {
+                let k = key(&k, true);
+                assert_eq!(art.find_with(&Raw, &k[..]), oracle.get(&k));
+            }
+            Op::Remove(k) => {
+                let k = key(&k, true);
+                assert_eq!(art.remove_with(&Raw, &k[..]), oracle.remove(&k));
+                assert_eq!(art.find_with(&Raw, &k[..]), None);
+            }
+            Op::Prefix(k) => {
+                let p = key(&k, false);
+                let expected = oracle.keys().filter(|k| k.starts_with(&p));
+                assert!(art.prefix_iter(&p).map(|(k, _)| k).eq(expected.map(|k| &k[..])));
+            }
+            Op::Switch => {
+                let mode = match art.path_check() {
+                    PathCheck::EveryNode => PathCheck::AtLeaf,
+                    PathCheck::AtLeaf => PathCheck::EveryNode,
+                };
+                art.set_path_check(mode);
+            }
+            Op::Check => art.check().unwrap(),
+        }
+    }
+    art.check().unwrap();
+    let pairs = art.iter().map(|(k, v)| (k.to_vec(), *v));
+    assert!(pairs.eq(oracle.into_iter()));
+});
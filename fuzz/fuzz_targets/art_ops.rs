@@ -0,0 +1,53 @@
+#![no_main]
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use radix::art::{Art, ArtKey};
+use std::collections::BTreeMap;
+
+// Keys are drawn from a small space so operations collide often,
+// then spread over all 4 bytes to grow nodes at every level
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Insert(u16, u32),
+    Find(u16),
+    Delete(u16),
+    Remove(u16),
+    // Walk the tree and check its structure
+    Check,
+}
+
+fn key(k: u16) -> u32 {
+    u32::from(k).wrapping_mul(0x9E37_79B1)
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mut art = Art::<u32, u32>::new();
+    let mut oracle = BTreeMap::new();
+    for op in ops {
+        match op {
+            Op::Insert(k, v) => {
+                art.insert(key(k), v);
+                oracle.insert(key(k), v);
+            }
+            Op::Find(k) => {
+                assert_eq!(art.find(key(k)), oracle.get(&key(k)));
+            }
+            Op::Delete(k) => {
+                art.delete(key(k));
+                oracle.remove(&key(k));
+                assert_eq!(art.find(key(k)), None);
+            }
+            Op::Remove(k) => {
+                assert_eq!(art.remove(key(k)), oracle.remove(&key(k)));
+            }
+            Op::Check => art.check().unwrap(),
+        }
+    }
+    art.check().unwrap();
+    // Iteration gives the keys in the order of the oracle
+    let pairs = art.iter().map(|(k, v)| (u32::from_bytes(k).unwrap(), *v));
+    assert!(pairs.eq(oracle.iter().map(|(k, v)| (*k, *v))));
+    if oracle.is_empty() {
+        assert_eq!(art.bfs_count(), 0);
+    }
+});
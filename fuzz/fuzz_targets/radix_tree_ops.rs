@@ -0,0 +1,43 @@
+#![no_main]
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use radix::radix::RadixTree;
+use std::collections::BTreeMap;
+
+// Keys are short strings over a tiny alphabet, so most of them
+// share prefixes and hit the split and compression paths
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Insert(Vec<u8>, u32),
+    Find(Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+fn key(k: &[u8]) -> String {
+    k.iter().take(8).map(|b| (b'a' + b % 4) as char).collect()
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mut tree = RadixTree::<u32>::new();
+    let mut oracle = BTreeMap::new();
+    for op in ops {
+        match op {
+            Op::Insert(k, v) => {
+                let k = key(&k);
+                assert_eq!(tree.insert(k.clone(), v), oracle.insert(k, v));
+            }
+            Op::Find(k) => {
+                let k = key(&k);
+                assert_eq!(tree.find(k.clone()), oracle.get(&k));
+            }
+            Op::Delete(k) => {
+                let k = key(&k);
+                assert_eq!(tree.remove(k.clone()), oracle.remove(&k));
+                assert_eq!(tree.find(k), None);
+            }
+        }
+    }
+    for (k, v) in &oracle {
+        assert_eq!(tree.find(k.clone()), Some(v));
+    }
+});
@@ -1,25 +1,186 @@
+use crate::codec::Codec;
+use crate::error::Error;
+#[cfg(feature = "serde")]
+use crate::export::{read_pairs, write_pairs, Format};
+use crate::index::SecondaryIndex;
+use crate::keys::{BigEndian, KeyCodec, Raw};
+use crate::namespace::Account;
+use crate::radix::RadixTree;
+use crate::sst::{SstReader, SstWriter};
+use crate::trie::TrieMap;
 use core::marker::PhantomData;
-use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Seek, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
 use std::ptr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
-#[cfg(target_arch = "x86")]
-use std::arch::x86::*;
-#[cfg(target_arch = "x86_64")]
-use std::arch::x86_64::*;
+// Byte searches of Node16 and the prefix comparison. With the `simd`
+// feature on x86 they compare 16 bytes at once with SSE2, otherwise, or
+// with `force_portable`, they go byte by byte
+#[cfg(all(
+    feature = "simd",
+    not(feature = "force_portable"),
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse2"
+))]
+mod bytes {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    // Index of the first of the `count` sorted keys greater than the byte
+    pub(super) fn insert_position(keys: &[u8; 16], count: usize, byte: u8) -> usize {
+        let mask = (1 << count) - 1;
+        let bitfield = unsafe {
+            // The comparison is signed, flipping the top bit makes it
+            // order bytes as unsigned
+            let flip = _mm_set1_epi8(i8::MIN);
+            let cmp = _mm_cmplt_epi8(
+                _mm_set1_epi8((byte ^ 0x80) as i8),
+                _mm_xor_si128(_mm_loadu_si128(keys.as_ptr() as *const __m128i), flip),
+            );
+            _mm_movemask_epi8(cmp) & mask
+        };
+        if bitfield > 0 {
+            bitfield.trailing_zeros() as usize
+        } else {
+            count
+        }
+    }
+
+    // Index of the byte among the first `count` keys
+    pub(super) fn position(keys: &[u8; 16], count: usize, byte: u8) -> Option<usize> {
+        let mask = (1 << count) - 1;
+        let bitfield = unsafe {
+            let cmp = _mm_cmpeq_epi8(
+                _mm_set1_epi8(byte as i8),
+                _mm_loadu_si128(keys.as_ptr() as *const __m128i),
+            );
+            _mm_movemask_epi8(cmp) & mask
+        };
+        if bitfield != 0 {
+            Some(bitfield.trailing_zeros() as usize)
+        } else {
+            None
+        }
+    }
+
+    // Number of equal bytes at the start of the slices
+    pub(super) fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+        let len = std::cmp::min(a.len(), b.len());
+        let mut i = 0;
+        while i + 16 <= len {
+            let equal = unsafe {
+                _mm_movemask_epi8(_mm_cmpeq_epi8(
+                    _mm_loadu_si128(a[i..].as_ptr() as *const __m128i),
+                    _mm_loadu_si128(b[i..].as_ptr() as *const __m128i),
+                ))
+            };
+            if equal != 0xffff {
+                return i + (!equal).trailing_zeros() as usize;
+            }
+            i += 16;
+        }
+        i + a[i..]
+            .iter()
+            .zip(b[i..].iter())
+            .take_while(|&(a, b)| a == b)
+            .count()
+    }
+}
+
+#[cfg(not(all(
+    feature = "simd",
+    not(feature = "force_portable"),
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse2"
+)))]
+mod bytes {
+    pub(super) fn insert_position(keys: &[u8; 16], count: usize, byte: u8) -> usize {
+        keys[..count]
+            .iter()
+            .position(|&key| byte < key)
+            .unwrap_or(count)
+    }
+
+    pub(super) fn position(keys: &[u8; 16], count: usize, byte: u8) -> Option<usize> {
+        keys[..count].iter().position(|&key| key == byte)
+    }
+
+    pub(super) fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+        a.iter().zip(b.iter()).take_while(|&(a, b)| a == b).count()
+    }
+}
+
+// With the `paranoid` feature the unsafe pointer paths check what they
+// rely on, in release builds as well
+macro_rules! paranoid_assert {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "paranoid") {
+            assert!($($arg)*);
+        }
+    };
+}
 
 trait ArtNode<T: 'static + std::fmt::Debug>: std::fmt::Debug {
     fn add(&mut self, node: *mut Node<T>, key: &[u8], depth: usize);
     fn find_child(&mut self, key: u8) -> Option<&mut *mut Node<T>>;
+    // Child of the key for lookups, which only read the node
+    fn child(&self, key: u8) -> Option<*mut Node<T>>;
+    // Copy of the node with copies of its subtrees
+    fn clone_node(&self) -> Box<dyn ArtNode<T>>
+    where
+        T: Clone;
     fn delete_child(
         &mut self,
         parent_node: *mut *mut Node<T>,
         ref_node: *mut *mut Node<T>,
         key: u8,
+        metrics: &Metrics,
     );
-    fn prefix(&self, key: &[u8]) -> usize;
     fn info(&self) -> &Info;
     fn info_mut(&mut self) -> &mut Info;
+    fn aggregate(&self) -> Option<&T>;
+    fn set_aggregate(&mut self, aggregate: Option<T>);
+    fn best(&self) -> *mut Node<T>;
+    fn set_best(&mut self, best: *mut Node<T>);
     fn child_pointers(&self) -> &[*mut Node<T>];
+    // Push childs to the stack in the descending key order,
+    // so they are popped in the ascending one
+    fn push_children(&self, stack: &mut Vec<*mut Node<T>>);
+    // Key of some leaf below the node. Partial bytes past `MAX_PREFIX_LEN`
+    // are not stored in the node, so they are taken from there
+    fn leaf_key(&self) -> Vec<u8> {
+        first_leaf_key(
+            *self
+                .child_pointers()
+                .iter()
+                .find(|ptr| !ptr.is_null())
+                .unwrap(),
+        )
+    }
+    // Number of key bytes from `depth` matching the partial prefix
+    fn prefix(&self, key_bytes: &[u8], depth: usize) -> usize {
+        let info = self.info();
+        let stored = std::cmp::min(info.partial_len, MAX_PREFIX_LEN);
+        let cm = common_prefix(&info.partial[..stored], &key_bytes[depth..]);
+        if cm < stored || info.partial_len == stored {
+            return cm;
+        }
+        let leaf_key = self.leaf_key();
+        cm + common_prefix(
+            &leaf_key[depth + cm..depth + info.partial_len],
+            &key_bytes[depth + cm..],
+        )
+    }
     // Check if we need to split the node, when we have an equal partial prefixes
     // and performs one if needed
     fn split_check(
@@ -27,23 +188,40 @@ trait ArtNode<T: 'static + std::fmt::Debug>: std::fmt::Debug {
         key_bytes: &[u8],
         depth: &mut usize,
         iter_node: &mut *mut Node<T>,
-        new_leaf: *mut Node<T>,
+        new_leaf: &mut NewLeaf<T>,
         parent_node: &mut *mut *mut Node<T>,
+        metrics: &Metrics,
     ) -> (bool, Option<&mut *mut Node<T>>) {
         // Number of matched bytes with the current node partial
-        let cm = self.prefix(&key_bytes[*depth..]);
-        let info = self.info_mut();
-        if cm != info.partial_len {
+        let cm = self.prefix(key_bytes, *depth);
+        let partial_len = self.info().partial_len;
+        if cm != partial_len {
+            metrics.incr(&metrics.prefix_mismatches);
+            metrics.incr(&metrics.splits);
+            trace_event!(
+                node = node_type(self.child_pointers().len()),
+                depth = *depth,
+                matched = cm,
+                "split"
+            );
+            metrics.alloc_inner::<T>(4);
             // Create a new node with the splitted partial to the matter of prefix
-            let mut new_node = Node4::new(&info.partial[..cm]);
+            let mut new_node = Node4::new(&key_bytes[*depth..*depth + cm]);
             // Add a new leaf and the current node as a childs
-            new_node.add(new_leaf, &key_bytes, *depth + cm);
-            new_node.add(*iter_node, &info.partial, cm);
-            info.partial_len -= cm;
-            // Split the partial to the matter of suffix
-            for i in 0..info.partial_len {
-                info.partial[i] = info.partial[cm + i];
+            new_node.add(new_leaf.take(metrics), key_bytes, *depth + cm);
+            if partial_len > MAX_PREFIX_LEN {
+                // The suffix isn't fully stored, read it from a leaf
+                let leaf_key = self.leaf_key();
+                let start = *depth + cm;
+                let stored = std::cmp::min(partial_len - cm, MAX_PREFIX_LEN);
+                new_node.add(*iter_node, &leaf_key, start);
+                self.info_mut().partial[..stored].copy_from_slice(&leaf_key[start..start + stored]);
+            } else {
+                new_node.add(*iter_node, &self.info().partial, cm);
+                // Split the partial to the matter of suffix
+                self.info_mut().partial.copy_within(cm..partial_len, 0);
             }
+            self.info_mut().partial_len -= cm;
             unsafe {
                 // Write to the place of the current node the new one
                 **parent_node = Box::into_raw(Box::new(Node::ArtNode(Box::new(new_node))));
@@ -51,7 +229,7 @@ trait ArtNode<T: 'static + std::fmt::Debug>: std::fmt::Debug {
             return (true, None);
         }
         // If a split is not needed find next child
-        *depth += info.partial_len;
+        *depth += partial_len;
         (false, self.find_child(key_bytes[*depth]))
     }
     fn insert(
@@ -59,20 +237,30 @@ trait ArtNode<T: 'static + std::fmt::Debug>: std::fmt::Debug {
         key_bytes: &[u8],
         depth: &mut usize,
         iter_node: &mut *mut Node<T>,
-        new_leaf: *mut Node<T>,
+        new_leaf: &mut NewLeaf<T>,
         parent_node: &mut *mut *mut Node<T>,
+        metrics: &Metrics,
     ) -> bool;
 }
 
 // Trait to have a byte representation of the accepted key types
-pub trait ArtKey {
+pub trait ArtKey: Sized {
     fn bytes(&self) -> Vec<u8>;
+    // Restore the key from its bytes, `None` if they don't represent a key.
+    // Only `scan_page` and `export` need it, they fail on the keys of types
+    // that keep this default
+    fn from_bytes(_bytes: &[u8]) -> Option<Self> {
+        None
+    }
 }
 
 impl ArtKey for String {
     fn bytes(&self) -> Vec<u8> {
         self.as_bytes().to_vec()
     }
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
 }
 
 // Because rust doesn't have the size_of of a generic types
@@ -83,6 +271,14 @@ macro_rules! doit {
         fn bytes(&self) -> Vec<u8> {
             self.to_be_bytes().to_vec()
         }
+        fn from_bytes(bytes: &[u8]) -> Option<Self> {
+            let mut array = [0; std::mem::size_of::<$t>()];
+            if bytes.len() != array.len() {
+                return None;
+            }
+            array.copy_from_slice(bytes);
+            Some(<$t>::from_be_bytes(array))
+        }
     })*)
 }
 doit! { i8 i16 i32 i64 i128 isize u8 u16 u32 u64 u128 usize }
@@ -98,6 +294,30 @@ enum Node<T> {
 // into chuncks
 const MAX_PREFIX_LEN: usize = 10;
 
+// Where lookups and deletes compare the compressed paths of inner nodes.
+// Only a lookup strategy, nodes keep the first `MAX_PREFIX_LEN` bytes of
+// their path either way, inserts need them to find where to split. The
+// paper's optimistic compression, which stores no path bytes, isn't done
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathCheck {
+    // Compare the path at every node, past the stored bytes against a
+    // leaf below the node
+    EveryNode,
+    // Skip the paths and compare the whole key once at the leaf
+    AtLeaf,
+}
+
+// What a delete does to the tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Deletion {
+    // Remove the leaf and shrink or merge the nodes above it right away
+    Eager,
+    // Only mark the leaf dead, `compact` removes the dead leaves later in
+    // one go. Saves the shrinks and merges of bursts of deletes that are
+    // followed by inserts of the same keys
+    Lazy,
+}
+
 // Struct that contains useful information shared between nodes
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
@@ -110,6 +330,21 @@ struct Info {
     partial_len: usize,
 }
 
+impl Info {
+    // Place `prefix_len` bytes in front of the partial prefix, used when
+    // a node takes the place of one of its ancestors. Only the bytes that
+    // fit into the partial have to be passed
+    fn prepend(&mut self, prefix: &[u8], prefix_len: usize) {
+        let mut partial = [0; MAX_PREFIX_LEN];
+        let len = std::cmp::min(prefix_len, MAX_PREFIX_LEN);
+        partial[..len].copy_from_slice(&prefix[..len]);
+        let rest = std::cmp::min(self.partial_len, MAX_PREFIX_LEN - len);
+        partial[len..len + rest].copy_from_slice(&self.partial[..rest]);
+        self.partial = partial;
+        self.partial_len += prefix_len;
+    }
+}
+
 // Node with 4 childs with one to one
 // child pointers and keys
 #[repr(C)]
@@ -118,6 +353,10 @@ struct Node4<T> {
     child_pointers: [*mut Node<T>; 4],
     info: Info,
     key: [u8; 4],
+    // Aggregate of the values below, see `set_aggregation`
+    aggregate: Option<T>,
+    // Leaf with the highest priority below, see `set_priority`
+    best: *mut Node<T>,
 }
 
 // Node with 16 childs with one to one
@@ -128,6 +367,51 @@ struct Node16<T> {
     child_pointers: [*mut Node<T>; 16],
     info: Info,
     key: [u8; 16],
+    aggregate: Option<T>,
+    best: *mut Node<T>,
+}
+
+// Set of bytes, the keys of the children of Node48 and Node256. Ordered
+// walks go over the set bits with `trailing_zeros`, not over all 256 keys
+#[derive(Debug, Clone, Copy, Default)]
+struct Bitmap([u64; 4]);
+
+impl Bitmap {
+    fn set(&mut self, byte: u8) {
+        self.0[byte as usize >> 6] |= 1 << (byte & 63);
+    }
+    fn clear(&mut self, byte: u8) {
+        self.0[byte as usize >> 6] &= !(1 << (byte & 63));
+    }
+    // Bytes of the set in the ascending order
+    fn iter(&self) -> Bits {
+        Bits {
+            words: self.0,
+            word: 0,
+        }
+    }
+}
+
+struct Bits {
+    words: [u64; 4],
+    word: usize,
+}
+
+impl Iterator for Bits {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        while self.word < 4 {
+            let bits = self.words[self.word];
+            if bits != 0 {
+                // Drop the lowest bit for the next call
+                self.words[self.word] &= bits - 1;
+                return Some((self.word * 64 + bits.trailing_zeros() as usize) as u8);
+            }
+            self.word += 1;
+        }
+        None
+    }
 }
 
 // Node with 48 childs
@@ -138,6 +422,10 @@ struct Node48<T> {
     // key[byte as usize] -> gives on of the 48 pointers
     key: [u8; 256],
     info: Info,
+    // Bytes with a child
+    present: Bitmap,
+    aggregate: Option<T>,
+    best: *mut Node<T>,
 }
 
 // std::fmt::Debug is not implemented for arrays with size >= 32
@@ -147,6 +435,7 @@ impl<T> std::fmt::Debug for Node48<T> {
             .field("child_pointers", &&self.child_pointers[..])
             .field("key", &&self.key[..])
             .field("info", &self.info)
+            .field("present", &self.present)
             .finish()
     }
 }
@@ -157,6 +446,10 @@ impl<T> std::fmt::Debug for Node48<T> {
 struct Node256<T> {
     child_pointers: [*mut Node<T>; 256],
     info: Info,
+    // Bytes with a child
+    present: Bitmap,
+    aggregate: Option<T>,
+    best: *mut Node<T>,
 }
 
 // std::fmt::Debug is not implemented for arrays with size >= 32
@@ -165,15 +458,21 @@ impl<T> std::fmt::Debug for Node256<T> {
         fmt.debug_struct("Node256")
             .field("child_pointers", &&self.child_pointers[..])
             .field("info", &self.info)
+            .field("present", &self.present)
             .finish()
     }
 }
 
 // A leaf node which contains a value and a full key
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct LeafNode<T> {
     key: Vec<u8>,
+    // Hash of the key, compared before the key itself so most long keys
+    // that don't match are told apart without reading them
+    fingerprint: u64,
+    // Deleted, but left in the tree until `compact`
+    dead: bool,
     value: T,
 }
 
@@ -188,8 +487,10 @@ impl<T> Node4<T> {
             info: Info {
                 count: 0,
                 partial,
-                partial_len: min,
+                partial_len: prefix.len(),
             },
+            aggregate: None,
+            best: ptr::null_mut(),
             key: [0; 4],
         }
     }
@@ -199,6 +500,8 @@ impl<T> Node4<T> {
         Self {
             child_pointers: [std::ptr::null_mut(); 4],
             info,
+            aggregate: None,
+            best: ptr::null_mut(),
             key: [0; 4],
         }
     }
@@ -224,37 +527,71 @@ impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node4<T> {
         self.child_pointers[i] = node;
     }
     fn find_child(&mut self, key: u8) -> Option<&mut *mut Node<T>> {
-        for i in 0..self.info.count as usize {
+        for i in 0..self.info.count {
             if key == self.key[i] {
                 return Some(&mut self.child_pointers[i]);
             }
         }
         None
     }
+    fn child(&self, key: u8) -> Option<*mut Node<T>> {
+        let i = self.key[..self.info.count].iter().position(|&k| k == key)?;
+        Some(self.child_pointers[i])
+    }
+    fn clone_node(&self) -> Box<dyn ArtNode<T>>
+    where
+        T: Clone,
+    {
+        let mut node = Self {
+            child_pointers: self.child_pointers,
+            aggregate: self.aggregate.clone(),
+            // Points into the other tree, the copy of the tree sets it
+            best: ptr::null_mut(),
+            ..*self
+        };
+        for child in node.child_pointers.iter_mut() {
+            *child = clone_tree(*child);
+        }
+        Box::new(node)
+    }
     fn info(&self) -> &Info {
         &self.info
     }
     fn info_mut(&mut self) -> &mut Info {
         &mut self.info
     }
+    fn aggregate(&self) -> Option<&T> {
+        self.aggregate.as_ref()
+    }
+    fn set_aggregate(&mut self, aggregate: Option<T>) {
+        self.aggregate = aggregate;
+    }
+    fn best(&self) -> *mut Node<T> {
+        self.best
+    }
+    fn set_best(&mut self, best: *mut Node<T>) {
+        self.best = best;
+    }
     fn child_pointers(&self) -> &[*mut Node<T>] {
         &self.child_pointers
     }
-    fn prefix(&self, key: &[u8]) -> usize {
-        common_prefix(&self.info.partial[..self.info.partial_len], &key)
+    fn push_children(&self, stack: &mut Vec<*mut Node<T>>) {
+        stack.extend(self.child_pointers[..self.info.count].iter().rev());
     }
     fn insert(
         &mut self,
         key_bytes: &[u8],
         depth: &mut usize,
         iter_node: &mut *mut Node<T>,
-        new_leaf: *mut Node<T>,
+        new_leaf: &mut NewLeaf<T>,
         parent_node: &mut *mut *mut Node<T>,
+        metrics: &Metrics,
     ) -> bool {
         // Condition to continue loop or not
         let mut cont = true;
         // Check for a split and perform split if needed
-        let (splitted, n) = self.split_check(key_bytes, depth, iter_node, new_leaf, parent_node);
+        let (splitted, n) =
+            self.split_check(key_bytes, depth, iter_node, new_leaf, parent_node, metrics);
         if splitted {
             return !splitted;
         }
@@ -263,26 +600,30 @@ impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node4<T> {
             *iter_node = *node;
         } else {
             if self.info.count < 4 {
-                self.add(new_leaf, &key_bytes, *depth);
+                self.add(new_leaf.take(metrics), key_bytes, *depth);
             } else {
                 // If we don't have space to insert a new node => expand
+                metrics.incr(&metrics.grows);
+                trace_event!(node = "Node4", depth = *depth, "grow to Node16");
+                metrics.resize::<T>(4, 16);
+                paranoid_assert!(self.info.count == 4, "growing a Node4 that isn't full");
                 unsafe {
                     let mut new_node = Node16::new_with_info(self.info);
                     // memcpy
                     ptr::copy_nonoverlapping(
-                        (&self.key).as_ptr(),
-                        (&mut new_node.key).as_mut_ptr(),
+                        self.key.as_ptr(),
+                        new_node.key.as_mut_ptr(),
                         self.info.count,
                     );
                     // memcpy
                     ptr::copy_nonoverlapping(
-                        (&self.child_pointers).as_ptr(),
-                        (&mut new_node.child_pointers).as_mut_ptr(),
+                        self.child_pointers.as_ptr(),
+                        new_node.child_pointers.as_mut_ptr(),
                         self.info.count,
                     );
-                    new_node.add(new_leaf, &key_bytes, *depth);
+                    new_node.add(new_leaf.take(metrics), key_bytes, *depth);
                     // Free memory for the current node
-                    Box::from_raw(*iter_node);
+                    drop(Box::from_raw(*iter_node));
                     **parent_node = Box::into_raw(Box::new(Node::ArtNode(Box::new(new_node))));
                 }
             }
@@ -295,63 +636,49 @@ impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node4<T> {
         parent_node: *mut *mut Node<T>,
         ref_node: *mut *mut Node<T>,
         _key: u8,
+        metrics: &Metrics,
     ) {
         unsafe {
             // Calculating offset in the `child_pointers` to basicly get an index
-            let position = ref_node.offset_from((&self.child_pointers).as_ptr());
+            let position = ref_node.offset_from(self.child_pointers.as_ptr());
+            paranoid_assert!(
+                0 <= position && (position as usize) < self.info.count,
+                "child pointer outside of the node"
+            );
             // memmove
             ptr::copy(
-                (&self.key).as_ptr().offset(position + 1),
-                (&mut self.key).as_mut_ptr().offset(position),
+                self.key.as_ptr().offset(position + 1),
+                self.key.as_mut_ptr().offset(position),
                 self.info.count - 1 - position as usize,
             );
             // memmove
             ptr::copy(
-                (&self.child_pointers).as_ptr().offset(position + 1),
-                (&mut self.child_pointers).as_mut_ptr().offset(position),
+                self.child_pointers.as_ptr().offset(position + 1),
+                self.child_pointers.as_mut_ptr().offset(position),
                 self.info.count - 1 - position as usize,
             );
         }
         self.info.count -= 1;
+        // Clear the vacated slot so it doesn't keep a dangling pointer
+        self.child_pointers[self.info.count] = ptr::null_mut();
         // If number of childs is equal 1, we want to concat
         // parent and child node together and free the memory
         if self.info.count == 1 {
+            metrics.incr(&metrics.shrinks);
+            trace_event!(node = "Node4", "merge into the only child");
+            metrics.free_inner::<T>(4);
             let node = self.child_pointers[0];
             if let Node::ArtNode(n) = unsafe { &mut *node } {
-                let mut prefix: usize = self.info.partial_len;
-                if prefix < MAX_PREFIX_LEN {
-                    // Place key-byte to the end of the partial
-                    // to later copy it to a leaf
-                    self.info.partial[prefix] = self.key[0];
-                    prefix += 1;
-                }
-                let info = n.info_mut();
-                unsafe {
-                    if prefix < MAX_PREFIX_LEN {
-                        // Calculate the remaining prefix
-                        let sub_prefix = std::cmp::min(info.partial_len, MAX_PREFIX_LEN - prefix);
-                        // Memcpy the remaining prefix to concat it
-                        ptr::copy_nonoverlapping(
-                            (&info.partial).as_ptr(),
-                            (&mut self.info.partial).as_mut_ptr().add(prefix),
-                            sub_prefix,
-                        );
-                        prefix += sub_prefix;
-                    }
-                    // Memcpy whole partial prefix
-                    ptr::copy_nonoverlapping(
-                        (&self.info.partial).as_ptr(),
-                        (&mut info.partial).as_mut_ptr(),
-                        std::cmp::min(prefix, MAX_PREFIX_LEN),
-                    );
-                    // Because we added key-byte to the end of partial
-                    // we have to add 1
-                    info.partial_len += self.info.partial_len + 1;
-                }
+                // The child partial already starts with its key-byte,
+                // so only our partial is placed in front of it
+                let info = self.info;
+                let stored = std::cmp::min(info.partial_len, MAX_PREFIX_LEN);
+                n.info_mut()
+                    .prepend(&info.partial[..stored], info.partial_len);
             }
             unsafe {
                 // Free the memory
-                Box::from_raw(*parent_node);
+                drop(Box::from_raw(*parent_node));
                 *parent_node = node;
             }
         }
@@ -359,6 +686,7 @@ impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node4<T> {
 }
 
 impl<T> Node16<T> {
+    #[allow(dead_code)]
     fn new(prefix: &[u8]) -> Self {
         let min = std::cmp::min(MAX_PREFIX_LEN, prefix.len());
         let mut partial = [0; MAX_PREFIX_LEN];
@@ -368,8 +696,10 @@ impl<T> Node16<T> {
             info: Info {
                 count: 0,
                 partial,
-                partial_len: min,
+                partial_len: prefix.len(),
             },
+            aggregate: None,
+            best: ptr::null_mut(),
             key: [0; 16],
         }
     }
@@ -378,6 +708,8 @@ impl<T> Node16<T> {
         Self {
             child_pointers: [std::ptr::null_mut(); 16],
             info,
+            aggregate: None,
+            best: ptr::null_mut(),
             key: [0; 16],
         }
     }
@@ -385,56 +717,39 @@ impl<T> Node16<T> {
 
 impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node16<T> {
     fn add(&mut self, node: *mut Node<T>, key: &[u8], depth: usize) {
-        // Create a mask with length equal to number
-        // of `child_pointers`
-        let mask = (1 << self.info.count) - 1;
-        unsafe {
-            // Compare less than with searched byte
-            // for 16 bytes at once
-            let cmp = _mm_cmplt_epi8(
-                _mm_set1_epi8(key[depth] as i8),
-                _mm_loadu_si128((&self.key).as_ptr() as *const __m128i),
-            );
-
-            // Apply the mask
-            let bitfield = _mm_movemask_epi8(cmp) & mask;
-            let i: usize;
-            if bitfield > 0 {
-                // Trailing zeros represents index
-                i = bitfield.trailing_zeros() as usize;
-                // Safe memmove (Maybe should make it unsafe to
-                // avoid unnecessary bound check
-                self.key.copy_within(i..self.info.count, i + 1);
-                self.child_pointers.copy_within(i..self.info.count, i + 1);
-            } else {
-                // If all elements is less than the key, insert to the end
-                i = self.info.count;
-            }
-            // Insert the new node
-            self.key[i] = key[depth];
-            self.child_pointers[i] = node;
-            self.info.count += 1;
-        }
+        paranoid_assert!(self.info.count < 16, "Node16 is full");
+        let i = bytes::insert_position(&self.key, self.info.count, key[depth]);
+        // Shift the greater keys to make space for the new one
+        self.key.copy_within(i..self.info.count, i + 1);
+        self.child_pointers.copy_within(i..self.info.count, i + 1);
+        // Insert the new node
+        self.key[i] = key[depth];
+        self.child_pointers[i] = node;
+        self.info.count += 1;
     }
     fn find_child(&mut self, key: u8) -> Option<&mut *mut Node<T>> {
-        let mask = (1 << self.info.count) - 1;
-        unsafe {
-            // Compare less than with searched byte
-            // for 16 bytes at once
-            let cmp = _mm_cmpeq_epi8(
-                _mm_set1_epi8(key as i8),
-                _mm_loadu_si128((&self.key).as_ptr() as *const __m128i),
-            );
-
-            // Apply the mask
-            let bitfield = _mm_movemask_epi8(cmp) & mask;
-            if bitfield != 0 {
-                // Return index
-                let i = bitfield.trailing_zeros() as usize;
-                return Some(&mut self.child_pointers[i]);
-            }
-            return None;
+        let i = bytes::position(&self.key, self.info.count, key)?;
+        Some(&mut self.child_pointers[i])
+    }
+    fn child(&self, key: u8) -> Option<*mut Node<T>> {
+        let i = bytes::position(&self.key, self.info.count, key)?;
+        Some(self.child_pointers[i])
+    }
+    fn clone_node(&self) -> Box<dyn ArtNode<T>>
+    where
+        T: Clone,
+    {
+        let mut node = Self {
+            child_pointers: self.child_pointers,
+            aggregate: self.aggregate.clone(),
+            // Points into the other tree, the copy of the tree sets it
+            best: ptr::null_mut(),
+            ..*self
+        };
+        for child in node.child_pointers.iter_mut() {
+            *child = clone_tree(*child);
         }
+        Box::new(node)
     }
     fn info(&self) -> &Info {
         &self.info
@@ -442,24 +757,38 @@ impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node16<T> {
     fn info_mut(&mut self) -> &mut Info {
         &mut self.info
     }
+    fn aggregate(&self) -> Option<&T> {
+        self.aggregate.as_ref()
+    }
+    fn set_aggregate(&mut self, aggregate: Option<T>) {
+        self.aggregate = aggregate;
+    }
+    fn best(&self) -> *mut Node<T> {
+        self.best
+    }
+    fn set_best(&mut self, best: *mut Node<T>) {
+        self.best = best;
+    }
     fn child_pointers(&self) -> &[*mut Node<T>] {
         &self.child_pointers
     }
-    fn prefix(&self, key: &[u8]) -> usize {
-        common_prefix(&self.info.partial[..self.info.partial_len], &key)
+    fn push_children(&self, stack: &mut Vec<*mut Node<T>>) {
+        stack.extend(self.child_pointers[..self.info.count].iter().rev());
     }
     fn insert(
         &mut self,
         key_bytes: &[u8],
         depth: &mut usize,
         iter_node: &mut *mut Node<T>,
-        new_leaf: *mut Node<T>,
+        new_leaf: &mut NewLeaf<T>,
         parent_node: &mut *mut *mut Node<T>,
+        metrics: &Metrics,
     ) -> bool {
         // Condition to continue loop or not
         let mut cont = true;
         // Check for a split and perform split if needed
-        let (splitted, n) = self.split_check(key_bytes, depth, iter_node, new_leaf, parent_node);
+        let (splitted, n) =
+            self.split_check(key_bytes, depth, iter_node, new_leaf, parent_node, metrics);
         if splitted {
             return !splitted;
         }
@@ -468,22 +797,27 @@ impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node16<T> {
             *iter_node = *node;
         } else {
             if self.info.count < 16 {
-                self.add(new_leaf, &key_bytes, *depth);
+                self.add(new_leaf.take(metrics), key_bytes, *depth);
             } else {
+                metrics.incr(&metrics.grows);
+                trace_event!(node = "Node16", depth = *depth, "grow to Node48");
+                metrics.resize::<T>(16, 48);
+                paranoid_assert!(self.info.count == 16, "growing a Node16 that isn't full");
                 unsafe {
                     // If we don't have space to insert a new node => expand
                     let mut new_node = Node48::new_with_info(self.info);
                     // Memcpy
                     ptr::copy_nonoverlapping(
-                        (&self.child_pointers).as_ptr(),
-                        (&mut new_node.child_pointers).as_mut_ptr(),
+                        self.child_pointers.as_ptr(),
+                        new_node.child_pointers.as_mut_ptr(),
                         self.info.count,
                     );
                     for i in 0..self.info.count {
                         new_node.key[self.key[i] as usize] = i as u8;
+                        new_node.present.set(self.key[i]);
                     }
-                    new_node.add(new_leaf, &key_bytes, *depth);
-                    Box::from_raw(*iter_node);
+                    new_node.add(new_leaf.take(metrics), key_bytes, *depth);
+                    drop(Box::from_raw(*iter_node));
                     **parent_node = Box::into_raw(Box::new(Node::ArtNode(Box::new(new_node))));
                 }
             }
@@ -496,33 +830,43 @@ impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node16<T> {
         parent_node: *mut *mut Node<T>,
         ref_node: *mut *mut Node<T>,
         _key: u8,
+        metrics: &Metrics,
     ) {
         unsafe {
             // Calculating offset in the `child_pointers` to basicly get an index
-            let position = ref_node.offset_from((&self.child_pointers).as_ptr());
+            let position = ref_node.offset_from(self.child_pointers.as_ptr());
+            paranoid_assert!(
+                0 <= position && (position as usize) < self.info.count,
+                "child pointer outside of the node"
+            );
             ptr::copy(
-                (&self.key).as_ptr().offset(position + 1),
-                (&mut self.key).as_mut_ptr().offset(position),
+                self.key.as_ptr().offset(position + 1),
+                self.key.as_mut_ptr().offset(position),
                 self.info.count - 1 - position as usize,
             );
             ptr::copy(
-                (&self.child_pointers).as_ptr().offset(position + 1),
-                (&mut self.child_pointers).as_mut_ptr().offset(position),
+                self.child_pointers.as_ptr().offset(position + 1),
+                self.child_pointers.as_mut_ptr().offset(position),
                 self.info.count - 1 - position as usize,
             );
         }
         self.info.count -= 1;
+        // Clear the vacated slot so it doesn't keep a dangling pointer
+        self.child_pointers[self.info.count] = ptr::null_mut();
         // If count == 3 we want to shrink `Node16` to `Node4`
         if self.info.count == 3 {
+            metrics.incr(&metrics.shrinks);
+            trace_event!(node = "Node16", "shrink to Node4");
+            metrics.resize::<T>(16, 4);
             let mut new_node = Node4::new_with_info(self.info);
             unsafe {
-                ptr::copy_nonoverlapping((&self.key).as_ptr(), (&mut new_node.key).as_mut_ptr(), 4);
+                ptr::copy_nonoverlapping(self.key.as_ptr(), new_node.key.as_mut_ptr(), 4);
                 ptr::copy_nonoverlapping(
-                    (&self.child_pointers).as_ptr(),
-                    (&mut new_node.child_pointers).as_mut_ptr(),
+                    self.child_pointers.as_ptr(),
+                    new_node.child_pointers.as_mut_ptr(),
                     4,
                 );
-                Box::from_raw(*parent_node);
+                drop(Box::from_raw(*parent_node));
                 *parent_node = Box::into_raw(Box::new(Node::ArtNode(Box::new(new_node))));
             }
         }
@@ -530,6 +874,7 @@ impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node16<T> {
 }
 
 impl<T> Node48<T> {
+    #[allow(dead_code)]
     fn new(prefix: &[u8]) -> Self {
         let min = std::cmp::min(MAX_PREFIX_LEN, prefix.len());
         let mut partial = [0; MAX_PREFIX_LEN];
@@ -539,8 +884,11 @@ impl<T> Node48<T> {
             info: Info {
                 count: 0,
                 partial,
-                partial_len: min,
+                partial_len: prefix.len(),
             },
+            present: Bitmap::default(),
+            aggregate: None,
+            best: ptr::null_mut(),
             key: [48; 256],
         }
     }
@@ -549,6 +897,9 @@ impl<T> Node48<T> {
         Self {
             child_pointers: [std::ptr::null_mut(); 48],
             info,
+            present: Bitmap::default(),
+            aggregate: None,
+            best: ptr::null_mut(),
             key: [48; 256],
         }
     }
@@ -563,6 +914,7 @@ impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node48<T> {
         }
         self.child_pointers[i] = node;
         self.key[key[depth] as usize] = i as u8;
+        self.present.set(key[depth]);
         self.info.count += 1;
     }
     fn find_child(&mut self, key: u8) -> Option<&mut *mut Node<T>> {
@@ -571,8 +923,27 @@ impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node48<T> {
         }
         None
     }
-    fn prefix(&self, key: &[u8]) -> usize {
-        common_prefix(&self.info.partial[..self.info.partial_len], &key)
+    fn child(&self, key: u8) -> Option<*mut Node<T>> {
+        match self.key[key as usize] {
+            48 => None,
+            position => Some(self.child_pointers[position as usize]),
+        }
+    }
+    fn clone_node(&self) -> Box<dyn ArtNode<T>>
+    where
+        T: Clone,
+    {
+        let mut node = Self {
+            child_pointers: self.child_pointers,
+            aggregate: self.aggregate.clone(),
+            // Points into the other tree, the copy of the tree sets it
+            best: ptr::null_mut(),
+            ..*self
+        };
+        for child in node.child_pointers.iter_mut() {
+            *child = clone_tree(*child);
+        }
+        Box::new(node)
     }
     fn info(&self) -> &Info {
         &self.info
@@ -580,21 +951,44 @@ impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node48<T> {
     fn info_mut(&mut self) -> &mut Info {
         &mut self.info
     }
+    fn aggregate(&self) -> Option<&T> {
+        self.aggregate.as_ref()
+    }
+    fn set_aggregate(&mut self, aggregate: Option<T>) {
+        self.aggregate = aggregate;
+    }
+    fn best(&self) -> *mut Node<T> {
+        self.best
+    }
+    fn set_best(&mut self, best: *mut Node<T>) {
+        self.best = best;
+    }
     fn child_pointers(&self) -> &[*mut Node<T>] {
         &self.child_pointers
     }
+    fn push_children(&self, stack: &mut Vec<*mut Node<T>>) {
+        let start = stack.len();
+        stack.extend(
+            self.present
+                .iter()
+                .map(|byte| self.child_pointers[self.key[byte as usize] as usize]),
+        );
+        stack[start..].reverse();
+    }
     fn insert(
         &mut self,
         key_bytes: &[u8],
         depth: &mut usize,
         iter_node: &mut *mut Node<T>,
-        new_leaf: *mut Node<T>,
+        new_leaf: &mut NewLeaf<T>,
         parent_node: &mut *mut *mut Node<T>,
+        metrics: &Metrics,
     ) -> bool {
         // Condition to continue loop or not
         let mut cont = true;
         // Check for a split and perform split if needed
-        let (splitted, n) = self.split_check(key_bytes, depth, iter_node, new_leaf, parent_node);
+        let (splitted, n) =
+            self.split_check(key_bytes, depth, iter_node, new_leaf, parent_node, metrics);
         if splitted {
             return !splitted;
         }
@@ -603,18 +997,21 @@ impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node48<T> {
             *iter_node = *node;
         } else {
             if self.info.count < 48 {
-                self.add(new_leaf, &key_bytes, *depth);
+                self.add(new_leaf.take(metrics), key_bytes, *depth);
             } else {
                 // If we don't have space to insert a new node => expand
+                metrics.incr(&metrics.grows);
+                trace_event!(node = "Node48", depth = *depth, "grow to Node256");
+                metrics.resize::<T>(48, 256);
                 let mut new_node = Node256::new_with_info(self.info);
-                for i in 0..256 {
-                    if self.key[i] != 48 {
-                        new_node.child_pointers[i] = self.child_pointers[self.key[i] as usize];
-                    }
+                for byte in self.present.iter() {
+                    new_node.child_pointers[byte as usize] =
+                        self.child_pointers[self.key[byte as usize] as usize];
                 }
-                new_node.add(new_leaf, &key_bytes, *depth);
+                new_node.present = self.present;
+                new_node.add(new_leaf.take(metrics), key_bytes, *depth);
                 unsafe {
-                    Box::from_raw(*iter_node);
+                    drop(Box::from_raw(*iter_node));
                     **parent_node = Box::into_raw(Box::new(Node::ArtNode(Box::new(new_node))));
                 }
             }
@@ -627,27 +1024,29 @@ impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node48<T> {
         parent_node: *mut *mut Node<T>,
         _ref_node: *mut *mut Node<T>,
         key: u8,
+        metrics: &Metrics,
     ) {
         // Delete child
         let mut position = self.key[key as usize];
+        paranoid_assert!(position < 48, "deleting a missing child");
         self.key[key as usize] = 48;
+        self.present.clear(key);
         self.child_pointers[position as usize] = ptr::null_mut();
         self.info.count -= 1;
 
         // If count == 12 we want to shrink `Node48` to `Node16`
         if self.info.count == 12 {
+            metrics.incr(&metrics.shrinks);
+            trace_event!(node = "Node48", "shrink to Node16");
+            metrics.resize::<T>(48, 16);
             let mut new_node = Node16::new_with_info(self.info);
-            let mut count = 0;
-            for i in 0..256 {
-                position = self.key[i];
-                if position != 48 {
-                    new_node.key[count] = i as u8;
-                    new_node.child_pointers[count] = self.child_pointers[position as usize];
-                    count += 1;
-                }
+            for (count, byte) in self.present.iter().enumerate() {
+                position = self.key[byte as usize];
+                new_node.key[count] = byte;
+                new_node.child_pointers[count] = self.child_pointers[position as usize];
             }
             unsafe {
-                Box::from_raw(*parent_node);
+                drop(Box::from_raw(*parent_node));
                 *parent_node = Box::into_raw(Box::new(Node::ArtNode(Box::new(new_node))));
             }
         }
@@ -655,6 +1054,7 @@ impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node48<T> {
 }
 
 impl<T> Node256<T> {
+    #[allow(dead_code)]
     fn new(prefix: &[u8]) -> Self {
         let min = std::cmp::min(MAX_PREFIX_LEN, prefix.len());
         let mut partial = [0; MAX_PREFIX_LEN];
@@ -664,8 +1064,11 @@ impl<T> Node256<T> {
             info: Info {
                 count: 0,
                 partial,
-                partial_len: min,
+                partial_len: prefix.len(),
             },
+            present: Bitmap::default(),
+            aggregate: None,
+            best: ptr::null_mut(),
         }
     }
 
@@ -673,6 +1076,9 @@ impl<T> Node256<T> {
         Self {
             child_pointers: [std::ptr::null_mut(); 256],
             info,
+            present: Bitmap::default(),
+            aggregate: None,
+            best: ptr::null_mut(),
         }
     }
 }
@@ -680,6 +1086,7 @@ impl<T> Node256<T> {
 impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node256<T> {
     fn add(&mut self, node: *mut Node<T>, key: &[u8], depth: usize) {
         self.child_pointers[key[depth] as usize] = node;
+        self.present.set(key[depth]);
         self.info.count += 1;
     }
     fn find_child(&mut self, key: u8) -> Option<&mut *mut Node<T>> {
@@ -688,30 +1095,73 @@ impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node256<T> {
         }
         None
     }
+    fn child(&self, key: u8) -> Option<*mut Node<T>> {
+        Some(self.child_pointers[key as usize]).filter(|child| !child.is_null())
+    }
+    fn clone_node(&self) -> Box<dyn ArtNode<T>>
+    where
+        T: Clone,
+    {
+        let mut node = Self {
+            child_pointers: self.child_pointers,
+            aggregate: self.aggregate.clone(),
+            // Points into the other tree, the copy of the tree sets it
+            best: ptr::null_mut(),
+            ..*self
+        };
+        for child in node.child_pointers.iter_mut() {
+            *child = clone_tree(*child);
+        }
+        Box::new(node)
+    }
     fn info(&self) -> &Info {
         &self.info
     }
     fn info_mut(&mut self) -> &mut Info {
         &mut self.info
     }
+    fn aggregate(&self) -> Option<&T> {
+        self.aggregate.as_ref()
+    }
+    fn set_aggregate(&mut self, aggregate: Option<T>) {
+        self.aggregate = aggregate;
+    }
+    fn best(&self) -> *mut Node<T> {
+        self.best
+    }
+    fn set_best(&mut self, best: *mut Node<T>) {
+        self.best = best;
+    }
     fn child_pointers(&self) -> &[*mut Node<T>] {
         &self.child_pointers
     }
-    fn prefix(&self, key: &[u8]) -> usize {
-        common_prefix(&self.info.partial[..self.info.partial_len], &key)
+    fn push_children(&self, stack: &mut Vec<*mut Node<T>>) {
+        let start = stack.len();
+        stack.extend(
+            self.present
+                .iter()
+                .map(|byte| self.child_pointers[byte as usize]),
+        );
+        stack[start..].reverse();
+    }
+    fn leaf_key(&self) -> Vec<u8> {
+        let byte = self.present.iter().next().unwrap();
+        first_leaf_key(self.child_pointers[byte as usize])
     }
     fn insert(
         &mut self,
         key_bytes: &[u8],
         depth: &mut usize,
         iter_node: &mut *mut Node<T>,
-        new_leaf: *mut Node<T>,
+        new_leaf: &mut NewLeaf<T>,
         parent_node: &mut *mut *mut Node<T>,
+        metrics: &Metrics,
     ) -> bool {
         // Condition to continue loop or not
         let mut cont = true;
         // Check for a split and perform split if needed
-        let (splitted, n) = self.split_check(key_bytes, depth, iter_node, new_leaf, parent_node);
+        let (splitted, n) =
+            self.split_check(key_bytes, depth, iter_node, new_leaf, parent_node, metrics);
         if splitted {
             return !splitted;
         }
@@ -719,7 +1169,7 @@ impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node256<T> {
             *parent_node = node;
             *iter_node = *node;
         } else {
-            self.add(new_leaf, &key_bytes, *depth);
+            self.add(new_leaf.take(metrics), key_bytes, *depth);
             cont = false;
         }
         cont
@@ -729,297 +1179,3805 @@ impl<T: 'static + std::fmt::Debug> ArtNode<T> for Node256<T> {
         parent_node: *mut *mut Node<T>,
         _ref_node: *mut *mut Node<T>,
         key: u8,
+        metrics: &Metrics,
     ) {
         // Delete child
+        paranoid_assert!(
+            !self.child_pointers[key as usize].is_null(),
+            "deleting a missing child"
+        );
         self.child_pointers[key as usize] = ptr::null_mut();
+        self.present.clear(key);
         self.info.count -= 1;
 
         // If count == 35 we wan't to shrink `Node256` to `Node48`
         // (35 is chosen because we don't want to reallocate too much)
         if self.info.count == 35 {
+            metrics.incr(&metrics.shrinks);
+            trace_event!(node = "Node256", "shrink to Node48");
+            metrics.resize::<T>(256, 48);
             let mut new_node = Node48::new_with_info(self.info);
-            let mut position = 0;
-            for i in 0..256 {
-                if !self.child_pointers[i].is_null() {
-                    new_node.child_pointers[position] = self.child_pointers[i];
-                    new_node.key[i] = position as u8;
-                    position += 1;
-                }
+            for (position, byte) in self.present.iter().enumerate() {
+                new_node.child_pointers[position] = self.child_pointers[byte as usize];
+                new_node.key[byte as usize] = position as u8;
             }
+            new_node.present = self.present;
             unsafe {
-                Box::from_raw(*parent_node);
+                drop(Box::from_raw(*parent_node));
                 *parent_node = Box::into_raw(Box::new(Node::ArtNode(Box::new(new_node))));
             }
         }
     }
 }
 
+// Leaf of an insert, only allocated once the insert knows it links a new
+// leaf and doesn't just rewrite the value of an existing one
+struct NewLeaf<'a, T> {
+    key: &'a [u8],
+    value: Option<T>,
+}
+
+impl<'a, T> NewLeaf<'a, T> {
+    fn take(&mut self, metrics: &Metrics) -> *mut Node<T> {
+        metrics.alloc_leaf::<T>(self.key.len());
+        let value = self.value.take().expect("the new leaf is linked once");
+        Box::into_raw(Box::new(Node::Leaf(LeafNode::new(value, self.key))))
+    }
+}
+
 impl<T> LeafNode<T> {
     fn new(value: T, key: &[u8]) -> Self {
         Self {
             value,
             key: key.to_vec(),
+            fingerprint: fingerprint(key),
+            dead: false,
         }
     }
+
+    // Whether the leaf holds the key, the bytes before `depth` are
+    // already known to match. Equal fingerprints mean equal last bytes,
+    // so only the bytes between `depth` and those are compared after them
+    fn matches(&self, key_bytes: &[u8], depth: usize) -> bool {
+        let len = key_bytes.len();
+        let end = std::cmp::max(len.saturating_sub(FINGERPRINT_LEN), depth);
+        self.key.len() == len
+            && self.fingerprint == fingerprint(key_bytes)
+            && self.key[depth..end] == key_bytes[depth..end]
+    }
+}
+
+// Number of bytes at the end of a key in its fingerprint
+const FINGERPRINT_LEN: usize = 8;
+
+// Last `FINGERPRINT_LEN` bytes of the key. Long keys sharing a prefix
+// mostly differ at the end, so most leaves that don't hold a key are told
+// apart in one compare, without reading the rest of the key. Hashing the
+// whole key instead would read all of it on every check
+fn fingerprint(key: &[u8]) -> u64 {
+    let tail = &key[key.len().saturating_sub(FINGERPRINT_LEN)..];
+    let mut bytes = [0; FINGERPRINT_LEN];
+    bytes[..tail.len()].copy_from_slice(tail);
+    u64::from_le_bytes(bytes)
+}
+
+// Inner node type with room for `capacity` children
+#[cfg(feature = "tracing")]
+fn node_type(capacity: usize) -> &'static str {
+    match capacity {
+        4 => "Node4",
+        16 => "Node16",
+        48 => "Node48",
+        _ => "Node256",
+    }
 }
 
 // Calculate a number of equal bytes in two slices
 fn common_prefix(key: &[u8], partial: &[u8]) -> usize {
-    key.iter()
-        .zip(partial.iter())
-        .take_while(|&(a, b)| a == b)
-        .count()
+    bytes::common_prefix(key, partial)
 }
 
-pub struct Art<K, T: 'static + std::fmt::Debug> {
-    root: *mut Node<T>,
-    key: PhantomData<K>,
+// Counters of structural changes in the tree. They are atomics so that
+// read-only lookups can record prefix mismatches as well, also from
+// several threads at once. Counting is off until `Art::set_metrics` turns
+// it on, the counters stay at 0 then. There is no counter of restarts:
+// operations on the tree never retry, it has no optimistic locks whose
+// version checks could fail
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counting: bool,
+    splits: AtomicU64,
+    grows: AtomicU64,
+    shrinks: AtomicU64,
+    prefix_mismatches: AtomicU64,
+    // Approximate number of bytes held by the nodes. It is a gauge rather
+    // than a counter, so `reset` leaves it alone
+    memory: AtomicUsize,
+    #[cfg(feature = "alloc-profile")]
+    profile: Profile,
 }
 
-// Free all tree recursive
-fn free_tree<T: 'static + std::fmt::Debug>(node: *mut Node<T>) {
-    if node.is_null() {
-        return;
-    }
-    if let Node::ArtNode(n) = unsafe { &*node } {
-        let child_pointers = n.child_pointers();
-        for ptr in child_pointers.iter() {
-            free_tree(*ptr);
+impl Clone for Metrics {
+    fn clone(&self) -> Self {
+        let copy = |counter: &AtomicU64| AtomicU64::new(counter.load(Ordering::Relaxed));
+        Self {
+            counting: self.counting,
+            splits: copy(&self.splits),
+            grows: copy(&self.grows),
+            shrinks: copy(&self.shrinks),
+            prefix_mismatches: copy(&self.prefix_mismatches),
+            memory: AtomicUsize::new(self.memory()),
+            #[cfg(feature = "alloc-profile")]
+            profile: self.profile.clone(),
         }
     }
-    unsafe {
-        Box::from_raw(node);
-    }
 }
 
-impl<K, T: 'static + std::fmt::Debug> Drop for Art<K, T> {
-    fn drop(&mut self) {
-        free_tree::<T>(self.root)
+// Index of an inner node type in `AllocProfile::kinds`, leaves are 0
+fn inner_kind(capacity: usize) -> usize {
+    match capacity {
+        4 => 1,
+        16 => 2,
+        48 => 3,
+        _ => 4,
     }
 }
 
-impl<K, T> Art<K, T>
-where
-    K: ArtKey + std::marker::Sized + std::fmt::Debug,
-    T: 'static + Clone + std::fmt::Debug,
-{
-    pub fn new() -> Self {
-        Self {
-            root: std::ptr::null_mut(),
-            key: PhantomData,
-        }
+// Allocation counters of the `alloc-profile` feature, by node type
+#[cfg(feature = "alloc-profile")]
+#[derive(Debug, Default)]
+struct Profile {
+    allocs: [AtomicU64; 5],
+    frees: [AtomicU64; 5],
+    live: [AtomicUsize; 5],
+    peak: AtomicUsize,
+}
+
+#[cfg(feature = "alloc-profile")]
+impl Profile {
+    fn alloc(&self, kind: usize, bytes: usize, memory: usize) {
+        self.allocs[kind].fetch_add(1, Ordering::Relaxed);
+        self.live[kind].fetch_add(bytes, Ordering::Relaxed);
+        self.peak.fetch_max(memory, Ordering::Relaxed);
     }
 
-    // Count a number of nodes in the tree
-    pub fn bfs_count(&self) -> usize {
-        let mut count = 0;
-        if self.root.is_null() {
-            return count;
-        }
-        let mut queue = VecDeque::new();
-        queue.push_back(self.root);
-        while !queue.is_empty() {
-            let node = queue.pop_front().unwrap();
-            match unsafe { &*node } {
-                Node::ArtNode(n) => {
-                    count += 1;
-                    let pointers = n.child_pointers();
-                    let info = n.info();
-                    for i in 0..info.count {
-                        queue.push_back(pointers[i]);
-                    }
-                }
-                Node::Leaf(_) => {
-                    count += 1;
-                }
-            }
-        }
-        count
+    fn free(&self, kind: usize, bytes: usize) {
+        self.frees[kind].fetch_add(1, Ordering::Relaxed);
+        self.live[kind].fetch_sub(bytes, Ordering::Relaxed);
     }
 
-    // Delete value from the tree
-    pub fn delete(&mut self, key: K) {
-        let key_bytes = key.bytes();
-        let mut ref_node = &mut self.root as *mut *mut Node<T>;
-        let mut parent_node = &mut self.root as *mut *mut Node<T>;
-        let mut iter_node = self.root;
-        let mut depth = 0;
-        let mut key = 0;
-        while !iter_node.is_null() {
-            unsafe {
-                println!("iter_node: {:?}, {:?}", *iter_node, key_bytes);
-            }
-            match unsafe { &mut *iter_node } {
-                Node::ArtNode(node) => {
-                    depth += node.prefix(&key_bytes[depth..]);
-                    // In this case we want last element
-                    if depth == key_bytes.len() {
-                        depth -= 1;
-                    }
-                    // Iterate until we hit a leaf or don't find any child
-                    if let Some(n) = node.find_child(key_bytes[depth]) {
-                        key = key_bytes[depth];
-                        parent_node = ref_node;
-                        ref_node = n;
-                        iter_node = *n;
-                    } else {
-                        break;
-                    }
-                }
-                Node::Leaf(node) => {
-                    depth += common_prefix(&node.key[depth..], &key_bytes[depth..]);
-                    if depth == node.key.len() {
-                        unsafe {
-                            match &mut **parent_node {
-                                Node::ArtNode(node) => {
-                                    node.delete_child(parent_node, ref_node, key);
-                                }
-                                // Initial case then parent and child node
-                                // might be leaves at the same time
-                                Node::Leaf(_) => {
-                                    *ref_node = ptr::null_mut();
-                                }
-                            }
-                            Box::from_raw(iter_node);
-                        }
-                    }
-                    break;
-                }
-            }
+    fn snapshot(&self) -> AllocProfile {
+        let kind = |i: usize| KindProfile {
+            allocs: self.allocs[i].load(Ordering::Relaxed),
+            frees: self.frees[i].load(Ordering::Relaxed),
+            live_bytes: self.live[i].load(Ordering::Relaxed),
+        };
+        AllocProfile {
+            kinds: [kind(0), kind(1), kind(2), kind(3), kind(4)],
+            peak_bytes: self.peak.load(Ordering::Relaxed),
         }
     }
+}
 
-    pub fn find(&self, key: K) -> Option<&T> {
-        let mut iter_node = self.root;
-        let key_bytes = key.bytes();
-        let mut depth = 0;
-        while !iter_node.is_null() {
-            unsafe {
-                println!("iter_node: {:?}, {:?}", *iter_node, key.bytes());
-            }
-            match unsafe { &mut *iter_node } {
-                Node::ArtNode(node) => {
-                    depth += node.prefix(&key_bytes[depth..]);
-                    if depth == key_bytes.len() {
-                        depth -= 1;
-                    }
-                    // Iterate until we hit a leaf or don't find any child
-                    if let Some(n) = node.find_child(key_bytes[depth]) {
-                        iter_node = *n;
-                    } else {
-                        break;
-                    }
-                }
-                Node::Leaf(node) => {
-                    depth += common_prefix(&node.key[depth..], &key_bytes[depth..]);
-                    if depth == node.key.len() {
-                        return Some(&node.value);
-                    } else {
-                        return None;
-                    }
-                }
+#[cfg(feature = "alloc-profile")]
+impl Clone for Profile {
+    fn clone(&self) -> Self {
+        let profile = Self::default();
+        let copy = |from: &[AtomicU64; 5], to: &[AtomicU64; 5]| {
+            for (from, to) in from.iter().zip(to.iter()) {
+                to.store(from.load(Ordering::Relaxed), Ordering::Relaxed);
             }
+        };
+        copy(&self.allocs, &profile.allocs);
+        copy(&self.frees, &profile.frees);
+        for (from, to) in self.live.iter().zip(profile.live.iter()) {
+            to.store(from.load(Ordering::Relaxed), Ordering::Relaxed);
         }
-        None
+        profile
+            .peak
+            .store(self.peak.load(Ordering::Relaxed), Ordering::Relaxed);
+        profile
+    }
+}
+
+// Allocations of one node type
+#[cfg(feature = "alloc-profile")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KindProfile {
+    pub allocs: u64,
+    pub frees: u64,
+    pub live_bytes: usize,
+}
+
+// Where the memory of a tree went, see `Art::alloc_profile`. Profiles
+// taken before and after a phase, e.g. a bulk load, tell what it cost
+#[cfg(feature = "alloc-profile")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocProfile {
+    // Leaves, Node4, Node16, Node48 and Node256
+    pub kinds: [KindProfile; 5],
+    // Most bytes held at once
+    pub peak_bytes: usize,
+}
+
+#[cfg(feature = "alloc-profile")]
+impl AllocProfile {
+    pub const KIND_NAMES: [&'static str; 5] = ["leaf", "Node4", "Node16", "Node48", "Node256"];
+
+    pub fn allocs(&self) -> u64 {
+        self.kinds.iter().map(|kind| kind.allocs).sum()
     }
 
-    pub fn insert(&mut self, key: K, value: T) {
-        let key_bytes = key.bytes();
-        if self.root.is_null() {
-            self.root = Box::into_raw(Box::new(Node::Leaf(LeafNode::new(value, &key_bytes))));
-            return;
+    pub fn live_bytes(&self) -> usize {
+        self.kinds.iter().map(|kind| kind.live_bytes).sum()
+    }
+
+    // Allocations per second since an earlier profile of the same tree
+    pub fn alloc_rate(&self, earlier: &AllocProfile, elapsed: std::time::Duration) -> f64 {
+        (self.allocs() - earlier.allocs()) as f64 / elapsed.as_secs_f64()
+    }
+}
+
+impl Metrics {
+    fn incr(&self, counter: &AtomicU64) {
+        if self.counting {
+            counter.fetch_add(1, Ordering::Relaxed);
         }
-        let mut depth = 0;
+    }
+
+    // `kind` is the index of the node type in `AllocProfile::kinds`
+    fn alloc(&self, kind: usize, bytes: usize) {
+        let before = self.memory.fetch_add(bytes, Ordering::Relaxed);
+        #[cfg(feature = "alloc-profile")]
+        self.profile.alloc(kind, bytes, before + bytes);
+        #[cfg(not(feature = "alloc-profile"))]
+        let _ = (kind, before);
+    }
+
+    fn free(&self, kind: usize, bytes: usize) {
+        self.memory.fetch_sub(bytes, Ordering::Relaxed);
+        #[cfg(feature = "alloc-profile")]
+        self.profile.free(kind, bytes);
+        #[cfg(not(feature = "alloc-profile"))]
+        let _ = kind;
+    }
+
+    fn alloc_leaf<T>(&self, key_len: usize) {
+        self.alloc(0, leaf_size::<T>(key_len));
+    }
+
+    fn free_leaf<T>(&self, key_len: usize) {
+        self.free(0, leaf_size::<T>(key_len));
+    }
+
+    fn alloc_inner<T>(&self, capacity: usize) {
+        self.alloc(inner_kind(capacity), inner_size::<T>(capacity));
+    }
+
+    fn free_inner<T>(&self, capacity: usize) {
+        self.free(inner_kind(capacity), inner_size::<T>(capacity));
+    }
+
+    // Account a node replaced by a node of another type
+    fn resize<T>(&self, from: usize, to: usize) {
+        self.alloc_inner::<T>(to);
+        self.free_inner::<T>(from);
+    }
+
+    // Number of nodes split because a new key diverged inside them
+    pub fn splits(&self) -> u64 {
+        self.splits.load(Ordering::Relaxed)
+    }
+
+    // Number of nodes replaced by a bigger node type
+    pub fn grows(&self) -> u64 {
+        self.grows.load(Ordering::Relaxed)
+    }
+
+    // Number of nodes replaced by a smaller node type or merged into a child
+    pub fn shrinks(&self) -> u64 {
+        self.shrinks.load(Ordering::Relaxed)
+    }
+
+    // Number of times a key didn't match the partial prefix of a node
+    pub fn prefix_mismatches(&self) -> u64 {
+        self.prefix_mismatches.load(Ordering::Relaxed)
+    }
+
+    // Approximate number of bytes held by the nodes of the tree
+    pub fn memory(&self) -> usize {
+        self.memory.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.splits.store(0, Ordering::Relaxed);
+        self.grows.store(0, Ordering::Relaxed);
+        self.shrinks.store(0, Ordering::Relaxed);
+        self.prefix_mismatches.store(0, Ordering::Relaxed);
+    }
+}
+
+// Size of an inner node with the given number of child slots,
+// including the enum and the boxed trait object around it
+fn inner_size<T>(capacity: usize) -> usize {
+    std::mem::size_of::<Node<T>>()
+        + match capacity {
+            4 => std::mem::size_of::<Node4<T>>(),
+            16 => std::mem::size_of::<Node16<T>>(),
+            48 => std::mem::size_of::<Node48<T>>(),
+            _ => std::mem::size_of::<Node256<T>>(),
+        }
+}
+
+// Size of a leaf, its full key is stored on the heap
+pub(crate) fn leaf_size<T>(key_len: usize) -> usize {
+    std::mem::size_of::<Node<T>>() + key_len
+}
+
+// Memory of the subtree computed from scratch
+fn subtree_memory<T: 'static + std::fmt::Debug>(node: *mut Node<T>) -> usize {
+    if node.is_null() {
+        return 0;
+    }
+    match unsafe { &*node } {
+        Node::ArtNode(n) => {
+            let pointers = n.child_pointers();
+            inner_size::<T>(pointers.len())
+                + pointers
+                    .iter()
+                    .map(|ptr| subtree_memory(*ptr))
+                    .sum::<usize>()
+        }
+        Node::Leaf(leaf) => leaf_size::<T>(leaf.key.len()),
+    }
+}
+
+// Key of the leftmost leaf in the subtree
+fn first_leaf_key<T: 'static + std::fmt::Debug>(mut node: *mut Node<T>) -> Vec<u8> {
+    loop {
+        match unsafe { &*node } {
+            Node::ArtNode(n) => {
+                node = *n
+                    .child_pointers()
+                    .iter()
+                    .find(|ptr| !ptr.is_null())
+                    .unwrap();
+            }
+            Node::Leaf(leaf) => return leaf.key.clone(),
+        }
+    }
+}
+
+// Check the structure of the subtree whose keys all start with `path`,
+// see `Art::check`. Children are found under the byte right after the
+// partial, which their own partials start with, so it isn't in their path
+fn check_subtree<T: 'static + std::fmt::Debug>(
+    node: *mut Node<T>,
+    path: &mut Vec<u8>,
+) -> Result<(), String> {
+    let n = match unsafe { &*node } {
+        Node::Leaf(leaf) if leaf.key.starts_with(path) => {
+            return Ok(());
+        }
+        Node::Leaf(leaf) => {
+            return Err(format!(
+                "leaf {:?} below the path {:?}",
+                String::from_utf8_lossy(&leaf.key),
+                String::from_utf8_lossy(path)
+            ))
+        }
+        Node::ArtNode(n) => n,
+    };
+    let info = n.info();
+    let pointers = n
+        .child_pointers()
+        .iter()
+        .filter(|ptr| !ptr.is_null())
+        .count();
+    if info.count != pointers || info.count < 2 {
+        return Err(format!(
+            "node at {:?} with {} children counts {}",
+            String::from_utf8_lossy(path),
+            pointers,
+            info.count
+        ));
+    }
+    // Bytes past the stored partial are checked against the leaves below
+    let depth = path.len();
+    let leaf_key = n.leaf_key();
+    let stored = std::cmp::min(info.partial_len, MAX_PREFIX_LEN);
+    if leaf_key.get(depth..depth + stored) != Some(&info.partial[..stored]) {
+        return Err(format!(
+            "node at {:?} stores a partial {:?} its leaves don't have",
+            String::from_utf8_lossy(path),
+            String::from_utf8_lossy(&info.partial[..stored])
+        ));
+    }
+    path.extend_from_slice(
+        &leaf_key[depth..std::cmp::min(depth + info.partial_len, leaf_key.len())],
+    );
+    let mut children = vec![];
+    n.push_children(&mut children);
+    let mut last = None;
+    for child in children.into_iter().rev() {
+        let byte = first_leaf_key(child).get(path.len()).copied();
+        if byte.is_none() || byte <= last || n.child(byte.unwrap()) != Some(child) {
+            return Err(format!(
+                "child {:?} of the node at {:?} is out of place",
+                byte,
+                String::from_utf8_lossy(path)
+            ));
+        }
+        last = byte;
+        check_subtree(child, path)?;
+    }
+    path.truncate(depth);
+    Ok(())
+}
+
+// Add the memory of the subtree starting at `depth` to the prefixes
+// of its keys, see `Art::memory_by_prefix`
+fn memory_by_prefix<T: 'static + std::fmt::Debug>(
+    node: *mut Node<T>,
+    depth: usize,
+    prefix_len: usize,
+    usage: &mut BTreeMap<Vec<u8>, usize>,
+) {
+    if node.is_null() {
+        return;
+    }
+    match unsafe { &*node } {
+        Node::ArtNode(n) => {
+            // All keys in the subtree share bytes up to the end of the partial
+            let depth = depth + n.info().partial_len;
+            if depth >= prefix_len {
+                let key = first_leaf_key(node);
+                *usage.entry(key[..prefix_len].to_vec()).or_insert(0) += subtree_memory(node);
+                return;
+            }
+            let pointers = n.child_pointers();
+            *usage.entry(vec![]).or_insert(0) += inner_size::<T>(pointers.len());
+            for ptr in pointers.iter() {
+                memory_by_prefix(*ptr, depth, prefix_len, usage);
+            }
+        }
+        Node::Leaf(leaf) => {
+            let prefix = &leaf.key[..std::cmp::min(prefix_len, leaf.key.len())];
+            *usage.entry(prefix.to_vec()).or_insert(0) += leaf_size::<T>(leaf.key.len());
+        }
+    }
+}
+
+// Error of `insert_unique` for a key that is already there, with the
+// value that didn't go in and the one that stays
+#[derive(Debug, PartialEq, Eq)]
+pub struct Collision<'a, T> {
+    pub value: T,
+    pub existing: &'a T,
+}
+
+impl<'a, T> std::fmt::Display for Collision<'a, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "key is already in the tree")
+    }
+}
+
+impl<'a, T: std::fmt::Debug> std::error::Error for Collision<'a, T> {}
+
+// Where `scan_page` stops, the last key of a page. It goes out to clients
+// as hex with `to_string` and comes back with `parse`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageToken(Vec<u8>);
+
+// Pairs of a page of `scan_page` with the token of the next one, if any
+pub type Page<K, T> = (Vec<(K, T)>, Option<PageToken>);
+
+impl std::fmt::Display for PageToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for PageToken {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let bad = || Error::Corrupt(format!("page token {:?}", s));
+        if !s.len().is_multiple_of(2) || !s.is_ascii() {
+            return Err(bad());
+        }
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| bad()))
+            .collect::<Result<_, _>>()
+            .map(PageToken)
+    }
+}
+
+// Iterator over the keys and values of the tree in the key order
+pub struct Iter<'a, T: 'static + std::fmt::Debug> {
+    stack: Vec<*mut Node<T>>,
+    marker: PhantomData<&'a T>,
+}
+
+impl<'a, T: 'static + std::fmt::Debug> Iterator for Iter<'a, T> {
+    type Item = (&'a [u8], &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(node) = self.stack.pop() {
+            paranoid_assert!(!node.is_null(), "null child pointer");
+            match unsafe { &*node } {
+                Node::ArtNode(n) => n.push_children(&mut self.stack),
+                Node::Leaf(leaf) if !leaf.dead => return Some((&leaf.key, &leaf.value)),
+                Node::Leaf(_) => {}
+            }
+        }
+        None
+    }
+}
+
+// Iterator over the pairs with keys in a range in the key order. Subtrees
+// entirely out of the range are skipped, the ones entirely in it are
+// walked without comparing keys
+pub struct RangeIter<'a, T: 'static + std::fmt::Debug> {
+    // Nodes with the depth they are at and whether they are in the range
+    stack: Vec<(*mut Node<T>, usize, bool)>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    marker: PhantomData<&'a T>,
+}
+
+impl<'a, T: 'static + std::fmt::Debug> Iterator for RangeIter<'a, T> {
+    type Item = (&'a [u8], &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let range = (
+            self.start.as_ref().map(Vec::as_slice),
+            self.end.as_ref().map(Vec::as_slice),
+        );
+        while let Some((node, depth, inside)) = self.stack.pop() {
+            match unsafe { &*node } {
+                Node::ArtNode(n) => {
+                    let end = depth + n.info().partial_len;
+                    let inside = inside
+                        || match covers(&range, &n.leaf_key()[..end]) {
+                            Some(false) => continue,
+                            Some(true) => true,
+                            None => false,
+                        };
+                    self.stack.extend(
+                        children(&**n)
+                            .into_iter()
+                            .rev()
+                            .map(|child| (child, end, inside)),
+                    );
+                }
+                Node::Leaf(leaf) if !leaf.dead && (inside || range.contains(&leaf.key[..])) => {
+                    return Some((&leaf.key, &leaf.value))
+                }
+                Node::Leaf(_) => {}
+            }
+        }
+        None
+    }
+}
+
+pub struct Art<K, T: 'static + std::fmt::Debug> {
+    root: *mut Node<T>,
+    key: PhantomData<K>,
+    metrics: Metrics,
+    memory_budget: Option<usize>,
+    max_key_len: Option<usize>,
+    path_check: PathCheck,
+    // Boxed, the index is an `Art` itself
+    reverse: Option<Box<Reverse<T>>>,
+    // Shared with the clones of the tree
+    aggregation: Option<Arc<Combine<T>>>,
+    priority: Option<Arc<Compare<T>>>,
+    deletion: Deletion,
+    // Number of dead leaves
+    tombstones: usize,
+    // Usage and quotas of the namespaces by their internal prefix
+    pub(crate) namespaces: BTreeMap<Vec<u8>, Account>,
+}
+
+// Combines the aggregates of two neighbouring ranges of keys
+type Combine<T> = dyn Fn(&T, &T) -> T + Send + Sync;
+
+// Orders values by their priority
+type Compare<T> = dyn Fn(&T, &T) -> std::cmp::Ordering + Send + Sync;
+
+// Keys an import inserted with the values they had before
+type Undo<T> = Vec<(Vec<u8>, Option<T>)>;
+
+// Live leaf with the highest priority among the children of an inner node
+// and the leaves they point at, the first one in the key order on ties
+fn best_child<T: 'static + std::fmt::Debug>(
+    node: &dyn ArtNode<T>,
+    compare: &Compare<T>,
+) -> *mut Node<T> {
+    let mut best: *mut Node<T> = ptr::null_mut();
+    for child in children(node) {
+        let leaf = match unsafe { &*child } {
+            Node::Leaf(leaf) if !leaf.dead => child,
+            Node::Leaf(_) => continue,
+            Node::ArtNode(n) => n.best(),
+        };
+        if let (Some(Node::Leaf(a)), Some(Node::Leaf(b))) =
+            unsafe { (leaf.as_ref(), best.as_ref()) }
+        {
+            if compare(&a.value, &b.value) != std::cmp::Ordering::Greater {
+                continue;
+            }
+        }
+        if !leaf.is_null() {
+            best = leaf;
+        }
+    }
+    best
+}
+
+// Set the best leaves of the whole subtree bottom up
+fn best_subtree<T: 'static + std::fmt::Debug>(node: *mut Node<T>, compare: &Compare<T>) {
+    if let Some(Node::ArtNode(n)) = unsafe { node.as_mut() } {
+        for child in children(&**n) {
+            best_subtree(child, compare);
+        }
+        let best = best_child(&**n, compare);
+        n.set_best(best);
+    }
+}
+
+// Children of an inner node in the key order
+fn children<T: 'static + std::fmt::Debug>(node: &dyn ArtNode<T>) -> Vec<*mut Node<T>> {
+    let mut children = vec![];
+    node.push_children(&mut children);
+    children.reverse();
+    children
+}
+
+// Shrink the nodes of the subtree in `slot` bottom up, see
+// `Art::shrink_to_fit`
+fn shrink_tree<T: 'static + Clone + std::fmt::Debug>(
+    slot: *mut *mut Node<T>,
+    metrics: &Metrics,
+) -> usize {
+    let node = unsafe { *slot };
+    let n = match unsafe { node.as_mut() } {
+        Some(Node::ArtNode(n)) => n,
+        _ => return 0,
+    };
+    let mut shrunk = 0;
+    for byte in 0..=255 {
+        if let Some(child) = n.find_child(byte) {
+            shrunk += shrink_tree(child as *mut *mut Node<T>, metrics);
+        }
+    }
+    let capacity = n.child_pointers().len();
+    let fit = match n.info().count {
+        0..=4 => 4,
+        5..=16 => 16,
+        17..=48 => 48,
+        _ => 256,
+    };
+    if fit >= capacity {
+        return shrunk;
+    }
+    let mut info = *n.info();
+    info.count = 0;
+    let mut new_node: Box<dyn ArtNode<T>> = match fit {
+        4 => Box::new(Node4::new_with_info(info)),
+        16 => Box::new(Node16::new_with_info(info)),
+        _ => Box::new(Node48::new_with_info(info)),
+    };
+    for byte in 0..=255 {
+        if let Some(child) = n.child(byte) {
+            new_node.add(child, &[byte], 0);
+        }
+    }
+    new_node.set_aggregate(n.aggregate().cloned());
+    new_node.set_best(n.best());
+    metrics.incr(&metrics.shrinks);
+    metrics.resize::<T>(capacity, fit);
+    unsafe {
+        drop(Box::from_raw(node));
+        *slot = Box::into_raw(Box::new(Node::ArtNode(new_node)));
+    }
+    shrunk + 1
+}
+
+// Aggregate of an inner node from the values and aggregates of its children
+fn combine_children<T: 'static + Clone + std::fmt::Debug>(
+    node: &dyn ArtNode<T>,
+    combine: &Combine<T>,
+) -> Option<T> {
+    let mut aggregate: Option<T> = None;
+    for child in children(node) {
+        let value = match unsafe { &*child } {
+            Node::Leaf(leaf) if !leaf.dead => Some(&leaf.value),
+            Node::Leaf(_) => None,
+            Node::ArtNode(n) => n.aggregate(),
+        };
+        if let Some(value) = value {
+            aggregate = Some(match aggregate {
+                Some(aggregate) => combine(&aggregate, value),
+                None => value.clone(),
+            });
+        }
+    }
+    aggregate
+}
+
+// Compute the aggregates of the whole subtree bottom up
+fn aggregate_subtree<T: 'static + Clone + std::fmt::Debug>(
+    node: *mut Node<T>,
+    combine: &Combine<T>,
+) {
+    if let Some(Node::ArtNode(n)) = unsafe { node.as_mut() } {
+        for child in children(&**n) {
+            aggregate_subtree(child, combine);
+        }
+        let aggregate = combine_children(&**n, combine);
+        n.set_aggregate(aggregate);
+    }
+}
+
+// `Some(true)` if all keys starting with `prefix` are in the range,
+// `Some(false)` if none are, `None` if it takes looking at the keys
+fn covers<R: RangeBounds<[u8]>>(range: &R, prefix: &[u8]) -> Option<bool> {
+    // Keys with the prefix sort after the prefix, and before the bytes
+    // greater than it that don't start with it
+    let before = |bound: &[u8]| prefix < bound && !bound.starts_with(prefix);
+    let after_start = match range.start_bound() {
+        Bound::Included(start) => prefix >= start,
+        Bound::Excluded(start) => prefix > start,
+        Bound::Unbounded => true,
+    };
+    let before_end = match range.end_bound() {
+        Bound::Included(end) | Bound::Excluded(end) => before(end),
+        Bound::Unbounded => true,
+    };
+    if after_start && before_end {
+        return Some(true);
+    }
+    let before_start = match range.start_bound() {
+        Bound::Included(start) | Bound::Excluded(start) => before(start),
+        Bound::Unbounded => false,
+    };
+    let after_end = match range.end_bound() {
+        Bound::Included(end) => prefix > end,
+        Bound::Excluded(end) => prefix >= end,
+        Bound::Unbounded => false,
+    };
+    if before_start || after_end {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+// Aggregate of the values in the range below the node found at `depth`.
+// Subtrees entirely in the range give their cached aggregate, so only the
+// nodes on the paths of the two bounds are opened
+fn aggregate_in<T: 'static + Clone + std::fmt::Debug, R: RangeBounds<[u8]>>(
+    node: *mut Node<T>,
+    depth: usize,
+    range: &R,
+    combine: &Combine<T>,
+) -> Option<T> {
+    match unsafe { node.as_ref() }? {
+        Node::Leaf(leaf) => {
+            if !leaf.dead && range.contains(&leaf.key[..]) {
+                Some(leaf.value.clone())
+            } else {
+                None
+            }
+        }
+        Node::ArtNode(n) => {
+            let end = depth + n.info().partial_len;
+            match covers(range, &n.leaf_key()[..end]) {
+                Some(true) => n.aggregate().cloned(),
+                Some(false) => None,
+                None => {
+                    let mut aggregate: Option<T> = None;
+                    for child in children(&**n) {
+                        if let Some(value) = aggregate_in(child, end, range, combine) {
+                            aggregate = Some(match aggregate {
+                                Some(aggregate) => combine(&aggregate, &value),
+                                None => value,
+                            });
+                        }
+                    }
+                    aggregate
+                }
+            }
+        }
+    }
+}
+
+// Keys by a number extracted from their values, see `enable_reverse_index`
+#[derive(Clone)]
+struct Reverse<T: 'static + std::fmt::Debug> {
+    extract: Arc<dyn Fn(&T) -> u64 + Send + Sync>,
+    index: SecondaryIndex<Vec<u8>, u64, Raw, BigEndian>,
+}
+
+// Hash of a value, the extractor for a reverse index from values to keys
+pub fn value_hash<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Copy of the subtree, null for null
+fn clone_tree<T: 'static + Clone + std::fmt::Debug>(node: *mut Node<T>) -> *mut Node<T> {
+    let copy = match unsafe { node.as_ref() } {
+        Some(Node::ArtNode(n)) => Node::ArtNode(n.clone_node()),
+        Some(Node::Leaf(leaf)) => Node::Leaf(leaf.clone()),
+        None => return ptr::null_mut(),
+    };
+    Box::into_raw(Box::new(copy))
+}
+
+// Free all tree recursive, accounting the freed nodes in `metrics`
+fn free_tree<T: 'static + std::fmt::Debug>(metrics: &Metrics, node: *mut Node<T>) {
+    if node.is_null() {
+        return;
+    }
+    match unsafe { &*node } {
+        Node::ArtNode(n) => {
+            let child_pointers = n.child_pointers();
+            for ptr in child_pointers.iter() {
+                free_tree(metrics, *ptr);
+            }
+            metrics.free_inner::<T>(child_pointers.len());
+        }
+        Node::Leaf(leaf) => metrics.free_leaf::<T>(leaf.key.len()),
+    }
+    unsafe {
+        drop(Box::from_raw(node));
+    }
+}
+
+// The tree owns all of its nodes, so it can be moved to another thread
+// together with them. Lookups only read the nodes and count into atomic
+// metrics, so it can be shared between threads as well
+unsafe impl<K: Send, T: 'static + std::fmt::Debug + Send> Send for Art<K, T> {}
+unsafe impl<K: Sync, T: 'static + std::fmt::Debug + Sync> Sync for Art<K, T> {}
+
+// Deep copy, the nodes aren't shared between the copies
+impl<K, T: 'static + Clone + std::fmt::Debug> Clone for Art<K, T> {
+    fn clone(&self) -> Self {
+        let art = Self {
+            root: clone_tree(self.root),
+            key: PhantomData,
+            metrics: self.metrics.clone(),
+            memory_budget: self.memory_budget,
+            max_key_len: self.max_key_len,
+            path_check: self.path_check,
+            reverse: self.reverse.clone(),
+            aggregation: self.aggregation.clone(),
+            priority: self.priority.clone(),
+            deletion: self.deletion,
+            tombstones: self.tombstones,
+            namespaces: self.namespaces.clone(),
+        };
+        if let Some(compare) = &art.priority {
+            best_subtree(art.root, &**compare);
+        }
+        art
+    }
+}
+
+impl<K, T: 'static + std::fmt::Debug> Drop for Art<K, T> {
+    fn drop(&mut self) {
+        free_tree::<T>(&self.metrics, self.root)
+    }
+}
+
+impl<K, T> Default for Art<K, T>
+where
+    K: ArtKey + std::marker::Sized + std::fmt::Debug,
+    T: 'static + Clone + std::fmt::Debug,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, T> Art<K, T>
+where
+    K: ArtKey + std::marker::Sized + std::fmt::Debug,
+    T: 'static + Clone + std::fmt::Debug,
+{
+    pub fn new() -> Self {
+        Self {
+            root: std::ptr::null_mut(),
+            key: PhantomData,
+            metrics: Metrics::default(),
+            memory_budget: None,
+            max_key_len: None,
+            path_check: PathCheck::EveryNode,
+            reverse: None,
+            aggregation: None,
+            priority: None,
+            deletion: Deletion::Eager,
+            tombstones: 0,
+            namespaces: BTreeMap::new(),
+        }
+    }
+
+    pub fn deletion(&self) -> Deletion {
+        self.deletion
+    }
+
+    // Dead leaves stay in the tree after switching back to `Eager`, until
+    // `compact` or an insert of their keys
+    pub fn set_deletion(&mut self, deletion: Deletion) {
+        self.deletion = deletion;
+    }
+
+    // Number of deleted keys still taking space in the tree
+    pub fn tombstones(&self) -> usize {
+        self.tombstones
+    }
+
+    // Remove the dead leaves of lazy deletes and shrink the nodes they
+    // leave underfull
+    pub fn compact(&mut self) {
+        let deletion = self.deletion;
+        self.deletion = Deletion::Eager;
+        for key in self.dead_keys() {
+            self.delete_bytes(&key);
+        }
+        self.deletion = deletion;
+    }
+
+    // Replace every inner node with the smallest node type its children
+    // fit in, e.g. after deletes that stayed above the thresholds which
+    // shrink nodes on the way. Returns the number of nodes replaced. Nodes
+    // are allocated one by one, there are no free lists or arenas to trim
+    pub fn shrink_to_fit(&mut self) -> usize {
+        shrink_tree(&mut self.root, &self.metrics)
+    }
+
+    fn dead_keys(&self) -> Vec<Vec<u8>> {
+        let mut dead = vec![];
+        let mut stack = vec![];
+        if !self.root.is_null() {
+            stack.push(self.root);
+        }
+        while let Some(node) = stack.pop() {
+            match unsafe { &*node } {
+                Node::ArtNode(n) => n.push_children(&mut stack),
+                Node::Leaf(leaf) if leaf.dead => dead.push(leaf.key.clone()),
+                Node::Leaf(_) => {}
+            }
+        }
+        dead
+    }
+
+    // Cache in every inner node the aggregate of the values below it, e.g.
+    // a sum, min or max, for `aggregate_range`. `combine` gets the
+    // aggregates of two ranges of keys in the key order. Inserts and
+    // deletes update the nodes on the path of the key
+    pub fn set_aggregation<F: Fn(&T, &T) -> T + Send + Sync + 'static>(&mut self, combine: F) {
+        aggregate_subtree(self.root, &combine);
+        self.aggregation = Some(Arc::new(combine));
+    }
+
+    pub fn clear_aggregation(&mut self) {
+        self.aggregation = None;
+    }
+
+    // Keep in every inner node the entry with the highest `priority` below
+    // it, for `max_in_prefix`. Inserts and deletes update the nodes on the
+    // path of the key
+    pub fn set_priority<P, F>(&mut self, priority: F)
+    where
+        P: Ord,
+        F: Fn(&T) -> P + Send + Sync + 'static,
+    {
+        let compare = move |a: &T, b: &T| priority(a).cmp(&priority(b));
+        best_subtree(self.root, &compare);
+        self.priority = Some(Arc::new(compare));
+    }
+
+    pub fn clear_priority(&mut self) {
+        self.priority = None;
+    }
+
+    // Entry with the highest priority among the keys starting with
+    // `prefix`, the first one in the key order on ties. Looks at the
+    // nodes on the path of the prefix only
+    pub fn max_in_prefix(&self, prefix: &[u8]) -> Option<(&[u8], &T)> {
+        self.priority.as_ref()?;
+        let node = match unsafe { self.prefix_root(prefix).as_ref() }? {
+            Node::ArtNode(n) => unsafe { n.best().as_ref() }?,
+            node => node,
+        };
+        match node {
+            Node::Leaf(leaf) if !leaf.dead && leaf.key.starts_with(prefix) => {
+                Some((&leaf.key, &leaf.value))
+            }
+            _ => None,
+        }
+    }
+
+    // Aggregate of the values with keys in the range in O(height) nodes,
+    // `None` without an aggregation or keys in the range
+    pub fn aggregate_range<R: RangeBounds<[u8]>>(&self, range: R) -> Option<T> {
+        let combine = self.aggregation.as_ref()?;
+        aggregate_in(self.root, 0, &range, &**combine)
+    }
+
+    // Aggregate of the values with keys starting with `prefix`
+    pub fn aggregate_prefix(&self, prefix: &[u8]) -> Option<T> {
+        // The keys with the prefix end before the prefix with its last
+        // byte short of 0xff incremented
+        let mut end = prefix.to_vec();
+        while end.last() == Some(&0xff) {
+            end.pop();
+        }
+        match end.last_mut() {
+            Some(last) => {
+                *last += 1;
+                self.aggregate_range((Bound::Included(prefix), Bound::Excluded(&end[..])))
+            }
+            None => self.aggregate_range((Bound::Included(prefix), Bound::Unbounded)),
+        }
+    }
+
+    // Recompute the aggregates and best leaves on the path of the key, the
+    // nodes whose subtrees an insert or a delete of it changed
+    fn refresh_path(&mut self, key_bytes: &[u8]) {
+        if self.aggregation.is_none() && self.priority.is_none() {
+            return;
+        }
+        let mut path = vec![];
+        let mut iter_node = self.root;
+        let mut depth = 0;
+        while let Some(Node::ArtNode(node)) = unsafe { iter_node.as_mut() } {
+            path.push(iter_node);
+            depth += node.info().partial_len;
+            if depth >= key_bytes.len() {
+                break;
+            }
+            match node.find_child(key_bytes[depth]) {
+                Some(n) => iter_node = *n,
+                None => break,
+            }
+        }
+        for node in path.into_iter().rev() {
+            if let Node::ArtNode(n) = unsafe { &mut *node } {
+                if let Some(combine) = &self.aggregation {
+                    let aggregate = combine_children(&**n, &**combine);
+                    n.set_aggregate(aggregate);
+                }
+                if let Some(compare) = &self.priority {
+                    let best = best_child(&**n, &**compare);
+                    n.set_best(best);
+                }
+            }
+        }
+    }
+
+    // Keep a second tree from `extract(value)` to the keys holding the
+    // value, e.g. `value_hash` or an id stored in the values, for
+    // `reverse_lookup`. Inserts and deletes keep it up to date
+    pub fn enable_reverse_index<F: Fn(&T) -> u64 + Send + Sync + 'static>(&mut self, extract: F) {
+        self.index_values(Arc::new(extract));
+    }
+
+    fn index_values(&mut self, extract: Arc<dyn Fn(&T) -> u64 + Send + Sync>) {
+        let mut reverse = Reverse {
+            extract,
+            index: SecondaryIndex::new(Raw, BigEndian),
+        };
+        for (key, value) in self.iter() {
+            reverse
+                .index
+                .insert(&(reverse.extract)(value), key.to_vec());
+        }
+        self.reverse = Some(Box::new(reverse));
+    }
+
+    pub fn disable_reverse_index(&mut self) {
+        self.reverse = None;
+    }
+
+    // Keys with values extracting to `extracted` in the key order, `None`
+    // without a reverse index. With `value_hash` it can list keys of other
+    // values with the same hash
+    pub fn reverse_lookup(&self, extracted: u64) -> Option<Vec<&[u8]>> {
+        let reverse = self.reverse.as_ref()?;
+        Some(
+            reverse
+                .index
+                .scan(&extracted)
+                .into_iter()
+                .map(|key| &key[..])
+                .collect(),
+        )
+    }
+
+    // Take the key out of the reverse index before its value goes away
+    fn reverse_remove(&mut self, key_bytes: &[u8]) {
+        if self.reverse.is_none() {
+            return;
+        }
+        let extracted = match (&self.reverse, self.find_bytes(key_bytes)) {
+            (Some(reverse), Some(value)) => (reverse.extract)(value),
+            _ => return,
+        };
+        if let Some(reverse) = self.reverse.as_mut() {
+            reverse.index.remove(&extracted, &key_bytes.to_vec());
+        }
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    // Count the structural changes in `metrics`, off by default. The
+    // memory usage is tracked either way
+    pub fn set_metrics(&mut self, on: bool) {
+        self.metrics.counting = on;
+    }
+
+    // Approximate number of bytes held by the nodes of the tree
+    pub fn memory_usage(&self) -> usize {
+        self.metrics.memory()
+    }
+
+    // Allocations and frees of the nodes by type, with the peak memory
+    #[cfg(feature = "alloc-profile")]
+    pub fn alloc_profile(&self) -> AllocProfile {
+        self.metrics.profile.snapshot()
+    }
+
+    // Approximate memory used by the keys sharing each prefix of `depth` bytes.
+    // Inner nodes above that depth are shared between several prefixes,
+    // their memory is reported under the empty prefix
+    pub fn memory_by_prefix(&self, depth: usize) -> BTreeMap<Vec<u8>, usize> {
+        let mut usage = BTreeMap::new();
+        memory_by_prefix(self.root, 0, depth, &mut usage);
+        usage
+    }
+
+    pub fn memory_budget(&self) -> Option<usize> {
+        self.memory_budget
+    }
+
+    // Limit the memory `try_insert` and the imports are allowed to use,
+    // `None` removes the limit
+    pub fn set_memory_budget(&mut self, budget: Option<usize>) {
+        self.memory_budget = budget;
+    }
+
+    pub fn path_check(&self) -> PathCheck {
+        self.path_check
+    }
+
+    // The mode can be switched at any time, the nodes are the same
+    pub fn set_path_check(&mut self, mode: PathCheck) {
+        self.path_check = mode;
+    }
+
+    pub fn max_key_len(&self) -> Option<usize> {
+        self.max_key_len
+    }
+
+    // Limit the length of the key bytes `try_insert` and the imports
+    // accept, `None` removes the limit
+    pub fn set_max_key_len(&mut self, max: Option<usize>) {
+        self.max_key_len = max;
+    }
+
+    // Iterate over the byte representation of the keys and the values
+    pub fn iter(&self) -> Iter<'_, T> {
+        let mut stack = vec![];
+        if !self.root.is_null() {
+            stack.push(self.root);
+        }
+        Iter {
+            stack,
+            marker: PhantomData,
+        }
+    }
+
+    // Pairs with keys starting with `prefix` in the key order
+    pub fn prefix_iter<'a>(&'a self, prefix: &[u8]) -> impl Iterator<Item = (&'a [u8], &'a T)> {
+        let iter_node = self.prefix_root(prefix);
+        let mut stack = vec![];
+        if !iter_node.is_null() {
+            stack.push(iter_node);
+        }
+        // A single leaf at the end of the path may not match, the prefix
+        // is copied so the pairs don't borrow it
+        let prefix = prefix.to_vec();
+        Iter {
+            stack,
+            marker: PhantomData,
+        }
+        .filter(move |(key, _)| key.starts_with(&prefix))
+    }
+
+    // Root of the subtree holding all keys with the prefix, null if there
+    // are none. A leaf may not have the prefix
+    fn prefix_root(&self, prefix: &[u8]) -> *mut Node<T> {
+        let mut iter_node = self.root;
+        let mut depth = 0;
+        // Follow the prefix down to the subtree holding all keys with it
+        while let Some(Node::ArtNode(node)) = unsafe { iter_node.as_ref() } {
+            let partial_len = node.info().partial_len;
+            let len = std::cmp::min(partial_len, prefix.len() - depth);
+            if node.prefix(prefix, depth) < len {
+                iter_node = ptr::null_mut();
+                break;
+            }
+            if depth + partial_len >= prefix.len() {
+                break;
+            }
+            depth += partial_len;
+            iter_node = node.child(prefix[depth]).unwrap_or(ptr::null_mut());
+        }
+        iter_node
+    }
+
+    // Pairs with keys in the range in the key order
+    pub fn range<R: RangeBounds<[u8]>>(&self, range: R) -> RangeIter<'_, T> {
+        let mut stack = vec![];
+        if !self.root.is_null() {
+            stack.push((self.root, 0, false));
+        }
+        RangeIter {
+            stack,
+            start: range.start_bound().map(|start| start.to_vec()),
+            end: range.end_bound().map(|end| end.to_vec()),
+            marker: PhantomData,
+        }
+    }
+
+    // First `k` pairs with keys starting with `prefix` in the key order,
+    // the rest of the subtree isn't visited
+    pub fn complete<'a>(&'a self, prefix: &[u8], k: usize) -> Vec<(&'a [u8], &'a T)> {
+        self.prefix_iter(prefix).take(k).collect()
+    }
+
+    // Up to `limit` pairs with keys starting with `prefix` in the key order,
+    // after the key of `resume`, and the token to resume from if there are
+    // more. The token is just the last key, so pages resume past it even
+    // if it was deleted since, and keys inserted before it are skipped.
+    // A limit of 0 can't make progress and gives `Error::InvalidArgument`,
+    // a key that doesn't decode to `K` gives `Error::Corrupt`
+    pub fn scan_page(
+        &self,
+        prefix: &[u8],
+        limit: usize,
+        resume: Option<&PageToken>,
+    ) -> Result<Page<K, T>, Error> {
+        if limit == 0 {
+            return Err(Error::InvalidArgument("page limit of 0".to_string()));
+        }
+        let from = match resume {
+            Some(token) if token.0[..] >= *prefix => Bound::Excluded(&token.0[..]),
+            _ => Bound::Included(prefix),
+        };
+        let mut pairs = self
+            .range((from, Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix));
+        let mut page = Vec::new();
+        let mut last = None;
+        for (key, value) in pairs.by_ref().take(limit) {
+            let decoded = K::from_bytes(key)
+                .ok_or_else(|| Error::Corrupt(format!("key {:?} doesn't decode", key)))?;
+            page.push((decoded, value.clone()));
+            last = Some(key);
+        }
+        let token = match (last, pairs.next()) {
+            (Some(last), Some(_)) => Some(PageToken(last.to_vec())),
+            _ => None,
+        };
+        Ok((page, token))
+    }
+
+    // Write all pairs as a sorted string table, see `sst` for the format
+    pub fn export_sst<W: Write>(&self, writer: W) -> io::Result<()>
+    where
+        T: Codec,
+    {
+        let mut sst = SstWriter::new(writer);
+        let mut value = vec![];
+        for (key, val) in self.iter() {
+            value.clear();
+            val.encode(&mut value);
+            sst.add(key, &value)?;
+        }
+        sst.finish()?;
+        Ok(())
+    }
+
+    // Insert all pairs of a sorted string table, returns their number.
+    // Either all of them go in or none. A first pass over the table checks
+    // the values, the key lengths and that no key is a prefix of another,
+    // the second inserts into the tree. The memory budget is checked on
+    // every insert, the pairs inserted before one past it are taken back
+    pub fn import_sst<R: Read + Seek>(&mut self, reader: R) -> Result<usize, Error>
+    where
+        T: Codec,
+    {
+        let mut sst = SstReader::new(reader)?;
+        let mut checked = Ok(());
+        let mut last: Option<Vec<u8>> = None;
+        let mut count = 0;
+        sst.for_each(|key, value| {
+            if checked.is_ok() {
+                checked = match T::decode(value) {
+                    Some(_) => self.check_import_key(key, last.as_deref()),
+                    None => Err(Error::Corrupt("bad value in sst".to_string())),
+                };
+                last = Some(key.to_vec());
+                count += 1;
+            }
+        })?;
+        checked?;
+        let mut undo = vec![];
+        let mut inserted = Ok(());
+        let read = sst.for_each(|key, value| {
+            if let (Ok(()), Some(value)) = (&inserted, T::decode(value)) {
+                inserted = self.import_pair(key, value, &mut undo);
+            }
+        });
+        if let Err(err) = read.map_err(Error::from).and(inserted) {
+            self.undo_import(undo);
+            return Err(err);
+        }
+        Ok(count)
+    }
+
+    // Whether a key can be imported: not longer than `max_key_len`, and
+    // neither a prefix of a key in the tree nor having one as a prefix.
+    // `previous` is the key before it in the key order, the keys of the
+    // import that aren't in the tree yet are only checked against it
+    fn check_import_key(&self, key_bytes: &[u8], previous: Option<&[u8]>) -> Result<(), Error> {
+        if let Some(max) = self.max_key_len {
+            if key_bytes.len() > max {
+                return Err(Error::KeyTooLong {
+                    len: key_bytes.len(),
+                    max,
+                });
+            }
+        }
+        if let Some(previous) = previous {
+            if previous != key_bytes && key_bytes.starts_with(previous) {
+                return Err(Error::KeyIsPrefix {
+                    key: previous.to_vec(),
+                });
+            }
+        }
+        // The path of the key ends at a node only if keys of the tree
+        // continue it, and at a leaf which may be a prefix of it
+        let shorter = match unsafe { self.prefix_root(key_bytes).as_ref() } {
+            Some(Node::ArtNode(_)) => Some(key_bytes),
+            Some(Node::Leaf(leaf)) if leaf.key.len() < key_bytes.len() => {
+                Some(&leaf.key[..]).filter(|&key| key_bytes.starts_with(key))
+            }
+            Some(Node::Leaf(leaf)) if leaf.key.len() > key_bytes.len() => {
+                Some(key_bytes).filter(|&key| leaf.key.starts_with(key))
+            }
+            _ => None,
+        };
+        match shorter {
+            Some(key) => Err(Error::KeyIsPrefix { key: key.to_vec() }),
+            None => Ok(()),
+        }
+    }
+
+    // Insert one pair of an import within the memory budget, and remember
+    // how to take it back
+    fn import_pair(&mut self, key_bytes: &[u8], value: T, undo: &mut Undo<T>) -> Result<(), Error> {
+        let old = self.try_insert_bytes(key_bytes, value)?;
+        undo.push((key_bytes.to_vec(), old));
+        Ok(())
+    }
+
+    // Take back the pairs of a failed import, the last one first. The
+    // inserted keys are removed right away even with lazy deletion
+    fn undo_import(&mut self, undo: Undo<T>) {
+        let deletion = std::mem::replace(&mut self.deletion, Deletion::Eager);
+        for (key, old) in undo.into_iter().rev() {
+            match old {
+                Some(value) => {
+                    self.insert_bytes(&key, value);
+                }
+                None => {
+                    self.delete_bytes(&key);
+                }
+            }
+        }
+        self.deletion = deletion;
+    }
+
+    // Write all pairs in a text format, keys are restored from their bytes.
+    // The export stops with `InvalidData` at a key that doesn't decode
+    #[cfg(feature = "serde")]
+    pub fn export<W: Write>(&self, writer: W, format: Format) -> io::Result<()>
+    where
+        K: serde::Serialize,
+        T: serde::Serialize,
+    {
+        let mut undecoded = None;
+        let pairs = self
+            .iter()
+            .map_while(|(key, value)| match K::from_bytes(key) {
+                Some(decoded) => Some((decoded, value)),
+                None => {
+                    undecoded = Some(key);
+                    None
+                }
+            });
+        write_pairs(writer, format, pairs)?;
+        match undecoded {
+            Some(key) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("key {:?} doesn't decode", key),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // Insert all pairs written by `export`, returns their number. Same as
+    // `import_sst` either all of them go in or none, the pairs are read and
+    // checked before the first insert
+    #[cfg(feature = "serde")]
+    pub fn import<R: Read>(&mut self, reader: R, format: Format) -> Result<usize, Error>
+    where
+        K: serde::de::DeserializeOwned,
+        T: serde::de::DeserializeOwned,
+    {
+        let mut pairs = vec![];
+        read_pairs(reader, format, |key: K, value: T| {
+            pairs.push((key.bytes(), value))
+        })?;
+        // Once sorted a key that is a prefix of others is a prefix of the
+        // one right after it
+        let mut keys: Vec<&[u8]> = pairs.iter().map(|(key, _)| &key[..]).collect();
+        keys.sort_unstable();
+        for (i, key) in keys.iter().enumerate() {
+            self.check_import_key(key, i.checked_sub(1).map(|i| keys[i]))?;
+        }
+        let count = pairs.len();
+        let mut undo = vec![];
+        for (key, value) in pairs {
+            if let Err(err) = self.import_pair(&key, value, &mut undo) {
+                self.undo_import(undo);
+                return Err(err);
+            }
+        }
+        Ok(count)
+    }
+
+    // Walk the whole tree and check its structure: the child counts, that
+    // the children are in order and found under their bytes, and that the
+    // leaves below a node share its path. Meant for tests and fuzzing, the
+    // error says which node is wrong
+    pub fn check(&self) -> Result<(), Error> {
+        if !self.root.is_null() {
+            check_subtree(self.root, &mut vec![]).map_err(Error::Corrupt)?;
+        }
+        let memory = subtree_memory(self.root);
+        if memory != self.memory_usage() {
+            return Err(Error::Corrupt(format!(
+                "nodes take {} bytes, {} are accounted",
+                memory,
+                self.memory_usage()
+            )));
+        }
+        Ok(())
+    }
+
+    // Count a number of nodes in the tree
+    pub fn bfs_count(&self) -> usize {
+        let mut count = 0;
+        if self.root.is_null() {
+            return count;
+        }
+        let mut queue = VecDeque::new();
+        queue.push_back(self.root);
+        while !queue.is_empty() {
+            let node = queue.pop_front().unwrap();
+            match unsafe { &*node } {
+                Node::ArtNode(n) => {
+                    count += 1;
+                    // The slots of a Node256 are the key bytes, so the
+                    // children aren't the first `count` of them
+                    let mut children = vec![];
+                    n.push_children(&mut children);
+                    queue.extend(children.into_iter().rev());
+                }
+                Node::Leaf(_) => {
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+
+    // Keep only the keys starting with `prefix`. The subtree holding them
+    // becomes the new root and the rest of the tree is freed at once
+    pub fn retain_prefix(&mut self, prefix: &[u8]) {
+        let mut ref_node = &mut self.root as *mut *mut Node<T>;
+        // Slot of the subtree to keep
+        let mut keep: *mut *mut Node<T> = ptr::null_mut();
+        let mut depth = 0;
+        while unsafe { !(*ref_node).is_null() } {
+            match unsafe { &mut **ref_node } {
+                Node::ArtNode(node) => {
+                    let partial_len = node.info().partial_len;
+                    let len = std::cmp::min(partial_len, prefix.len() - depth);
+                    if node.prefix(prefix, depth) < len {
+                        break;
+                    }
+                    // All keys below match the prefix
+                    if depth + partial_len >= prefix.len() {
+                        keep = ref_node;
+                        break;
+                    }
+                    depth += partial_len;
+                    if let Some(n) = node.find_child(prefix[depth]) {
+                        ref_node = n;
+                    } else {
+                        break;
+                    }
+                }
+                Node::Leaf(node) => {
+                    if node.key.starts_with(prefix) {
+                        keep = ref_node;
+                    }
+                    break;
+                }
+            }
+        }
+        if ptr::eq(keep, &self.root) {
+            return;
+        }
+        let node = if keep.is_null() {
+            ptr::null_mut()
+        } else {
+            unsafe { *keep }
+        };
+        if let Some(Node::ArtNode(n)) = unsafe { node.as_mut() } {
+            // The new root has to cover the bytes of the path to it
+            let leaf_key = n.leaf_key();
+            n.info_mut().prepend(&leaf_key[..depth], depth);
+        }
+        unsafe {
+            if !keep.is_null() {
+                // Detach the subtree so it isn't freed with the rest
+                *keep = ptr::null_mut();
+            }
+        }
+        free_tree(&self.metrics, self.root);
+        self.root = node;
+        self.recount_namespaces();
+        self.tombstones = self.dead_keys().len();
+        if let Some(reverse) = self.reverse.take() {
+            self.index_values(reverse.extract);
+        }
+    }
+
+    // Delete the keys starting with `prefix`, returns how many there were.
+    // One delete per key, so the aggregates, the reverse index and the
+    // node sizes stay as they would after deleting them one by one
+    pub fn delete_prefix(&mut self, prefix: &[u8]) -> usize {
+        let keys: Vec<_> = self
+            .prefix_iter(prefix)
+            .map(|(key, _)| key.to_vec())
+            .collect();
+        for key in keys.iter() {
+            self.delete_bytes(key);
+        }
+        keys.len()
+    }
+
+    // Delete value from the tree
+    pub fn delete(&mut self, key: K) {
+        self.delete_bytes(&key.bytes());
+    }
+
+    // Delete and return the value the key had
+    pub fn remove(&mut self, key: K) -> Option<T> {
+        self.delete_bytes(&key.bytes())
+    }
+
+    fn delete_bytes(&mut self, key_bytes: &[u8]) -> Option<T> {
+        let old = self.remove_leaf(key_bytes);
+        if old.is_some() && !self.namespaces.is_empty() {
+            self.count_in_namespace(key_bytes, false);
+        }
+        old
+    }
+
+    fn remove_leaf(&mut self, key_bytes: &[u8]) -> Option<T> {
+        trace_span!("art.delete", key_len = key_bytes.len());
+        self.reverse_remove(key_bytes);
+        let mut ref_node = &mut self.root as *mut *mut Node<T>;
+        let mut parent_node = &mut self.root as *mut *mut Node<T>;
+        let mut iter_node = self.root;
+        let mut depth = 0;
+        let mut key = 0;
+        let mut old = None;
+        let at_leaf = self.path_check == PathCheck::AtLeaf;
+        while !iter_node.is_null() {
+            match unsafe { &mut *iter_node } {
+                Node::ArtNode(node) if at_leaf => {
+                    depth += node.info().partial_len;
+                    if depth >= key_bytes.len() {
+                        break;
+                    }
+                    match node.find_child(key_bytes[depth]) {
+                        Some(n) => {
+                            key = key_bytes[depth];
+                            parent_node = ref_node;
+                            ref_node = n;
+                            iter_node = *n;
+                        }
+                        None => break,
+                    }
+                }
+                Node::ArtNode(node) => {
+                    let cm = node.prefix(key_bytes, depth);
+                    if cm != node.info().partial_len {
+                        break;
+                    }
+                    depth += cm;
+                    // In this case we want last element
+                    if depth == key_bytes.len() {
+                        depth -= 1;
+                    }
+                    // Iterate until we hit a leaf or don't find any child
+                    if let Some(n) = node.find_child(key_bytes[depth]) {
+                        key = key_bytes[depth];
+                        parent_node = ref_node;
+                        ref_node = n;
+                        iter_node = *n;
+                    } else {
+                        break;
+                    }
+                }
+                Node::Leaf(node) => {
+                    if !node.matches(key_bytes, if at_leaf { 0 } else { depth }) {
+                        break;
+                    }
+                    if self.deletion == Deletion::Lazy {
+                        if !node.dead {
+                            node.dead = true;
+                            self.tombstones += 1;
+                            old = Some(node.value.clone());
+                        }
+                        break;
+                    }
+                    let dead = node.dead;
+                    if dead {
+                        self.tombstones -= 1;
+                    }
+                    self.metrics.free_leaf::<T>(node.key.len());
+                    unsafe {
+                        match &mut **parent_node {
+                            Node::ArtNode(node) => {
+                                node.delete_child(parent_node, ref_node, key, &self.metrics);
+                            }
+                            // Initial case then parent and child node
+                            // might be leaves at the same time
+                            Node::Leaf(_) => {
+                                *ref_node = ptr::null_mut();
+                            }
+                        }
+                        if let (Node::Leaf(leaf), false) = (*Box::from_raw(iter_node), dead) {
+                            old = Some(leaf.value);
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+        self.refresh_path(key_bytes);
+        old
+    }
+
+    pub fn find(&self, key: K) -> Option<&T> {
+        self.find_bytes(&key.bytes())
+    }
+
+    fn find_bytes(&self, key_bytes: &[u8]) -> Option<&T> {
+        trace_span!("art.find", key_len = key_bytes.len());
+        let mut iter_node = self.root;
+        let mut depth = 0;
+        let at_leaf = self.path_check == PathCheck::AtLeaf;
+        while !iter_node.is_null() {
+            match unsafe { &*iter_node } {
+                Node::ArtNode(node) if at_leaf => {
+                    depth += node.info().partial_len;
+                    if depth >= key_bytes.len() {
+                        break;
+                    }
+                    match node.child(key_bytes[depth]) {
+                        Some(n) => iter_node = n,
+                        None => break,
+                    }
+                }
+                Node::ArtNode(node) => {
+                    let cm = node.prefix(key_bytes, depth);
+                    if cm != node.info().partial_len {
+                        self.metrics.incr(&self.metrics.prefix_mismatches);
+                        // The key leaves the path here, the leaf below
+                        // would only be compared past this node
+                        break;
+                    }
+                    depth += cm;
+                    if depth == key_bytes.len() {
+                        depth -= 1;
+                    }
+                    // Iterate until we hit a leaf or don't find any child
+                    if let Some(n) = node.child(key_bytes[depth]) {
+                        iter_node = n;
+                    } else {
+                        break;
+                    }
+                }
+                Node::Leaf(node) => {
+                    if node.matches(key_bytes, if at_leaf { 0 } else { depth }) && !node.dead {
+                        return Some(&node.value);
+                    } else {
+                        return None;
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    // Number of bytes inserting the key would add to the tree,
+    // follows the same path as `insert` without changing anything
+    fn insert_cost(&self, key_bytes: &[u8]) -> usize {
+        let leaf = leaf_size::<T>(key_bytes.len());
+        let mut iter_node = self.root;
+        let mut depth = 0;
+        while !iter_node.is_null() {
+            match unsafe { &*iter_node } {
+                Node::ArtNode(node) => {
+                    let info = *node.info();
+                    // The key diverges inside the partial => split
+                    if node.prefix(key_bytes, depth) != info.partial_len {
+                        return leaf + inner_size::<T>(4);
+                    }
+                    depth += info.partial_len;
+                    if let Some(n) = node.child(key_bytes[depth]) {
+                        iter_node = n;
+                        continue;
+                    }
+                    let capacity = node.child_pointers().len();
+                    if info.count < capacity {
+                        return leaf;
+                    }
+                    // No space for a new child => expand
+                    let grown = match capacity {
+                        4 => 16,
+                        16 => 48,
+                        _ => 256,
+                    };
+                    return leaf + inner_size::<T>(grown) - inner_size::<T>(capacity);
+                }
+                Node::Leaf(node) => {
+                    let cm = depth + common_prefix(&node.key[depth..], &key_bytes[depth..]);
+                    // Rewriting a value doesn't allocate
+                    if cm == key_bytes.len() {
+                        return 0;
+                    }
+                    return leaf + inner_size::<T>(4);
+                }
+            }
+        }
+        leaf
+    }
+
+    // Returns the value the key had
+    pub fn insert(&mut self, key: K, value: T) -> Option<T> {
+        self.insert_bytes(&key.bytes(), value)
+    }
+
+    // Insert only if the key isn't there, otherwise the value comes back
+    // with the existing one, in the same walk down the tree
+    pub fn insert_unique(&mut self, key: K, value: T) -> Result<(), Collision<'_, T>> {
+        let key_bytes = key.bytes();
+        match self.insert_leaf(&key_bytes, value, false) {
+            Ok(_) => {
+                if !self.namespaces.is_empty() {
+                    self.count_in_namespace(&key_bytes, true);
+                }
+                Ok(())
+            }
+            Err((value, existing)) => Err(Collision {
+                value,
+                existing: unsafe { &*existing },
+            }),
+        }
+    }
+
+    // Insert unless the key is longer than `max_key_len` or the memory
+    // budget doesn't allow it
+    pub fn try_insert(&mut self, key: K, value: T) -> Result<(), Error> {
+        self.try_insert_bytes(&key.bytes(), value).map(|_| ())
+    }
+
+    fn try_insert_bytes(&mut self, key_bytes: &[u8], value: T) -> Result<Option<T>, Error> {
+        if let Some(max) = self.max_key_len {
+            if key_bytes.len() > max {
+                return Err(Error::KeyTooLong {
+                    len: key_bytes.len(),
+                    max,
+                });
+            }
+        }
+        if let Some(budget) = self.memory_budget {
+            let needed = self.metrics.memory() + self.insert_cost(key_bytes);
+            if needed > budget {
+                return Err(Error::CapacityExceeded { needed, budget });
+            }
+        }
+        Ok(self.insert_bytes(key_bytes, value))
+    }
+
+    // Same as `insert`, `find` and `delete` with keys of any type the codec
+    // encodes, in place of the `ArtKey` bytes
+    pub fn insert_with<Q: ?Sized, C: KeyCodec<Q>>(
+        &mut self,
+        codec: &C,
+        key: &Q,
+        value: T,
+    ) -> Option<T> {
+        self.insert_bytes(&codec.to_bytes(key), value)
+    }
+
+    pub fn find_with<Q: ?Sized, C: KeyCodec<Q>>(&self, codec: &C, key: &Q) -> Option<&T> {
+        self.find_bytes(&codec.to_bytes(key))
+    }
+
+    pub fn delete_with<Q: ?Sized, C: KeyCodec<Q>>(&mut self, codec: &C, key: &Q) {
+        self.delete_bytes(&codec.to_bytes(key));
+    }
+
+    pub fn remove_with<Q: ?Sized, C: KeyCodec<Q>>(&mut self, codec: &C, key: &Q) -> Option<T> {
+        self.delete_bytes(&codec.to_bytes(key))
+    }
+
+    fn insert_bytes(&mut self, key_bytes: &[u8], value: T) -> Option<T> {
+        let old = match self.insert_leaf(key_bytes, value, true) {
+            Ok(old) => old,
+            Err(_) => unreachable!("an insert that overwrites doesn't collide"),
+        };
+        if old.is_none() && !self.namespaces.is_empty() {
+            self.count_in_namespace(key_bytes, true);
+        }
+        old
+    }
+
+    // Returns the value the key had. Without `overwrite` a live leaf of the
+    // key is left as it is, the value comes back with the one in the leaf
+    fn insert_leaf(
+        &mut self,
+        key_bytes: &[u8],
+        value: T,
+        overwrite: bool,
+    ) -> Result<Option<T>, (T, *const T)> {
+        trace_span!("art.insert", key_len = key_bytes.len());
+        let extracted = self
+            .reverse
+            .as_ref()
+            .map(|reverse| (reverse.extract)(&value));
+        let mut new_leaf = NewLeaf {
+            key: key_bytes,
+            value: Some(value),
+        };
+        let mut depth = 0;
         let mut iter_node = self.root;
         let mut parent_node = &mut self.root as *mut *mut Node<T>;
-        let new_leaf = Box::into_raw(Box::new(Node::Leaf(LeafNode::new(
-            value.clone(),
-            &key_bytes,
-        ))));
+        let mut old = None;
+        if self.root.is_null() {
+            self.root = new_leaf.take(&self.metrics);
+        }
         while !iter_node.is_null() {
             match unsafe { &mut *iter_node } {
                 Node::ArtNode(node) => {
                     if !node.insert(
-                        &key_bytes,
+                        key_bytes,
                         &mut depth,
                         &mut iter_node,
-                        new_leaf,
+                        &mut new_leaf,
                         &mut parent_node,
+                        &self.metrics,
                     ) {
                         break;
                     }
                 }
-                // Either rewrite or split the node
-                Node::Leaf(node) => {
-                    let cm = depth + common_prefix(&node.key[depth..], &key_bytes[depth..]);
-                    println!(
-                        "{:?}, {:?}, {:?}",
-                        &key_bytes[depth..cm],
-                        &key_bytes,
-                        &node.key
-                    );
-                    // Rewrite value of existing node
-                    if key_bytes.len() == cm {
-                        println!("{:?}, {:?}, {:?}", value, node.value, key);
-                        node.value = value;
-                        break;
+                // Either rewrite or split the node
+                Node::Leaf(node) => {
+                    let cm = depth + common_prefix(&node.key[depth..], &key_bytes[depth..]);
+                    // Rewrite value of existing node, the value of a dead
+                    // one isn't in the map anymore
+                    if key_bytes.len() == cm {
+                        let value = new_leaf.value.take().unwrap();
+                        if !overwrite && !node.dead {
+                            return Err((value, &node.value as *const T));
+                        }
+                        let replaced = std::mem::replace(&mut node.value, value);
+                        if node.dead {
+                            node.dead = false;
+                            self.tombstones -= 1;
+                        } else {
+                            old = Some(replaced);
+                        }
+                        break;
+                    }
+                    // Split node
+                    self.metrics.incr(&self.metrics.splits);
+                    self.metrics.alloc_inner::<T>(4);
+                    let mut new_node = Node4::new(&key_bytes[depth..cm]);
+                    new_node.add(new_leaf.take(&self.metrics), key_bytes, cm);
+                    new_node.add(iter_node, &node.key, cm);
+                    unsafe {
+                        *parent_node = Box::into_raw(Box::new(Node::ArtNode(Box::new(new_node))));
+                    }
+                    break;
+                }
+            }
+        }
+        if let (Some(reverse), Some(extracted)) = (self.reverse.as_mut(), extracted) {
+            if let Some(old) = old.as_ref() {
+                reverse
+                    .index
+                    .remove(&(reverse.extract)(old), &key_bytes.to_vec());
+            }
+            reverse.index.insert(&extracted, key_bytes.to_vec());
+        }
+        self.refresh_path(key_bytes);
+        Ok(old)
+    }
+}
+
+// Name of the tree image `CowArt::backup_to` writes
+const BACKUP_FILE: &str = "art.sst";
+
+// Node of the versions of a `CowArt`: the bytes of the path from its
+// parent, the value of the key ending here and the children ordered by the
+// first byte of their paths. Versions share the nodes they have in common,
+// a node is only changed through `Arc::make_mut`, which copies it if
+// another version still uses it
+#[derive(Debug, Clone)]
+struct CowNode<T> {
+    path: Vec<u8>,
+    value: Option<T>,
+    children: Vec<Arc<CowNode<T>>>,
+}
+
+impl<T> CowNode<T> {
+    fn child(&self, byte: u8) -> Result<usize, usize> {
+        self.children
+            .binary_search_by_key(&byte, |child| child.path[0])
+    }
+}
+
+// Insert below `node` the key whose bytes past the path of `node` are `rest`
+fn cow_insert<T: Clone>(node: &mut Arc<CowNode<T>>, rest: &[u8], value: T) -> Option<T> {
+    let node = Arc::make_mut(node);
+    if rest.is_empty() {
+        return node.value.replace(value);
+    }
+    let i = match node.child(rest[0]) {
+        Ok(i) => i,
+        Err(i) => {
+            let leaf = CowNode {
+                path: rest.to_vec(),
+                value: Some(value),
+                children: vec![],
+            };
+            node.children.insert(i, Arc::new(leaf));
+            return None;
+        }
+    };
+    let child = &mut node.children[i];
+    let cm = common_prefix(&child.path, rest);
+    if cm < child.path.len() {
+        // The key leaves the path of the child, which goes below a new node
+        // with the shared part
+        Arc::make_mut(child).path.drain(..cm);
+        let split = CowNode {
+            path: rest[..cm].to_vec(),
+            value: None,
+            children: vec![],
+        };
+        let lower = std::mem::replace(child, Arc::new(split));
+        Arc::make_mut(child).children.push(lower);
+    }
+    cow_insert(child, &rest[cm..], value)
+}
+
+// Remove the key below `node`, which has to be there. A child left without
+// a value is removed or merged with its only child
+fn cow_remove<T: Clone>(node: &mut Arc<CowNode<T>>, rest: &[u8]) -> Option<T> {
+    let node = Arc::make_mut(node);
+    if rest.is_empty() {
+        return node.value.take();
+    }
+    let i = node.child(rest[0]).ok()?;
+    let len = node.children[i].path.len();
+    let old = cow_remove(&mut node.children[i], &rest[len..]);
+    let child = &node.children[i];
+    if child.value.is_none() && child.children.len() < 2 {
+        let child = node.children.remove(i);
+        if let Some(grandchild) = child.children.first() {
+            let mut grandchild = Arc::clone(grandchild);
+            let path = &mut Arc::make_mut(&mut grandchild).path;
+            path.splice(0..0, child.path.iter().copied());
+            node.children.insert(i, grandchild);
+        }
+    }
+    old
+}
+
+// Version of a `CowArt`. Cloning one only clones the `Arc` of the root,
+// a write to the clone copies the nodes on the path to its key
+pub struct CowVersion<K, T> {
+    root: Arc<CowNode<T>>,
+    len: usize,
+    key: PhantomData<K>,
+}
+
+impl<K, T> Clone for CowVersion<K, T> {
+    fn clone(&self) -> Self {
+        Self {
+            root: Arc::clone(&self.root),
+            len: self.len,
+            key: PhantomData,
+        }
+    }
+}
+
+impl<K: ArtKey, T: Clone> Default for CowVersion<K, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: ArtKey, T: Clone> CowVersion<K, T> {
+    pub fn new() -> Self {
+        Self {
+            root: Arc::new(CowNode {
+                path: vec![],
+                value: None,
+                children: vec![],
+            }),
+            len: 0,
+            key: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn find(&self, key: K) -> Option<&T> {
+        self.find_bytes(&key.bytes())
+    }
+
+    fn find_bytes(&self, key_bytes: &[u8]) -> Option<&T> {
+        let mut node = &*self.root;
+        let mut rest = key_bytes;
+        while !rest.is_empty() {
+            node = &node.children[node.child(rest[0]).ok()?];
+            rest = rest.strip_prefix(&node.path[..])?;
+        }
+        node.value.as_ref()
+    }
+
+    // Returns the value the key had
+    pub fn insert(&mut self, key: K, value: T) -> Option<T> {
+        self.insert_bytes(&key.bytes(), value)
+    }
+
+    fn insert_bytes(&mut self, key_bytes: &[u8], value: T) -> Option<T> {
+        let old = cow_insert(&mut self.root, key_bytes, value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn remove(&mut self, key: K) -> Option<T> {
+        let key_bytes = key.bytes();
+        // Nothing is copied for a key that isn't there
+        self.find_bytes(&key_bytes)?;
+        self.len -= 1;
+        cow_remove(&mut self.root, &key_bytes)
+    }
+
+    pub fn delete(&mut self, key: K) {
+        self.remove(key);
+    }
+
+    // Pairs in the key order
+    pub fn iter(&self) -> CowIter<'_, T> {
+        CowIter {
+            stack: vec![(&*self.root, 0)],
+            key: vec![],
+        }
+    }
+}
+
+// Iterator over the keys and values of a `CowVersion` in the key order.
+// The keys are put together from the paths, so they come out owned
+pub struct CowIter<'a, T> {
+    // Nodes to visit with the length of the key above them
+    stack: Vec<(&'a CowNode<T>, usize)>,
+    key: Vec<u8>,
+}
+
+impl<'a, T> Iterator for CowIter<'a, T> {
+    type Item = (Vec<u8>, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, depth)) = self.stack.pop() {
+            self.key.truncate(depth);
+            self.key.extend_from_slice(&node.path);
+            let depth = self.key.len();
+            self.stack
+                .extend(node.children.iter().rev().map(|child| (&**child, depth)));
+            if let Some(value) = node.value.as_ref() {
+                return Some((self.key.clone(), value));
+            }
+        }
+        None
+    }
+}
+
+// Tree for read-mostly use from many threads, e.g. a routing table.
+// Readers take the current version with `load`, which holds a read lock
+// only to clone the `Arc` of its root, and search it without locks, a
+// version never changes. Writers copy the nodes on the paths to the keys
+// they change, share the rest with the current version and swap the new
+// root in, one writer at a time. Nodes are freed with the last version
+// using them. Unlike `Art`, a key may be a prefix of another
+pub struct CowArt<K, T> {
+    // Only held to clone or replace the version
+    current: RwLock<CowVersion<K, T>>,
+    writer: Mutex<()>,
+}
+
+impl<K: ArtKey, T: Clone> Default for CowArt<K, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, T: 'static + Clone + std::fmt::Debug> From<&Art<K, T>> for CowArt<K, T>
+where
+    K: ArtKey + std::marker::Sized + std::fmt::Debug,
+{
+    fn from(art: &Art<K, T>) -> Self {
+        let mut version = CowVersion::new();
+        for (key, value) in art.iter() {
+            version.insert_bytes(key, value.clone());
+        }
+        Self::from(version)
+    }
+}
+
+impl<K, T> From<CowVersion<K, T>> for CowArt<K, T> {
+    fn from(version: CowVersion<K, T>) -> Self {
+        Self {
+            current: RwLock::new(version),
+            writer: Mutex::new(()),
+        }
+    }
+}
+
+impl<K: ArtKey, T: Clone> CowArt<K, T> {
+    pub fn new() -> Self {
+        Self::from(CowVersion::new())
+    }
+
+    // A writer panicking leaves the old version in place, so a poisoned
+    // lock is still used
+    pub fn load(&self) -> CowVersion<K, T> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn find(&self, key: K) -> Option<T> {
+        self.load().find(key).cloned()
+    }
+
+    // Change the current version with `f` and make the result the current
+    // one. Writers wait for each other, so no write is lost, and the nodes
+    // `f` changes are copied only once however often it changes them
+    pub fn update<R>(&self, f: impl FnOnce(&mut CowVersion<K, T>) -> R) -> R {
+        let _writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let mut version = self.load();
+        let result = f(&mut version);
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = version;
+        result
+    }
+
+    pub fn insert(&self, key: K, value: T) -> Option<T> {
+        self.update(|version| version.insert(key, value))
+    }
+
+    pub fn delete(&self, key: K) {
+        self.update(|version| version.delete(key));
+    }
+
+    // Write the current version to `dir/art.sst` while writers go on with
+    // newer ones, returns the number of pairs in it. The file is synced
+    // under a temporary name and renamed, so a failed backup keeps the
+    // previous one
+    pub fn backup_to<P: AsRef<Path>>(&self, dir: P) -> Result<usize, Error>
+    where
+        T: Codec,
+    {
+        let version = self.load();
+        fs::create_dir_all(&dir)?;
+        let path = dir.as_ref().join(BACKUP_FILE);
+        let tmp = dir.as_ref().join(format!("{}.tmp", BACKUP_FILE));
+        let mut sst = SstWriter::new(io::BufWriter::new(File::create(&tmp)?));
+        let mut value = vec![];
+        for (key, val) in version.iter() {
+            value.clear();
+            val.encode(&mut value);
+            sst.add(&key, &value)?;
+        }
+        let file = sst.finish()?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(version.len())
+    }
+
+    // Tree from the last `backup_to` into `dir`
+    pub fn restore_from<P: AsRef<Path>>(dir: P) -> Result<Self, Error>
+    where
+        T: Codec,
+    {
+        let mut version = CowVersion::new();
+        let mut corrupt = false;
+        SstReader::new(File::open(dir.as_ref().join(BACKUP_FILE))?)?.for_each(|key, value| {
+            match T::decode(value) {
+                Some(value) => {
+                    version.insert_bytes(key, value);
+                }
+                None => corrupt = true,
+            }
+        })?;
+        if corrupt {
+            return Err(Error::Corrupt("bad value in sst".to_string()));
+        }
+        Ok(Self::from(version))
+    }
+}
+
+// Rebuild a radix tree or a trie of chars from the pairs of a tree with
+// string keys and the other way around. No key of an `Art` may be a prefix
+// of another, so the keys going into one have to be, e.g. ending with \0,
+// and a tree or a trie with a key that is a prefix of another gives
+// `Error::KeyIsPrefix`. Keys of an `Art` that aren't UTF-8 give `Error::Utf8`
+impl<V: 'static + Clone + std::fmt::Debug> TryFrom<&Art<String, V>> for RadixTree<V> {
+    type Error = Error;
+
+    fn try_from(art: &Art<String, V>) -> Result<Self, Error> {
+        let mut tree = RadixTree::new();
+        for (key, value) in art.iter() {
+            tree.insert(std::str::from_utf8(key)?.to_string(), value.clone());
+        }
+        Ok(tree)
+    }
+}
+
+impl<V: 'static + Clone + std::fmt::Debug> TryFrom<&RadixTree<V>> for Art<String, V> {
+    type Error = Error;
+
+    fn try_from(tree: &RadixTree<V>) -> Result<Self, Error> {
+        art_of_strings(
+            tree.iter()
+                .map(|(key, value)| (key, value.clone()))
+                .collect(),
+        )
+    }
+}
+
+impl<V: 'static + Clone + std::fmt::Debug> TryFrom<&Art<String, V>> for TrieMap<char, V> {
+    type Error = Error;
+
+    fn try_from(art: &Art<String, V>) -> Result<Self, Error> {
+        let mut trie = TrieMap::new();
+        for (key, value) in art.iter() {
+            trie.insert(&mut std::str::from_utf8(key)?.chars(), value.clone());
+        }
+        Ok(trie)
+    }
+}
+
+impl<V: 'static + Clone + std::fmt::Debug> TryFrom<&TrieMap<char, V>> for Art<String, V> {
+    type Error = Error;
+
+    fn try_from(trie: &TrieMap<char, V>) -> Result<Self, Error> {
+        let pairs = trie
+            .iter()
+            .map(|(key, value)| (key.into_iter().collect(), value.clone()));
+        art_of_strings(pairs.collect())
+    }
+}
+
+// Tree of the pairs, checked before anything goes in. Once sorted a key
+// that is a prefix of others is a prefix of the one right after it
+fn art_of_strings<V: 'static + Clone + std::fmt::Debug>(
+    mut pairs: Vec<(String, V)>,
+) -> Result<Art<String, V>, Error> {
+    pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    if let Some(pair) = pairs.windows(2).find(|w| w[1].0.starts_with(&w[0].0)) {
+        return Err(Error::KeyIsPrefix {
+            key: pair[0].0.clone().into_bytes(),
+        });
+    }
+    let mut art = Art::new();
+    for (key, value) in pairs {
+        art.insert(key, value);
+    }
+    Ok(art)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_add_and_find() {
+        let mut art = Art::<u32, u32>::new();
+        let mut data = std::collections::HashMap::new();
+        let mut rng = rand::thread_rng();
+
+        for _i in 0..100_000 {
+            data.insert(rng.gen::<u32>(), rng.gen::<u32>());
+        }
+
+        for (key, val) in &data {
+            art.insert(*key, *val);
+        }
+
+        for (key, val) in &data {
+            assert_eq!(val, art.find(*key).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_add_and_delete() {
+        let mut art = Art::<u32, u32>::new();
+        let mut data = std::collections::HashMap::new();
+        let mut rng = rand::thread_rng();
+
+        for _i in 0..100_000 {
+            data.insert(rng.gen::<u32>(), rng.gen::<u32>());
+        }
+
+        for (key, val) in &data {
+            art.insert(*key, *val);
+        }
+
+        for key in data.keys() {
+            art.delete(*key);
+        }
+        assert_eq!(0, art.bfs_count());
+    }
+
+    #[test]
+    fn test_bfs_count() {
+        // A Node256 whose children aren't in its first slots
+        let mut art = Art::<u32, u32>::new();
+        for byte in 0..256 {
+            art.insert(byte << 24, byte);
+        }
+        for byte in 0..100 {
+            art.delete(byte << 24);
+        }
+        assert_eq!(157, art.bfs_count());
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let mut art = Art::<u32, u64>::new();
+        art.set_aggregation(|a, b| a + b);
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        // Full nodes in the two low bytes, then deletes leaving them about
+        // half full, above the delete thresholds
+        for key in 0..(1 << 16) {
+            art.insert(key, key as u64);
+            data.insert(key, key as u64);
+        }
+        for key in 0..(1 << 16) {
+            if rng.gen_range(0, 5) < 2 || (key >> 8) % 7 == 0 && key & 0xff >= 10 {
+                art.delete(key);
+                data.remove(&key);
+            }
+        }
+        let before = art.memory_usage();
+        let shrunk = art.shrink_to_fit();
+        assert!(shrunk > 0 && art.memory_usage() < before);
+        art.check().unwrap();
+        assert!(art.iter().map(|(_, v)| v).eq(data.values()));
+        for key in 0..(1 << 16) {
+            assert_eq!(data.get(&key), art.find(key));
+        }
+        assert_eq!(Some(data.values().sum()), art.aggregate_prefix(&[]));
+        // Every node is as small as it can be now
+        let mut stack = vec![art.root];
+        while let Some(node) = stack.pop() {
+            if let Node::ArtNode(n) = unsafe { &*node } {
+                let capacity = n.child_pointers().len();
+                let smaller = match capacity {
+                    4 => 0,
+                    16 => 4,
+                    48 => 16,
+                    _ => 48,
+                };
+                assert!(n.info().count > smaller);
+                n.push_children(&mut stack);
+            }
+        }
+        assert_eq!(0, art.shrink_to_fit());
+        // The shrunk nodes still grow and shrink
+        for key in 0..(1 << 16) {
+            art.insert(key, 1);
+        }
+        for key in 0..(1 << 16) {
+            art.delete(key);
+        }
+        assert_eq!(0, art.memory_usage());
+    }
+
+    #[test]
+    fn test_delete_then_drop() {
+        // Deleting from a Node4/Node16 must not leave a freed pointer behind
+        // for `Drop` or the shrink to Node4 to free again
+        let mut art = Art::<u32, u32>::new();
+        for key in 0..20u32 {
+            art.insert(key, key);
+        }
+        for key in (0..20u32).step_by(3) {
+            art.delete(key);
+            art.check().unwrap();
+        }
+        for key in 0..20u32 {
+            assert_eq!(art.find(key).is_some(), key % 3 != 0);
+        }
+        // The same in a Node4 that stays one, the input the fuzzer found
+        let mut art = Art::<u32, u32>::new();
+        for key in 0..3u32 {
+            art.insert(key, key);
+        }
+        art.delete(0);
+        art.check().unwrap();
+        assert_eq!(vec![1, 2], art.iter().map(|(_, v)| *v).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut art = Art::<u32, u32>::new();
+        // Nothing is counted until it's turned on
+        art.insert(9, 9);
+        art.insert(8, 8);
+        art.delete(9);
+        art.delete(8);
+        assert_eq!(0, art.metrics().splits() + art.metrics().shrinks());
+        art.set_metrics(true);
+        // The second key splits the root leaf, the fifth grows Node4 to Node16
+        for key in 0..5u32 {
+            art.insert(key, key);
+        }
+        assert_eq!(1, art.metrics().splits());
+        assert_eq!(1, art.metrics().grows());
+        // Diverges inside the partial prefix of the root
+        art.insert(1 << 24, 0);
+        assert_eq!(2, art.metrics().splits());
+        assert_eq!(1, art.metrics().prefix_mismatches());
+        art.metrics().reset();
+        assert_eq!(0, art.metrics().splits());
+        // Node16 shrinks to Node4, which then collapses twice
+        // as its children are removed
+        for key in 0..5u32 {
+            art.delete(key);
+        }
+        assert_eq!(3, art.metrics().shrinks());
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let mut art = Art::<u32, u32>::new();
+        let mut rng = rand::thread_rng();
+        for _i in 0..10_000 {
+            art.insert(rng.gen::<u32>(), 0);
+        }
+        let usage = art.memory_usage();
+        assert!(usage > 0);
+
+        art.set_memory_budget(Some(usage + 100_000));
+        let mut inserted = vec![];
+        loop {
+            let key = rng.gen::<u32>();
+            match art.try_insert(key, 1) {
+                Ok(()) => inserted.push(key),
+                Err(Error::CapacityExceeded { needed, budget }) => {
+                    assert!(needed > budget);
+                    break;
+                }
+                Err(err) => panic!("{}", err),
+            }
+        }
+        assert!(art.memory_usage() <= usage + 100_000);
+        assert_eq!(subtree_memory(art.root), art.memory_usage());
+        for key in &inserted {
+            assert_eq!(Some(&1), art.find(*key));
+        }
+
+        for key in inserted.iter() {
+            art.delete(*key);
+        }
+        assert_eq!(subtree_memory(art.root), art.memory_usage());
+
+        art.set_memory_budget(Some(art.memory_usage()));
+        assert!(matches!(
+            art.try_insert(inserted[0], 1),
+            Err(Error::CapacityExceeded { .. })
+        ));
+        art.set_memory_budget(None);
+        art.try_insert(inserted[0], 1).unwrap();
+        assert_eq!(Some(&1), art.find(inserted[0]));
+    }
+
+    #[test]
+    fn test_memory_by_prefix() {
+        let mut art = Art::<u32, u32>::new();
+        let mut rng = rand::thread_rng();
+        // Two tenants, the first one with a lot more keys
+        for _i in 0..1000 {
+            art.insert(1 << 24 | rng.gen::<u16>() as u32, 0);
+        }
+        for _i in 0..10 {
+            art.insert(2 << 24 | rng.gen::<u16>() as u32, 0);
+        }
+        let usage = art.memory_by_prefix(1);
+        assert_eq!(art.memory_usage(), usage.values().sum::<usize>());
+        assert!(usage[&vec![1]] > usage[&vec![2]]);
+
+        // At full key length every leaf is its own prefix
+        art.insert(3 << 24, 0);
+        let usage = art.memory_by_prefix(4);
+        assert_eq!(art.memory_usage(), usage.values().sum::<usize>());
+        assert_eq!(leaf_size::<u32>(4), usage[&(3u32 << 24).to_be_bytes()[..]]);
+    }
+
+    #[test]
+    fn test_delete_merges_prefix() {
+        let mut art = Art::<u32, u32>::new();
+        art.insert(0x0102_0304, 1);
+        art.insert(0x0102_0305, 2);
+        art.insert(0x0109_0909, 3);
+        // The root collapses into the node holding the first two keys
+        art.delete(0x0109_0909);
+        assert_eq!(Some(&1), art.find(0x0102_0304));
+        assert_eq!(Some(&2), art.find(0x0102_0305));
+        assert_eq!(None, art.find(0x0109_0909));
+    }
+
+    #[cfg(feature = "alloc-profile")]
+    #[test]
+    fn test_alloc_profile() {
+        let mut art = Art::<u32, u32>::new();
+        let mut rng = rand::thread_rng();
+        let mut peak = 0;
+        for _ in 0..5_000 {
+            art.insert(rng.gen_range(0, 100_000), 0);
+            peak = peak.max(art.memory_usage());
+        }
+        let loaded = art.alloc_profile();
+        assert_eq!(art.memory_usage(), loaded.live_bytes());
+        // A grown node is allocated before the old one is freed
+        let slack = inner_size::<u32>(256);
+        assert!(peak <= loaded.peak_bytes && loaded.peak_bytes <= peak + slack);
+        let live: u64 = loaded.kinds.iter().map(|k| k.allocs - k.frees).sum();
+        assert_eq!(art.bfs_count() as u64, live);
+        // Growing Node4 to Node16 frees the Node4
+        assert!(loaded.kinds[2].allocs > 0 && loaded.kinds[1].frees > 0);
+
+        let keys: Vec<_> = art.iter().map(|(key, _)| key.to_vec()).collect();
+        for key in keys.iter().skip(10) {
+            art.delete_bytes(key);
+        }
+        art.retain_prefix(&keys[0][..1]);
+        let profile = art.alloc_profile();
+        assert_eq!(art.memory_usage(), profile.live_bytes());
+        assert_eq!(loaded.peak_bytes, profile.peak_bytes);
+        assert_eq!(profile.kinds[0].allocs, loaded.kinds[0].allocs);
+        // Only shrinking nodes allocate during deletes
+        let shrinks = (profile.allocs() - loaded.allocs()) as f64;
+        assert_eq!(
+            shrinks / 2.0,
+            profile.alloc_rate(&loaded, std::time::Duration::from_secs(2))
+        );
+        assert_eq!(profile, art.clone().alloc_profile());
+    }
+
+    #[test]
+    fn test_retain_prefix() {
+        let mut art = Art::<u32, u32>::new();
+        let mut data = std::collections::HashMap::new();
+        let mut rng = rand::thread_rng();
+        for _i in 0..10_000 {
+            let key = (rng.gen::<u32>() % 4) << 24 | rng.gen::<u16>() as u32;
+            data.insert(key, rng.gen::<u32>());
+        }
+        for (key, val) in &data {
+            art.insert(*key, *val);
+        }
+
+        art.retain_prefix(&[2]);
+        for (key, val) in &data {
+            if key >> 24 == 2 {
+                assert_eq!(Some(val), art.find(*key));
+            } else {
+                assert_eq!(None, art.find(*key));
+            }
+        }
+        assert_eq!(subtree_memory(art.root), art.memory_usage());
+
+        // Narrow down to a single key
+        let (key, val) = data.iter().find(|(key, _)| *key >> 24 == 2).unwrap();
+        art.retain_prefix(&key.to_be_bytes());
+        assert_eq!(Some(val), art.find(*key));
+        assert_eq!(1, art.bfs_count());
+
+        art.retain_prefix(&[3]);
+        assert_eq!(0, art.bfs_count());
+        assert_eq!(0, art.memory_usage());
+    }
+
+    #[test]
+    fn test_delete_prefix() {
+        let mut art = Art::<u32, u32>::new();
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for _ in 0..10_000 {
+            let key = (rng.gen::<u32>() % 4) << 24 | rng.gen::<u16>() as u32;
+            art.insert(key, key);
+            data.insert(key, key);
+        }
+        let doomed = data.keys().filter(|key| *key >> 8 == 0x02_00_07).count();
+        assert_eq!(doomed, art.delete_prefix(&[2, 0, 7]));
+        let doomed = data.keys().filter(|key| *key >> 24 == 1).count();
+        assert_eq!(doomed, art.delete_prefix(&[1]));
+        data.retain(|key, _| *key >> 24 != 1 && *key >> 8 != 0x02_00_07);
+        assert!(art.iter().map(|(_, v)| v).eq(data.values()));
+        assert_eq!(subtree_memory(art.root), art.memory_usage());
+        assert_eq!(0, art.delete_prefix(&[1]));
+        let (&key, &value) = data.iter().next().unwrap();
+        assert_eq!(Some(value), art.remove(key));
+        assert_eq!(None, art.remove(key));
+        data.remove(&key);
+        art.set_deletion(Deletion::Lazy);
+        let (&key, &value) = data.iter().next().unwrap();
+        assert_eq!(Some(value), art.remove(key));
+        assert_eq!(None, art.remove(key));
+        data.remove(&key);
+        art.set_deletion(Deletion::Eager);
+        art.compact();
+        assert_eq!(data.len(), art.delete_prefix(&[]));
+        assert_eq!(0, art.memory_usage());
+    }
+
+    #[test]
+    fn test_scan_page() {
+        let mut art = Art::<u32, u32>::new();
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for _ in 0..5_000 {
+            let key = (rng.gen::<u32>() % 4) << 24 | rng.gen::<u16>() as u32;
+            art.insert(key, key);
+            data.insert(key, key);
+        }
+        let prefix = [2];
+        let mut seen = vec![];
+        let mut touched = std::collections::HashSet::new();
+        let untouched: Vec<_> = data.range(2 << 24..3 << 24).map(|(&k, _)| k).collect();
+        let mut token: Option<PageToken> = None;
+        loop {
+            let limit = rng.gen_range(1, 100);
+            // Through a string, as a client would send it back
+            let resume = token.map(|t| t.to_string().parse().unwrap());
+            let (page, next) = art.scan_page(&prefix, limit, resume.as_ref()).unwrap();
+            assert!(page.len() <= limit);
+            assert!(next.is_none() || page.len() == limit);
+            seen.extend(page.iter().map(|&(key, _)| key));
+            token = next;
+            if token.is_none() {
+                break;
+            }
+            // Writes between the pages, on both sides of the cursor
+            for _ in 0..20 {
+                let key = 2 << 24 | rng.gen::<u16>() as u32;
+                touched.insert(key);
+                if rng.gen() {
+                    art.insert(key, key);
+                    data.insert(key, key);
+                } else {
+                    art.delete(key);
+                    data.remove(&key);
+                }
+            }
+        }
+        // In order, no key twice and every key there all along
+        assert!(seen.windows(2).all(|w| w[0] < w[1]));
+        assert!(seen.iter().all(|key| key >> 24 == 2));
+        assert!(untouched
+            .iter()
+            .filter(|key| !touched.contains(key))
+            .all(|key| seen.binary_search(key).is_ok()));
+
+        let expected: Vec<_> = data.range(3 << 24..).map(|(&k, &v)| (k, v)).collect();
+        let page = art.scan_page(&[3], data.len(), None).unwrap();
+        assert_eq!((expected, None), page);
+        assert_eq!((vec![], None), art.scan_page(&[9], 10, None).unwrap());
+        assert!(matches!(
+            art.scan_page(&[2], 0, None),
+            Err(Error::InvalidArgument(_))
+        ));
+        // A token before the prefix starts from the prefix
+        let before = Some(PageToken(vec![1, 0xff]));
+        let (page, _) = art.scan_page(&[2], 1, before.as_ref()).unwrap();
+        assert_eq!(
+            data.range(2 << 24..).next().map(|(&k, &v)| (k, v)),
+            page.first().copied()
+        );
+        assert!("abc".parse::<PageToken>().is_err() && "zz".parse::<PageToken>().is_err());
+        assert_eq!(Ok(PageToken(vec![0, 0xab])), "00ab".parse().map_err(|_| ()));
+
+        // Keys that aren't keys of `K` fail the page rather than go missing
+        let mut art = Art::<String, u32>::new();
+        art.insert("a".to_string(), 1);
+        art.insert_with(&Raw, &[b'b', 0xff][..], 2);
+        let (page, token) = art.scan_page(b"", 1, None).unwrap();
+        assert_eq!(vec![("a".to_string(), 1)], page);
+        assert!(matches!(
+            art.scan_page(b"", 1, token.as_ref()),
+            Err(Error::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_iter_order() {
+        let mut art = Art::<u32, u32>::new();
+        let mut data = std::collections::BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for _i in 0..10_000 {
+            data.insert(rng.gen::<u32>(), rng.gen::<u32>());
+        }
+        for (key, val) in &data {
+            art.insert(*key, *val);
+        }
+        let pairs = art.iter().collect::<Vec<_>>();
+        assert_eq!(data.len(), pairs.len());
+        for ((key, val), (art_key, art_val)) in data.iter().zip(pairs) {
+            assert_eq!(&key.to_be_bytes(), art_key);
+            assert_eq!(val, art_val);
+        }
+    }
+
+    #[test]
+    fn test_export_import_sst() {
+        let mut art = Art::<u32, u64>::new();
+        let mut rng = rand::thread_rng();
+        for _i in 0..10_000 {
+            art.insert(rng.gen::<u32>(), rng.gen::<u64>());
+        }
+        let mut file = vec![];
+        art.export_sst(&mut file).unwrap();
+
+        let mut imported = Art::<u32, u64>::new();
+        let count = imported.import_sst(std::io::Cursor::new(&file)).unwrap();
+        assert_eq!(art.iter().count(), count);
+        assert!(art.iter().eq(imported.iter()));
+
+        // Values of the wrong size don't decode
+        let mut wrong = Art::<u32, u32>::new();
+        let imported = wrong.import_sst(std::io::Cursor::new(&file));
+        assert!(matches!(imported, Err(Error::Corrupt(_))));
+
+        // The limits apply to the imported pairs
+        let mut limited = Art::<u32, u64>::new();
+        limited.set_max_key_len(Some(2));
+        let imported = limited.import_sst(std::io::Cursor::new(&file));
+        assert!(matches!(
+            imported,
+            Err(Error::KeyTooLong { len: 4, max: 2 })
+        ));
+
+        // An import past the memory budget leaves the tree as it was
+        let mut limited = Art::<u32, u64>::new();
+        limited.insert(7, 7);
+        let memory = limited.memory_usage();
+        limited.set_memory_budget(Some(art.memory_usage() / 2));
+        let imported = limited.import_sst(std::io::Cursor::new(&file));
+        assert!(matches!(imported, Err(Error::CapacityExceeded { .. })));
+        assert_eq!(
+            vec![(&7u32.to_be_bytes()[..], &7)],
+            limited.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(memory, limited.memory_usage());
+        limited.check().unwrap();
+
+        // Same when the budget runs out half way, the overwritten values
+        // come back and the lazily deleted keys stay dead
+        let mut limited = Art::<u32, u64>::new();
+        limited.set_deletion(Deletion::Lazy);
+        let (first, _) = art.iter().next().unwrap();
+        let first = u32::from_bytes(first).unwrap();
+        limited.insert(first, 7);
+        limited.insert(first ^ 1, 7);
+        limited.delete(first ^ 1);
+        let before: Vec<_> = limited.iter().map(|(k, v)| (k.to_vec(), *v)).collect();
+        limited.set_memory_budget(Some(art.memory_usage() / 2));
+        let imported = limited.import_sst(std::io::Cursor::new(&file));
+        assert!(matches!(imported, Err(Error::CapacityExceeded { .. })));
+        assert!(limited
+            .iter()
+            .map(|(k, v)| (k.to_vec(), *v))
+            .eq(before.into_iter()));
+        assert_eq!(None, limited.find(first ^ 1));
+        limited.check().unwrap();
+
+        // Keys that are prefixes of others, in the table or in the tree
+        let mut file = vec![];
+        let mut sst = SstWriter::new(&mut file);
+        for key in [&b"ab"[..], b"abc", b"b"].iter() {
+            sst.add(key, &1u64.to_be_bytes()).unwrap();
+        }
+        sst.finish().unwrap();
+        let mut prefixed = Art::<String, u64>::new();
+        assert!(matches!(
+            prefixed.import_sst(std::io::Cursor::new(&file)),
+            Err(Error::KeyIsPrefix { key }) if key == b"ab"
+        ));
+        for (tree, shorter) in [("abcd", "abc"), ("a", "a"), ("b", "b"), ("bc", "b")].iter() {
+            let mut prefixed = Art::<String, u64>::new();
+            prefixed.insert(tree.to_string(), 0);
+            prefixed.insert("x".to_string(), 0);
+            let mut file = vec![];
+            let mut sst = SstWriter::new(&mut file);
+            sst.add(b"abc", &1u64.to_be_bytes()).unwrap();
+            sst.add(b"b", &1u64.to_be_bytes()).unwrap();
+            sst.finish().unwrap();
+            let imported = prefixed.import_sst(std::io::Cursor::new(&file));
+            let conflict = *tree != "b";
+            assert_eq!(conflict, imported.is_err(), "{}", tree);
+            if let Err(Error::KeyIsPrefix { key }) = imported {
+                assert_eq!(shorter.as_bytes(), &key[..]);
+                assert_eq!(2, prefixed.iter().count());
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_export_import_text() {
+        let mut art = Art::<u32, (String, u32)>::new();
+        let mut rng = rand::thread_rng();
+        for i in 0..1000 {
+            art.insert(rng.gen::<u32>(), (format!("value, {}", i), i));
+        }
+        for format in [Format::Csv, Format::JsonLines].iter() {
+            let mut file = vec![];
+            art.export(&mut file, *format).unwrap();
+            let mut imported = Art::<u32, (String, u32)>::new();
+            assert_eq!(1000, imported.import(&file[..], *format).unwrap());
+            assert!(art.iter().eq(imported.iter()));
+        }
+
+        let mut file = vec![];
+        art.export(&mut file, Format::JsonLines).unwrap();
+        let line = String::from_utf8(file).unwrap();
+        let (key, value) = art.iter().next().unwrap();
+        assert!(line.starts_with(&format!(
+            "{{\"key\":{},\"value\":[\"{}\",{}]}}",
+            u32::from_bytes(key).unwrap(),
+            value.0,
+            value.1
+        )));
+
+        let mut imported = Art::<u32, (String, u32)>::new();
+        assert!(imported
+            .import(&b"{\"key\":1}"[..], Format::JsonLines)
+            .is_err());
+
+        // A pair past the limits leaves the tree as it was
+        let file = b"{\"key\":\"a\",\"value\":1}\n{\"key\":\"abc\",\"value\":2}\n";
+        let mut limited = Art::<String, u32>::new();
+        limited.insert("b".to_string(), 0);
+        limited.set_max_key_len(Some(2));
+        assert!(matches!(
+            limited.import(&file[..], Format::JsonLines),
+            Err(Error::KeyTooLong { len: 3, max: 2 })
+        ));
+        assert_eq!(vec![(&b"b"[..], &0)], limited.iter().collect::<Vec<_>>());
+        limited.check().unwrap();
+
+        // Keys that don't decode stop the export
+        #[derive(Debug, serde::Serialize)]
+        struct Opaque(u32);
+        impl ArtKey for Opaque {
+            fn bytes(&self) -> Vec<u8> {
+                self.0.bytes()
+            }
+        }
+        let mut opaque = Art::<Opaque, u32>::new();
+        opaque.insert(Opaque(1), 1);
+        let err = opaque.export(vec![], Format::Csv).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn test_long_prefix() {
+        // Shared prefixes longer than MAX_PREFIX_LEN only keep their
+        // first bytes in the node
+        let mut art = Art::<String, usize>::new();
+        let keys = [
+            "user:0000000001\0",
+            "user:0000000002\0",
+            "user:00000000\0",
+            "user:1\0",
+            "a very long key indeed\0",
+            "a very long key\0",
+        ];
+        for (i, key) in keys.iter().enumerate() {
+            art.insert(key.to_string(), i);
+        }
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(Some(&i), art.find(key.to_string()));
+        }
+        assert_eq!(None, art.find("user:0000000003\0".to_string()));
+        art.delete(keys[3].to_string());
+        art.delete(keys[4].to_string());
+        assert_eq!(Some(&0), art.find(keys[0].to_string()));
+        art.retain_prefix(b"user:00000000");
+        let left: Vec<_> = art.iter().map(|(_, value)| *value).collect();
+        assert_eq!(vec![2, 0, 1], left);
+    }
+
+    #[test]
+    fn test_long_shared_prefixes() {
+        // Keys sharing 12 to 41 bytes, past what a node stores, that split
+        // and merge inner nodes before, inside and after the stored bytes
+        let mut rng = rand::thread_rng();
+        let stems: Vec<Vec<u8>> = (0..6)
+            .map(|_| {
+                let len = rng.gen_range(12, 42);
+                (0..len).map(|_| rng.gen_range(b'a', b'c')).collect()
+            })
+            .collect();
+        let key = |rng: &mut rand::rngs::ThreadRng| {
+            let stem = &stems[rng.gen_range(0, stems.len())];
+            let mut key = stem[..rng.gen_range(0, stem.len() + 1)].to_vec();
+            for _ in 0..rng.gen_range(0, 3) {
+                key.push(rng.gen_range(b'a', b'd'));
+            }
+            key.push(0);
+            key
+        };
+        for mode in [PathCheck::EveryNode, PathCheck::AtLeaf].iter() {
+            let mut art = Art::<String, u32>::new();
+            art.set_path_check(*mode);
+            let mut data = BTreeMap::new();
+            for i in 0..5_000 {
+                let k = key(&mut rng);
+                if rng.gen_range(0, 3) == 0 {
+                    art.delete_with(&Raw, &k[..]);
+                    data.remove(&k);
+                } else {
+                    art.insert_with(&Raw, &k[..], i);
+                    data.insert(k, i);
+                }
+                let k = key(&mut rng);
+                assert_eq!(data.get(&k), art.find_with(&Raw, &k[..]));
+            }
+            let pairs: Vec<_> = art.iter().map(|(k, v)| (k.to_vec(), *v)).collect();
+            assert_eq!(data.clone().into_iter().collect::<Vec<_>>(), pairs);
+            art.check().unwrap();
+            for stem in stems.iter() {
+                for len in [11, stem.len() - 1, stem.len()].iter() {
+                    let prefix = &stem[..*len];
+                    let expected = data.keys().filter(|k| k.starts_with(prefix)).count();
+                    assert_eq!(expected, art.prefix_iter(prefix).count());
+                }
+            }
+            // Emptying the tree merges every node on the way
+            let keys: Vec<_> = data.keys().cloned().collect();
+            for k in keys.iter().skip(1) {
+                art.delete_with(&Raw, &k[..]);
+                data.remove(k);
+                if rng.gen_range(0, 50) == 0 {
+                    art.check().unwrap();
+                    for (k, v) in data.iter() {
+                        assert_eq!(Some(v), art.find_with(&Raw, &k[..]));
+                    }
+                }
+            }
+            assert_eq!(1, art.iter().count());
+            // Cutting the tree down to a prefix longer than the stored bytes
+            let stem = &stems[0];
+            let prefix = &stem[..stem.len() - 1];
+            for k in keys.iter().filter(|k| k.starts_with(prefix)) {
+                art.insert_with(&Raw, &k[..], 0);
+            }
+            art.retain_prefix(prefix);
+            let expected = keys.iter().filter(|k| k.starts_with(prefix));
+            assert!(art.iter().map(|(k, _)| k).eq(expected.map(|k| &k[..])));
+            art.check().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_range() {
+        use std::ops::Bound;
+        let mut art = Art::<String, u32>::new();
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        let key = |rng: &mut rand::rngs::ThreadRng| {
+            let len = rng.gen_range(0, 4);
+            let mut key: Vec<u8> = (0..len).map(|_| rng.gen_range(b'a', b'f')).collect();
+            key.push(0);
+            key
+        };
+        for i in 0..2_000 {
+            let k = key(&mut rng);
+            art.insert_with(&Raw, &k[..], i);
+            data.insert(k, i);
+        }
+        for _ in 0..200 {
+            let (a, b) = (key(&mut rng), key(&mut rng));
+            let (start, end) = if a <= b { (a, b) } else { (b, a) };
+            let range = (Bound::Excluded(&start[..]), Bound::Included(&end[..]));
+            let expected: Vec<_> = data
+                .range::<[u8], _>(range)
+                .map(|(k, v)| (&k[..], v))
+                .collect();
+            assert_eq!(expected, art.range(range).collect::<Vec<_>>());
+        }
+        assert_eq!(data.len(), art.range(..).count());
+    }
+
+    #[test]
+    fn test_max_in_prefix() {
+        // Bids by instrument, the best one is the highest price
+        let mut art = Art::<String, (u32, u32)>::new();
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for i in 0..3_000 {
+            if i == 100 {
+                art.set_priority(|bid: &(u32, u32)| bid.0);
+            }
+            if i == 1_500 {
+                art.set_deletion(Deletion::Lazy);
+            }
+            if i % 1_000 == 999 {
+                art = art.clone();
+            }
+            let len = rng.gen_range(0, 5);
+            let mut key: String = (0..len)
+                .map(|_| ['a', 'b', 'c'][rng.gen_range(0, 3)])
+                .collect();
+            key.push('\0');
+            if rng.gen_range(0, 3) == 0 {
+                art.delete(key.clone());
+                data.remove(&key);
+            } else {
+                let bid = (rng.gen_range(0, 50), i);
+                art.insert(key.clone(), bid);
+                data.insert(key, bid);
+            }
+            if i >= 100 {
+                let prefix = &b"abc"[..rng.gen_range(0, 4)];
+                let mut best: Option<(&[u8], &(u32, u32))> = None;
+                for (key, bid) in data.iter() {
+                    if key.as_bytes().starts_with(prefix) && best.is_none_or(|b| bid.0 > b.1 .0) {
+                        best = Some((key.as_bytes(), bid));
                     }
-                    // Split node
-                    let mut new_node = Node4::new(&key_bytes[depth..cm]);
-                    //node.key = node.key.to_vec();
-                    new_node.add(new_leaf, &key_bytes, cm);
-                    new_node.add(iter_node, &node.key, cm);
-                    unsafe {
-                        *parent_node = Box::into_raw(Box::new(Node::ArtNode(Box::new(new_node))));
+                }
+                assert_eq!(best, art.max_in_prefix(prefix));
+            }
+        }
+        art.clear_priority();
+        assert_eq!(None, art.max_in_prefix(b""));
+    }
+
+    #[test]
+    fn test_clone() {
+        let mut art = Art::<String, u32>::new();
+        art.set_aggregation(|a, b| a + b);
+        for (i, key) in ["a\0", "ab\0", "b\0"].iter().enumerate() {
+            art.insert(key.to_string(), i as u32);
+        }
+        let mut copy = art.clone();
+        copy.insert("c\0".to_string(), 10);
+        copy.delete("a\0".to_string());
+        assert_eq!(Some(&0), art.find("a\0".to_string()));
+        assert_eq!(None, art.find("c\0".to_string()));
+        assert_eq!(Some(3), art.aggregate_prefix(b""));
+        assert_eq!(Some(13), copy.aggregate_prefix(b""));
+        assert_eq!(subtree_memory(copy.root), copy.memory_usage());
+    }
+
+    #[test]
+    fn test_overwrite() {
+        // Overwrites give the old value back and don't allocate or leak
+        // a leaf, every clone of the counter is either in the tree or gone
+        let counter = std::rc::Rc::new(());
+        let mut art = Art::<u32, std::rc::Rc<()>>::new();
+        let mut fresh = Art::<u32, std::rc::Rc<()>>::new();
+        for key in 0..10 {
+            assert!(art.insert(key, counter.clone()).is_none());
+            fresh.insert(key, counter.clone());
+        }
+        for i in 0..1_000 {
+            assert!(art.insert(i % 10, counter.clone()).is_some());
+        }
+        assert_eq!(21, std::rc::Rc::strong_count(&counter));
+        assert_eq!(fresh.memory_usage(), art.memory_usage());
+
+        art.set_deletion(Deletion::Lazy);
+        art.delete(3);
+        assert!(art.insert(3, counter.clone()).is_none());
+        assert_eq!(0, art.tombstones());
+        drop((art, fresh));
+        assert_eq!(1, std::rc::Rc::strong_count(&counter));
+    }
+
+    #[test]
+    fn test_insert_unique() {
+        let mut art = Art::<String, u32>::new();
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for i in 0..2_000 {
+            let key = format!("{}\0", rng.gen_range(0, 500));
+            match art.insert_unique(key.clone(), i) {
+                Ok(()) => assert!(data.insert(key, i).is_none()),
+                Err(collision) => {
+                    assert_eq!(i, collision.value);
+                    assert_eq!(Some(collision.existing), data.get(&key));
+                }
+            }
+        }
+        assert!(art.iter().map(|(_, v)| v).eq(data.values()));
+        // A lazily deleted key is free again
+        art.set_deletion(Deletion::Lazy);
+        let key = data.keys().next().unwrap().clone();
+        art.delete(key.clone());
+        assert_eq!(Ok(()), art.insert_unique(key.clone(), 7));
+        assert_eq!(
+            Err(Collision {
+                value: 8,
+                existing: &7
+            }),
+            art.insert_unique(key, 8)
+        );
+    }
+
+    #[test]
+    fn test_cow() {
+        // Every version has all the keys with the same value, readers
+        // never see a half done batch
+        let art = Arc::new(CowArt::<u32, u32>::new());
+        art.update(|tree| {
+            for key in 0..100 {
+                tree.insert(key, 0);
+            }
+        });
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let art = Arc::clone(&art);
+                std::thread::spawn(move || {
+                    for _ in 0..200 {
+                        let tree = art.load();
+                        let version = *tree.find(0).unwrap();
+                        assert!((0..100).all(|key| tree.find(key) == Some(&version)));
                     }
-                    break;
+                })
+            })
+            .collect();
+        for version in 1..=50 {
+            art.update(|tree| {
+                for key in 0..100 {
+                    tree.insert(key, version);
                 }
+            });
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        art.delete(5);
+        assert_eq!(None, art.find(5));
+        assert_eq!(Some(50), art.find(6));
+    }
+
+    #[test]
+    fn test_cow_paths() {
+        fn nodes<T>(node: &Arc<CowNode<T>>, found: &mut Vec<*const CowNode<T>>) {
+            found.push(Arc::as_ptr(node));
+            for child in node.children.iter() {
+                nodes(child, found);
+            }
+        }
+        // Keys of a few bytes, some of them prefixes of others
+        let mut version = CowVersion::<String, u32>::new();
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        let mut old = vec![];
+        for i in 0..5_000 {
+            let len = rng.gen_range(0, 5);
+            let key: String = (0..len)
+                .map(|_| ['a', 'b', 'c'][rng.gen_range(0, 3)])
+                .collect();
+            let before = (version.clone(), data.clone());
+            let mut shared = vec![];
+            nodes(&before.0.root, &mut shared);
+            if rng.gen_range(0, 3) == 0 {
+                assert_eq!(data.remove(&key), version.remove(key.clone()));
+            } else {
+                assert_eq!(data.insert(key.clone(), i), version.insert(key.clone(), i));
+            }
+            // A write copies the path to its key and a node split or merged
+            // on the way, the rest is shared with the version before
+            let mut copied = vec![];
+            nodes(&version.root, &mut copied);
+            copied.retain(|node| !shared.contains(node));
+            assert!(copied.len() <= key.len() + 2);
+            assert_eq!(data.len(), version.len());
+            if i % 500 == 0 {
+                old.push(before);
+            }
+        }
+        // Older versions didn't change
+        for (version, data) in old.iter().chain(Some(&(version, data))) {
+            let pairs: Vec<_> = version.iter().map(|(k, v)| (k, *v)).collect();
+            let expected: Vec<_> = data
+                .iter()
+                .map(|(k, v)| (k.clone().into_bytes(), *v))
+                .collect();
+            assert_eq!(expected, pairs);
+            for (k, v) in data.iter() {
+                assert_eq!(Some(v), version.find(k.clone()));
             }
         }
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use rand::Rng;
+    #[test]
+    fn test_backup() {
+        let dir = std::env::temp_dir().join(format!("radix-backup-{}", std::process::id()));
+        let art = Arc::new(CowArt::<u32, u32>::new());
+        art.update(|tree| {
+            for key in 0..1_000 {
+                tree.insert(key, key);
+            }
+        });
+        // Writes go on during the backup, it gets one version or the next
+        let writer = {
+            let art = Arc::clone(&art);
+            std::thread::spawn(move || {
+                for key in 0..1_000 {
+                    art.insert(key, key + 1);
+                }
+            })
+        };
+        assert_eq!(1_000, art.backup_to(&dir).unwrap());
+        writer.join().unwrap();
+        let restored = CowArt::<u32, u32>::restore_from(&dir).unwrap();
+        let tree = restored.load();
+        let bumped = (0..1_000)
+            .filter(|key| tree.find(*key) == Some(&(key + 1)))
+            .count();
+        assert!((0..bumped as u32).all(|key| tree.find(key) == Some(&(key + 1))));
+        assert!((bumped as u32..1_000).all(|key| tree.find(key) == Some(&key)));
+
+        art.backup_to(&dir).unwrap();
+        let restored = CowArt::<u32, u32>::restore_from(&dir).unwrap();
+        assert!(restored.load().iter().eq(art.load().iter()));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            CowArt::<u32, u32>::restore_from(&dir),
+            Err(Error::Io(_))
+        ));
+    }
 
     #[test]
-    fn test_add_and_find() {
-        let mut art = Art::<u32, u32>::new();
-        let mut data = std::collections::HashMap::new();
+    fn test_lazy_deletion() {
+        let mut art = Art::<String, u32>::new();
+        art.set_deletion(Deletion::Lazy);
+        art.set_metrics(true);
+        let mut data = BTreeMap::new();
+        let mut deleted = std::collections::BTreeSet::new();
         let mut rng = rand::thread_rng();
+        let mut shrinks = 0;
+        for i in 0..5_000 {
+            let len = rng.gen_range(0, 4);
+            let mut key: String = (0..len)
+                .map(|_| rng.gen_range(b'a', b'z') as char)
+                .collect();
+            key.push('\0');
+            if rng.gen_range(0, 2) == 0 {
+                art.delete(key.clone());
+                if data.remove(&key).is_some() {
+                    deleted.insert(key.clone());
+                }
+            } else {
+                art.insert(key.clone(), i);
+                data.insert(key.clone(), i);
+                deleted.remove(&key);
+            }
+            assert_eq!(data.get(&key), art.find(key.clone()));
+            assert_eq!(deleted.len(), art.tombstones());
+            if i % 1_000 == 999 {
+                // Nothing shrinks until the compaction
+                assert_eq!(shrinks, art.metrics().shrinks());
+                assert_eq!(
+                    data.values().copied().collect::<Vec<_>>(),
+                    art.iter().map(|(_, v)| *v).collect::<Vec<_>>()
+                );
+                art.compact();
+                deleted.clear();
+                assert_eq!(0, art.tombstones());
+                shrinks = art.metrics().shrinks();
+                art.check().unwrap();
+            }
+        }
+    }
 
-        for _i in 0..100_000 {
-            data.insert(rng.gen::<u32>(), rng.gen::<u32>());
+    #[test]
+    fn test_bitmap() {
+        let mut bitmap = Bitmap::default();
+        let mut set = std::collections::BTreeSet::new();
+        let mut rng = rand::thread_rng();
+        for _ in 0..1_000 {
+            let byte: u8 = rng.gen();
+            if rng.gen() {
+                bitmap.set(byte);
+                set.insert(byte);
+            } else {
+                bitmap.clear(byte);
+                set.remove(&byte);
+            }
         }
+        assert_eq!(
+            set.into_iter().collect::<Vec<_>>(),
+            bitmap.iter().collect::<Vec<_>>()
+        );
 
-        for (key, val) in &data {
-            art.insert(key.clone(), val.clone());
+        // Nodes going back and forth between Node16, Node48 and Node256
+        let mut art = Art::<String, u8>::new();
+        let mut data = BTreeMap::new();
+        for i in 0..5_000 {
+            let key = vec![rng.gen_range(1, 256) as u8, 0];
+            if data.len() > [5, 40, 100, 250][i / 500 % 4] {
+                art.delete_with(&Raw, &key[..]);
+                data.remove(&key);
+            } else {
+                art.insert_with(&Raw, &key[..], key[0]);
+                data.insert(key.clone(), key[0]);
+            }
+            assert_eq!(data.get(&key), art.find_with(&Raw, &key[..]));
         }
+        let found: Vec<_> = art.iter().map(|(_, v)| *v).collect();
+        assert_eq!(data.values().copied().collect::<Vec<_>>(), found);
+    }
 
-        for (key, val) in &data {
-            assert_eq!(val, art.find(key.clone()).unwrap());
+    #[test]
+    fn test_aggregate_range() {
+        use std::ops::Bound;
+        let mut art = Art::<String, u64>::new();
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        let key = |rng: &mut rand::rngs::ThreadRng| {
+            // A wide first byte so the nodes grow past Node4
+            let len = rng.gen_range(0, 5);
+            let mut key: Vec<u8> = (0..len)
+                .map(|i| match rng.gen_range(0, 4) {
+                    0 if i == 0 => rng.gen_range(1, 256) as u8,
+                    n => [b'a', b'b', 0xff, 0xff][n],
+                })
+                .collect();
+            key.push(0);
+            key
+        };
+        for i in 0..3_000 {
+            // Set part way, with pairs in the tree already
+            if i == 200 {
+                art.set_aggregation(|a, b| a + b);
+            }
+            let k = key(&mut rng);
+            if rng.gen_range(0, 3) == 0 {
+                art.delete_with(&Raw, &k[..]);
+                data.remove(&k);
+            } else {
+                let value = rng.gen_range(0, 100);
+                art.insert_with(&Raw, &k[..], value);
+                data.insert(k, value);
+            }
+            let (a, b) = (key(&mut rng), key(&mut rng));
+            let (start, end) = if a <= b { (a, b) } else { (b, a) };
+            let bounds = [
+                (Bound::Included(&start[..]), Bound::Excluded(&end[..])),
+                (Bound::Excluded(&start[..]), Bound::Included(&end[..])),
+                (Bound::Unbounded, Bound::Included(&end[..])),
+                (Bound::Included(&start[..]), Bound::Unbounded),
+            ];
+            if i >= 200 {
+                for range in bounds.iter() {
+                    let values: Vec<_> = data.range::<[u8], _>(*range).map(|(_, v)| *v).collect();
+                    let sum = Some(values.iter().sum()).filter(|_| !values.is_empty());
+                    assert_eq!(sum, art.aggregate_range(*range));
+                }
+                let prefix = &start[..rng.gen_range(0, start.len())];
+                let sum: u64 = data
+                    .iter()
+                    .filter(|(k, _)| k.starts_with(prefix))
+                    .map(|(_, v)| v)
+                    .sum();
+                assert_eq!(sum, art.aggregate_prefix(prefix).unwrap_or(0));
+            }
         }
+
+        // Max of the values, in place of the sum
+        art.set_aggregation(|a, b| *a.max(b));
+        let max = data.values().max().copied();
+        assert_eq!(max, art.aggregate_range(..));
+        art.clear_aggregation();
+        assert_eq!(None, art.aggregate_prefix(b""));
     }
 
     #[test]
-    fn test_add_and_delete() {
-        let mut art = Art::<u32, u32>::new();
-        let mut data = std::collections::HashMap::new();
+    fn test_reverse_index() {
+        let mut art = Art::<String, u32>::new();
+        let mut data = BTreeMap::new();
         let mut rng = rand::thread_rng();
+        let key = |rng: &mut rand::rngs::ThreadRng| {
+            let len = rng.gen_range(0, 6);
+            let mut key: String = (0..len).map(|_| ['a', 'b'][rng.gen_range(0, 2)]).collect();
+            key.push('\0');
+            key
+        };
+        for i in 0..3_000 {
+            // Enabled part way, with pairs in the tree already
+            if i == 500 {
+                art.enable_reverse_index(value_hash);
+            }
+            let k = key(&mut rng);
+            if rng.gen_range(0, 3) == 0 {
+                art.delete(k.clone());
+                data.remove(&k);
+            } else {
+                let value = rng.gen_range(0, 10);
+                art.insert(k.clone(), value);
+                data.insert(k, value);
+            }
+        }
+        art.retain_prefix(b"a");
+        data.retain(|k, _| k.starts_with('a'));
+        for value in 0..10 {
+            let keys: Vec<_> = data
+                .iter()
+                .filter(|(_, v)| **v == value)
+                .map(|(k, _)| k.as_bytes())
+                .collect();
+            assert_eq!(Some(keys), art.reverse_lookup(value_hash(&value)));
+        }
 
-        for _i in 0..100_000 {
-            data.insert(rng.gen::<u32>(), rng.gen::<u32>());
+        // Keys by a field of the values
+        let mut art = Art::<String, (u64, &str)>::new();
+        art.enable_reverse_index(|value| value.0);
+        art.insert("a\0".to_string(), (1, "x"));
+        art.insert("b\0".to_string(), (2, "y"));
+        art.insert("c\0".to_string(), (1, "z"));
+        art.insert("a\0".to_string(), (2, "x"));
+        assert_eq!(Some(vec![&b"c\0"[..]]), art.reverse_lookup(1));
+        assert_eq!(Some(vec![&b"a\0"[..], b"b\0"]), art.reverse_lookup(2));
+        // A value that doesn't go in isn't indexed
+        assert!(art.insert_unique("b\0".to_string(), (1, "w")).is_err());
+        assert_eq!(Some(vec![&b"c\0"[..]]), art.reverse_lookup(1));
+        assert_eq!(Ok(()), art.insert_unique("d\0".to_string(), (1, "w")));
+        assert_eq!(Some(vec![&b"c\0"[..], b"d\0"]), art.reverse_lookup(1));
+        art.disable_reverse_index();
+        assert_eq!(None, art.reverse_lookup(2));
+    }
+
+    #[test]
+    fn test_long_keys() {
+        // 1KB keys only telling apart in their last bytes, the leaf checks
+        // go through the fingerprints, and keys with the same last bytes
+        // through the rest of the key
+        let mut art = Art::<String, usize>::new();
+        let stem = "k".repeat(1_000);
+        for i in 0..100 {
+            art.insert(format!("{}{:03}\0", stem, i), i);
+        }
+        for mode in [PathCheck::EveryNode, PathCheck::AtLeaf].iter() {
+            art.set_path_check(*mode);
+            for i in 0..100 {
+                assert_eq!(Some(&i), art.find(format!("{}{:03}\0", stem, i)));
+                assert_eq!(None, art.find(format!("{}{:03}\0", stem, i + 100)));
+                assert_eq!(None, art.find(format!("x{}{:03}\0", &stem[1..], i)));
+                assert_eq!(None, art.find(format!("{}x{:03}\0", &stem[1..], i)));
+            }
         }
+        assert_ne!(fingerprint(b"ab"), fingerprint(b"ba"));
+        assert_eq!(fingerprint(b"x12345678"), fingerprint(b"y12345678"));
+        let leaf = LeafNode::new(0, b"x12345678");
+        assert!(leaf.matches(b"x12345678", 0) && leaf.matches(b"x12345678", 9));
+        assert!(!leaf.matches(b"y12345678", 0) && !leaf.matches(b"12345678", 0));
+    }
 
-        for (key, val) in &data {
-            art.insert(key.clone(), val.clone());
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing() {
+        use std::sync::{Arc, Mutex};
+        use tracing::field::Field;
+        use tracing::{span, Event, Metadata, Subscriber};
+
+        // Names of the spans and the fields of the events
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+            fn new_span(&self, span: &span::Attributes) -> span::Id {
+                let name = span.metadata().name().to_string();
+                self.0.lock().unwrap().push(name);
+                span::Id::from_u64(1)
+            }
+            fn record(&self, _: &span::Id, _: &span::Record) {}
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, event: &Event) {
+                let mut fields = vec![];
+                event.record(&mut |field: &Field, value: &dyn std::fmt::Debug| {
+                    fields.push(format!("{}={:?}", field.name(), value));
+                });
+                self.0.lock().unwrap().push(fields.join(" "));
+            }
+            fn enter(&self, _: &span::Id) {}
+            fn exit(&self, _: &span::Id) {}
+        }
+
+        let records = Arc::new(Mutex::new(vec![]));
+        tracing::subscriber::with_default(Recorder(records.clone()), || {
+            let mut art = Art::<u32, u32>::new();
+            for key in 0..5 {
+                art.insert(key, key);
+            }
+            art.find(3);
+            // Down to 3 children a Node16 shrinks
+            art.delete(3);
+            art.delete(4);
+        });
+        let records = records.lock().unwrap();
+        assert_eq!(5, records.iter().filter(|r| *r == "art.insert").count());
+        assert!(records.contains(&"art.find".to_string()));
+        assert!(records.contains(&"art.delete".to_string()));
+        assert!(records.contains(&"message=grow to Node16 node=\"Node4\" depth=3".to_string()));
+        assert!(records.contains(&"message=shrink to Node4 node=\"Node16\"".to_string()));
+    }
+
+    #[test]
+    fn test_common_prefix() {
+        // Long enough to go through the 16 byte chunks and their tails
+        let mut rng = rand::thread_rng();
+        for _ in 0..1_000 {
+            let a: Vec<u8> = (0..rng.gen_range(0, 60)).map(|_| rng.gen()).collect();
+            let mut b = a.clone();
+            b.truncate(rng.gen_range(0, a.len() + 1));
+            if !b.is_empty() && rng.gen() {
+                let i = rng.gen_range(0, b.len());
+                b[i] = b[i].wrapping_add(1);
+            }
+            let expected = a.iter().zip(b.iter()).take_while(|(a, b)| a == b).count();
+            assert_eq!(expected, common_prefix(&a, &b));
+            assert_eq!(expected, common_prefix(&b, &a));
         }
+    }
+
+    #[test]
+    fn test_key_codec() {
+        use crate::keys::{BigEndian, NullTerminated, Raw};
+        // Composite (user, timestamp) keys, a user's keys come in time order
+        // with the negative timestamps first
+        let codec = (NullTerminated(Raw), BigEndian);
+        let mut art = Art::<String, i64>::new();
+        for (user, time) in [("bob", 5), ("al", -3), ("bob", -7), ("al", 2), ("bo", 1)].iter() {
+            art.insert_with(&codec, &(user.to_string(), *time), *time);
+        }
+        assert_eq!(
+            Some(&-7),
+            art.find_with(&codec, &("bob".to_string(), -7i64))
+        );
+        assert_eq!(None, art.find_with(&codec, &("bo".to_string(), 5i64)));
+        let times: Vec<_> = art.prefix_iter(b"bob\0").map(|(_, time)| *time).collect();
+        assert_eq!(vec![-7, 5], times);
+        art.delete_with(&codec, &("al".to_string(), -3i64));
+        let times: Vec<_> = art.iter().map(|(_, time)| *time).collect();
+        assert_eq!(vec![2, 1, -7, 5], times);
+    }
+
+    #[test]
+    fn test_path_check() {
+        // Long keys sharing paths well past MAX_PREFIX_LEN, looked up in
+        // both modes and with the mode switched in between
+        let mut art = Art::<String, u32>::new();
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        let stems = [
+            "a".repeat(25),
+            "ab".repeat(15),
+            format!("{}b", "a".repeat(18)),
+        ];
+        let key = |rng: &mut rand::rngs::ThreadRng| {
+            let mut key = stems[rng.gen_range(0, stems.len())].clone();
+            // Misses differing inside the skipped bytes
+            if rng.gen_range(0, 10) == 0 {
+                let pos = rng.gen_range(0, key.len());
+                key.replace_range(pos..pos + 1, "c");
+            }
+            for _ in 0..rng.gen_range(0, 3) {
+                key.push(['a', 'b'][rng.gen_range(0, 2)]);
+            }
+            key.push('\0');
+            key
+        };
+        for i in 0..5_000 {
+            if i % 500 == 0 {
+                let mode = [PathCheck::EveryNode, PathCheck::AtLeaf][i / 500 % 2];
+                art.set_path_check(mode);
+                assert_eq!(mode, art.path_check());
+            }
+            let k = key(&mut rng);
+            if rng.gen_range(0, 3) == 0 {
+                art.delete(k.clone());
+                data.remove(&k);
+            } else {
+                art.insert(k.clone(), i as u32);
+                data.insert(k.clone(), i as u32);
+            }
+            let k = key(&mut rng);
+            assert_eq!(data.get(&k), art.find(k.clone()));
+        }
+        art.check().unwrap();
+        for mode in [PathCheck::EveryNode, PathCheck::AtLeaf].iter() {
+            art.set_path_check(*mode);
+            for (k, v) in data.iter() {
+                assert_eq!(Some(v), art.find(k.clone()));
+            }
+        }
+        let found: Vec<_> = art.iter().map(|(_, v)| *v).collect();
+        assert_eq!(data.values().copied().collect::<Vec<_>>(), found);
+    }
+
+    #[test]
+    fn test_conversions() {
+        let mut trie = TrieMap::new();
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for i in 0..2_000 {
+            let len = rng.gen_range(0, 8);
+            let mut key: String = (0..len)
+                .map(|_| ['a', 'b', 'ä'][rng.gen_range(0, 3)])
+                .collect();
+            key.push('\0');
+            trie.insert(&mut key.chars(), i);
+            data.insert(key, i);
+        }
+        let art = Art::<String, i32>::try_from(&trie).unwrap();
+        let tree = RadixTree::try_from(&art).unwrap();
+        let art = Art::<String, i32>::try_from(&tree).unwrap();
+        let trie = TrieMap::try_from(&art).unwrap();
+
+        let mut found: Vec<_> = art
+            .iter()
+            .map(|(key, value)| (String::from_bytes(key).unwrap(), *value))
+            .collect();
+        found.sort_unstable();
+        let expected: Vec<_> = data.into_iter().collect();
+        assert_eq!(expected, found);
+        let mut found: Vec<_> = tree.iter().map(|(key, value)| (key, *value)).collect();
+        found.sort_unstable();
+        assert_eq!(expected, found);
+        let mut found: Vec<_> = trie
+            .iter()
+            .map(|(key, value)| (key.into_iter().collect(), *value))
+            .collect();
+        found.sort_unstable();
+        assert_eq!(expected, found);
+
+        // Keys that are prefixes of others are fine in a radix tree or a
+        // trie but not in an `Art`
+        let mut tree = RadixTree::new();
+        tree.insert("testing".to_string(), 1);
+        tree.insert("test".to_string(), 2);
+        assert!(matches!(
+            Art::try_from(&tree),
+            Err(Error::KeyIsPrefix { key }) if key == b"test"
+        ));
+        let mut trie = TrieMap::new();
+        trie.insert(&mut "ab".chars(), 1);
+        trie.insert(&mut "a".chars(), 2);
+        assert!(Art::<String, i32>::try_from(&trie).is_err());
+        let mut art = Art::<String, i32>::new();
+        art.insert_with(&Raw, &[0xff][..], 1);
+        assert!(matches!(RadixTree::try_from(&art), Err(Error::Utf8(_))));
+        assert!(matches!(TrieMap::try_from(&art), Err(Error::Utf8(_))));
+    }
 
+    #[test]
+    fn test_complete() {
+        let mut art = Art::<u32, u32>::new();
+        let mut data = std::collections::BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for _i in 0..10_000 {
+            let key = (rng.gen::<u32>() % 4) << 24 | rng.gen::<u16>() as u32;
+            data.insert(key, rng.gen::<u32>());
+        }
         for (key, val) in &data {
-            art.delete(key.clone());
+            art.insert(*key, *val);
         }
-        assert_eq!(0, art.bfs_count());
+        for prefix in [&[][..], &[1], &[2, 0], &[3, 0, 7], &[9]].iter() {
+            let found: Vec<_> = art
+                .complete(prefix, 10)
+                .into_iter()
+                .map(|(_, val)| *val)
+                .collect();
+            let expected: Vec<_> = data
+                .iter()
+                .filter(|(key, _)| key.to_be_bytes().starts_with(prefix))
+                .map(|(_, val)| *val)
+                .take(10)
+                .collect();
+            assert_eq!(expected, found);
+        }
+
+        let mut art = Art::<String, usize>::new();
+        for (i, key) in ["user:0000000001\0", "user:0000000002\0", "user:1\0"]
+            .iter()
+            .enumerate()
+        {
+            art.insert(key.to_string(), i);
+        }
+        let found: Vec<_> = art
+            .complete(b"user:000", 5)
+            .into_iter()
+            .map(|(_, value)| *value)
+            .collect();
+        assert_eq!(vec![0, 1], found);
+        assert!(art.complete(b"user:0000000003", 5).is_empty());
     }
 }
@@ -0,0 +1,422 @@
+use radix::art::{Art, ArtKey, PageToken};
+use std::env;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+// Number of keys returned by one SCAN call if COUNT isn't given
+const SCAN_COUNT: usize = 10;
+// Refuse bulk strings and arrays bigger than redis does
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+// Refuse longer inline commands and array or bulk headers, as redis does
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+// Keys are stored null-terminated, so one key can be a prefix of another
+#[derive(Debug)]
+struct Key(Vec<u8>);
+
+impl ArtKey for Key {
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes = self.0.clone();
+        bytes.push(0);
+        bytes
+    }
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes.split_last() {
+            Some((0, bytes)) => Some(Key(bytes.to_vec())),
+            _ => None,
+        }
+    }
+}
+
+type Store = Arc<Mutex<Art<Key, Vec<u8>>>>;
+
+// Lock the store even if a connection panicked while holding it, so one
+// bad command doesn't lock every other client out
+fn lock(store: &Store) -> MutexGuard<'_, Art<Key, Vec<u8>>> {
+    store.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Int(usize),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Status(status) => write!(out, "+{}\r\n", status),
+            Reply::Error(err) => write!(out, "-ERR {}\r\n", err),
+            Reply::Int(n) => write!(out, ":{}\r\n", n),
+            Reply::Bulk(None) => write!(out, "$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Array(replies) => {
+                write!(out, "*{}\r\n", replies.len())?;
+                for reply in replies.iter() {
+                    reply.write(out)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Read a line without the trailing "\r\n", `None` on a closed connection
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = vec![];
+    let limit = MAX_LINE_LEN as u64 + 2;
+    if reader.take(limit).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        if line.len() as u64 == limit {
+            return Err(invalid("line too long"));
+        }
+        return Err(invalid("unexpected end of stream"));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn read_len(line: &[u8]) -> io::Result<usize> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|len| len.parse().ok())
+        .filter(|&len| len <= MAX_BULK_LEN)
+        .ok_or_else(|| invalid("invalid length"))
+}
+
+// Read a command either as an array of bulk strings, which is what
+// clients send, or as an inline space separated line
+fn read_command<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|byte| byte.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect();
+        return Ok(Some(args));
+    }
+    let count = read_len(&line[1..])?;
+    let mut args = Vec::with_capacity(std::cmp::min(count, 1024));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| invalid("unexpected end of stream"))?;
+        if line.first() != Some(&b'$') {
+            return Err(invalid("expected a bulk string"));
+        }
+        let len = read_len(&line[1..])?;
+        // Grow with the data that comes in rather than trusting the length
+        let mut arg = vec![];
+        reader.take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() != len + 2 {
+            return Err(invalid("unexpected end of stream"));
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid("bulk string without a line end"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+// Only prefix patterns are supported, they map directly onto the tree
+fn pattern_prefix(pattern: &[u8]) -> Result<&[u8], String> {
+    let prefix = pattern.strip_suffix(b"*").unwrap_or(pattern);
+    if prefix.iter().any(|byte| b"*?[]\\".contains(byte)) || prefix.len() == pattern.len() {
+        return Err("only `prefix*` patterns are supported".to_string());
+    }
+    Ok(prefix)
+}
+
+fn parse_usize(arg: &[u8]) -> Result<usize, String> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| "value is not an integer or out of range".to_string())
+}
+
+fn key(arg: &[u8]) -> Result<Key, String> {
+    if arg.contains(&0) {
+        return Err("keys can't contain a null byte".to_string());
+    }
+    Ok(Key(arg.to_vec()))
+}
+
+fn execute(store: &Store, args: &[Vec<u8>]) -> Result<Reply, String> {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    let arity = |min: usize, max: usize| {
+        if args.len() < min || args.len() > max {
+            return Err(format!("wrong number of arguments for '{}' command", name));
+        }
+        Ok(())
+    };
+    match name.as_str() {
+        "PING" => {
+            arity(1, 2)?;
+            match args.get(1) {
+                Some(msg) => Ok(Reply::Bulk(Some(msg.clone()))),
+                None => Ok(Reply::Status("PONG")),
+            }
+        }
+        // Clients ask for the command table on connect
+        "COMMAND" => Ok(Reply::Array(vec![])),
+        "GET" => {
+            arity(2, 2)?;
+            let art = lock(store);
+            Ok(Reply::Bulk(art.find(key(&args[1])?).cloned()))
+        }
+        "SET" => {
+            arity(3, 3)?;
+            let key = key(&args[1])?;
+            lock(store).insert(key, args[2].clone());
+            Ok(Reply::Status("OK"))
+        }
+        "DEL" => {
+            arity(2, usize::MAX)?;
+            let mut art = lock(store);
+            let mut deleted = 0;
+            for arg in args[1..].iter() {
+                if art.remove(key(arg)?).is_some() {
+                    deleted += 1;
+                }
+            }
+            Ok(Reply::Int(deleted))
+        }
+        "KEYS" => {
+            arity(2, 2)?;
+            let prefix = pattern_prefix(&args[1])?;
+            let art = lock(store);
+            let keys = art
+                .prefix_iter(prefix)
+                .map(|(key, _)| Reply::Bulk(Some(key[..key.len() - 1].to_vec())))
+                .collect();
+            Ok(Reply::Array(keys))
+        }
+        // The cursor is the page token of the last key returned, keys
+        // inserted or deleted in between don't shift the pages
+        "SCAN" => {
+            arity(2, 6)?;
+            let cursor = match &args[1][..] {
+                b"0" => None,
+                cursor => Some(
+                    std::str::from_utf8(cursor)
+                        .ok()
+                        .and_then(|cursor| cursor.parse::<PageToken>().ok())
+                        .ok_or_else(|| "invalid cursor".to_string())?,
+                ),
+            };
+            let mut prefix: &[u8] = b"";
+            let mut count = SCAN_COUNT;
+            for option in args[2..].chunks(2) {
+                if option.len() != 2 {
+                    return Err("syntax error".to_string());
+                }
+                match String::from_utf8_lossy(&option[0])
+                    .to_ascii_uppercase()
+                    .as_str()
+                {
+                    "MATCH" => prefix = pattern_prefix(&option[1])?,
+                    "COUNT" => count = std::cmp::max(parse_usize(&option[1])?, 1),
+                    _ => return Err("syntax error".to_string()),
+                }
+            }
+            let (keys, next) = lock(store)
+                .scan_page(prefix, count, cursor.as_ref())
+                .map_err(|err| err.to_string())?;
+            let next = next.map_or_else(|| "0".to_string(), |token| token.to_string());
+            Ok(Reply::Array(vec![
+                Reply::Bulk(Some(next.into_bytes())),
+                Reply::Array(
+                    keys.into_iter()
+                        .map(|(key, _)| Reply::Bulk(Some(key.0)))
+                        .collect(),
+                ),
+            ]))
+        }
+        _ => Err(format!("unknown command '{}'", name)),
+    }
+}
+
+fn serve(store: Store, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    while let Some(args) = read_command(&mut reader)? {
+        if args.is_empty() {
+            continue;
+        }
+        if args[0].eq_ignore_ascii_case(b"QUIT") {
+            Reply::Status("OK").write(&mut writer)?;
+            break;
+        }
+        match execute(&store, &args) {
+            Ok(reply) => reply.write(&mut writer)?,
+            Err(err) => Reply::Error(err).write(&mut writer)?,
+        }
+        // Pipelined commands are answered in one write
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()
+}
+
+fn main() -> io::Result<()> {
+    let addr = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:6379".to_string());
+    let listener = TcpListener::bind(&addr)?;
+    eprintln!("listening on {}", addr);
+    let store = Store::default();
+    for stream in listener.incoming() {
+        // A failed accept only loses that connection
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("accept failed: {}", err);
+                continue;
+            }
+        };
+        let store = store.clone();
+        thread::spawn(move || {
+            if let Err(err) = serve(store, stream) {
+                eprintln!("connection closed: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Run an inline command and give its reply as it goes on the wire
+    fn run(store: &Store, command: &str) -> String {
+        let line = format!("{}\r\n", command);
+        let args = read_command(&mut line.as_bytes()).unwrap().unwrap();
+        let reply = execute(store, &args).unwrap_or_else(Reply::Error);
+        let mut out = vec![];
+        reply.write(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_read_command() {
+        let mut reader = &b"*2\r\n$3\r\nGET\r\n$4\r\na\r\nb\r\n  PING  x\r\n"[..];
+        let args = read_command(&mut reader).unwrap().unwrap();
+        assert_eq!(vec![b"GET".to_vec(), b"a\r\nb".to_vec()], args);
+        let args = read_command(&mut reader).unwrap().unwrap();
+        assert_eq!(vec![b"PING".to_vec(), b"x".to_vec()], args);
+        assert!(read_command(&mut reader).unwrap().is_none());
+        // Lengths that don't match the data
+        for bad in [&b"*1\r\n$5\r\nGET\r\n"[..], b"*1\r\n$x\r\n", b"*1\r\n"].iter() {
+            assert!(read_command(&mut &bad[..]).is_err());
+        }
+        let huge = format!("*1\r\n${}\r\nGET\r\n", MAX_BULK_LEN);
+        assert!(read_command(&mut huge.as_bytes()).is_err());
+        // Inline commands up to the limit
+        let line = format!("GET {}\r\n", "a".repeat(MAX_LINE_LEN - 4));
+        assert_eq!(
+            2,
+            read_command(&mut line.as_bytes()).unwrap().unwrap().len()
+        );
+        let line = format!("GET {}\r\n", "a".repeat(MAX_LINE_LEN));
+        let err = read_command(&mut line.as_bytes()).unwrap_err();
+        assert_eq!("line too long", err.to_string());
+    }
+
+    #[test]
+    fn test_commands() {
+        let store = Store::default();
+        assert_eq!("+OK\r\n", run(&store, "SET a 1"));
+        assert_eq!("+OK\r\n", run(&store, "SET ab 2"));
+        assert_eq!("+OK\r\n", run(&store, "SET b 3"));
+        assert_eq!("$1\r\n2\r\n", run(&store, "GET ab"));
+        assert_eq!("$-1\r\n", run(&store, "GET abc"));
+        assert_eq!("*2\r\n$1\r\na\r\n$2\r\nab\r\n", run(&store, "KEYS a*"));
+        assert!(run(&store, "KEYS a?").starts_with("-ERR"));
+        assert_eq!(":2\r\n", run(&store, "DEL a b c"));
+        assert_eq!("*1\r\n$2\r\nab\r\n", run(&store, "KEYS *"));
+        assert!(run(&store, "GET").starts_with("-ERR wrong number"));
+        assert!(run(&store, "FLUSHALL").starts_with("-ERR unknown"));
+    }
+
+    #[test]
+    fn test_scan() {
+        let store = Store::default();
+        for i in 0..50 {
+            run(&store, &format!("SET user:{:02} {}", i, i));
+            run(&store, &format!("SET other:{} {}", i, i));
+        }
+        // Pages resume after the last key returned, deletes in between
+        // don't make the scan skip keys
+        let mut cursor = "0".to_string();
+        let mut found = vec![];
+        loop {
+            let args = vec![
+                b"SCAN".to_vec(),
+                cursor.clone().into_bytes(),
+                b"MATCH".to_vec(),
+                b"user:*".to_vec(),
+                b"COUNT".to_vec(),
+                b"7".to_vec(),
+            ];
+            let (next, keys) = match execute(&store, &args).unwrap() {
+                Reply::Array(mut parts) => match (parts.remove(0), parts.remove(0)) {
+                    (Reply::Bulk(Some(next)), Reply::Array(keys)) => (next, keys),
+                    _ => panic!("unexpected reply"),
+                },
+                _ => panic!("unexpected reply"),
+            };
+            assert!(keys.len() <= 7);
+            for key in keys {
+                if let Reply::Bulk(Some(key)) = key {
+                    found.push(String::from_utf8(key).unwrap());
+                }
+            }
+            run(&store, &format!("DEL user:{:02}", found.len() - 7));
+            cursor = String::from_utf8(next).unwrap();
+            if cursor == "0" {
+                break;
+            }
+        }
+        let expected: Vec<_> = (0..50).map(|i| format!("user:{:02}", i)).collect();
+        assert_eq!(expected, found);
+        assert!(run(&store, "SCAN zz").starts_with("-ERR invalid cursor"));
+    }
+
+    #[test]
+    fn test_poisoned_store() {
+        let store = Store::default();
+        run(&store, "SET a 1");
+        let other = store.clone();
+        let _ = thread::spawn(move || {
+            let _art = other.lock().unwrap();
+            panic!("connection panicked");
+        })
+        .join();
+        assert!(store.is_poisoned());
+        assert_eq!("$1\r\n1\r\n", run(&store, "GET a"));
+        assert_eq!("+OK\r\n", run(&store, "SET b 2"));
+    }
+}
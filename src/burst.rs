@@ -0,0 +1,272 @@
+use crate::art::ArtKey;
+use core::marker::PhantomData;
+use std::collections::HashMap;
+
+// Burst trie. Keys live in small hash buckets hanging off trie nodes, a
+// bucket holds the key suffixes after the bytes of the path to it. When a
+// bucket grows past `BURST_LIMIT` it bursts into a trie node with a bucket
+// per next byte, so dense sets of short string keys stay mostly hashed
+
+// Number of keys a bucket can hold before it bursts
+const BURST_LIMIT: usize = 32;
+
+#[derive(Debug)]
+enum Child<T> {
+    Node(Box<TrieNode<T>>),
+    Bucket(HashMap<Vec<u8>, T>),
+}
+
+#[derive(Debug)]
+struct TrieNode<T> {
+    // Value of the key ending at the node
+    value: Option<T>,
+    children: Vec<Option<Child<T>>>,
+}
+
+impl<T> TrieNode<T> {
+    fn new() -> Self {
+        Self {
+            value: None,
+            children: (0..256).map(|_| None).collect(),
+        }
+    }
+
+    // Node replacing the bucket, buckets that are still too big burst again
+    fn burst(bucket: HashMap<Vec<u8>, T>) -> Self {
+        let mut node = Self::new();
+        for (mut suffix, value) in bucket {
+            if suffix.is_empty() {
+                node.value = Some(value);
+                continue;
+            }
+            let byte = suffix.remove(0);
+            let child =
+                node.children[byte as usize].get_or_insert_with(|| Child::Bucket(HashMap::new()));
+            if let Child::Bucket(bucket) = child {
+                bucket.insert(suffix, value);
+            }
+        }
+        for child in node.children.iter_mut() {
+            if let Some(Child::Bucket(bucket)) = child {
+                if bucket.len() > BURST_LIMIT {
+                    let bucket = std::mem::take(bucket);
+                    *child = Some(Child::Node(Box::new(Self::burst(bucket))));
+                }
+            }
+        }
+        node
+    }
+}
+
+enum Frame<'a, T> {
+    Node(&'a TrieNode<T>, Vec<u8>),
+    Bucket(&'a HashMap<Vec<u8>, T>, Vec<u8>),
+    Pair(Vec<u8>, &'a T),
+}
+
+// Buckets are unordered, they get sorted when the iterator reaches them
+pub struct Iter<'a, T> {
+    stack: Vec<Frame<'a, T>>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (Vec<u8>, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(frame) = self.stack.pop() {
+            match frame {
+                Frame::Pair(key, value) => return Some((key, value)),
+                Frame::Node(node, prefix) => {
+                    for (byte, child) in node.children.iter().enumerate().rev() {
+                        let mut key = prefix.clone();
+                        key.push(byte as u8);
+                        match child {
+                            Some(Child::Node(node)) => self.stack.push(Frame::Node(node, key)),
+                            Some(Child::Bucket(bucket)) => {
+                                self.stack.push(Frame::Bucket(bucket, key))
+                            }
+                            None => {}
+                        }
+                    }
+                    if let Some(value) = &node.value {
+                        self.stack.push(Frame::Pair(prefix, value));
+                    }
+                }
+                Frame::Bucket(bucket, prefix) => {
+                    let mut pairs: Vec<_> = bucket.iter().collect();
+                    pairs.sort_unstable_by(|a, b| b.0.cmp(a.0));
+                    self.stack.extend(pairs.into_iter().map(|(suffix, value)| {
+                        let mut key = prefix.clone();
+                        key.extend_from_slice(suffix);
+                        Frame::Pair(key, value)
+                    }));
+                }
+            }
+        }
+        None
+    }
+}
+
+pub struct BurstTrie<K, T> {
+    root: TrieNode<T>,
+    key: PhantomData<K>,
+}
+
+impl<K: ArtKey, T> Default for BurstTrie<K, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: ArtKey, T> BurstTrie<K, T> {
+    pub fn new() -> Self {
+        Self {
+            root: TrieNode::new(),
+            key: PhantomData,
+        }
+    }
+
+    pub fn find(&self, key: K) -> Option<&T> {
+        let key_bytes = key.bytes();
+        trace_span!("burst.find", key_len = key_bytes.len());
+        let mut node = &self.root;
+        for (depth, byte) in key_bytes.iter().enumerate() {
+            match node.children[*byte as usize].as_ref()? {
+                Child::Node(child) => node = child,
+                Child::Bucket(bucket) => return bucket.get(&key_bytes[depth + 1..]),
+            }
+        }
+        node.value.as_ref()
+    }
+
+    // Keys with their values in the key order
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            stack: vec![Frame::Node(&self.root, vec![])],
+        }
+    }
+
+    // Pairs with keys starting with `prefix` in the key order
+    pub fn prefix_iter<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = (Vec<u8>, &'a T)> {
+        let mut stack = vec![Frame::Node(&self.root, vec![])];
+        for (depth, byte) in prefix.iter().enumerate() {
+            // A bucket holds the rest of the prefix, keep it
+            let node = match stack.last() {
+                Some(Frame::Node(node, _)) => *node,
+                _ => break,
+            };
+            stack.pop();
+            match &node.children[*byte as usize] {
+                Some(Child::Node(child)) => stack.push(Frame::Node(child, prefix.to_vec())),
+                Some(Child::Bucket(bucket)) => {
+                    stack.push(Frame::Bucket(bucket, prefix[..=depth].to_vec()))
+                }
+                None => {}
+            }
+        }
+        // Only keys of a bucket may not match the rest of the prefix
+        Iter { stack }.filter(move |(key, _)| key.starts_with(prefix))
+    }
+
+    pub fn insert(&mut self, key: K, value: T) {
+        let key_bytes = key.bytes();
+        trace_span!("burst.insert", key_len = key_bytes.len());
+        let mut node = &mut self.root;
+        for (depth, byte) in key_bytes.iter().enumerate() {
+            let child =
+                node.children[*byte as usize].get_or_insert_with(|| Child::Bucket(HashMap::new()));
+            let bucket = match child {
+                Child::Node(child) => {
+                    node = child;
+                    continue;
+                }
+                Child::Bucket(bucket) => bucket,
+            };
+            bucket.insert(key_bytes[depth + 1..].to_vec(), value);
+            if bucket.len() > BURST_LIMIT {
+                let bucket = std::mem::take(bucket);
+                *child = Child::Node(Box::new(TrieNode::burst(bucket)));
+            }
+            return;
+        }
+        node.value = Some(value);
+    }
+
+    pub fn delete(&mut self, key: K) {
+        let key_bytes = key.bytes();
+        trace_span!("burst.delete", key_len = key_bytes.len());
+        let mut node = &mut self.root;
+        for (depth, byte) in key_bytes.iter().enumerate() {
+            let slot = &mut node.children[*byte as usize];
+            match slot {
+                Some(Child::Node(child)) => node = child,
+                Some(Child::Bucket(bucket)) => {
+                    bucket.remove(&key_bytes[depth + 1..]);
+                    if bucket.is_empty() {
+                        *slot = None;
+                    }
+                    return;
+                }
+                None => return,
+            }
+        }
+        node.value = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_add_find_and_delete() {
+        let mut trie = BurstTrie::<String, u32>::new();
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+
+        for _i in 0..20_000 {
+            // Short keys over a few letters to burst buckets and get
+            // prefixes of other keys
+            let len = rng.gen_range(0, 8);
+            let key: String = (0..len)
+                .map(|_| ['a', 'b', 'c', 'd'][rng.gen_range(0, 4)])
+                .collect();
+            let value = rng.gen::<u32>();
+            trie.insert(key.clone(), value);
+            data.insert(key, value);
+        }
+        for (key, val) in &data {
+            assert_eq!(Some(val), trie.find(key.clone()));
+        }
+        assert!(trie.iter().map(|(key, val)| (key, *val)).eq(data
+            .iter()
+            .map(|(key, val)| (key.as_bytes().to_vec(), *val))));
+
+        let keys: Vec<_> = data.keys().cloned().collect();
+        for key in keys.iter().step_by(2) {
+            trie.delete(key.clone());
+            data.remove(key);
+        }
+        for key in keys.iter() {
+            assert_eq!(data.get(key), trie.find(key.clone()));
+        }
+        for prefix in ["", "a", "ab", "abcdab"].iter() {
+            let found: Vec<_> = trie
+                .prefix_iter(prefix.as_bytes())
+                .map(|(_, val)| *val)
+                .collect();
+            let expected: Vec<_> = data
+                .iter()
+                .filter(|(key, _)| key.starts_with(prefix))
+                .map(|(_, val)| *val)
+                .collect();
+            assert_eq!(expected, found);
+        }
+        for key in keys.iter() {
+            trie.delete(key.clone());
+        }
+        assert_eq!(0, trie.iter().count());
+    }
+}
@@ -0,0 +1,70 @@
+// Binary representation of the values stored in the trees,
+// used by the on-disk formats
+pub trait Codec: Sized {
+    fn encode(&self, buf: &mut Vec<u8>);
+    // `None` if the bytes are not a valid encoding
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+impl Codec for Vec<u8> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(bytes.to_vec())
+    }
+}
+
+impl Codec for String {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.as_bytes());
+    }
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+// Same as for `ArtKey`, generate the implementations for integers
+macro_rules! doit {
+    ($($t:ty)*) => ($(impl Codec for $t {
+        fn encode(&self, buf: &mut Vec<u8>) {
+            buf.extend_from_slice(&self.to_be_bytes());
+        }
+        fn decode(bytes: &[u8]) -> Option<Self> {
+            let mut array = [0; std::mem::size_of::<$t>()];
+            if bytes.len() != array.len() {
+                return None;
+            }
+            array.copy_from_slice(bytes);
+            Some(<$t>::from_be_bytes(array))
+        }
+    })*)
+}
+doit! { i8 i16 i32 i64 i128 isize u8 u16 u32 u64 u128 usize }
+
+// LEB128 encoding of the lengths and offsets
+pub(crate) fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+// Read a varint at `pos` and move `pos` past it
+pub(crate) fn read_varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *buf.get(*pos)?;
+        *pos += 1;
+        if shift > 63 {
+            return None;
+        }
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+    }
+}
@@ -0,0 +1,266 @@
+use crate::art::Art;
+use crate::keys::Raw;
+use std::borrow::Cow;
+
+// Block compression of values, LZ4 comes with the `lz4` feature and other
+// codecs can be plugged in the same way
+pub trait Compressor {
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+
+    fn decompress(&self, data: &[u8]) -> Vec<u8>;
+}
+
+#[cfg(feature = "lz4")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl Compressor for Lz4 {
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        lz4_flex::compress_prepend_size(data)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Vec<u8> {
+        lz4_flex::decompress_size_prepended(data).expect("corrupted lz4 value")
+    }
+}
+
+// Value in a leaf, compressed only if it was worth it
+#[derive(Debug, Clone)]
+enum Packed {
+    Plain(Vec<u8>),
+    Compressed { raw_len: usize, data: Vec<u8> },
+}
+
+impl Packed {
+    fn raw_len(&self) -> usize {
+        match self {
+            Packed::Plain(data) => data.len(),
+            Packed::Compressed { raw_len, .. } => *raw_len,
+        }
+    }
+
+    fn stored_len(&self) -> usize {
+        match self {
+            Packed::Plain(data) | Packed::Compressed { data, .. } => data.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub values: usize,
+    pub compressed: usize,
+    pub raw_bytes: usize,
+    pub stored_bytes: usize,
+}
+
+impl CompressionStats {
+    // Raw bytes per stored byte, 1.0 when empty
+    pub fn ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            1.0
+        } else {
+            self.raw_bytes as f64 / self.stored_bytes as f64
+        }
+    }
+
+    fn add(&mut self, packed: &Packed) {
+        self.values += 1;
+        self.compressed += matches!(packed, Packed::Compressed { .. }) as usize;
+        self.raw_bytes += packed.raw_len();
+        self.stored_bytes += packed.stored_len();
+    }
+
+    fn remove(&mut self, packed: &Packed) {
+        self.values -= 1;
+        self.compressed -= matches!(packed, Packed::Compressed { .. }) as usize;
+        self.raw_bytes -= packed.raw_len();
+        self.stored_bytes -= packed.stored_len();
+    }
+}
+
+// Byte map on the ART that keeps values of at least `threshold` bytes
+// compressed in the leaves, for big values like JSON documents where memory
+// matters more than the CPU to decompress them on every read. Values that
+// don't get smaller are kept as they are. Keys go in as they are, so as in
+// `Art` no key can be a prefix of another
+pub struct CompressedArt<C> {
+    art: Art<String, Packed>,
+    compressor: C,
+    threshold: usize,
+    stats: CompressionStats,
+}
+
+impl<C: Compressor> CompressedArt<C> {
+    pub fn new(compressor: C, threshold: usize) -> Self {
+        Self {
+            art: Art::new(),
+            compressor,
+            threshold,
+            stats: CompressionStats::default(),
+        }
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    // Only applies to values inserted from now on
+    pub fn set_threshold(&mut self, threshold: usize) {
+        self.threshold = threshold;
+    }
+
+    pub fn stats(&self) -> CompressionStats {
+        self.stats
+    }
+
+    pub fn len(&self) -> usize {
+        self.stats.values
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stats.values == 0
+    }
+
+    fn pack(&self, value: &[u8]) -> Packed {
+        if value.len() >= self.threshold {
+            let data = self.compressor.compress(value);
+            if data.len() < value.len() {
+                return Packed::Compressed {
+                    raw_len: value.len(),
+                    data,
+                };
+            }
+        }
+        Packed::Plain(value.to_vec())
+    }
+
+    fn unpack<'a>(&self, packed: &'a Packed) -> Cow<'a, [u8]> {
+        match packed {
+            Packed::Plain(data) => Cow::Borrowed(data),
+            Packed::Compressed { data, .. } => Cow::Owned(self.compressor.decompress(data)),
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) {
+        if let Some(old) = self.art.find_with(&Raw, key) {
+            self.stats.remove(old);
+        }
+        let packed = self.pack(value);
+        self.stats.add(&packed);
+        self.art.insert_with(&Raw, key, packed);
+    }
+
+    // Borrowed for plain values, decompressed for the others
+    pub fn get(&self, key: &[u8]) -> Option<Cow<'_, [u8]>> {
+        self.art
+            .find_with(&Raw, key)
+            .map(|packed| self.unpack(packed))
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        match self.art.find_with(&Raw, key) {
+            Some(old) => {
+                self.stats.remove(old);
+                self.art.delete_with(&Raw, key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Entries in the key order, decompressing as it goes
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], Cow<'_, [u8]>)> {
+        self.art
+            .iter()
+            .map(move |(key, packed)| (key, self.unpack(packed)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+    use std::collections::BTreeMap;
+
+    // Run length encoding as (count, byte) pairs, enough to test the layer
+    // without a compression feature
+    struct Rle;
+
+    impl Compressor for Rle {
+        fn compress(&self, data: &[u8]) -> Vec<u8> {
+            let mut out = vec![];
+            for &b in data {
+                match out.len() {
+                    n if n > 0 && out[n - 1] == b && out[n - 2] < 255 => out[n - 2] += 1,
+                    _ => out.extend_from_slice(&[1, b]),
+                }
+            }
+            out
+        }
+
+        fn decompress(&self, data: &[u8]) -> Vec<u8> {
+            data.chunks(2)
+                .flat_map(|pair| std::iter::repeat_n(pair[1], pair[0] as usize))
+                .collect()
+        }
+    }
+
+    fn random_value(rng: &mut impl Rng) -> Vec<u8> {
+        let len = rng.gen_range(0, 300);
+        if rng.gen() {
+            vec![rng.gen_range(b'a', b'c'); len]
+        } else {
+            (0..len).map(|_| rng.gen()).collect()
+        }
+    }
+
+    #[test]
+    fn test_compressed_art() {
+        let mut art = CompressedArt::new(Rle, 16);
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for _ in 0..2_000 {
+            let key = format!("{}\0", rng.gen_range(0, 300)).into_bytes();
+            if rng.gen_range(0, 4) == 0 {
+                assert_eq!(data.remove(&key).is_some(), art.remove(&key));
+            } else {
+                let value = random_value(&mut rng);
+                art.insert(&key, &value);
+                data.insert(key, value);
+            }
+        }
+        for (key, value) in data.iter() {
+            assert_eq!(&value[..], &art.get(key).unwrap()[..]);
+        }
+        let found: Vec<_> = art
+            .iter()
+            .map(|(k, v)| (k.to_vec(), v.into_owned()))
+            .collect();
+        assert_eq!(data.clone().into_iter().collect::<Vec<_>>(), found);
+
+        let stats = art.stats();
+        assert_eq!(data.len(), stats.values);
+        assert_eq!(
+            data.values().map(|v| v.len()).sum::<usize>(),
+            stats.raw_bytes
+        );
+        assert!(stats.compressed > 0 && stats.stored_bytes < stats.raw_bytes);
+        assert!(stats.ratio() > 1.0);
+        assert_eq!(None, art.get(b"none\0"));
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_lz4() {
+        let mut art = CompressedArt::new(Lz4, 64);
+        let json = r#"{"name": "radix", "tags": ["tree", "trie", "tree", "trie"]}"#.repeat(50);
+        art.insert(b"doc", json.as_bytes());
+        art.insert(b"small", b"{}");
+        assert_eq!(json.as_bytes(), &art.get(b"doc").unwrap()[..]);
+        assert_eq!(b"{}", &art.get(b"small").unwrap()[..]);
+        assert_eq!(1, art.stats().compressed);
+        assert!(art.stats().ratio() > 5.0);
+    }
+}
@@ -0,0 +1,251 @@
+use crate::art::ArtKey;
+use core::marker::PhantomData;
+
+// Crit-bit tree, a PATRICIA trie branching on single bits. There is one
+// internal node per key besides the first, each knows the position of the
+// bit its subtrees differ in. Every key byte is preceded by a bit telling
+// it exists, so a key that ends sorts before its extensions and one key
+// can be a prefix of another
+
+#[derive(Debug)]
+struct Leaf<T> {
+    key: Vec<u8>,
+    value: T,
+}
+
+#[derive(Debug)]
+struct Internal<T> {
+    // Position of the critical bit, see `bit`
+    pos: usize,
+    children: [CbNode<T>; 2],
+}
+
+#[derive(Debug)]
+enum CbNode<T> {
+    // Only an empty tree or a node being moved
+    Empty,
+    Leaf(Leaf<T>),
+    Internal(Box<Internal<T>>),
+}
+
+// Bit of the key, every byte takes 9 positions: whether it exists
+// and then its bits starting with the highest
+fn bit(key: &[u8], pos: usize) -> bool {
+    match (key.get(pos / 9), pos % 9) {
+        (Some(_), 0) => true,
+        (Some(byte), offset) => byte & (0x100 >> offset) as u8 != 0,
+        (None, _) => false,
+    }
+}
+
+// Position of the first bit two keys differ in
+fn critical_bit(a: &[u8], b: &[u8]) -> Option<usize> {
+    let common = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+    match (a.get(common), b.get(common)) {
+        (Some(x), Some(y)) => Some(common * 9 + 1 + (x ^ y).leading_zeros() as usize),
+        (None, None) => None,
+        _ => Some(common * 9),
+    }
+}
+
+impl<T> CbNode<T> {
+    // Leaf the key would be found at if it was in the tree
+    fn closest(&self, key: &[u8]) -> Option<&Leaf<T>> {
+        let mut node = self;
+        loop {
+            match node {
+                CbNode::Empty => return None,
+                CbNode::Leaf(leaf) => return Some(leaf),
+                CbNode::Internal(internal) => {
+                    node = &internal.children[bit(key, internal.pos) as usize];
+                }
+            }
+        }
+    }
+}
+
+// Insert the leaf below the node, the leaf key differs from the keys
+// of the subtree first in the bit `pos`
+fn insert_at<T>(node: &mut CbNode<T>, pos: usize, leaf: Leaf<T>) {
+    match node {
+        CbNode::Internal(internal) if internal.pos < pos => {
+            let child = &mut internal.children[bit(&leaf.key, internal.pos) as usize];
+            insert_at(child, pos, leaf);
+        }
+        CbNode::Leaf(old) if old.key == leaf.key => old.value = leaf.value,
+        // The new key diverges above the node, put an internal one in its place
+        _ => {
+            let right = bit(&leaf.key, pos);
+            let old = std::mem::replace(node, CbNode::Empty);
+            let leaf = CbNode::Leaf(leaf);
+            let children = if right { [old, leaf] } else { [leaf, old] };
+            *node = CbNode::Internal(Box::new(Internal { pos, children }));
+        }
+    }
+}
+
+fn delete_at<T>(node: &mut CbNode<T>, key: &[u8]) {
+    let internal = match &mut *node {
+        CbNode::Internal(internal) => internal,
+        _ => return,
+    };
+    let dir = bit(key, internal.pos) as usize;
+    match &internal.children[dir] {
+        // The sibling takes the place of the parent
+        CbNode::Leaf(leaf) if leaf.key == key => {
+            let sibling = std::mem::replace(&mut internal.children[1 - dir], CbNode::Empty);
+            *node = sibling;
+        }
+        CbNode::Internal(_) => delete_at(&mut internal.children[dir], key),
+        _ => {}
+    }
+}
+
+pub struct Iter<'a, T> {
+    stack: Vec<&'a CbNode<T>>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (&'a [u8], &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(node) = self.stack.pop() {
+            match node {
+                CbNode::Empty => {}
+                CbNode::Leaf(leaf) => return Some((&leaf.key, &leaf.value)),
+                CbNode::Internal(internal) => {
+                    self.stack.push(&internal.children[1]);
+                    self.stack.push(&internal.children[0]);
+                }
+            }
+        }
+        None
+    }
+}
+
+pub struct CritBit<K, T> {
+    root: CbNode<T>,
+    key: PhantomData<K>,
+}
+
+impl<K: ArtKey, T> Default for CritBit<K, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: ArtKey, T> CritBit<K, T> {
+    pub fn new() -> Self {
+        Self {
+            root: CbNode::Empty,
+            key: PhantomData,
+        }
+    }
+
+    pub fn find(&self, key: K) -> Option<&T> {
+        let key_bytes = key.bytes();
+        trace_span!("critbit.find", key_len = key_bytes.len());
+        match self.root.closest(&key_bytes) {
+            Some(leaf) if leaf.key == key_bytes => Some(&leaf.value),
+            _ => None,
+        }
+    }
+
+    // Keys with their values in the key order
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            stack: vec![&self.root],
+        }
+    }
+
+    // Pairs with keys starting with `prefix` in the key order
+    pub fn prefix_iter<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = (&'a [u8], &'a T)> {
+        // Follow the prefix bits, past them the whole subtree matches
+        // except for the bits skipped by the internal nodes
+        let mut node = &self.root;
+        while let CbNode::Internal(internal) = node {
+            if internal.pos >= 9 * prefix.len() {
+                break;
+            }
+            node = &internal.children[bit(prefix, internal.pos) as usize];
+        }
+        let iter = Iter { stack: vec![node] };
+        iter.filter(move |(key, _)| key.starts_with(prefix))
+    }
+
+    pub fn insert(&mut self, key: K, value: T) {
+        let leaf = Leaf {
+            key: key.bytes(),
+            value,
+        };
+        trace_span!("critbit.insert", key_len = leaf.key.len());
+        match self.root.closest(&leaf.key) {
+            Some(closest) => {
+                // An equal key rewrites the value of its leaf
+                let pos = critical_bit(&closest.key, &leaf.key).unwrap_or(usize::MAX);
+                insert_at(&mut self.root, pos, leaf);
+            }
+            None => self.root = CbNode::Leaf(leaf),
+        }
+    }
+
+    pub fn delete(&mut self, key: K) {
+        let key_bytes = key.bytes();
+        trace_span!("critbit.delete", key_len = key_bytes.len());
+        match &self.root {
+            CbNode::Leaf(leaf) if leaf.key == key_bytes => self.root = CbNode::Empty,
+            _ => delete_at(&mut self.root, &key_bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_add_find_and_delete() {
+        let mut tree = CritBit::<String, u32>::new();
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+
+        for _i in 0..20_000 {
+            // Short keys over a few letters to get prefixes of other keys
+            let len = rng.gen_range(0, 8);
+            let key: String = (0..len)
+                .map(|_| ['\0', 'a', 'b', '~'][rng.gen_range(0, 4)])
+                .collect();
+            let value = rng.gen::<u32>();
+            tree.insert(key.clone(), value);
+            data.insert(key, value);
+        }
+        for (key, val) in &data {
+            assert_eq!(Some(val), tree.find(key.clone()));
+        }
+        assert!(tree.iter().map(|(key, val)| (key.to_vec(), *val)).eq(data
+            .iter()
+            .map(|(key, val)| (key.as_bytes().to_vec(), *val))));
+
+        let keys: Vec<_> = data.keys().cloned().collect();
+        for key in keys.iter().step_by(2) {
+            tree.delete(key.clone());
+            data.remove(key);
+        }
+        for key in keys.iter() {
+            assert_eq!(data.get(key), tree.find(key.clone()));
+        }
+        let found: Vec<_> = tree.prefix_iter(b"ab").map(|(_, val)| *val).collect();
+        let expected: Vec<_> = data
+            .iter()
+            .filter(|(key, _)| key.starts_with("ab"))
+            .map(|(_, val)| *val)
+            .collect();
+        assert_eq!(expected, found);
+        for key in keys.iter() {
+            tree.delete(key.clone());
+        }
+        assert_eq!(0, tree.iter().count());
+    }
+}
@@ -0,0 +1,251 @@
+use std::io;
+
+// Static double-array trie mapping byte keys to u32 values.
+//
+// Every state of the trie is a unit of two little endian u32: `base` and
+// `check`. The transition from state `s` by byte `b` goes to `t = base[s]
+// + b + 1` if `check[t] == s`. A key ending at `s` has a terminal unit at
+// `base[s]`, whose `base` holds the value. The units are stored as plain
+// bytes after a magic number, so a built trie can be written out as is and
+// later used straight from a memory mapped file:
+//     u32 MAGIC | base[0] | check[0] | base[1] | check[1] | ...
+
+const MAGIC: u32 = 0x6461_7431;
+const HEADER_SIZE: usize = 4;
+const UNIT_SIZE: usize = 8;
+// Check of a unit no state owns
+const FREE: u32 = u32::MAX;
+
+// Error returned when the keys given to `DoubleArray::build` aren't
+// sorted or have duplicates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfOrder {
+    pub index: usize,
+}
+
+impl std::fmt::Display for OutOfOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "key {} is not greater than the previous one", self.index)
+    }
+}
+
+impl std::error::Error for OutOfOrder {}
+
+struct Builder<'a> {
+    keys: &'a [(Vec<u8>, u32)],
+    base: Vec<u32>,
+    check: Vec<u32>,
+    // No unit below is free
+    first_free: usize,
+}
+
+impl<'a> Builder<'a> {
+    fn is_free(&self, index: usize) -> bool {
+        index >= self.check.len() || self.check[index] == FREE
+    }
+
+    fn reserve(&mut self, index: usize, state: u32) {
+        if index >= self.check.len() {
+            self.base.resize(index + 1, 0);
+            self.check.resize(index + 1, FREE);
+        }
+        self.check[index] = state;
+        while !self.is_free(self.first_free) {
+            self.first_free += 1;
+        }
+    }
+
+    // Place the children of the state built from keys `lo..hi`,
+    // which share their first `depth` bytes
+    fn build(&mut self, state: u32, lo: usize, hi: usize, depth: usize) {
+        // Child codes with the ranges of their keys, 0 ends a key
+        let mut children: Vec<(usize, usize, usize)> = vec![];
+        for i in lo..hi {
+            let code = self.keys[i]
+                .0
+                .get(depth)
+                .map_or(0, |&byte| byte as usize + 1);
+            match children.last_mut() {
+                Some(last) if last.0 == code => last.2 = i + 1,
+                _ => children.push((code, i, i + 1)),
+            }
+        }
+        // First base where all children fit
+        let mut base = std::cmp::max(self.first_free, children[0].0 + 1) - children[0].0;
+        while !children.iter().all(|child| self.is_free(base + child.0)) {
+            base += 1;
+        }
+        self.base[state as usize] = base as u32;
+        for child in children.iter() {
+            self.reserve(base + child.0, state);
+        }
+        for &(code, lo, hi) in children.iter() {
+            let index = base + code;
+            if code == 0 {
+                self.base[index] = self.keys[lo].1;
+            } else {
+                self.build(index as u32, lo, hi, depth + 1);
+            }
+        }
+    }
+}
+
+pub struct DoubleArray<D = Vec<u8>> {
+    data: D,
+}
+
+impl DoubleArray<Vec<u8>> {
+    // Build the trie from keys in the increasing order
+    pub fn build<I, K>(pairs: I) -> Result<Self, OutOfOrder>
+    where
+        I: IntoIterator<Item = (K, u32)>,
+        K: AsRef<[u8]>,
+    {
+        let keys: Vec<_> = pairs
+            .into_iter()
+            .map(|(key, value)| (key.as_ref().to_vec(), value))
+            .collect();
+        if let Some(i) = (1..keys.len()).find(|&i| keys[i - 1].0 >= keys[i].0) {
+            return Err(OutOfOrder { index: i });
+        }
+        let mut builder = Builder {
+            keys: &keys,
+            // The root is never a child, it doesn't need a check
+            base: vec![0],
+            check: vec![FREE],
+            first_free: 1,
+        };
+        if !keys.is_empty() {
+            builder.build(0, 0, keys.len(), 0);
+        }
+        let mut data = Vec::with_capacity(HEADER_SIZE + UNIT_SIZE * builder.base.len());
+        data.extend_from_slice(&MAGIC.to_le_bytes());
+        for (base, check) in builder.base.iter().zip(builder.check.iter()) {
+            data.extend_from_slice(&base.to_le_bytes());
+            data.extend_from_slice(&check.to_le_bytes());
+        }
+        Ok(Self { data })
+    }
+}
+
+impl<D: AsRef<[u8]>> DoubleArray<D> {
+    // Use the bytes of a built trie, for example a memory mapped file
+    pub fn new(data: D) -> io::Result<Self> {
+        let bytes = data.as_ref();
+        if bytes.len() < HEADER_SIZE + UNIT_SIZE
+            || !(bytes.len() - HEADER_SIZE).is_multiple_of(UNIT_SIZE)
+            || bytes[..HEADER_SIZE] != MAGIC.to_le_bytes()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a double-array trie",
+            ));
+        }
+        Ok(Self { data })
+    }
+
+    // Bytes to write out and load with `new`
+    pub fn as_bytes(&self) -> &[u8] {
+        self.data.as_ref()
+    }
+
+    fn len(&self) -> usize {
+        (self.data.as_ref().len() - HEADER_SIZE) / UNIT_SIZE
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        let bytes = &self.data.as_ref()[offset..offset + 4];
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    fn base(&self, index: usize) -> usize {
+        self.read(HEADER_SIZE + UNIT_SIZE * index) as usize
+    }
+
+    fn check(&self, index: usize) -> u32 {
+        self.read(HEADER_SIZE + UNIT_SIZE * index + 4)
+    }
+
+    // Child of the state by the code, codes are bytes plus one and 0 ends a key
+    fn child(&self, state: usize, code: usize) -> Option<usize> {
+        let index = self.base(state) + code;
+        if index < self.len() && self.check(index) == state as u32 {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn value(&self, state: usize) -> Option<u32> {
+        self.child(state, 0).map(|index| self.base(index) as u32)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<u32> {
+        let mut state = 0;
+        for &byte in key {
+            state = self.child(state, byte as usize + 1)?;
+        }
+        self.value(state)
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Keys that are prefixes of `input` as their lengths with values,
+    // shortest first. This is the lookup a tokenizer does at every position
+    pub fn common_prefixes<'a>(
+        &'a self,
+        input: &'a [u8],
+    ) -> impl Iterator<Item = (usize, u32)> + 'a {
+        let mut state = Some(0);
+        (0..=input.len())
+            .map_while(move |len| {
+                let current = state?;
+                state = input
+                    .get(len)
+                    .and_then(|&byte| self.child(current, byte as usize + 1));
+                Some(self.value(current).map(|value| (len, value)))
+            })
+            .flatten()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_build_and_get() {
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for i in 0..10_000 {
+            let len = rng.gen_range(0, 10);
+            let key: Vec<u8> = (0..len).map(|_| rng.gen_range(b'a', b'f')).collect();
+            data.insert(key, i);
+        }
+        let trie = DoubleArray::build(data.iter().map(|(key, value)| (key, *value))).unwrap();
+        let trie = DoubleArray::new(trie.as_bytes()).unwrap();
+        for (key, value) in data.iter() {
+            assert_eq!(Some(*value), trie.get(key));
+        }
+        for _i in 0..10_000 {
+            let len = rng.gen_range(0, 12);
+            let key: Vec<u8> = (0..len).map(|_| rng.gen_range(b'a', b'h')).collect();
+            assert_eq!(data.get(&key).copied(), trie.get(&key));
+        }
+
+        let trie = DoubleArray::build(vec![("a", 1), ("ab", 2), ("abd", 3), ("b", 4)]).unwrap();
+        let found: Vec<_> = trie.common_prefixes(b"abde").collect();
+        assert_eq!(vec![(1, 1), (2, 2), (3, 3)], found);
+        assert_eq!(0, trie.common_prefixes(b"c").count());
+
+        assert_eq!(
+            Err(OutOfOrder { index: 1 }),
+            DoubleArray::build(vec![("b", 1), ("a", 2)]).map(|_| ())
+        );
+        assert!(DoubleArray::new(&b"junk"[..]).is_err());
+    }
+}
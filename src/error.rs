@@ -0,0 +1,116 @@
+use std::fmt;
+use std::io;
+use std::str::Utf8Error;
+use std::string::FromUtf8Error;
+
+// Error of the fallible operations of the crate: inserts past a limit
+// set on a structure, loads and imports of bad data
+#[derive(Debug)]
+pub enum Error {
+    // Key longer than the limit of the structure
+    KeyTooLong {
+        len: usize,
+        max: usize,
+    },
+    // Key that is a prefix of another, in a structure that can't hold both
+    KeyIsPrefix {
+        key: Vec<u8>,
+    },
+    // Insert that would take the structure over its memory budget
+    CapacityExceeded {
+        needed: usize,
+        budget: usize,
+    },
+    // Insert that would take a namespace over its quota, with the usage it
+    // would have
+    QuotaExceeded {
+        namespace: String,
+        entries: usize,
+        bytes: usize,
+    },
+    // Argument a call can't do anything with, with what is wrong with it
+    InvalidArgument(String),
+    // Serialized data that doesn't decode or a tree that breaks its
+    // invariants, with what is wrong with it
+    Corrupt(String),
+    // Bytes of a key or a label that aren't UTF-8
+    Utf8(Utf8Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::KeyTooLong { len, max } => {
+                write!(f, "key of {} bytes is longer than {} bytes", len, max)
+            }
+            Error::KeyIsPrefix { key } => write!(
+                f,
+                "key {:?} is a prefix of another key",
+                String::from_utf8_lossy(key)
+            ),
+            Error::CapacityExceeded { needed, budget } => write!(
+                f,
+                "insert needs {} bytes, memory budget is {} bytes",
+                needed, budget
+            ),
+            Error::QuotaExceeded {
+                namespace,
+                entries,
+                bytes,
+            } => write!(
+                f,
+                "insert takes namespace {:?} to {} entries and {} bytes, over its quota",
+                namespace, entries, bytes
+            ),
+            Error::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            Error::Corrupt(msg) => write!(f, "corrupt data: {}", msg),
+            Error::Utf8(err) => write!(f, "invalid UTF-8: {}", err),
+            Error::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Utf8(err) => Some(err),
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<Utf8Error> for Error {
+    fn from(err: Utf8Error) -> Self {
+        Error::Utf8(err)
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(err: FromUtf8Error) -> Self {
+        Error::Utf8(err.utf8_error())
+    }
+}
+
+// So `?` keeps working in functions returning `io::Result`
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            err @ (Error::KeyTooLong { .. }
+            | Error::KeyIsPrefix { .. }
+            | Error::InvalidArgument(_)) => io::Error::new(io::ErrorKind::InvalidInput, err),
+            err @ (Error::CapacityExceeded { .. } | Error::QuotaExceeded { .. }) => {
+                io::Error::other(err)
+            }
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+// Text formats of the key/value pairs, one pair per line.
+// `Csv` writes the key followed by the value fields, so the value has
+// to serialize to a flat record. `JsonLines` writes `{"key":..,"value":..}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    JsonLines,
+}
+
+#[derive(Serialize)]
+struct PairRef<'a, K, T> {
+    key: K,
+    value: &'a T,
+}
+
+#[derive(Deserialize)]
+struct Pair<K, T> {
+    key: K,
+    value: T,
+}
+
+pub(crate) fn write_pairs<'a, W, K, T, I>(writer: W, format: Format, pairs: I) -> io::Result<()>
+where
+    W: Write,
+    K: Serialize,
+    T: Serialize + 'a,
+    I: Iterator<Item = (K, &'a T)>,
+{
+    match format {
+        Format::Csv => {
+            let mut csv = csv::WriterBuilder::new()
+                .has_headers(false)
+                .flexible(true)
+                .from_writer(writer);
+            for (key, value) in pairs {
+                csv.serialize((key, value))?;
+            }
+            csv.flush()
+        }
+        Format::JsonLines => {
+            let mut writer = writer;
+            for (key, value) in pairs {
+                serde_json::to_writer(&mut writer, &PairRef { key, value })?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()
+        }
+    }
+}
+
+// Call `f` for every pair read, stops at the first malformed line
+pub(crate) fn read_pairs<R, K, T, F>(reader: R, format: Format, mut f: F) -> io::Result<()>
+where
+    R: Read,
+    K: DeserializeOwned,
+    T: DeserializeOwned,
+    F: FnMut(K, T),
+{
+    match format {
+        Format::Csv => {
+            let mut csv = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .flexible(true)
+                .from_reader(reader);
+            for pair in csv.deserialize() {
+                let (key, value) = pair?;
+                f(key, value);
+            }
+        }
+        Format::JsonLines => {
+            for line in BufReader::new(reader).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let pair: Pair<K, T> = serde_json::from_str(&line)?;
+                f(pair.key, pair.value);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,337 @@
+use crate::codec::{read_varint, write_varint};
+use crate::error::Error;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt::Write;
+
+// Read-only radix tree serialized into one byte blob and searched in
+// place, so a table built ahead of time, e.g. from build.rs, costs nothing
+// to load. Every node is written after its children:
+//     varint prefix_len | prefix | varint value_len + 1 (0 if none) | value
+//     | varint child_count | first byte of every child | u32 child offsets
+// and the blob ends with the footer:
+//     u32 root_offset | u32 len | u32 MAGIC (little endian)
+// The first byte of a child is in its parent, not in its prefix. Unlike
+// `Art` a key can be a prefix of another
+
+const FOOTER_SIZE: usize = 12;
+const MAGIC: u32 = 0x7a72_6661;
+
+fn corrupt(msg: &str) -> Error {
+    Error::Corrupt(format!("frozen art: {}", msg))
+}
+
+// Pairs in any order, the last value of a key wins
+#[derive(Debug, Clone, Default)]
+pub struct FrozenBuilder {
+    pairs: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl FrozenBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) {
+        self.pairs
+            .insert(key.as_ref().to_vec(), value.as_ref().to_vec());
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let pairs: Vec<_> = self.pairs.iter().collect();
+        let mut blob = vec![];
+        let root = write_node(&mut blob, &pairs, 0);
+        blob.extend_from_slice(&(root as u32).to_le_bytes());
+        blob.extend_from_slice(&(pairs.len() as u32).to_le_bytes());
+        blob.extend_from_slice(&MAGIC.to_le_bytes());
+        blob
+    }
+
+    // Rust source of a static holding the blob, for build.rs to write into
+    // OUT_DIR and the crate to `include!` and load with `from_static`
+    pub fn to_rust(&self, name: &str) -> String {
+        let mut source = format!("pub static {}: &[u8] = b\"", name);
+        for byte in self.build() {
+            for c in std::ascii::escape_default(byte) {
+                source.push(c as char);
+            }
+        }
+        writeln!(source, "\";").unwrap();
+        source
+    }
+}
+
+// Write the node of the sorted pairs sharing their first `depth` bytes,
+// returns its offset
+fn write_node(blob: &mut Vec<u8>, pairs: &[(&Vec<u8>, &Vec<u8>)], depth: usize) -> usize {
+    let (first, last) = match (pairs.first(), pairs.last()) {
+        (Some(first), Some(last)) => (&first.0[depth..], &last.0[depth..]),
+        _ => (&[][..], &[][..]),
+    };
+    // The first and the last keys share what all of them share
+    let prefix_len = first
+        .iter()
+        .zip(last.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let depth = depth + prefix_len;
+    let (value, rest) = match pairs.split_first() {
+        Some((pair, rest)) if pair.0.len() == depth => (Some(pair.1), rest),
+        _ => (None, pairs),
+    };
+    let mut children = vec![];
+    let mut start = 0;
+    for i in 1..=rest.len() {
+        if i == rest.len() || rest[i].0[depth] != rest[start].0[depth] {
+            let offset = write_node(blob, &rest[start..i], depth + 1);
+            children.push((rest[start].0[depth], offset));
+            start = i;
+        }
+    }
+    let offset = blob.len();
+    write_varint(blob, prefix_len as u64);
+    blob.extend_from_slice(&first[..prefix_len]);
+    match value {
+        Some(value) => {
+            write_varint(blob, value.len() as u64 + 1);
+            blob.extend_from_slice(value);
+        }
+        None => write_varint(blob, 0),
+    }
+    write_varint(blob, children.len() as u64);
+    blob.extend(children.iter().map(|&(byte, _)| byte));
+    for &(_, offset) in children.iter() {
+        blob.extend_from_slice(&(offset as u32).to_le_bytes());
+    }
+    offset
+}
+
+// Node read from the blob
+struct Node<'a> {
+    offset: usize,
+    prefix: &'a [u8],
+    value: Option<&'a [u8]>,
+    bytes: &'a [u8],
+    offsets: &'a [u8],
+}
+
+impl<'a> Node<'a> {
+    // Children are written first, an offset that isn't lower is damage
+    // that could make a cycle
+    fn child(&self, i: usize) -> Option<usize> {
+        let child = u32::from_le_bytes(self.offsets[4 * i..4 * i + 4].try_into().unwrap());
+        Some(child as usize).filter(|&child| child < self.offset)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrozenArt<'a> {
+    blob: &'a [u8],
+    root: usize,
+    len: usize,
+}
+
+impl FrozenArt<'static> {
+    pub fn from_static(blob: &'static [u8]) -> Result<Self, Error> {
+        Self::new(blob)
+    }
+}
+
+impl<'a> FrozenArt<'a> {
+    // Only the footer is checked here, a blob damaged elsewhere gives
+    // wrong answers or `None`, never a panic
+    pub fn new(blob: &'a [u8]) -> Result<Self, Error> {
+        if blob.len() < FOOTER_SIZE {
+            return Err(corrupt("too short"));
+        }
+        let footer = &blob[blob.len() - FOOTER_SIZE..];
+        let word = |i: usize| u32::from_le_bytes(footer[4 * i..4 * i + 4].try_into().unwrap());
+        if word(2) != MAGIC {
+            return Err(corrupt("bad magic"));
+        }
+        if word(0) as usize >= blob.len() - FOOTER_SIZE {
+            return Err(corrupt("root past the end"));
+        }
+        Ok(Self {
+            blob: &blob[..blob.len() - FOOTER_SIZE],
+            root: word(0) as usize,
+            len: word(1) as usize,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn node(&self, offset: usize) -> Option<Node<'a>> {
+        let blob = self.blob;
+        let mut pos = offset;
+        let prefix_len = read_varint(blob, &mut pos)? as usize;
+        let prefix = blob.get(pos..pos.checked_add(prefix_len)?)?;
+        pos += prefix_len;
+        let value = match read_varint(blob, &mut pos)? as usize {
+            0 => None,
+            len => {
+                let value = blob.get(pos..pos.checked_add(len - 1)?)?;
+                pos += len - 1;
+                Some(value)
+            }
+        };
+        let count = read_varint(blob, &mut pos)? as usize;
+        let bytes = blob.get(pos..pos.checked_add(count)?)?;
+        let offsets = blob.get(pos + count..pos.checked_add(count.checked_mul(5)?)?)?;
+        Some(Node {
+            offset,
+            prefix,
+            value,
+            bytes,
+            offsets,
+        })
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&'a [u8]> {
+        let mut node = self.node(self.root)?;
+        let mut key = key;
+        loop {
+            key = key.strip_prefix(node.prefix)?;
+            let (byte, rest) = match key.split_first() {
+                Some(split) => split,
+                None => return node.value,
+            };
+            let i = node.bytes.binary_search(byte).ok()?;
+            node = self.node(node.child(i)?)?;
+            key = rest;
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Pairs in the key order
+    pub fn iter(&self) -> FrozenIter<'a> {
+        FrozenIter {
+            art: *self,
+            stack: vec![(self.root, 0, None)],
+            key: vec![],
+        }
+    }
+}
+
+pub struct FrozenIter<'a> {
+    art: FrozenArt<'a>,
+    // (offset, key length at the parent, first byte of the node)
+    stack: Vec<(usize, usize, Option<u8>)>,
+    key: Vec<u8>,
+}
+
+impl<'a> Iterator for FrozenIter<'a> {
+    type Item = (Vec<u8>, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((offset, len, byte)) = self.stack.pop() {
+            let node = self.art.node(offset)?;
+            self.key.truncate(len);
+            self.key.extend(byte);
+            self.key.extend_from_slice(node.prefix);
+            for i in (0..node.bytes.len()).rev() {
+                if let Some(child) = node.child(i) {
+                    self.stack
+                        .push((child, self.key.len(), Some(node.bytes[i])));
+                }
+            }
+            if let Some(value) = node.value {
+                return Some((self.key.clone(), value));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_frozen() {
+        let mut builder = FrozenBuilder::new();
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for _ in 0..3_000 {
+            let len = rng.gen_range(0, 6);
+            let key: Vec<u8> = (0..len).map(|_| rng.gen_range(b'a', b'd')).collect();
+            let value = rng.gen::<u32>().to_string();
+            builder.insert(&key, &value);
+            data.insert(key, value.into_bytes());
+        }
+        let blob: &'static [u8] = Box::leak(builder.build().into_boxed_slice());
+        let art = FrozenArt::from_static(blob).unwrap();
+        assert_eq!(data.len(), art.len());
+        for (key, value) in data.iter() {
+            assert_eq!(Some(&value[..]), art.get(key));
+        }
+        for _ in 0..1_000 {
+            let len = rng.gen_range(0, 8);
+            let key: Vec<u8> = (0..len).map(|_| rng.gen_range(b'a', b'f')).collect();
+            assert_eq!(data.get(&key).map(|v| &v[..]), art.get(&key));
+        }
+        let pairs: Vec<_> = art.iter().map(|(k, v)| (k, v.to_vec())).collect();
+        assert_eq!(data.into_iter().collect::<Vec<_>>(), pairs);
+
+        let empty = FrozenBuilder::new().build();
+        let art = FrozenArt::new(&empty).unwrap();
+        assert!(art.is_empty() && art.get(b"").is_none() && art.iter().next().is_none());
+        assert!(matches!(
+            FrozenArt::new(&empty[..empty.len() - 1]),
+            Err(Error::Corrupt(_))
+        ));
+        assert!(FrozenArt::new(&blob[..blob.len() - 1]).is_err());
+        for _ in 0..200 {
+            let mut damaged = blob.to_vec();
+            let i = rng.gen_range(0, damaged.len());
+            damaged[i] = rng.gen();
+            if let Ok(art) = FrozenArt::new(&damaged) {
+                art.get(b"abc");
+                art.iter().count();
+            }
+        }
+    }
+
+    #[test]
+    fn test_to_rust() {
+        let mut builder = FrozenBuilder::new();
+        builder.insert("json", "application/json");
+        builder.insert("js", "text/javascript");
+        let source = builder.to_rust("MIME_TYPES");
+        assert!(source.starts_with("pub static MIME_TYPES: &[u8] = b\""));
+        assert!(source.ends_with("\";\n"));
+        // What `include!` would give back
+        let literal = &source[source.find('"').unwrap() + 1..source.len() - 3];
+        let mut blob = vec![];
+        let mut chars = literal.bytes();
+        while let Some(c) = chars.next() {
+            blob.push(match (c, c == b'\\') {
+                (_, false) => c,
+                _ => match chars.next().unwrap() {
+                    b'x' => {
+                        let hex = [chars.next().unwrap(), chars.next().unwrap()];
+                        u8::from_str_radix(std::str::from_utf8(&hex).unwrap(), 16).unwrap()
+                    }
+                    b'n' => b'\n',
+                    b't' => b'\t',
+                    b'r' => b'\r',
+                    escaped => escaped,
+                },
+            });
+        }
+        assert_eq!(builder.build(), blob);
+        let art = FrozenArt::new(&blob).unwrap();
+        assert_eq!(Some(&b"text/javascript"[..]), art.get(b"js"));
+        assert_eq!(None, art.get(b"j"));
+    }
+}
@@ -0,0 +1,384 @@
+use crate::datrie::OutOfOrder;
+use std::collections::HashMap;
+use std::ops::Bound;
+
+// Minimal acyclic finite state transducer built from sorted keys.
+//
+// Keys are added in the increasing order, so once a key is added the states
+// past its common prefix with the previous key are final and can be frozen.
+// Every frozen state is looked up in a registry of equal states first, which
+// merges common suffixes and keeps the automaton minimal. Values are spread
+// over the transitions as outputs, the value of a key is the sum of the
+// outputs along its path plus the final output of its last state
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct State {
+    is_final: bool,
+    final_output: u64,
+    // (byte, output, target) sorted by the byte
+    transitions: Vec<(u8, u64, usize)>,
+}
+
+// State that still can get transitions, its last one has no target yet
+#[derive(Debug, Default)]
+struct Unfinished {
+    is_final: bool,
+    final_output: u64,
+    transitions: Vec<(u8, u64, usize)>,
+}
+
+impl Unfinished {
+    // Move part of the incoming output down to all ways out of the state
+    fn prepend_output(&mut self, output: u64) {
+        if self.is_final {
+            self.final_output += output;
+        }
+        for transition in self.transitions.iter_mut() {
+            transition.1 += output;
+        }
+    }
+}
+
+pub struct FstBuilder {
+    states: Vec<State>,
+    registry: HashMap<State, usize>,
+    // States along the last key, the first one is the root
+    unfinished: Vec<Unfinished>,
+    last: Option<Vec<u8>>,
+    len: usize,
+}
+
+impl Default for FstBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FstBuilder {
+    pub fn new() -> Self {
+        Self {
+            states: vec![],
+            registry: HashMap::new(),
+            unfinished: vec![Unfinished::default()],
+            last: None,
+            len: 0,
+        }
+    }
+
+    // Keys have to be added in the increasing order
+    pub fn insert(&mut self, key: &[u8], mut value: u64) -> Result<(), OutOfOrder> {
+        let prefix_len = match &self.last {
+            Some(last) if last.as_slice() >= key => return Err(OutOfOrder { index: self.len }),
+            Some(last) => last.iter().zip(key).take_while(|(a, b)| a == b).count(),
+            None => 0,
+        };
+        self.freeze(prefix_len);
+        // Keep the smaller output on the shared transitions
+        for depth in 0..prefix_len {
+            let transition = self.unfinished[depth].transitions.last_mut().unwrap();
+            let common = std::cmp::min(transition.1, value);
+            let rest = transition.1 - common;
+            transition.1 = common;
+            value -= common;
+            self.unfinished[depth + 1].prepend_output(rest);
+        }
+        if prefix_len == key.len() {
+            // Only the empty key can end at the root
+            let root = &mut self.unfinished[0];
+            root.is_final = true;
+            root.final_output = value;
+        } else {
+            for (i, &byte) in key[prefix_len..].iter().enumerate() {
+                let output = if i == 0 { value } else { 0 };
+                self.unfinished
+                    .last_mut()
+                    .unwrap()
+                    .transitions
+                    .push((byte, output, 0));
+                self.unfinished.push(Unfinished::default());
+            }
+            self.unfinished.last_mut().unwrap().is_final = true;
+        }
+        self.last = Some(key.to_vec());
+        self.len += 1;
+        Ok(())
+    }
+
+    // Freeze the unfinished states deeper than `depth`
+    fn freeze(&mut self, depth: usize) {
+        while self.unfinished.len() > depth + 1 {
+            let state = self.unfinished.pop().unwrap();
+            let id = self.register(State {
+                is_final: state.is_final,
+                final_output: state.final_output,
+                transitions: state.transitions,
+            });
+            self.unfinished
+                .last_mut()
+                .unwrap()
+                .transitions
+                .last_mut()
+                .unwrap()
+                .2 = id;
+        }
+    }
+
+    fn register(&mut self, state: State) -> usize {
+        if let Some(&id) = self.registry.get(&state) {
+            return id;
+        }
+        self.states.push(state.clone());
+        self.registry.insert(state, self.states.len() - 1);
+        self.states.len() - 1
+    }
+
+    pub fn finish(mut self) -> Fst {
+        self.freeze(0);
+        let root = self.unfinished.pop().unwrap();
+        let root = self.register(State {
+            is_final: root.is_final,
+            final_output: root.final_output,
+            transitions: root.transitions,
+        });
+        Fst {
+            states: self.states,
+            root,
+            len: self.len,
+        }
+    }
+}
+
+pub struct Fst {
+    states: Vec<State>,
+    root: usize,
+    len: usize,
+}
+
+struct Frame {
+    state: usize,
+    // Index of the next transition to follow
+    next: usize,
+    output: u64,
+    // Whether the key ending at the state was already yielded
+    visited: bool,
+}
+
+// Keys with their values in the key order
+pub struct Stream<'a> {
+    fst: &'a Fst,
+    stack: Vec<Frame>,
+    key: Vec<u8>,
+}
+
+impl<'a> Iterator for Stream<'a> {
+    type Item = (Vec<u8>, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let frame = self.stack.last_mut()?;
+            let state = &self.fst.states[frame.state];
+            if !frame.visited {
+                frame.visited = true;
+                if state.is_final {
+                    return Some((self.key.clone(), frame.output + state.final_output));
+                }
+            }
+            if let Some(&(byte, output, target)) = state.transitions.get(frame.next) {
+                frame.next += 1;
+                let output = frame.output + output;
+                self.key.push(byte);
+                self.stack.push(Frame {
+                    state: target,
+                    next: 0,
+                    output,
+                    visited: false,
+                });
+            } else {
+                self.stack.pop();
+                self.key.pop();
+            }
+        }
+    }
+}
+
+impl Fst {
+    // Set of keys, all values are zero
+    pub fn from_keys<I, K>(keys: I) -> Result<Self, OutOfOrder>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        let mut builder = FstBuilder::new();
+        for key in keys {
+            builder.insert(key.as_ref(), 0)?;
+        }
+        Ok(builder.finish())
+    }
+
+    // Number of keys
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Number of states, equal suffixes of keys share them
+    pub fn state_count(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<u64> {
+        let mut state = &self.states[self.root];
+        let mut output = 0;
+        for byte in key {
+            let i = state
+                .transitions
+                .binary_search_by_key(byte, |transition| transition.0)
+                .ok()?;
+            let (_, out, target) = state.transitions[i];
+            output += out;
+            state = &self.states[target];
+        }
+        if state.is_final {
+            Some(output + state.final_output)
+        } else {
+            None
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn iter(&self) -> Stream<'_> {
+        self.seek(Bound::Unbounded)
+    }
+
+    // Stream starting at the first key within the lower bound
+    fn seek(&self, start: Bound<&[u8]>) -> Stream<'_> {
+        let (bound, included) = match start {
+            Bound::Included(key) => (key, true),
+            Bound::Excluded(key) => (key, false),
+            Bound::Unbounded => (&[][..], true),
+        };
+        let mut stream = Stream {
+            fst: self,
+            stack: vec![],
+            key: vec![],
+        };
+        let mut state = self.root;
+        let mut output = 0;
+        for &byte in bound {
+            let transitions = &self.states[state].transitions;
+            // Keys shorter than the bound are smaller, skip them
+            let next = transitions.partition_point(|transition| transition.0 < byte);
+            let found = transitions
+                .get(next)
+                .filter(|transition| transition.0 == byte);
+            stream.stack.push(Frame {
+                state,
+                next: next + found.is_some() as usize,
+                output,
+                visited: true,
+            });
+            match found {
+                Some(&(_, out, target)) => {
+                    stream.key.push(byte);
+                    state = target;
+                    output += out;
+                }
+                None => return stream,
+            }
+        }
+        stream.stack.push(Frame {
+            state,
+            next: 0,
+            output,
+            visited: !included,
+        });
+        stream
+    }
+
+    // Keys within the bounds with their values in the key order
+    pub fn range<'a>(
+        &'a self,
+        start: Bound<&[u8]>,
+        end: Bound<&'a [u8]>,
+    ) -> impl Iterator<Item = (Vec<u8>, u64)> + 'a {
+        self.seek(start).take_while(move |(key, _)| match end {
+            Bound::Included(end) => key.as_slice() <= end,
+            Bound::Excluded(end) => key.as_slice() < end,
+            Bound::Unbounded => true,
+        })
+    }
+
+    // Keys starting with the prefix with their values in the key order
+    pub fn prefix<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = (Vec<u8>, u64)> + 'a {
+        self.seek(Bound::Included(prefix))
+            .take_while(move |(key, _)| key.starts_with(prefix))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_build_and_query() {
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for _i in 0..10_000 {
+            let len = rng.gen_range(0, 8);
+            let key: Vec<u8> = (0..len).map(|_| rng.gen_range(b'a', b'e')).collect();
+            data.insert(key, rng.gen_range(0, 1000));
+        }
+        let mut builder = FstBuilder::new();
+        for (key, value) in data.iter() {
+            builder.insert(key, *value).unwrap();
+        }
+        let fst = builder.finish();
+        assert_eq!(data.len(), fst.len());
+        for _i in 0..10_000 {
+            let len = rng.gen_range(0, 9);
+            let key: Vec<u8> = (0..len).map(|_| rng.gen_range(b'a', b'f')).collect();
+            assert_eq!(data.get(&key).copied(), fst.get(&key));
+        }
+        assert!(fst
+            .iter()
+            .eq(data.iter().map(|(key, value)| (key.clone(), *value))));
+
+        let found: Vec<_> = fst.prefix(b"ab").collect();
+        let expected: Vec<_> = data
+            .iter()
+            .filter(|(key, _)| key.starts_with(b"ab"))
+            .map(|(key, value)| (key.clone(), *value))
+            .collect();
+        assert_eq!(expected, found);
+
+        let found: Vec<_> = fst
+            .range(Bound::Excluded(&b"abc"[..]), Bound::Included(&b"cab"[..]))
+            .collect();
+        let expected: Vec<_> = data
+            .range::<[u8], _>((Bound::Excluded(&b"abc"[..]), Bound::Included(&b"cab"[..])))
+            .map(|(key, value)| (key.clone(), *value))
+            .collect();
+        assert_eq!(expected, found);
+    }
+
+    #[test]
+    fn test_minimal() {
+        // The states after "a" and "c" are the same
+        let fst = Fst::from_keys(["ab", "cb"]).unwrap();
+        assert_eq!(3, fst.state_count());
+        assert!(fst.contains(b"cb"));
+        assert!(!fst.contains(b"c"));
+        assert_eq!(
+            Err(OutOfOrder { index: 1 }),
+            Fst::from_keys(["b", "a"]).map(|fst| fst.len())
+        );
+    }
+}
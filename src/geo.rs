@@ -0,0 +1,214 @@
+use crate::art::Art;
+use crate::keys::Raw;
+use std::convert::TryInto;
+
+const BASE32: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+// Characters of the geohash in the keys, about 4cm by 2cm cells
+pub const PRECISION: usize = 12;
+
+// Most cells a box gets split into, a bigger box gets coarser cells and
+// more points to filter out
+const MAX_CELLS: u64 = 16;
+
+// Cell of (lat, lon) at `bits` bits of geohash, as the longitude and
+// latitude halves of the interleaved bits
+fn cell(lat: f64, lon: f64, bits: usize) -> (u64, u64) {
+    let (lon_bits, lat_bits) = (bits - bits / 2, bits / 2);
+    let scale = |x: f64, min: f64, span: f64, bits: usize| {
+        let n = (1u64 << bits) as f64;
+        (((x - min) / span * n).max(0.0) as u64).min((1u64 << bits) - 1)
+    };
+    (
+        scale(lon, -180.0, 360.0, lon_bits),
+        scale(lat, -90.0, 180.0, lat_bits),
+    )
+}
+
+// Geohash of the cell, the bits alternate starting with the longitude
+fn hash(lon_cell: u64, lat_cell: u64, bits: usize) -> String {
+    let (mut lon_bits, mut lat_bits) = (bits - bits / 2, bits / 2);
+    let mut code = 0u64;
+    for i in 0..bits {
+        let bit = if i % 2 == 0 {
+            lon_bits -= 1;
+            (lon_cell >> lon_bits) & 1
+        } else {
+            lat_bits -= 1;
+            (lat_cell >> lat_bits) & 1
+        };
+        code = code << 1 | bit;
+    }
+    (0..bits / 5)
+        .rev()
+        .map(|i| BASE32[(code >> (5 * i)) as usize & 31] as char)
+        .collect()
+}
+
+// Geohash of the point with `precision` characters, up to 12
+pub fn encode(lat: f64, lon: f64, precision: usize) -> String {
+    assert!(precision <= PRECISION);
+    let (lon_cell, lat_cell) = cell(lat, lon, 5 * precision);
+    hash(lon_cell, lat_cell, 5 * precision)
+}
+
+// Center of the cell of a geohash, None if it has a character outside of
+// the alphabet
+pub fn decode(hash: &str) -> Option<(f64, f64)> {
+    let (mut lat, mut lon) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut even = true;
+    for c in hash.bytes() {
+        let code = BASE32.iter().position(|&b| b == c)?;
+        for i in (0..5).rev() {
+            let range: &mut (f64, f64) = if even { &mut lon } else { &mut lat };
+            let mid = (range.0 + range.1) / 2.0;
+            if code >> i & 1 == 1 {
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            even = !even;
+        }
+    }
+    Some(((lat.0 + lat.1) / 2.0, (lon.0 + lon.1) / 2.0))
+}
+
+// Points with values, keyed by their geohash followed by the exact
+// coordinates, so nearby points share prefixes and two points only collide
+// if they are the same point
+pub struct GeoIndex<T: 'static + std::fmt::Debug> {
+    art: Art<String, T>,
+}
+
+impl<T: 'static + Clone + std::fmt::Debug> Default for GeoIndex<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: 'static + Clone + std::fmt::Debug> GeoIndex<T> {
+    pub fn new() -> Self {
+        Self { art: Art::new() }
+    }
+
+    fn key(lat: f64, lon: f64) -> Vec<u8> {
+        let mut key = encode(lat, lon, PRECISION).into_bytes();
+        key.extend_from_slice(&lat.to_bits().to_be_bytes());
+        key.extend_from_slice(&lon.to_bits().to_be_bytes());
+        key
+    }
+
+    fn point(key: &[u8]) -> (f64, f64) {
+        let bits = |i: usize| f64::from_bits(u64::from_be_bytes(key[i..i + 8].try_into().unwrap()));
+        (bits(PRECISION), bits(PRECISION + 8))
+    }
+
+    // Replaces the value of the same point
+    pub fn insert(&mut self, lat: f64, lon: f64, value: T) {
+        self.art.insert_with(&Raw, &Self::key(lat, lon)[..], value);
+    }
+
+    pub fn get(&self, lat: f64, lon: f64) -> Option<&T> {
+        self.art.find_with(&Raw, &Self::key(lat, lon)[..])
+    }
+
+    pub fn remove(&mut self, lat: f64, lon: f64) {
+        self.art.delete_with(&Raw, &Self::key(lat, lon)[..]);
+    }
+
+    // Geohash prefixes of the cells covering the box, as fine as they can
+    // be without going over MAX_CELLS
+    fn cover(min: (f64, f64), max: (f64, f64)) -> Vec<String> {
+        let mut bits = 5 * PRECISION;
+        loop {
+            let (lon0, lat0) = cell(min.0, min.1, bits);
+            let (lon1, lat1) = cell(max.0, max.1, bits);
+            if bits == 0 || (lon1 - lon0 + 1) * (lat1 - lat0 + 1) <= MAX_CELLS {
+                let mut cells = Vec::new();
+                for lon in lon0..=lon1 {
+                    for lat in lat0..=lat1 {
+                        cells.push(hash(lon, lat, bits));
+                    }
+                }
+                cells.sort();
+                return cells;
+            }
+            bits -= 5;
+        }
+    }
+
+    // Points with `min.0 <= lat <= max.0` and `min.1 <= lon <= max.1` as
+    // (lat, lon, value), in geohash order. The box doesn't wrap around the
+    // antimeridian, split it in two for that
+    pub fn within(&self, min: (f64, f64), max: (f64, f64)) -> Vec<(f64, f64, &T)> {
+        if min.0 > max.0 || min.1 > max.1 {
+            return Vec::new();
+        }
+        let mut points = Vec::new();
+        for prefix in Self::cover(min, max) {
+            for (key, value) in self.art.prefix_iter(prefix.as_bytes()) {
+                let (lat, lon) = Self::point(key);
+                if min.0 <= lat && lat <= max.0 && min.1 <= lon && lon <= max.1 {
+                    points.push((lat, lon, value));
+                }
+            }
+        }
+        points
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_geohash() {
+        assert_eq!("ezs42", encode(42.6, -5.6, 5));
+        assert_eq!("u4pruydqqvj", encode(57.64911, 10.40744, 11));
+        assert_eq!("", encode(57.64911, 10.40744, 0));
+        let (lat, lon) = decode("u4pruydqqvj").unwrap();
+        assert!((lat - 57.64911).abs() < 1e-5 && (lon - 10.40744).abs() < 1e-5);
+        assert_eq!(None, decode("u4a"));
+        assert_eq!("zzzzzzzzzzzz", encode(90.0, 180.0, 12));
+        assert_eq!("000000000000", encode(-90.0, -180.0, 12));
+    }
+
+    #[test]
+    fn test_within() {
+        let mut index = GeoIndex::new();
+        let mut points = Vec::new();
+        let mut rng = rand::thread_rng();
+        for i in 0..3_000 {
+            // Half of them clustered around Paris
+            let (lat, lon) = if i % 2 == 0 {
+                (rng.gen_range(48.0, 49.5), rng.gen_range(1.5, 3.0))
+            } else {
+                (rng.gen_range(-90.0, 90.0), rng.gen_range(-180.0, 180.0))
+            };
+            index.insert(lat, lon, i);
+            points.push((lat, lon, i));
+        }
+        for size in [0.01, 0.3, 5.0, 100.0, 400.0].iter() {
+            for _ in 0..20 {
+                let lat = rng.gen_range(47.5, 50.0);
+                let lon = rng.gen_range(1.0, 3.5);
+                let (min, max) = ((lat - size, lon - size), (lat + size, lon + size));
+                let mut expected: Vec<_> = points
+                    .iter()
+                    .filter(|p| min.0 <= p.0 && p.0 <= max.0 && min.1 <= p.1 && p.1 <= max.1)
+                    .map(|p| p.2)
+                    .collect();
+                let mut found: Vec<_> = index.within(min, max).into_iter().map(|p| *p.2).collect();
+                expected.sort();
+                found.sort();
+                assert_eq!(expected, found);
+            }
+        }
+        let (lat, lon, value) = points[7];
+        assert_eq!(Some(&value), index.get(lat, lon));
+        index.remove(lat, lon);
+        assert_eq!(None, index.get(lat, lon));
+        assert!(index.within((1.0, 1.0), (0.0, 0.0)).is_empty());
+    }
+}
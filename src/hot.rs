@@ -0,0 +1,574 @@
+use crate::art::ArtKey;
+use crate::error::Error;
+use core::marker::PhantomData;
+
+// Height Optimized Trie. It is a binary patricia trie cut into compound
+// nodes of at most `FANOUT` entries, every node discriminates its entries
+// only by a few bit positions of the key. When a node overflows its root
+// bit is pulled up into the parent, so nodes stay wide and the trie low.
+// Keys are compared as if padded with zero bytes, so unlike `Art` a key
+// may be a prefix of another one unless they differ only by zero bytes
+
+// Max number of entries in a compound node
+const FANOUT: usize = 32;
+
+#[derive(Debug)]
+struct Leaf<T> {
+    key: Vec<u8>,
+    value: T,
+}
+
+#[derive(Debug)]
+enum Child<T> {
+    Leaf(Box<Leaf<T>>),
+    Node(Box<HotNode<T>>),
+}
+
+// Entry of a compound node. Bit `i` of the masks stands for `bits[i]`
+// of the node, `path` marks the bits on the way to the entry in the
+// binary trie of the node and `partial` the ones where it goes right
+#[derive(Debug)]
+struct Entry<T> {
+    path: u64,
+    partial: u64,
+    child: Child<T>,
+}
+
+#[derive(Debug)]
+struct HotNode<T> {
+    // Discriminative bit positions in the ascending order
+    bits: Vec<usize>,
+    // Entries in the key order
+    entries: Vec<Entry<T>>,
+}
+
+// Bit of the key at the position, bytes past the end are zeros
+fn bit(key: &[u8], pos: usize) -> bool {
+    key.get(pos / 8)
+        .is_some_and(|byte| byte & (0x80 >> (pos % 8)) != 0)
+}
+
+// Position of the first bit two keys differ in
+fn mismatch(a: &[u8], b: &[u8]) -> Option<usize> {
+    let len = std::cmp::max(a.len(), b.len());
+    (0..len).find_map(|i| {
+        let x = a.get(i).copied().unwrap_or(0) ^ b.get(i).copied().unwrap_or(0);
+        if x != 0 {
+            Some(i * 8 + x.leading_zeros() as usize)
+        } else {
+            None
+        }
+    })
+}
+
+// Mask of the node bits below index `i`
+fn below(i: usize) -> u64 {
+    (1 << i) - 1
+}
+
+// Insert a zero bit at index `i` of the mask
+fn widen(mask: u64, i: usize) -> u64 {
+    (mask & below(i)) | ((mask & !below(i)) << 1)
+}
+
+// Remove the bit at index `i` of the mask
+fn narrow(mask: u64, i: usize) -> u64 {
+    (mask & below(i)) | ((mask >> 1) & !below(i))
+}
+
+impl<T> HotNode<T> {
+    // Bits of the key at the discriminative positions of the node
+    fn dense(&self, key: &[u8]) -> u64 {
+        self.bits
+            .iter()
+            .enumerate()
+            .filter(|&(_, &pos)| bit(key, pos))
+            .fold(0, |dense, (i, _)| dense | 1 << i)
+    }
+
+    // Index of the entry the key belongs to
+    fn search(&self, key: &[u8]) -> usize {
+        let dense = self.dense(key);
+        self.entries
+            .iter()
+            .position(|entry| dense & entry.path == entry.partial)
+            .unwrap()
+    }
+
+    // Index of the bit position, added to the node if it isn't there yet
+    fn add_bit(&mut self, pos: usize) -> usize {
+        match self.bits.binary_search(&pos) {
+            Ok(i) => i,
+            Err(i) => {
+                self.bits.insert(i, pos);
+                for entry in self.entries.iter_mut() {
+                    entry.path = widen(entry.path, i);
+                    entry.partial = widen(entry.partial, i);
+                }
+                i
+            }
+        }
+    }
+
+    // Drop bit positions no entry goes through anymore
+    fn remove_unused_bits(&mut self) {
+        let mut i = 0;
+        while i < self.bits.len() {
+            if self.entries.iter().any(|entry| entry.path & 1 << i != 0) {
+                i += 1;
+                continue;
+            }
+            self.bits.remove(i);
+            for entry in self.entries.iter_mut() {
+                entry.path = narrow(entry.path, i);
+                entry.partial = narrow(entry.partial, i);
+            }
+        }
+    }
+
+    // Branch at the bit position `pos` off the subtree of the binary trie
+    // holding the entry `at`. `right` tells on which side the new child goes
+    fn branch(&mut self, at: usize, pos: usize, right: bool, child: Child<T>) {
+        let i = self.add_bit(pos);
+        let path = self.entries[at].path & below(i);
+        let partial = self.entries[at].partial & below(i);
+        // Entries of the subtree agree with `at` on the bits above `pos`
+        let mut first = None;
+        let mut last = 0;
+        for (j, entry) in self.entries.iter_mut().enumerate() {
+            if entry.path & path == path && entry.partial & path == partial {
+                entry.path |= 1 << i;
+                if !right {
+                    entry.partial |= 1 << i;
+                }
+                first.get_or_insert(j);
+                last = j;
+            }
+        }
+        let entry = Entry {
+            path: path | 1 << i,
+            partial: if right { partial | 1 << i } else { partial },
+            child,
+        };
+        let index = if right { last + 1 } else { first.unwrap() };
+        self.entries.insert(index, entry);
+    }
+
+    // Split the node by its root bit into two children
+    fn split(mut self) -> (usize, Child<T>, Child<T>) {
+        let pos = self.bits[0];
+        let mid = self
+            .entries
+            .iter()
+            .position(|entry| entry.partial & 1 != 0)
+            .unwrap();
+        let right = self.entries.split_off(mid);
+        let bits = self.bits;
+        let half = |entries: Vec<Entry<T>>| {
+            let mut node = HotNode {
+                bits: bits.clone(),
+                entries,
+            };
+            for entry in node.entries.iter_mut() {
+                entry.path &= !1;
+                entry.partial &= !1;
+            }
+            node.remove_unused_bits();
+            node.into_child()
+        };
+        let right = half(right);
+        let left = half(self.entries);
+        (pos, left, right)
+    }
+
+    // A node with a single entry is replaced by the entry's child
+    fn into_child(mut self) -> Child<T> {
+        if self.entries.len() == 1 {
+            self.entries.pop().unwrap().child
+        } else {
+            Child::Node(Box::new(self))
+        }
+    }
+}
+
+impl<T> Child<T> {
+    // Key of some leaf below, all leaves share bits up to the first
+    // discriminative one
+    fn leaf_key(&self) -> &[u8] {
+        match self {
+            Child::Leaf(leaf) => &leaf.key,
+            Child::Node(node) => node.entries[0].child.leaf_key(),
+        }
+    }
+}
+
+pub struct Iter<'a, T> {
+    stack: Vec<&'a Child<T>>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (&'a [u8], &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(child) = self.stack.pop() {
+            match child {
+                Child::Leaf(leaf) => return Some((&leaf.key, &leaf.value)),
+                Child::Node(node) => self
+                    .stack
+                    .extend(node.entries.iter().rev().map(|entry| &entry.child)),
+            }
+        }
+        None
+    }
+}
+
+pub struct Hot<K, T> {
+    root: Option<Child<T>>,
+    key: PhantomData<K>,
+}
+
+impl<K: ArtKey, T> Default for Hot<K, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: ArtKey, T> Hot<K, T> {
+    pub fn new() -> Self {
+        Self {
+            root: None,
+            key: PhantomData,
+        }
+    }
+
+    pub fn find(&self, key: K) -> Option<&T> {
+        let key_bytes = key.bytes();
+        trace_span!("hot.find", key_len = key_bytes.len());
+        let mut child = self.root.as_ref()?;
+        loop {
+            match child {
+                Child::Node(node) => child = &node.entries[node.search(&key_bytes)].child,
+                Child::Leaf(leaf) if leaf.key == key_bytes => return Some(&leaf.value),
+                Child::Leaf(_) => return None,
+            }
+        }
+    }
+
+    // Keys with their values in the key order
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            stack: self.root.iter().collect(),
+        }
+    }
+
+    // Pairs with keys starting with `prefix` in the key order
+    pub fn prefix_iter<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = (&'a [u8], &'a T)> {
+        // Descend while the discriminative bits are inside the prefix,
+        // below that all keys share the prefix bits
+        let mut child = self.root.as_ref();
+        while let Some(Child::Node(node)) = child {
+            let entry = &node.entries[node.search(prefix)];
+            let inside = node
+                .bits
+                .iter()
+                .enumerate()
+                .all(|(i, &pos)| entry.path & 1 << i == 0 || pos < prefix.len() * 8);
+            if !inside {
+                break;
+            }
+            child = Some(&entry.child);
+        }
+        let iter = Iter {
+            stack: child.into_iter().collect(),
+        };
+        iter.filter(move |(key, _)| key.starts_with(prefix))
+    }
+
+    // Panics on a key differing from one in the trie only by trailing zero
+    // bytes, see `try_insert`
+    pub fn insert(&mut self, key: K, value: T) {
+        if let Err(err) = self.try_insert(key, value) {
+            panic!("{}", err);
+        }
+    }
+
+    // Insert unless the key differs from one in the trie only by trailing
+    // zero bytes, e.g. "a\0" with "a" there, which gives
+    // `Error::KeyIsPrefix` with the shorter key and leaves the trie as it
+    // was
+    pub fn try_insert(&mut self, key: K, value: T) -> Result<(), Error> {
+        let key_bytes = key.bytes();
+        trace_span!("hot.insert", key_len = key_bytes.len());
+        let leaf_key = match self.root.as_ref() {
+            Some(root) => Self::find_leaf(root, &key_bytes),
+            None => {
+                self.root = Some(Child::Leaf(Box::new(Leaf {
+                    key: key_bytes,
+                    value,
+                })));
+                return Ok(());
+            }
+        };
+        let pos = match mismatch(leaf_key, &key_bytes) {
+            Some(pos) => pos,
+            None if leaf_key == &key_bytes[..] => {
+                self.overwrite(&key_bytes, value);
+                return Ok(());
+            }
+            None => {
+                let key = std::cmp::min_by_key(leaf_key, &key_bytes[..], |key| key.len());
+                return Err(Error::KeyIsPrefix { key: key.to_vec() });
+            }
+        };
+        let right = bit(&key_bytes, pos);
+        let leaf = Child::Leaf(Box::new(Leaf {
+            key: key_bytes,
+            value,
+        }));
+        let root = self.root.take().unwrap();
+        self.root = Some(match Self::insert_at(root, pos, right, leaf) {
+            Ok(root) => root,
+            // The root overflowed, the halves get a new root above them
+            Err((pos, left, right)) => Self::pair(pos, left, right),
+        });
+        Ok(())
+    }
+
+    fn find_leaf<'a>(mut child: &'a Child<T>, key: &[u8]) -> &'a [u8] {
+        loop {
+            match child {
+                Child::Node(node) => child = &node.entries[node.search(key)].child,
+                Child::Leaf(leaf) => return &leaf.key,
+            }
+        }
+    }
+
+    fn overwrite(&mut self, key: &[u8], value: T) {
+        let mut child = self.root.as_mut().unwrap();
+        loop {
+            match child {
+                Child::Node(node) => {
+                    let i = node.search(key);
+                    child = &mut node.entries[i].child;
+                }
+                Child::Leaf(leaf) => {
+                    leaf.value = value;
+                    return;
+                }
+            }
+        }
+    }
+
+    // Node with two children split at the bit position
+    fn pair(pos: usize, left: Child<T>, right: Child<T>) -> Child<T> {
+        Child::Node(Box::new(HotNode {
+            bits: vec![pos],
+            entries: vec![
+                Entry {
+                    path: 1,
+                    partial: 0,
+                    child: left,
+                },
+                Entry {
+                    path: 1,
+                    partial: 1,
+                    child: right,
+                },
+            ],
+        }))
+    }
+
+    // Insert a leaf branching at the bit position below `child`. Returns the
+    // halves of `child` if it overflowed and has to be split by the caller
+    fn insert_at(
+        child: Child<T>,
+        pos: usize,
+        right: bool,
+        leaf: Child<T>,
+    ) -> Result<Child<T>, (usize, Child<T>, Child<T>)> {
+        let mut node = match child {
+            // Branch right above the leaf
+            Child::Leaf(_) => {
+                return Ok(if right {
+                    Self::pair(pos, child, leaf)
+                } else {
+                    Self::pair(pos, leaf, child)
+                })
+            }
+            Child::Node(node) => node,
+        };
+        let at = node.search(leaf.leaf_key());
+        let entry = &node.entries[at];
+        // The new branch is inside the node if the path to the entry
+        // goes through a later bit, or if it ends with a leaf
+        let inside = node
+            .bits
+            .iter()
+            .enumerate()
+            .any(|(i, &bit)| entry.path & 1 << i != 0 && bit > pos);
+        if inside || matches!(entry.child, Child::Leaf(_)) {
+            node.branch(at, pos, right, leaf);
+        } else {
+            // Placeholder keeps the entry while its child is moved out
+            let child = std::mem::replace(
+                &mut node.entries[at].child,
+                Child::Node(Box::new(HotNode {
+                    bits: vec![],
+                    entries: vec![],
+                })),
+            );
+            match Self::insert_at(child, pos, right, leaf) {
+                Ok(child) => node.entries[at].child = child,
+                // Pull the root bit of the overflowed child into this node
+                Err((split, left, right)) => {
+                    node.entries[at].child = left;
+                    node.branch(at, split, true, right);
+                }
+            }
+        }
+        if node.entries.len() > FANOUT {
+            return Err(node.split());
+        }
+        Ok(Child::Node(node))
+    }
+
+    pub fn delete(&mut self, key: K) {
+        let key_bytes = key.bytes();
+        trace_span!("hot.delete", key_len = key_bytes.len());
+        if let Some(Child::Leaf(leaf)) = &self.root {
+            if leaf.key == key_bytes {
+                self.root = None;
+            }
+            return;
+        }
+        if let Some(root) = self.root.take() {
+            self.root = Some(Self::delete_at(root, &key_bytes));
+        }
+    }
+
+    fn delete_at(child: Child<T>, key: &[u8]) -> Child<T> {
+        let mut node = match child {
+            Child::Node(node) => node,
+            leaf => return leaf,
+        };
+        let at = node.search(key);
+        match &node.entries[at].child {
+            Child::Leaf(leaf) if leaf.key != key => return Child::Node(node),
+            Child::Leaf(_) => {
+                let entry = node.entries.remove(at);
+                // The deepest branch of the leaf goes away with it, the
+                // subtree on the other side takes its place
+                let last = 63 - entry.path.leading_zeros() as usize;
+                let path = entry.path & below(last);
+                let partial = entry.partial & below(last);
+                for other in node.entries.iter_mut() {
+                    if other.path & (path | 1 << last) == path | 1 << last
+                        && other.partial & path == partial
+                    {
+                        other.path &= !(1 << last);
+                        other.partial &= !(1 << last);
+                    }
+                }
+                node.remove_unused_bits();
+            }
+            Child::Node(_) => {
+                let child = std::mem::replace(
+                    &mut node.entries[at].child,
+                    Child::Node(Box::new(HotNode {
+                        bits: vec![],
+                        entries: vec![],
+                    })),
+                );
+                node.entries[at].child = Self::delete_at(child, key);
+                return Child::Node(node);
+            }
+        }
+        node.into_child()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_add_find_and_delete() {
+        let mut hot = Hot::<u32, u32>::new();
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+
+        for _i in 0..100_000 {
+            // Small keys to get overwrites and dense subtrees
+            let key = rng.gen::<u32>() >> rng.gen_range(0, 24);
+            let value = rng.gen::<u32>();
+            hot.insert(key, value);
+            data.insert(key, value);
+        }
+        for (key, val) in &data {
+            assert_eq!(Some(val), hot.find(*key));
+        }
+        assert!(hot
+            .iter()
+            .map(|(key, val)| (u32::from_bytes(key).unwrap(), *val))
+            .eq(data.iter().map(|(key, val)| (*key, *val))));
+
+        let keys: Vec<_> = data.keys().copied().collect();
+        for key in keys.iter().step_by(2) {
+            hot.delete(*key);
+            data.remove(key);
+        }
+        for key in keys.iter() {
+            assert_eq!(data.get(key), hot.find(*key));
+        }
+        for key in keys.iter() {
+            hot.delete(*key);
+        }
+        assert_eq!(0, hot.iter().count());
+    }
+
+    #[test]
+    fn test_trailing_zeros() {
+        let mut hot = Hot::<String, u32>::new();
+        hot.insert("a".to_string(), 1);
+        hot.insert("b".to_string(), 2);
+        assert!(matches!(
+            hot.try_insert("a\0".to_string(), 3),
+            Err(Error::KeyIsPrefix { key }) if key == b"a"
+        ));
+        // Nothing changed and the trie still works
+        assert_eq!(Some(&1), hot.find("a".to_string()));
+        assert_eq!(Some(&2), hot.find("b".to_string()));
+        assert_eq!(None, hot.find("a\0".to_string()));
+        hot.try_insert("a".to_string(), 4).unwrap();
+        assert_eq!(Some(&4), hot.find("a".to_string()));
+        let caught = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            hot.insert("b\0\0".to_string(), 5)
+        }));
+        assert!(caught.is_err());
+        assert_eq!(2, hot.iter().count());
+    }
+
+    #[test]
+    fn test_prefix_iter() {
+        let mut hot = Hot::<String, usize>::new();
+        let words = [
+            "apple\0",
+            "apply\0",
+            "ape\0",
+            "banana\0",
+            "band\0",
+            "bandana\0",
+        ];
+        for (i, word) in words.iter().enumerate() {
+            hot.insert(word.to_string(), i);
+        }
+        let found: Vec<_> = hot.prefix_iter(b"ap").map(|(_, i)| *i).collect();
+        assert_eq!(vec![2, 0, 1], found);
+        let found: Vec<_> = hot.prefix_iter(b"band").map(|(_, i)| *i).collect();
+        assert_eq!(vec![4, 5], found);
+        assert_eq!(0, hot.prefix_iter(b"c").count());
+        assert_eq!(6, hot.prefix_iter(b"").count());
+    }
+}
@@ -0,0 +1,120 @@
+use crate::art::Art;
+use crate::keys::{KeyCodec, Raw};
+
+// Secondary index from `SK` to the primary keys of the records that have
+// it, on top of an ART keyed by the secondary key followed by the primary
+// key, so one secondary key can point at many records. Both codecs must
+// write keys no key is a prefix of, e.g. `NullTerminated` or `BigEndian`,
+// otherwise the scan of one secondary key would see the next ones. Tuples
+// of such codecs give indexes on several fields
+#[derive(Clone)]
+pub struct SecondaryIndex<PK: 'static + std::fmt::Debug, SK, P, S> {
+    art: Art<String, PK>,
+    pk_codec: P,
+    sk_codec: S,
+    len: usize,
+    sk: std::marker::PhantomData<SK>,
+}
+
+impl<PK, SK, P, S> SecondaryIndex<PK, SK, P, S>
+where
+    PK: 'static + Clone + std::fmt::Debug,
+    P: KeyCodec<PK>,
+    S: KeyCodec<SK>,
+{
+    pub fn new(pk_codec: P, sk_codec: S) -> Self {
+        Self {
+            art: Art::new(),
+            pk_codec,
+            sk_codec,
+            len: 0,
+            sk: std::marker::PhantomData,
+        }
+    }
+
+    // Number of (secondary key, primary key) pairs
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn key(&self, sk: &SK, pk: &PK) -> Vec<u8> {
+        let mut key = self.sk_codec.to_bytes(sk);
+        self.pk_codec.encode(pk, &mut key);
+        key
+    }
+
+    // Returns false if the pair was already there
+    pub fn insert(&mut self, sk: &SK, pk: PK) -> bool {
+        let key = self.key(sk, &pk);
+        if self.art.find_with(&Raw, &key[..]).is_some() {
+            return false;
+        }
+        self.art.insert_with(&Raw, &key[..], pk);
+        self.len += 1;
+        true
+    }
+
+    // Returns false if the pair wasn't there
+    pub fn remove(&mut self, sk: &SK, pk: &PK) -> bool {
+        let key = self.key(sk, pk);
+        if self.art.find_with(&Raw, &key[..]).is_none() {
+            return false;
+        }
+        self.art.delete_with(&Raw, &key[..]);
+        self.len -= 1;
+        true
+    }
+
+    pub fn contains(&self, sk: &SK, pk: &PK) -> bool {
+        self.art.find_with(&Raw, &self.key(sk, pk)[..]).is_some()
+    }
+
+    // Primary keys under the secondary key, in the order of their encoding
+    pub fn scan(&self, sk: &SK) -> Vec<&PK> {
+        let prefix = self.sk_codec.to_bytes(sk);
+        self.art.prefix_iter(&prefix).map(|(_, pk)| pk).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::keys::{BigEndian, NullTerminated};
+    use rand::Rng;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    #[test]
+    fn test_secondary_index() {
+        // Users by (city, age), checked against a map of sets
+        let mut index = SecondaryIndex::new(BigEndian, (NullTerminated(Raw), BigEndian));
+        let mut data: BTreeMap<(String, u8), BTreeSet<u32>> = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        let cities = ["", "paris", "par", "parisa"];
+        for _ in 0..5_000 {
+            let sk = (
+                cities[rng.gen_range(0, cities.len())].to_string(),
+                rng.gen_range(0, 3),
+            );
+            let pk = rng.gen_range(0, 50);
+            let set = data.entry(sk.clone()).or_default();
+            if rng.gen_range(0, 3) == 0 {
+                assert_eq!(set.remove(&pk), index.remove(&sk, &pk));
+            } else {
+                assert_eq!(set.insert(pk), index.insert(&sk, pk));
+            }
+            assert!(set.contains(&pk) == index.contains(&sk, &pk));
+        }
+        for (sk, set) in data.iter() {
+            assert_eq!(set.iter().collect::<Vec<_>>(), index.scan(sk));
+        }
+        assert_eq!(
+            data.values().map(|set| set.len()).sum::<usize>(),
+            index.len()
+        );
+        assert!(index.scan(&("lyon".to_string(), 1)).is_empty());
+    }
+}
@@ -0,0 +1,279 @@
+// Encoders turning keys into bytes that sort like the keys, for the maps
+// keyed by bytes. `Raw` and `BigEndian` write the keys, `NullTerminated`,
+// `Reversed`, `AsciiLowercase` and `Tokenized` transform what the codec they
+// wrap writes, and a tuple of codecs writes a tuple of keys one after another.
+// In a tuple every part but the last needs a fixed length or a terminator,
+// otherwise ("ab", "c") and ("a", "bc") would encode the same
+pub trait KeyCodec<K: ?Sized> {
+    fn encode(&self, key: &K, out: &mut Vec<u8>);
+
+    fn to_bytes(&self, key: &K) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode(key, &mut out);
+        out
+    }
+}
+
+// Bytes of strings and byte slices as they are
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Raw;
+
+impl KeyCodec<str> for Raw {
+    fn encode(&self, key: &str, out: &mut Vec<u8>) {
+        out.extend_from_slice(key.as_bytes());
+    }
+}
+
+impl KeyCodec<String> for Raw {
+    fn encode(&self, key: &String, out: &mut Vec<u8>) {
+        out.extend_from_slice(key.as_bytes());
+    }
+}
+
+impl KeyCodec<[u8]> for Raw {
+    fn encode(&self, key: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(key);
+    }
+}
+
+impl KeyCodec<Vec<u8>> for Raw {
+    fn encode(&self, key: &Vec<u8>, out: &mut Vec<u8>) {
+        out.extend_from_slice(key);
+    }
+}
+
+// Integers in big-endian, signed ones with the sign bit flipped so the
+// negative numbers sort before the positive ones
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BigEndian;
+
+macro_rules! big_endian {
+    ($($t:ty)*) => ($(impl KeyCodec<$t> for BigEndian {
+        fn encode(&self, key: &$t, out: &mut Vec<u8>) {
+            out.extend_from_slice(&key.to_be_bytes());
+        }
+    })*)
+}
+big_endian! { u8 u16 u32 u64 u128 usize }
+
+macro_rules! big_endian_signed {
+    ($($t:ty)*) => ($(impl KeyCodec<$t> for BigEndian {
+        fn encode(&self, key: &$t, out: &mut Vec<u8>) {
+            let start = out.len();
+            out.extend_from_slice(&key.to_be_bytes());
+            out[start] ^= 0x80;
+        }
+    })*)
+}
+big_endian_signed! { i8 i16 i32 i64 i128 isize }
+
+// What the inner codec writes followed by \0, so no key is a prefix of
+// another and a shorter key sorts before its extensions. The inner codec
+// must not write \0 itself
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullTerminated<C = Raw>(pub C);
+
+impl<K: ?Sized, C: KeyCodec<K>> KeyCodec<K> for NullTerminated<C> {
+    fn encode(&self, key: &K, out: &mut Vec<u8>) {
+        let start = out.len();
+        self.0.encode(key, out);
+        assert!(
+            !out[start..].contains(&0),
+            "null byte in a null-terminated key"
+        );
+        out.push(0);
+    }
+}
+
+// What the inner codec writes back to front, e.g. domain names to look
+// them up by their suffix
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Reversed<C = Raw>(pub C);
+
+impl<K: ?Sized, C: KeyCodec<K>> KeyCodec<K> for Reversed<C> {
+    fn encode(&self, key: &K, out: &mut Vec<u8>) {
+        let start = out.len();
+        self.0.encode(key, out);
+        out[start..].reverse();
+    }
+}
+
+// What the inner codec writes with the ASCII letters in lowercase
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsciiLowercase<C = Raw>(pub C);
+
+impl<K: ?Sized, C: KeyCodec<K>> KeyCodec<K> for AsciiLowercase<C> {
+    fn encode(&self, key: &K, out: &mut Vec<u8>) {
+        let start = out.len();
+        self.0.encode(key, out);
+        out[start..].make_ascii_lowercase();
+    }
+}
+
+// What the inner codec writes passed through a keyed permutation of every
+// byte that depends on the bytes before it, so the tree holds tokens in
+// place of the raw identifiers. Keys sharing a prefix share the tokens of
+// that prefix and a key and its token have the same length, so lookups,
+// prefix scans and prefix deletes by a tokenized prefix still work, but
+// the tokens don't sort like the keys and a range of keys isn't a range of
+// tokens. The permutation of a byte is the one of Crypto-PAn: every bit is
+// flipped by a bit of HMAC-SHA256 of the bytes before it and of the bits
+// before it in the byte. Wrap a whole tuple codec rather than its parts so
+// every part depends on the ones before it. The tree keeps no raw key but
+// the secret is in memory as long as the codec is
+#[cfg(feature = "tokenize")]
+#[derive(Clone)]
+pub struct Tokenized<C = Raw> {
+    inner: C,
+    secret: Vec<u8>,
+}
+
+#[cfg(feature = "tokenize")]
+impl<C> std::fmt::Debug for Tokenized<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Tokenized { .. }")
+    }
+}
+
+#[cfg(feature = "tokenize")]
+impl<C> Tokenized<C> {
+    pub fn new(inner: C, secret: &[u8]) -> Self {
+        Self {
+            inner,
+            secret: secret.to_vec(),
+        }
+    }
+
+    // 255 bits, one for each node of the binary tree over the bits of a
+    // byte, for the byte after `state`. The first state is empty and every
+    // byte chains the next one, so the bits depend on all the bytes before
+    fn pad(&self, state: &[u8]) -> [u8; 32] {
+        hmac_sha256::HMAC::mac(state, &self.secret)
+    }
+
+    fn next_state(&self, pad: &[u8; 32], byte: u8) -> [u8; 32] {
+        let mut mac = hmac_sha256::HMAC::new(&self.secret);
+        mac.update(pad);
+        mac.update([byte]);
+        mac.finalize()
+    }
+
+    // Permute bytes in place, back to the inner encoding if `inverse`
+    fn permute(&self, bytes: &mut [u8], inverse: bool) {
+        let mut pad = self.pad(&[]);
+        for byte in bytes.iter_mut() {
+            let (mut node, mut out) = (1, 0u8);
+            for bit in (0..8).rev() {
+                let flip = pad[node / 8] >> (node % 8) & 1;
+                let new = (*byte >> bit & 1) ^ flip;
+                // Walk the tree by the bit of the raw byte
+                node = 2 * node + if inverse { new } else { *byte >> bit & 1 } as usize;
+                out |= new << bit;
+            }
+            let raw = if inverse { out } else { *byte };
+            *byte = out;
+            let state = self.next_state(&pad, raw);
+            pad = self.pad(&state);
+        }
+    }
+
+    // Bytes the inner codec wrote for a token, e.g. of a key from `iter`
+    pub fn detokenize(&self, token: &[u8]) -> Vec<u8> {
+        let mut bytes = token.to_vec();
+        self.permute(&mut bytes, true);
+        bytes
+    }
+}
+
+#[cfg(feature = "tokenize")]
+impl<K: ?Sized, C: KeyCodec<K>> KeyCodec<K> for Tokenized<C> {
+    fn encode(&self, key: &K, out: &mut Vec<u8>) {
+        let start = out.len();
+        self.inner.encode(key, out);
+        self.permute(&mut out[start..], false);
+    }
+}
+
+macro_rules! tuple_codec {
+    ($(($($c:ident $k:ident $i:tt),*))*) => ($(
+        impl<$($k,)* $($c: KeyCodec<$k>),*> KeyCodec<($($k,)*)> for ($($c,)*) {
+            fn encode(&self, key: &($($k,)*), out: &mut Vec<u8>) {
+                $(self.$i.encode(&key.$i, out);)*
+            }
+        }
+    )*)
+}
+tuple_codec! {
+    (A KA 0, B KB 1)
+    (A KA 0, B KB 1, C KC 2)
+    (A KA 0, B KB 1, C KC 2, D KD 3)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_order() {
+        // Encoded keys sort like the keys
+        let codec = (NullTerminated(Raw), BigEndian, BigEndian);
+        let mut keys = vec![];
+        let mut rng = rand::thread_rng();
+        for _ in 0..1_000 {
+            let len = rng.gen_range(0, 4);
+            let name: String = (0..len).map(|_| ['a', 'b'][rng.gen_range(0, 2)]).collect();
+            keys.push((name, rng.gen_range(-300i16, 300), rng.gen::<u64>() % 3));
+        }
+        let mut encoded: Vec<_> = keys.iter().map(|key| (codec.to_bytes(key), key)).collect();
+        encoded.sort();
+        let decoded: Vec<_> = encoded.into_iter().map(|(_, key)| key.clone()).collect();
+        keys.sort();
+        assert_eq!(keys, decoded);
+    }
+
+    #[test]
+    fn test_transforms() {
+        let codec = NullTerminated(AsciiLowercase(Reversed(Raw)));
+        assert_eq!(b"moc.elpmaxe\0".to_vec(), codec.to_bytes("Example.COM"));
+        assert_eq!(
+            vec![0x7f, 0xff, 1, 2],
+            (BigEndian, Raw).to_bytes(&(-1i16, vec![1, 2]))
+        );
+    }
+
+    #[cfg(feature = "tokenize")]
+    #[test]
+    fn test_tokenized() {
+        let codec = Tokenized::new(Raw, b"secret");
+        let mut rng = rand::thread_rng();
+        let mut keys = vec![];
+        for _ in 0..500 {
+            let len = rng.gen_range(0, 6);
+            let key: Vec<u8> = (0..len).map(|_| rng.gen_range(0, 4)).collect();
+            keys.push(key);
+        }
+        for a in keys.iter() {
+            let token = codec.to_bytes(a);
+            assert_eq!(a.len(), token.len());
+            assert_eq!(*a, codec.detokenize(&token));
+            for b in keys.iter() {
+                let other = codec.to_bytes(b);
+                // Same prefixes and only those, so the same keys
+                assert_eq!(b.starts_with(a), other.starts_with(&token));
+            }
+        }
+        // The raw bytes don't show and another secret gives other tokens
+        let key = b"user:alice@example.com";
+        let token = codec.to_bytes(&key[..]);
+        assert!(!token.windows(5).any(|w| w == b"alice"));
+        assert_ne!(token, Tokenized::new(Raw, b"other").to_bytes(&key[..]));
+        assert_eq!("Tokenized { .. }", format!("{:?}", codec));
+    }
+
+    #[test]
+    #[should_panic(expected = "null byte in a null-terminated key")]
+    fn test_null_in_terminated_key() {
+        NullTerminated(Raw).to_bytes("a\0b");
+    }
+}
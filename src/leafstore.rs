@@ -0,0 +1,277 @@
+use crate::art::Art;
+use crate::error::Error;
+use crate::keys::Raw;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// Where the values of a `StoredArt` live. The tree only keeps the handles
+// `put` hands out, so a store can keep the values in memory, in a file or
+// in an external blob store
+pub trait LeafStore {
+    type Handle: 'static + Copy + std::fmt::Debug;
+
+    fn put(&mut self, value: &[u8]) -> Result<Self::Handle, Error>;
+
+    fn get(&self, handle: Self::Handle) -> Result<Cow<'_, [u8]>, Error>;
+
+    // The handle isn't used again after this
+    fn free(&mut self, handle: Self::Handle) -> Result<(), Error>;
+}
+
+// Values in memory, in slots reused after they are freed
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    slots: Vec<Option<Vec<u8>>>,
+    free: Vec<usize>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl LeafStore for MemoryStore {
+    type Handle = usize;
+
+    fn put(&mut self, value: &[u8]) -> Result<usize, Error> {
+        match self.free.pop() {
+            Some(slot) => {
+                self.slots[slot] = Some(value.to_vec());
+                Ok(slot)
+            }
+            None => {
+                self.slots.push(Some(value.to_vec()));
+                Ok(self.slots.len() - 1)
+            }
+        }
+    }
+
+    fn get(&self, handle: usize) -> Result<Cow<'_, [u8]>, Error> {
+        match self.slots.get(handle) {
+            Some(Some(value)) => Ok(Cow::Borrowed(value)),
+            _ => Err(Error::Corrupt(format!("no value in slot {}", handle))),
+        }
+    }
+
+    fn free(&mut self, handle: usize) -> Result<(), Error> {
+        self.get(handle)?;
+        self.slots[handle] = None;
+        self.free.push(handle);
+        Ok(())
+    }
+}
+
+// Place of a value in a `FileStore`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub offset: u64,
+    pub len: usize,
+}
+
+// Values appended to a file and read back with a seek on every get, so
+// only the handles take memory. Freed values stay in the file as dead
+// bytes until the file is rewritten
+#[derive(Debug)]
+pub struct FileStore {
+    file: RefCell<File>,
+    len: u64,
+    dead: u64,
+}
+
+impl FileStore {
+    // Truncates the file, the handles of an old store are gone with the
+    // tree that held them
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self {
+            file: RefCell::new(file),
+            len: 0,
+            dead: 0,
+        })
+    }
+
+    // Bytes in the file, live or not
+    pub fn file_len(&self) -> u64 {
+        self.len
+    }
+
+    // Bytes of freed values
+    pub fn dead_bytes(&self) -> u64 {
+        self.dead
+    }
+}
+
+impl LeafStore for FileStore {
+    type Handle = Extent;
+
+    fn put(&mut self, value: &[u8]) -> Result<Extent, Error> {
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(self.len))?;
+        file.write_all(value)?;
+        let extent = Extent {
+            offset: self.len,
+            len: value.len(),
+        };
+        self.len += value.len() as u64;
+        Ok(extent)
+    }
+
+    fn get(&self, extent: Extent) -> Result<Cow<'_, [u8]>, Error> {
+        if extent.offset + extent.len as u64 > self.len {
+            return Err(Error::Corrupt(format!(
+                "{:?} past the end of the file",
+                extent
+            )));
+        }
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(extent.offset))?;
+        let mut value = vec![0; extent.len];
+        file.read_exact(&mut value)?;
+        Ok(Cow::Owned(value))
+    }
+
+    fn free(&mut self, extent: Extent) -> Result<(), Error> {
+        self.dead += extent.len as u64;
+        Ok(())
+    }
+}
+
+// Byte map on the ART with the values in a `LeafStore`. Keys go in as they
+// are, so as in `Art` no key can be a prefix of another
+pub struct StoredArt<S: LeafStore> {
+    art: Art<String, S::Handle>,
+    store: S,
+    len: usize,
+}
+
+impl<S: LeafStore> StoredArt<S> {
+    pub fn new(store: S) -> Self {
+        Self {
+            art: Art::new(),
+            store,
+            len: 0,
+        }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Handle of the value under the key, without reading the value
+    pub fn handle(&self, key: &[u8]) -> Option<S::Handle> {
+        self.art.find_with(&Raw, key).copied()
+    }
+
+    // Frees the value it replaces
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let handle = self.store.put(value)?;
+        match self.handle(key) {
+            Some(old) => self.store.free(old)?,
+            None => self.len += 1,
+        }
+        self.art.insert_with(&Raw, key, handle);
+        Ok(())
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Cow<'_, [u8]>>, Error> {
+        match self.handle(key) {
+            Some(handle) => self.store.get(handle).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Returns false if the key wasn't there
+    pub fn remove(&mut self, key: &[u8]) -> Result<bool, Error> {
+        match self.handle(key) {
+            Some(handle) => {
+                self.art.delete_with(&Raw, key);
+                self.len -= 1;
+                self.store.free(handle)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Keys and handles in the key order
+    pub fn handles(&self) -> impl Iterator<Item = (&[u8], S::Handle)> {
+        self.art.iter().map(|(key, handle)| (key, *handle))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+    use std::collections::BTreeMap;
+
+    fn check<S: LeafStore>(mut art: StoredArt<S>) -> StoredArt<S> {
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for _ in 0..2_000 {
+            let key = format!("{}\0", rng.gen_range(0, 200)).into_bytes();
+            if rng.gen_range(0, 4) == 0 {
+                assert_eq!(data.remove(&key).is_some(), art.remove(&key).unwrap());
+            } else {
+                let len = rng.gen_range(0, 100);
+                let value: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+                art.insert(&key, &value).unwrap();
+                data.insert(key, value);
+            }
+        }
+        assert_eq!(data.len(), art.len());
+        for (key, value) in data.iter() {
+            assert_eq!(&value[..], &art.get(key).unwrap().unwrap()[..]);
+        }
+        let keys: Vec<_> = art.handles().map(|(key, _)| key.to_vec()).collect();
+        assert!(keys.iter().eq(data.keys()));
+        assert_eq!(None, art.get(b"none\0").unwrap());
+        art
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let art = check(StoredArt::new(MemoryStore::new()));
+        // Freed slots get reused, one more for the value replacing another
+        assert!(art.store().slots.len() <= 201);
+        let mut store = MemoryStore::new();
+        let handle = store.put(b"value").unwrap();
+        store.free(handle).unwrap();
+        assert!(matches!(store.get(handle), Err(Error::Corrupt(_))));
+        assert!(store.free(handle).is_err());
+    }
+
+    #[test]
+    fn test_file_store() {
+        let path = std::env::temp_dir().join(format!("radix-leaves-{}.bin", std::process::id()));
+        let art = check(StoredArt::new(FileStore::create(&path).unwrap()));
+        let store = art.store();
+        let live: usize = art.handles().map(|(_, extent)| extent.len).sum();
+        assert_eq!(store.file_len(), live as u64 + store.dead_bytes());
+        assert_eq!(store.file_len(), std::fs::metadata(&path).unwrap().len());
+        let past = Extent {
+            offset: store.file_len(),
+            len: 1,
+        };
+        assert!(matches!(store.get(past), Err(Error::Corrupt(_))));
+        drop(art);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
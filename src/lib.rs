@@ -0,0 +1,33 @@
+#[macro_use]
+mod trace;
+
+pub mod art;
+pub mod burst;
+pub mod codec;
+pub mod compress;
+pub mod critbit;
+pub mod datrie;
+mod error;
+#[cfg(feature = "serde")]
+pub mod export;
+pub mod frozen;
+pub mod fst;
+pub mod geo;
+pub mod hot;
+pub mod index;
+pub mod keys;
+pub mod leafstore;
+pub mod namespace;
+pub mod qptrie;
+pub mod radix;
+pub mod router;
+pub mod sst;
+pub mod suffix;
+pub mod testing;
+pub mod timeseries;
+pub mod trie;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod weighted;
+
+pub use error::Error;
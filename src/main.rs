@@ -1,41 +1,159 @@
-#![feature(ptr_offset_from)]
-mod art;
-mod radix;
-mod trie;
+use radix::art::{Art, ArtKey};
+use radix::export::Format;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+const USAGE: &str = "usage: radix-cli [--db PATH] <command>
+
+commands:
+    put <key> <value>    insert or overwrite a value
+    get <key>            print the value of the key
+    del <key>            delete the key
+    scan [prefix]        print keys and values in order
+    load <file>          insert pairs from a .csv or json lines file
+    dump [file]          write all pairs as json lines (or csv for .csv)
+    stats                print the size of the store
+
+The store is kept in the sst file given by --db, $RADIX_DB or ./radix.db";
+
+// Keys are stored null-terminated, so one key can be a prefix of another
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+struct Key(String);
+
+impl ArtKey for Key {
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes = self.0.as_bytes().to_vec();
+        bytes.push(0);
+        bytes
+    }
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (last, bytes) = bytes.split_last()?;
+        if *last != 0 {
+            return None;
+        }
+        String::from_bytes(bytes).map(Key)
+    }
+}
+
+type Store = Art<Key, String>;
+
+fn open(path: &Path) -> io::Result<Store> {
+    let mut store = Store::new();
+    match File::open(path) {
+        Ok(file) => {
+            store.import_sst(file)?;
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    Ok(store)
+}
+
+// Write to a temporary file first, so a failed write keeps the old store
+fn save(store: &Store, path: &Path) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = BufWriter::new(File::create(&tmp)?);
+    store.export_sst(&mut file)?;
+    // On disk before the rename, or a crash could leave an empty store
+    file.into_inner()?.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+fn format_of(path: &str) -> Format {
+    if path.ends_with(".csv") {
+        Format::Csv
+    } else {
+        Format::JsonLines
+    }
+}
+
+fn key(arg: Option<String>) -> Result<Key, Box<dyn Error>> {
+    let key = arg.ok_or("missing key")?;
+    if key.contains('\0') {
+        return Err("keys can't contain a null byte".into());
+    }
+    Ok(Key(key))
+}
+
+fn run(mut args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let mut db = PathBuf::from(env::var("RADIX_DB").unwrap_or_else(|_| "radix.db".to_string()));
+    if args.first().map(String::as_str) == Some("--db") {
+        args.remove(0);
+        if args.is_empty() {
+            return Err("missing path after --db".into());
+        }
+        db = PathBuf::from(args.remove(0));
+    }
+    let mut args = args.into_iter();
+    let command = args.next().ok_or(USAGE)?;
+    let mut store = open(&db)?;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match command.as_str() {
+        "put" => {
+            let key = key(args.next())?;
+            let value = args.next().ok_or("missing value")?;
+            store.insert(key, value);
+            save(&store, &db)?;
+        }
+        "get" => match store.find(key(args.next())?) {
+            Some(value) => writeln!(out, "{}", value)?,
+            None => return Err("not found".into()),
+        },
+        "del" => {
+            let key = key(args.next())?;
+            if store.find(Key(key.0.clone())).is_none() {
+                return Err("not found".into());
+            }
+            store.delete(key);
+            save(&store, &db)?;
+        }
+        "scan" => {
+            let prefix = args.next().unwrap_or_default();
+            for (key, value) in store.prefix_iter(prefix.as_bytes()) {
+                // Skip the null terminator
+                let key = String::from_utf8_lossy(&key[..key.len() - 1]);
+                writeln!(out, "{}\t{}", key, value)?;
+            }
+        }
+        "load" => {
+            let path = args.next().ok_or("missing file")?;
+            let count = store.import(File::open(&path)?, format_of(&path))?;
+            save(&store, &db)?;
+            writeln!(out, "loaded {} pairs", count)?;
+        }
+        "dump" => match args.next() {
+            Some(path) => {
+                let file = BufWriter::new(File::create(&path)?);
+                store.export(file, format_of(&path))?;
+            }
+            None => store.export(&mut out, Format::JsonLines)?,
+        },
+        "stats" => {
+            writeln!(out, "keys: {}", store.iter().count())?;
+            writeln!(out, "nodes: {}", store.bfs_count())?;
+            writeln!(out, "memory: {} bytes", store.memory_usage())?;
+            writeln!(
+                out,
+                "file: {} bytes",
+                fs::metadata(&db).map_or(0, |m| m.len())
+            )?;
+        }
+        _ => return Err(format!("unknown command `{}`\n\n{}", command, USAGE).into()),
+    }
+    Ok(())
+}
 
 fn main() {
-    let mut art = art::Art::<u32, u32>::new();
-    println!("first insert ---------------------");
-    art.insert(10, 10);
-    println!("second insert ---------------------");
-    art.insert(20, 120);
-    println!("third insert ---------------------");
-    art.insert(30, 240);
-    println!("forth insert ---------------------");
-    art.insert(40, 480);
-    println!("fifth insert ---------------------");
-    art.insert(50, 960);
-    println!("six insert ---------------------");
-    art.insert(300, 1920);
-    println!("seventh insert ---------------------");
-    art.insert(301, 3840);
-    println!("first find ---------------------");
-    println!("{}", art.find(10).unwrap());
-    println!("second find ---------------------");
-    println!("{}", art.find(20).unwrap());
-    println!("third find ---------------------");
-    println!("{}", art.find(30).unwrap());
-    println!("fourth find ---------------------");
-    println!("{}", art.find(40).unwrap());
-    println!("fifth find ---------------------");
-    println!("{}", art.find(50).unwrap());
-    println!("seventh find ---------------------");
-    println!("{}", art.find(301).unwrap());
-    art.delete(10);
-    art.delete(20);
-    art.delete(30);
-    art.delete(40);
-    art.delete(50);
-    art.delete(300);
-    art.delete(301);
+    if let Err(err) = run(env::args().skip(1).collect()) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
 }
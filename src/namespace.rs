@@ -0,0 +1,484 @@
+use crate::art::{leaf_size, Art, ArtKey};
+use crate::error::Error;
+use crate::keys::{KeyCodec, NullTerminated, Raw};
+
+// Internal prefix of the keys of a namespace, the name null-terminated so
+// the keys of one namespace never start with the prefix of another
+fn prefix(name: &str) -> Vec<u8> {
+    NullTerminated(Raw).to_bytes(name)
+}
+
+// What an insert does when the namespace is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaPolicy {
+    // Fail with `Error::QuotaExceeded`
+    Reject,
+    // Delete the first keys of the namespace in the key order until the new
+    // one fits, e.g. the oldest with keys starting with a timestamp
+    EvictFirst,
+}
+
+// Limits of a namespace, bytes are counted as the leaves take them in the
+// tree, key and internal prefix included, without the inner nodes shared
+// with other namespaces
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub max_entries: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub policy: QuotaPolicy,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub entries: usize,
+    pub bytes: usize,
+    // Keys deleted by `QuotaPolicy::EvictFirst`
+    pub evictions: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Account {
+    quota: Option<Quota>,
+    usage: Usage,
+}
+
+// Namespaces split one tree into independent maps, each with its own keys
+// under an internal prefix, in place of one tree per map. A tree holding
+// namespaces should only get keys through them, a key inserted directly
+// could land in one. Once a namespace is opened the tree counts its usage
+// on every insert and delete under its prefix, whichever way they come
+impl<K, T> Art<K, T>
+where
+    K: ArtKey + std::marker::Sized + std::fmt::Debug,
+    T: 'static + Clone + std::fmt::Debug,
+{
+    // The name can't have a \0 in it. Opening a namespace for the first
+    // time counts the keys already under its prefix
+    pub fn namespace(&mut self, name: &str) -> NamespaceHandle<'_, K, T> {
+        let prefix = prefix(name);
+        if !self.namespaces.contains_key(&prefix) {
+            let usage = self.count_prefix(&prefix);
+            let account = Account { quota: None, usage };
+            self.namespaces.insert(prefix.clone(), account);
+        }
+        NamespaceHandle { prefix, art: self }
+    }
+
+    // Delete the keys of the namespace with its quota and usage, returns
+    // how many keys there were
+    pub fn drop_namespace(&mut self, name: &str) -> usize {
+        self.namespaces.remove(&prefix(name));
+        self.delete_prefix(&prefix(name))
+    }
+
+    fn count_prefix(&self, prefix: &[u8]) -> Usage {
+        let mut usage = Usage::default();
+        for (key, _) in self.prefix_iter(prefix) {
+            usage.entries += 1;
+            usage.bytes += leaf_size::<T>(key.len());
+        }
+        usage
+    }
+
+    // Count a key that came in or went away in the namespace it belongs to,
+    // the internal prefix of a key ends at its first \0
+    pub(crate) fn count_in_namespace(&mut self, key: &[u8], added: bool) {
+        let end = match key.iter().position(|&b| b == 0) {
+            Some(end) => end + 1,
+            None => return,
+        };
+        if let Some(account) = self.namespaces.get_mut(&key[..end]) {
+            let usage = &mut account.usage;
+            if added {
+                usage.entries += 1;
+                usage.bytes += leaf_size::<T>(key.len());
+            } else {
+                usage.entries -= 1;
+                usage.bytes -= leaf_size::<T>(key.len());
+            }
+        }
+    }
+
+    // Count the namespaces again from scratch, after a change that doesn't
+    // go key by key
+    pub(crate) fn recount_namespaces(&mut self) {
+        let prefixes: Vec<_> = self.namespaces.keys().cloned().collect();
+        for prefix in prefixes {
+            let usage = self.count_prefix(&prefix);
+            let account = self.namespaces.get_mut(&prefix).unwrap();
+            account.usage = Usage {
+                evictions: account.usage.evictions,
+                ..usage
+            };
+        }
+    }
+
+    pub fn namespace_usage(&self, name: &str) -> Usage {
+        self.namespaces
+            .get(&prefix(name))
+            .map(|account| account.usage)
+            .unwrap_or_default()
+    }
+
+    // Names and usage of the namespaces that got keys or a quota, in the
+    // order of their names
+    pub fn namespaces(&self) -> impl Iterator<Item = (&str, Usage)> {
+        self.namespaces.iter().map(|(prefix, account)| {
+            let name = std::str::from_utf8(&prefix[..prefix.len() - 1]).unwrap();
+            (name, account.usage)
+        })
+    }
+}
+
+// The map API of `Art` scoped to a namespace, keys go in and come out
+// without the internal prefix. Only `try_insert` enforces the quota
+pub struct NamespaceHandle<'a, K, T: 'static + std::fmt::Debug> {
+    art: &'a mut Art<K, T>,
+    prefix: Vec<u8>,
+}
+
+impl<K, T> NamespaceHandle<'_, K, T>
+where
+    K: ArtKey + std::marker::Sized + std::fmt::Debug,
+    T: 'static + Clone + std::fmt::Debug,
+{
+    pub fn name(&self) -> &str {
+        std::str::from_utf8(&self.prefix[..self.prefix.len() - 1]).unwrap()
+    }
+
+    fn key(&self, key_bytes: &[u8]) -> Vec<u8> {
+        let mut key = self.prefix.clone();
+        key.extend_from_slice(key_bytes);
+        key
+    }
+
+    pub fn quota(&self) -> Option<Quota> {
+        self.art
+            .namespaces
+            .get(&self.prefix)
+            .and_then(|account| account.quota)
+    }
+
+    // A quota under the current usage only applies to the inserts from now
+    // on, nothing is evicted right away
+    pub fn set_quota(&mut self, quota: Option<Quota>) {
+        self.account().quota = quota;
+    }
+
+    pub fn usage(&self) -> Usage {
+        self.art
+            .namespaces
+            .get(&self.prefix)
+            .map(|account| account.usage)
+            .unwrap_or_default()
+    }
+
+    fn account(&mut self) -> &mut Account {
+        self.art.namespaces.entry(self.prefix.clone()).or_default()
+    }
+
+    fn try_put(&mut self, key: &[u8], value: T) -> Result<Option<T>, Error> {
+        // Replacing a value takes nothing more
+        if let (Some(quota), None) = (self.quota(), self.art.find_with(&Raw, key)) {
+            let bytes = leaf_size::<T>(key.len());
+            // A key that can't fit even alone evicts nothing
+            if quota.max_entries == Some(0) || quota.max_bytes.is_some_and(|max| bytes > max) {
+                return Err(Error::QuotaExceeded {
+                    namespace: self.name().to_string(),
+                    entries: 1,
+                    bytes,
+                });
+            }
+            loop {
+                let usage = self.usage();
+                let (entries, bytes) = (usage.entries + 1, usage.bytes + bytes);
+                if quota.max_entries.is_none_or(|max| entries <= max)
+                    && quota.max_bytes.is_none_or(|max| bytes <= max)
+                {
+                    break;
+                }
+                let first = match quota.policy {
+                    QuotaPolicy::Reject => None,
+                    QuotaPolicy::EvictFirst => self
+                        .art
+                        .prefix_iter(&self.prefix)
+                        .next()
+                        .map(|(key, _)| key.to_vec()),
+                };
+                match first {
+                    Some(first) => {
+                        self.art.delete_with(&Raw, &first[..]);
+                        self.account().usage.evictions += 1;
+                    }
+                    None => {
+                        return Err(Error::QuotaExceeded {
+                            namespace: self.name().to_string(),
+                            entries,
+                            bytes,
+                        })
+                    }
+                }
+            }
+        }
+        Ok(self.art.insert_with(&Raw, key, value))
+    }
+
+    // Returns the value the key had
+    pub fn insert(&mut self, key: K, value: T) -> Option<T> {
+        let key = self.key(&key.bytes());
+        self.art.insert_with(&Raw, &key[..], value)
+    }
+
+    // Insert within the quota, evicting or failing as its policy says.
+    // Returns the value the key had
+    pub fn try_insert(&mut self, key: K, value: T) -> Result<Option<T>, Error> {
+        let key = self.key(&key.bytes());
+        self.try_put(&key, value)
+    }
+
+    pub fn find(&self, key: K) -> Option<&T> {
+        self.art.find_with(&Raw, &self.key(&key.bytes())[..])
+    }
+
+    pub fn delete(&mut self, key: K) {
+        self.remove(key);
+    }
+
+    // Delete and return the value the key had
+    pub fn remove(&mut self, key: K) -> Option<T> {
+        let key = self.key(&key.bytes());
+        self.art.remove_with(&Raw, &key[..])
+    }
+
+    pub fn insert_with<Q: ?Sized, C: KeyCodec<Q>>(
+        &mut self,
+        codec: &C,
+        key: &Q,
+        value: T,
+    ) -> Option<T> {
+        let key = self.key(&codec.to_bytes(key));
+        self.art.insert_with(&Raw, &key[..], value)
+    }
+
+    pub fn try_insert_with<Q: ?Sized, C: KeyCodec<Q>>(
+        &mut self,
+        codec: &C,
+        key: &Q,
+        value: T,
+    ) -> Result<Option<T>, Error> {
+        let key = self.key(&codec.to_bytes(key));
+        self.try_put(&key, value)
+    }
+
+    pub fn find_with<Q: ?Sized, C: KeyCodec<Q>>(&self, codec: &C, key: &Q) -> Option<&T> {
+        self.art
+            .find_with(&Raw, &self.key(&codec.to_bytes(key))[..])
+    }
+
+    pub fn delete_with<Q: ?Sized, C: KeyCodec<Q>>(&mut self, codec: &C, key: &Q) {
+        self.remove_with(codec, key);
+    }
+
+    pub fn remove_with<Q: ?Sized, C: KeyCodec<Q>>(&mut self, codec: &C, key: &Q) -> Option<T> {
+        let key = self.key(&codec.to_bytes(key));
+        self.art.remove_with(&Raw, &key[..])
+    }
+
+    // Pairs of the namespace in the key order
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &T)> {
+        self.prefix_iter(&[])
+    }
+
+    // Pairs of the namespace with keys starting with `prefix`
+    pub fn prefix_iter(&self, prefix: &[u8]) -> impl Iterator<Item = (&[u8], &T)> {
+        let skip = self.prefix.len();
+        self.art
+            .prefix_iter(&self.key(prefix))
+            .map(move |(key, value)| (&key[skip..], value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.usage().entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Delete the keys of the namespace, returns how many there were. The
+    // quota stays
+    pub fn clear(&mut self) -> usize {
+        self.art.delete_prefix(&self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::art::Deletion;
+    use rand::Rng;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_namespaces() {
+        let names = ["users", "user", "orders", ""];
+        let mut art = Art::<u32, u32>::new();
+        let mut data = vec![BTreeMap::new(); names.len()];
+        let mut rng = rand::thread_rng();
+        for _ in 0..10_000 {
+            let i = rng.gen_range(0, names.len());
+            let key = rng.gen_range(0, 1_000);
+            let mut ns = art.namespace(names[i]);
+            assert_eq!(names[i], ns.name());
+            if rng.gen_range(0, 4) == 0 {
+                ns.delete(key);
+                data[i].remove(&key);
+            } else {
+                let value = rng.gen();
+                assert_eq!(data[i].insert(key, value), ns.insert(key, value));
+            }
+        }
+        for (i, name) in names.iter().enumerate() {
+            let ns = art.namespace(name);
+            assert_eq!(data[i].len(), ns.len());
+            let pairs: Vec<_> = ns
+                .iter()
+                .map(|(key, value)| (u32::from_bytes(key).unwrap(), *value))
+                .collect();
+            assert_eq!(data[i].clone().into_iter().collect::<Vec<_>>(), pairs);
+            for key in 0..1_000 {
+                assert_eq!(data[i].get(&key), ns.find(key));
+            }
+            let low = data[i].range(..256).count();
+            assert_eq!(low, ns.prefix_iter(&[0, 0, 0]).count());
+        }
+
+        // Dropping one leaves the others alone
+        assert_eq!(data[1].len(), art.drop_namespace("user"));
+        assert!(art.namespace("user").is_empty());
+        assert_eq!(data[0].len(), art.namespace("users").len());
+        let mut ns = art.namespace("orders");
+        assert_eq!(data[2].len(), ns.clear());
+        assert_eq!(0, art.drop_namespace("orders"));
+        assert_eq!(data[0].len() + data[3].len(), art.iter().count());
+
+        let mut ns = art.namespace("users");
+        ns.insert_with(&Raw, &[1, 2, 3, 4][..], 7);
+        assert_eq!(Some(&7), ns.find(0x01020304));
+        ns.delete_with(&Raw, &[1, 2, 3, 4][..]);
+        assert_eq!(None, ns.find_with(&Raw, &[1, 2, 3, 4][..]));
+    }
+
+    #[test]
+    fn test_quotas() {
+        let mut art = Art::<u64, u32>::new();
+        let mut ns = art.namespace("tenant");
+        ns.set_quota(Some(Quota {
+            max_entries: Some(100),
+            max_bytes: None,
+            policy: QuotaPolicy::Reject,
+        }));
+        for key in 0..100 {
+            assert_eq!(None, ns.try_insert(key, 0).unwrap());
+        }
+        assert!(matches!(
+            ns.try_insert(100, 0),
+            Err(Error::QuotaExceeded { entries: 101, .. })
+        ));
+        // Replacing and going over with `insert` still work
+        assert_eq!(Some(0), ns.try_insert(7, 1).unwrap());
+        ns.insert(100, 0);
+        assert_eq!(101, ns.usage().entries);
+        ns.delete(100);
+        ns.delete(100);
+        assert_eq!(100, ns.len());
+
+        // Bytes as the leaves take them, evicting the first keys
+        let size = leaf_size::<u32>(ns.key(&[0; 8]).len());
+        assert_eq!(100 * size, ns.usage().bytes);
+        ns.set_quota(Some(Quota {
+            max_entries: None,
+            max_bytes: Some(50 * size),
+            policy: QuotaPolicy::EvictFirst,
+        }));
+        ns.try_insert(1_000, 0).unwrap();
+        assert_eq!(50, ns.len());
+        assert_eq!(51, ns.usage().evictions);
+        assert_eq!(None, ns.find(50));
+        assert_eq!(Some(&0), ns.find(51));
+        ns.set_quota(Some(Quota {
+            max_entries: None,
+            max_bytes: Some(size - 1),
+            policy: QuotaPolicy::EvictFirst,
+        }));
+        // Keys that can't fit alone fail without evicting anything
+        assert!(ns.try_insert_with(&Raw, &[0; 8][..], 0).is_err());
+        assert_eq!(50, ns.len());
+        ns.set_quota(Some(Quota {
+            max_entries: Some(0),
+            max_bytes: None,
+            policy: QuotaPolicy::EvictFirst,
+        }));
+        assert!(matches!(
+            ns.try_insert(2_000, 0),
+            Err(Error::QuotaExceeded { entries: 1, .. })
+        ));
+        assert_eq!(50, ns.len());
+        assert_eq!(51, ns.usage().evictions);
+        assert_eq!(Some(0), ns.remove(1_000));
+        assert_eq!(None, ns.remove(1_000));
+        assert_eq!(49, ns.len());
+
+        // Usage of every namespace, as the tree sees it
+        art.namespace("other").insert(1, 1);
+        let usage: Vec<_> = art
+            .namespaces()
+            .map(|(name, u)| (name, u.entries))
+            .collect();
+        assert_eq!(vec![("other", 1), ("tenant", 49)], usage);
+        let leaves: usize = art.namespaces().map(|(_, u)| u.bytes).sum();
+        let in_tree: usize = art.iter().map(|(key, _)| leaf_size::<u32>(key.len())).sum();
+        assert_eq!(in_tree, leaves);
+        assert_eq!(1, art.drop_namespace("other"));
+        assert_eq!(Usage::default(), art.namespace_usage("other"));
+        assert!(art.namespace("tenant").quota().is_some());
+    }
+
+    #[test]
+    fn test_usage_outside_handles() {
+        let mut art = Art::<u32, u32>::new();
+        let key = |name: &str, key: u32| {
+            let mut bytes = prefix(name);
+            bytes.extend_from_slice(&key.to_be_bytes());
+            bytes
+        };
+        // Keys already there when the namespace is opened count
+        for i in 0..5 {
+            art.insert_with(&Raw, &key("logs", i)[..], i);
+        }
+        assert_eq!(5, art.namespace("logs").len());
+        assert_eq!(5, art.namespace("logs").iter().count());
+        art.namespace("metrics").insert(1, 1);
+
+        // Writes to the tree itself keep the usage right
+        assert_eq!(5, art.delete_prefix(b"logs\0"));
+        assert_eq!(0, art.namespace_usage("logs").entries);
+        art.namespace("logs").insert(7, 7);
+        assert_eq!(1, art.namespace("logs").len());
+        art.insert_with(&Raw, &key("logs", 8)[..], 8);
+        art.delete_with(&Raw, &key("logs", 7)[..]);
+        assert_eq!(1, art.namespace("logs").len());
+        art.set_deletion(Deletion::Lazy);
+        art.namespace("logs").insert(9, 9);
+        art.delete_with(&Raw, &key("logs", 9)[..]);
+        assert_eq!(1, art.namespace("logs").len());
+        art.compact();
+        assert_eq!(1, art.namespace("logs").len());
+        art.namespace("logs").insert(9, 9);
+        art.retain_prefix(b"logs\0");
+        assert_eq!(2, art.namespace("logs").len());
+        assert_eq!(0, art.namespace_usage("metrics").entries);
+        let bytes: usize = art.iter().map(|(key, _)| leaf_size::<u32>(key.len())).sum();
+        assert_eq!(bytes, art.namespace_usage("logs").bytes);
+    }
+}
@@ -0,0 +1,300 @@
+use crate::art::ArtKey;
+use core::marker::PhantomData;
+
+// Quad-bit popcount patricia trie. Every branch looks at one nibble of the
+// key and keeps its twigs in a dense array, a bitmap tells which nibbles are
+// present and the popcount of the lower bits gives the position of a twig.
+// Bit 0 of the bitmap stands for a key that ends before the nibble, so one
+// key can be a prefix of another
+
+#[derive(Debug)]
+struct Leaf<T> {
+    key: Vec<u8>,
+    value: T,
+}
+
+#[derive(Debug)]
+struct Branch<T> {
+    // Index of the nibble the branch looks at
+    index: usize,
+    bitmap: u32,
+    // Twigs in the nibble order
+    twigs: Vec<QpNode<T>>,
+}
+
+#[derive(Debug)]
+enum QpNode<T> {
+    Leaf(Leaf<T>),
+    Branch(Branch<T>),
+}
+
+// Nibble of the key plus one, zero if the key is shorter
+fn nibble(key: &[u8], index: usize) -> u32 {
+    // The high nibble of a byte goes first
+    let shift = 4 - 4 * (index % 2);
+    key.get(index / 2)
+        .map_or(0, |byte| 1 + ((byte >> shift) & 0xf) as u32)
+}
+
+// Index of the first nibble two keys differ in
+fn first_diff(a: &[u8], b: &[u8]) -> Option<usize> {
+    (0..2 * std::cmp::max(a.len(), b.len())).find(|&i| nibble(a, i) != nibble(b, i))
+}
+
+impl<T> Branch<T> {
+    fn has(&self, nibble: u32) -> bool {
+        self.bitmap & 1 << nibble != 0
+    }
+
+    fn twig(&self, nibble: u32) -> usize {
+        (self.bitmap & ((1 << nibble) - 1)).count_ones() as usize
+    }
+}
+
+impl<T> QpNode<T> {
+    // Leaf sharing the longest prefix with the key. A missing nibble
+    // means the key diverges here, so any twig will do
+    fn closest(&self, key: &[u8]) -> &Leaf<T> {
+        let mut node = self;
+        loop {
+            match node {
+                QpNode::Leaf(leaf) => return leaf,
+                QpNode::Branch(branch) => {
+                    let nibble = nibble(key, branch.index);
+                    node = if branch.has(nibble) {
+                        &branch.twigs[branch.twig(nibble)]
+                    } else {
+                        &branch.twigs[0]
+                    };
+                }
+            }
+        }
+    }
+}
+
+pub struct Iter<'a, T> {
+    stack: Vec<&'a QpNode<T>>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (&'a [u8], &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(node) = self.stack.pop() {
+            match node {
+                QpNode::Leaf(leaf) => return Some((&leaf.key, &leaf.value)),
+                QpNode::Branch(branch) => self.stack.extend(branch.twigs.iter().rev()),
+            }
+        }
+        None
+    }
+}
+
+pub struct QpTrie<K, T> {
+    root: Option<QpNode<T>>,
+    key: PhantomData<K>,
+}
+
+impl<K: ArtKey, T> Default for QpTrie<K, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: ArtKey, T> QpTrie<K, T> {
+    pub fn new() -> Self {
+        Self {
+            root: None,
+            key: PhantomData,
+        }
+    }
+
+    pub fn find(&self, key: K) -> Option<&T> {
+        let key_bytes = key.bytes();
+        trace_span!("qptrie.find", key_len = key_bytes.len());
+        let mut node = self.root.as_ref()?;
+        loop {
+            match node {
+                QpNode::Leaf(leaf) if leaf.key == key_bytes => return Some(&leaf.value),
+                QpNode::Leaf(_) => return None,
+                QpNode::Branch(branch) => {
+                    let nibble = nibble(&key_bytes, branch.index);
+                    if !branch.has(nibble) {
+                        return None;
+                    }
+                    node = &branch.twigs[branch.twig(nibble)];
+                }
+            }
+        }
+    }
+
+    // Keys with their values in the key order
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            stack: self.root.iter().collect(),
+        }
+    }
+
+    // Pairs with keys starting with `prefix` in the key order
+    pub fn prefix_iter<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = (&'a [u8], &'a T)> {
+        let mut node = self.root.as_ref();
+        // Follow the prefix nibbles, past them the whole subtree matches
+        // except for the nibbles skipped by the branches
+        while let Some(QpNode::Branch(branch)) = node {
+            if branch.index >= 2 * prefix.len() {
+                break;
+            }
+            let nibble = nibble(prefix, branch.index);
+            node = if branch.has(nibble) {
+                Some(&branch.twigs[branch.twig(nibble)])
+            } else {
+                None
+            };
+        }
+        let iter = Iter {
+            stack: node.into_iter().collect(),
+        };
+        iter.filter(move |(key, _)| key.starts_with(prefix))
+    }
+
+    pub fn insert(&mut self, key: K, value: T) {
+        let key_bytes = key.bytes();
+        trace_span!("qptrie.insert", key_len = key_bytes.len());
+        let leaf = Leaf {
+            key: key_bytes,
+            value,
+        };
+        match self.root.as_mut() {
+            Some(root) => {
+                match first_diff(&root.closest(&leaf.key).key, &leaf.key) {
+                    Some(diff) => insert_at(root, diff, leaf),
+                    // The key is already there, rewrite its value
+                    None => insert_at(root, usize::MAX, leaf),
+                }
+            }
+            None => self.root = Some(QpNode::Leaf(leaf)),
+        }
+    }
+
+    pub fn delete(&mut self, key: K) {
+        let key_bytes = key.bytes();
+        trace_span!("qptrie.delete", key_len = key_bytes.len());
+        match &self.root {
+            Some(QpNode::Leaf(leaf)) if leaf.key == key_bytes => self.root = None,
+            Some(QpNode::Branch(_)) => delete_at(self.root.as_mut().unwrap(), &key_bytes),
+            _ => {}
+        }
+    }
+}
+
+// Insert the leaf below the node, the leaf key differs from the keys
+// of the subtree first in the nibble `diff`
+fn insert_at<T>(node: &mut QpNode<T>, diff: usize, leaf: Leaf<T>) {
+    let nibble_new = nibble(&leaf.key, diff);
+    match node {
+        // All keys below share the nibbles up to `diff` with the new key
+        QpNode::Branch(branch) if branch.index < diff => {
+            let twig = branch.twig(nibble(&leaf.key, branch.index));
+            insert_at(&mut branch.twigs[twig], diff, leaf);
+        }
+        QpNode::Branch(branch) if branch.index == diff => {
+            let twig = branch.twig(nibble_new);
+            branch.bitmap |= 1 << nibble_new;
+            branch.twigs.insert(twig, QpNode::Leaf(leaf));
+        }
+        QpNode::Leaf(old) if old.key == leaf.key => old.value = leaf.value,
+        // The new key diverges above the node, put a branch in its place
+        _ => {
+            let nibble_old = nibble(&node.closest(&[]).key, diff);
+            let twigs = vec![QpNode::Leaf(leaf)];
+            let old = std::mem::replace(
+                node,
+                QpNode::Branch(Branch {
+                    index: diff,
+                    bitmap: 1 << nibble_old | 1 << nibble_new,
+                    twigs,
+                }),
+            );
+            if let QpNode::Branch(branch) = node {
+                let twig = branch.twig(nibble_old);
+                branch.twigs.insert(twig, old);
+            }
+        }
+    }
+}
+
+fn delete_at<T>(node: &mut QpNode<T>, key: &[u8]) {
+    let branch = match &mut *node {
+        QpNode::Branch(branch) => branch,
+        QpNode::Leaf(_) => return,
+    };
+    let nibble = nibble(key, branch.index);
+    if !branch.has(nibble) {
+        return;
+    }
+    let twig = branch.twig(nibble);
+    match &branch.twigs[twig] {
+        QpNode::Leaf(leaf) if leaf.key != key => {}
+        QpNode::Leaf(_) => {
+            branch.twigs.remove(twig);
+            branch.bitmap &= !(1 << nibble);
+            // A branch with a single twig is replaced by the twig
+            if branch.twigs.len() == 1 {
+                let last = branch.twigs.pop().unwrap();
+                *node = last;
+            }
+        }
+        QpNode::Branch(_) => delete_at(&mut branch.twigs[twig], key),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_add_find_and_delete() {
+        let mut trie = QpTrie::<String, u32>::new();
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+
+        for _i in 0..20_000 {
+            // Short keys over a few letters to get prefixes of other keys
+            let len = rng.gen_range(0, 8);
+            let key: String = (0..len)
+                .map(|_| ['a', 'b', 'q', '~'][rng.gen_range(0, 4)])
+                .collect();
+            let value = rng.gen::<u32>();
+            trie.insert(key.clone(), value);
+            data.insert(key, value);
+        }
+        for (key, val) in &data {
+            assert_eq!(Some(val), trie.find(key.clone()));
+        }
+        assert!(trie.iter().map(|(key, val)| (key.to_vec(), *val)).eq(data
+            .iter()
+            .map(|(key, val)| (key.as_bytes().to_vec(), *val))));
+
+        let keys: Vec<_> = data.keys().cloned().collect();
+        for key in keys.iter().step_by(2) {
+            trie.delete(key.clone());
+            data.remove(key);
+        }
+        for key in keys.iter() {
+            assert_eq!(data.get(key), trie.find(key.clone()));
+        }
+        let found: Vec<_> = trie.prefix_iter(b"ab").map(|(_, val)| *val).collect();
+        let expected: Vec<_> = data
+            .iter()
+            .filter(|(key, _)| key.starts_with("ab"))
+            .map(|(_, val)| *val)
+            .collect();
+        assert_eq!(expected, found);
+        for key in keys.iter() {
+            trie.delete(key.clone());
+        }
+        assert_eq!(0, trie.iter().count());
+    }
+}
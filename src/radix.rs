@@ -1,223 +1,1153 @@
+use crate::codec::{read_varint, write_varint, Codec};
+use crate::error::Error;
+use crate::keys::KeyCodec;
+use crate::trie::TrieMap;
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+#[cfg(not(target_arch = "wasm32"))]
 use std::collections::VecDeque;
-use std::ops::{Index, IndexMut};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::ops::{Bound, Index, IndexMut, RangeBounds};
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+#[cfg(feature = "graphemes")]
+use unicode_segmentation::UnicodeSegmentation;
 
+// Units the labels are split at, keys are matched unit by unit, so a
+// split never lands inside one and every prefix the tree reports is made
+// of whole units
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Boundary {
+    Char,
+    // Extended grapheme clusters, like a letter with its combining marks
+    #[cfg(feature = "graphemes")]
+    Grapheme,
+}
+
+// Case folding of the keys. A key is stored and looked up folded, the
+// key as it was inserted is kept and comes back from the iterators
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Case {
+    Sensitive,
+    // Only 'A'..='Z', e.g. for host and header names
+    Ascii,
+    // Full Unicode case folding, like "Straße" and "STRASSE"
+    #[cfg(feature = "unicode-case")]
+    Unicode,
+}
+
+impl Case {
+    fn fold(self, key: &str) -> Cow<'_, str> {
+        match self {
+            Case::Sensitive => Cow::Borrowed(key),
+            Case::Ascii if key.bytes().any(|b| b.is_ascii_uppercase()) => {
+                Cow::Owned(key.to_ascii_lowercase())
+            }
+            Case::Ascii => Cow::Borrowed(key),
+            #[cfg(feature = "unicode-case")]
+            Case::Unicode => match caseless::default_case_fold_str(key) {
+                folded if folded == key => Cow::Borrowed(key),
+                folded => Cow::Owned(folded),
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
 struct Edge {
-    target_node: usize,
+    target_node: Idx,
     label: String,
 }
 
 impl Edge {
-    fn new(target_node: usize, label: String) -> Self {
+    fn new(target_node: Idx, label: String) -> Self {
         Self { target_node, label }
     }
 }
 
+// Only leaves have values, a key ending at an inner node has its value
+// at the end of an empty edge
+#[derive(Clone)]
 struct Node<T> {
-    edges: Vec<usize>,
-    value: T,
-    is_leaf: bool,
+    edges: Vec<Idx>,
+    value: Option<T>,
+    // Key as inserted when folding its case changed it
+    key: Option<String>,
 }
 
 impl<T> Node<T> {
     fn new(value: T) -> Self {
         Self {
             edges: vec![],
-            value,
-            is_leaf: true,
+            value: Some(value),
+            key: None,
+        }
+    }
+
+    fn inner() -> Self {
+        Self {
+            edges: vec![],
+            value: None,
+            key: None,
         }
     }
+
+    fn is_leaf(&self) -> bool {
+        self.value.is_some()
+    }
 }
 
+// Where the lookup of a key stopped
 struct Ans {
     exists: bool,
+    // Number of matched key bytes
     count: usize,
+    // Last followed edge with the nodes on both of its ends
+    edge: Idx,
+    node: Idx,
+    parent: Idx,
+    // Edge followed before the last one, the one leading to `parent`
+    parent_edge: Idx,
+    // Edge from `node` whose label matches the rest of the key only
+    // partially, with the length of the matching part
+    split: Option<(Idx, usize)>,
+}
+
+// Index into an arena. A slot gets a new generation when it's freed, so an
+// index kept from before doesn't reach the value put in the slot later,
+// using it panics instead
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Idx {
+    slot: usize,
+    generation: u32,
 }
 
+// The root node and the edge to it are the first slots of their arenas
+// and are never freed
+const ROOT: Idx = Idx {
+    slot: 0,
+    generation: 0,
+};
+
+#[derive(Clone)]
 struct Arena<T> {
     arr: Vec<T>,
+    // Generation of every slot
+    generations: Vec<u32>,
+    // Free slots
     arr_idx: Vec<usize>,
 }
 
-impl<T> Index<usize> for Arena<T> {
+impl<T> Index<Idx> for Arena<T> {
     type Output = T;
 
-    fn index(&self, index: usize) -> &Self::Output {
-        &self.arr[index]
+    fn index(&self, index: Idx) -> &Self::Output {
+        assert_eq!(
+            self.generations[index.slot], index.generation,
+            "stale arena index"
+        );
+        &self.arr[index.slot]
     }
 }
 
-impl<T> IndexMut<usize> for Arena<T> {
-    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.arr[index]
+impl<T> IndexMut<Idx> for Arena<T> {
+    fn index_mut(&mut self, index: Idx) -> &mut Self::Output {
+        assert_eq!(
+            self.generations[index.slot], index.generation,
+            "stale arena index"
+        );
+        &mut self.arr[index.slot]
     }
 }
 
 impl<T> Arena<T> {
     fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    fn with_capacity(capacity: usize) -> Self {
         Self {
-            arr: vec![],
+            arr: Vec::with_capacity(capacity),
+            generations: Vec::with_capacity(capacity),
             arr_idx: vec![],
         }
     }
 
-    fn new_with_size(size: usize) -> Self {
-        let mut arr_idx = Vec::with_capacity(size);
-        for i in 0..size {
-            arr_idx[i] = i;
-        }
-        Self {
-            arr: Vec::with_capacity(size),
-            arr_idx,
-        }
+    // Room for `additional` more values on top of the free slots
+    fn reserve(&mut self, additional: usize) {
+        let needed = additional.saturating_sub(self.arr_idx.len());
+        self.arr.reserve(needed);
+        self.generations.reserve(needed);
     }
 
-    fn insert(&mut self, val: T) -> usize {
-        if self.arr_idx.is_empty() {
-            self.arr.push(val);
-            return self.arr.len() - 1;
-        } else {
-            self.arr[self.arr_idx[0]] = val;
-            let idx = self.arr_idx[0];
-            self.arr_idx.remove(0);
-            return idx;
+    fn insert(&mut self, val: T) -> Idx {
+        match self.arr_idx.pop() {
+            Some(slot) => {
+                self.arr[slot] = val;
+                Idx {
+                    slot,
+                    generation: self.generations[slot],
+                }
+            }
+            None => {
+                self.arr.push(val);
+                self.generations.push(0);
+                Idx {
+                    slot: self.arr.len() - 1,
+                    generation: 0,
+                }
+            }
         }
     }
 
-    fn is_empty(&self) -> bool {
-        self.arr.is_empty()
+    fn delete(&mut self, idx: Idx) {
+        assert_eq!(
+            self.generations[idx.slot], idx.generation,
+            "stale arena index"
+        );
+        self.generations[idx.slot] = idx.generation.wrapping_add(1);
+        self.arr_idx.push(idx.slot);
     }
 
-    fn delete(&mut self, idx: usize) {
-        //self.arr.remove(idx);
-        self.arr_idx.push(idx);
+    // Drop the free slots and move the rest down, returns the new index
+    // of every old slot, `None` for the free ones. Generations start over
+    fn compact(&mut self) -> Vec<Option<Idx>> {
+        let mut free = vec![false; self.arr.len()];
+        for &slot in self.arr_idx.iter() {
+            free[slot] = true;
+        }
+        let mut next = 0;
+        let remap: Vec<_> = free
+            .iter()
+            .map(|&free| {
+                if free {
+                    return None;
+                }
+                next += 1;
+                Some(Idx {
+                    slot: next - 1,
+                    generation: 0,
+                })
+            })
+            .collect();
+        let arr = std::mem::take(&mut self.arr);
+        self.arr = arr
+            .into_iter()
+            .zip(free.iter())
+            .filter(|(_, free)| !**free)
+            .map(|(val, _)| val)
+            .collect();
+        self.arr.shrink_to_fit();
+        self.generations = vec![0; self.arr.len()];
+        self.arr_idx = vec![];
+        remap
+    }
+}
+
+// Iterator over the keys and values of the tree, the keys are built from
+// the edge labels along the path to each leaf. Edges are sorted, so with
+// the char boundary the keys come in the increasing order, with the
+// grapheme one they are compared grapheme by grapheme
+pub struct Iter<'a, T> {
+    tree: &'a RadixTree<T>,
+    stack: Vec<(Idx, String)>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (String, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node_idx, key)) = self.stack.pop() {
+            let node = &self.tree.nodes[node_idx];
+            if let Some(value) = &node.value {
+                return Some((node.key.clone().unwrap_or(key), value));
+            }
+            for &e_idx in node.edges.iter().rev() {
+                let edge = &self.tree.edges[e_idx];
+                self.stack
+                    .push((edge.target_node, key.clone() + &edge.label));
+            }
+        }
+        None
     }
 }
 
+// Memory held by a tree, see `RadixTree::memory_usage`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryUsage {
+    // Slots allocated in the node and edge arenas
+    pub node_capacity: usize,
+    pub edge_capacity: usize,
+    // Slots on the free lists, waiting to be reused
+    pub free_nodes: usize,
+    pub free_edges: usize,
+    // Bytes of the edge labels, as allocated
+    pub label_bytes: usize,
+    // Size of a node and an edge slot, without what they point to
+    pub node_size: usize,
+    pub edge_size: usize,
+    // Everything together, including the lists of edges of the nodes, the
+    // free lists and the generations of the slots
+    pub total: usize,
+}
+
+#[derive(Clone)]
 pub struct RadixTree<T> {
     nodes: Arena<Node<T>>,
     edges: Arena<Edge>,
+    boundary: Boundary,
+    case: Case,
+    // Number of keys, the arenas also hold inner nodes and free slots
+    len: usize,
 }
 
-impl<T: std::default::Default + std::fmt::Debug + std::clone::Clone> RadixTree<T> {
+impl<T> Default for RadixTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> RadixTree<T> {
     pub fn new() -> Self {
+        Self::with_boundary(Boundary::Char)
+    }
+
+    pub fn with_boundary(boundary: Boundary) -> Self {
+        Self::with_options(boundary, Case::Sensitive)
+    }
+
+    pub fn with_case(case: Case) -> Self {
+        Self::with_options(Boundary::Char, case)
+    }
+
+    pub fn with_options(boundary: Boundary, case: Case) -> Self {
+        Self::with_arenas(Arena::new(), Arena::new(), boundary, case)
+    }
+
+    // Room for `nodes` nodes and `edges` edges, the root and the edge to it
+    // included. A key takes a leaf and an edge, and at most one more inner
+    // node and edge when it splits a label or goes past another key
+    pub fn with_capacity(nodes: usize, edges: usize) -> Self {
+        Self::with_arenas(
+            Arena::with_capacity(nodes),
+            Arena::with_capacity(edges),
+            Boundary::Char,
+            Case::Sensitive,
+        )
+    }
+
+    fn with_arenas(
+        nodes: Arena<Node<T>>,
+        edges: Arena<Edge>,
+        boundary: Boundary,
+        case: Case,
+    ) -> Self {
         let mut radix_tree = Self {
-            nodes: Arena::<Node<T>>::new(),
-            edges: Arena::<Edge>::new(),
+            nodes,
+            edges,
+            boundary,
+            case,
+            len: 0,
         };
-        let mut zero_node = Node::new(T::default());
-        zero_node.is_leaf = false;
-        let zero_node_idx = radix_tree.nodes.insert(zero_node);
+        let zero_node_idx = radix_tree.nodes.insert(Node::inner());
         radix_tree
             .edges
             .insert(Edge::new(zero_node_idx, "".to_string()));
         radix_tree
     }
 
-    fn common_prefix(&self, first_str: &str, second_str: &str) -> Option<String> {
-        let matching = first_str
-            .chars()
-            .zip(second_str.chars())
+    fn units<'a>(&self, s: &'a str) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+        match self.boundary {
+            Boundary::Char => Box::new(s.char_indices().map(move |(i, c)| &s[i..i + c.len_utf8()])),
+            #[cfg(feature = "graphemes")]
+            Boundary::Grapheme => Box::new(s.graphemes(true)),
+        }
+    }
+
+    fn fold<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        self.case.fold(key)
+    }
+
+    // Length in bytes of the common prefix, it never ends inside a unit
+    fn common_prefix(&self, first_str: &str, second_str: &str) -> usize {
+        self.units(first_str)
+            .zip(self.units(second_str))
             .take_while(|&(a, b)| a == b)
-            .count();
-        if matching > 0 {
-            return Some(first_str[..matching].to_string());
+            .map(|(a, _)| a.len())
+            .sum()
+    }
+
+    // Edge of the node starting with the same unit as `rest`, or the empty
+    // one if `rest` is empty
+    fn next_edge(&self, node_idx: Idx, rest: &str) -> Option<Idx> {
+        let first = self.units(rest).next();
+        self.nodes[node_idx].edges.iter().copied().find(|&e_idx| {
+            let label = &self.edges[e_idx].label;
+            self.units(label).next() == first
+        })
+    }
+
+    fn lookup(&self, key: &str) -> Ans {
+        let mut ans = Ans {
+            exists: false,
+            count: 0,
+            edge: ROOT,
+            node: ROOT,
+            parent: ROOT,
+            parent_edge: ROOT,
+            split: None,
+        };
+        while !self.nodes[ans.node].is_leaf() {
+            let node_idx = ans.node;
+            // Labels of the edges of a node start with different units,
+            // the empty label leads to the value of the key ending here
+            //      "test"
+            //      /    \
+            //    ""     "ing"
+            let rest = &key[ans.count..];
+            let e_idx = match self.next_edge(node_idx, rest) {
+                Some(e_idx) => e_idx,
+                None => return ans,
+            };
+            let label = &self.edges[e_idx].label;
+            let common = self.common_prefix(rest, label);
+            if common < label.len() {
+                // The key goes on past a split point inside the label
+                ans.split = Some((e_idx, common));
+                return ans;
+            }
+            ans.count += label.len();
+            ans.parent_edge = ans.edge;
+            ans.edge = e_idx;
+            ans.parent = node_idx;
+            ans.node = self.edges[e_idx].target_node;
         }
-        None
+        ans.exists = ans.count == key.len();
+        ans
     }
 
-    fn lookup(&mut self, key: String) -> (Ans, usize, usize) {
-        let mut idx = 0;
-        let mut node_idx = 0;
-        let mut prev_node_idx = 0;
-        let mut count = 0;
-        let mut found = true;
-        while found && !self.nodes[node_idx].is_leaf && count <= key.len() {
-            found = false;
+    // Merge every inner node with a single edge into the edge leading to
+    // it. `remove` already does it for the nodes it touches, so lookups
+    // never have to and only need a shared reference
+    pub fn compress(&mut self) {
+        let mut stack = vec![ROOT];
+        while let Some(node_idx) = stack.pop() {
             for e_idx in self.nodes[node_idx].edges.clone() {
-                {
+                loop {
                     let target_node_idx = self.edges[e_idx].target_node;
-                    // lazy prefix compression
-                    if self.nodes[target_node_idx].edges.len() == 1 {
-                        let compressed_edge = self.nodes[target_node_idx].edges[0];
-                        let label = self.edges[compressed_edge].label.clone();
-                        self.edges[e_idx].label = self.edges[e_idx].label.clone() + &label;
-                        self.nodes.delete(target_node_idx);
-                        self.edges[e_idx].target_node = self.edges[compressed_edge].target_node;
-                        self.edges.delete(compressed_edge);
+                    let target = &self.nodes[target_node_idx];
+                    if target.edges.len() != 1 || target.value.is_some() {
+                        break;
                     }
+                    let compressed_edge = target.edges[0];
+                    let label = self.edges[compressed_edge].label.clone();
+                    self.edges[e_idx].label.push_str(&label);
+                    self.edges[e_idx].target_node = self.edges[compressed_edge].target_node;
+                    self.nodes.delete(target_node_idx);
+                    self.edges.delete(compressed_edge);
                 }
+                stack.push(self.edges[e_idx].target_node);
+            }
+        }
+    }
+
+    pub fn find(&self, key: String) -> Option<&T> {
+        self.get_key_value(&key).map(|(_, value)| value)
+    }
+
+    // The key as it was inserted with its value, differs from `key` only
+    // by the case when the tree folds it
+    pub fn get_key_value(&self, key: &str) -> Option<(String, &T)> {
+        trace_span!("radix.find", key_len = key.len());
+        let key = self.fold(key);
+        let ans = self.lookup(&key);
+        if !ans.exists {
+            return None;
+        }
+        let node = &self.nodes[ans.node];
+        let value = node.value.as_ref()?;
+        Some((node.key.clone().unwrap_or_else(|| key.into_owned()), value))
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut T> {
+        let ans = self.lookup(&self.fold(key));
+        if !ans.exists {
+            return None;
+        }
+        self.nodes[ans.node].value.as_mut()
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            tree: self,
+            stack: vec![(ROOT, String::new())],
+        }
+    }
+
+    // Visit the edges depth first in the order of the keys. The visitor
+    // gets the depth of the edge, 1 for the edges of the root, the key up
+    // to the end of the edge, its label and the value at its end, `None`
+    // for an inner node. Keys are the ones in the tree, folded when the
+    // tree folds the case. Returning false skips everything below the edge
+    pub fn walk<F>(&self, mut visitor: F)
+    where
+        F: FnMut(usize, &str, &str, Option<&T>) -> bool,
+    {
+        let mut stack: Vec<_> = self.nodes[ROOT]
+            .edges
+            .iter()
+            .rev()
+            .map(|&e_idx| (1, String::new(), e_idx))
+            .collect();
+        while let Some((depth, mut key, e_idx)) = stack.pop() {
+            let edge = &self.edges[e_idx];
+            let node = &self.nodes[edge.target_node];
+            key.push_str(&edge.label);
+            if visitor(depth, &key, &edge.label, node.value.as_ref()) {
+                for &child in node.edges.iter().rev() {
+                    stack.push((depth + 1, key.clone(), child));
+                }
+            }
+        }
+    }
+
+    // Pairs with keys in the range, like `tree.range("b".."d")`
+    pub fn range<'r, R: RangeBounds<&'r str>>(&self, range: R) -> Range<'_, T> {
+        let bound = |bound: Bound<&&str>| match bound {
+            Bound::Included(s) => Bound::Included(self.fold(s).into_owned()),
+            Bound::Excluded(s) => Bound::Excluded(self.fold(s).into_owned()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        Range {
+            tree: self,
+            stack: vec![(ROOT, String::new())],
+            start: bound(range.start_bound()),
+            end: bound(range.end_bound()),
+        }
+    }
+
+    // Pairs with keys starting with `prefix`, only the subtree below the
+    // end of the prefix is visited
+    pub fn find_prefix(&self, prefix: &str) -> Iter<'_, T> {
+        let prefix = &*self.fold(prefix);
+        let mut node_idx = ROOT;
+        let mut key = String::new();
+        let mut stack = vec![];
+        loop {
+            let rest = &prefix[key.len()..];
+            if rest.is_empty() {
+                stack.push((node_idx, key));
+                break;
+            }
+            let edge = match self.next_edge(node_idx, rest) {
+                Some(e_idx) => &self.edges[e_idx],
+                None => break,
+            };
+            let common = self.common_prefix(rest, &edge.label);
+            if common == rest.len() {
+                // The prefix ends inside the label, all keys below match
+                stack.push((edge.target_node, key + &edge.label));
+                break;
+            }
+            if common < edge.label.len() {
+                break;
+            }
+            key += &edge.label;
+            node_idx = edge.target_node;
+        }
+        Iter { tree: self, stack }
+    }
+
+    // First `k` pairs with keys starting with `prefix` in the key order,
+    // the rest of the subtree isn't visited
+    pub fn complete(&self, prefix: &str, k: usize) -> Vec<(String, &T)> {
+        self.find_prefix(prefix).take(k).collect()
+    }
+
+    // Keys within the Levenshtein distance `max_distance` of the query,
+    // counted in chars, with their values and distances. Closest first,
+    // in the key order for the same distance. Subtrees no match can be in
+    // are never visited
+    pub fn approximate_matches(
+        &self,
+        query: &str,
+        max_distance: usize,
+    ) -> Vec<(String, &T, usize)> {
+        let query: Vec<char> = self.fold(query).chars().collect();
+        let mut matches = vec![];
+        let mut stack = vec![(ROOT, String::new(), (0..=query.len()).collect::<Vec<_>>())];
+        while let Some((node_idx, key, row)) = stack.pop() {
+            let node = &self.nodes[node_idx];
+            if let Some(value) = &node.value {
+                let distance = row[query.len()];
+                if distance <= max_distance {
+                    let key = node.key.clone().unwrap_or_else(|| key.clone());
+                    matches.push((key, value, distance));
+                }
+            }
+            'edges: for &e_idx in node.edges.iter().rev() {
                 let edge = &self.edges[e_idx];
-                // if the label have a prefix of a suffix of the key
-                // example: looking for the word "testing" when we already
-                // have "test", "tests", "testing"
-                //      "test"
-                //      /    \
-                //   "s"     "ing"
-                if &edge.label != "" && key[count..].starts_with(&edge.label) {
-                    //println!("Key: {}, label: {}", &key[count..], &edge.label);
-                    count += edge.label.len();
-                    idx = e_idx;
-                    found = true;
-                    break;
-                } else {
-                    // in a case when a label might be longer we need to consider to split the node
-                    // if there is a common prefix > 0
-                    if let Some(cp) = self.common_prefix(&key[count..], &edge.label) {
-                        println!("{}, {}", cp.len(), count);
-                        // TODO: make it more clear
-                        count += key.len();
-                        idx = e_idx;
-                        break;
-                    } else if &edge.label == "" && count == key.len() {
-                        idx = e_idx;
-                        found = true;
-                        break;
+                let mut row = row.clone();
+                for c in edge.label.chars() {
+                    // Next row of the Levenshtein table after reading `c`
+                    let mut next = vec![row[0] + 1];
+                    for (i, q) in query.iter().enumerate() {
+                        let replace = row[i] + (*q != c) as usize;
+                        next.push(replace.min(row[i + 1] + 1).min(next[i] + 1));
+                    }
+                    // Distances never go down further along the keys, so
+                    // the smallest one bounds every match below
+                    if *next.iter().min().unwrap() > max_distance {
+                        continue 'edges;
                     }
+                    row = next;
                 }
+                stack.push((edge.target_node, key.clone() + &edge.label, row));
             }
-            if found {
-                prev_node_idx = node_idx;
-                node_idx = self.edges[idx].target_node;
-            }
-        }
-        // if exact same key was found
-        //println!("{}, {}, {}, {}, {}, {}", idx, self.edges[idx].target_node, count, key.len(),
-        //self.nodes[self.edges[idx].target_node].is_leaf, found);
-        if self.nodes[node_idx].is_leaf && count == key.len() {
-            return (
-                Ans {
-                    exists: true,
-                    count,
-                },
-                idx,
-                prev_node_idx,
-            );
         }
-        (
-            Ans {
-                exists: false,
-                count,
-            },
-            idx,
-            prev_node_idx,
-        )
+        // Stable, the keys were found in their order
+        matches.sort_by_key(|&(_, _, distance)| distance);
+        matches
     }
 
-    pub fn find(&mut self, key: String) -> Option<&T> {
-        let (ans, idx, _) = self.lookup(key);
+    // Longest stored key that is a prefix of the query with its value
+    pub fn longest_match(&self, query: &str) -> Option<(String, &T)> {
+        let query = self.fold(query);
+        let mut node_idx = ROOT;
+        let mut len = 0;
+        let mut best = None;
+        loop {
+            let node = &self.nodes[node_idx];
+            if node.value.is_some() {
+                best = Some((len, node));
+                break;
+            }
+            let rest = &query[len..];
+            let mut next = None;
+            for &e_idx in node.edges.iter() {
+                let edge = &self.edges[e_idx];
+                if edge.label.is_empty() {
+                    // A key ends here
+                    best = Some((len, &self.nodes[edge.target_node]));
+                } else if self.common_prefix(rest, &edge.label) == edge.label.len() {
+                    next = Some(edge);
+                }
+            }
+            match next {
+                Some(edge) => {
+                    len += edge.label.len();
+                    node_idx = edge.target_node;
+                }
+                None => break,
+            }
+        }
+        let (len, node) = best?;
+        let key = node.key.clone().unwrap_or_else(|| query[..len].to_string());
+        Some((key, node.value.as_ref()?))
+    }
+
+    // Give the memory of the freed arena slots back. Slots are renumbered,
+    // so the edge and node indices are fixed up, live indices only point
+    // to live slots
+    pub fn shrink_to_fit(&mut self) {
+        let node_remap = self.nodes.compact();
+        let edge_remap = self.edges.compact();
+        for node in self.nodes.arr.iter_mut() {
+            for e_idx in node.edges.iter_mut() {
+                *e_idx = edge_remap[e_idx.slot].unwrap();
+            }
+            node.edges.shrink_to_fit();
+        }
+        for edge in self.edges.arr.iter_mut() {
+            edge.target_node = node_remap[edge.target_node.slot].unwrap();
+            edge.label.shrink_to_fit();
+        }
+    }
+
+    // Bytes allocated by the arenas and the labels, the values count only
+    // with their size, not with the heap memory they may own
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let index_size = std::mem::size_of::<Idx>();
+        let node_size = std::mem::size_of::<Node<T>>();
+        let edge_size = std::mem::size_of::<Edge>();
+        let label_bytes = self.edges.arr.iter().map(|e| e.label.capacity()).sum();
+        let edge_lists: usize = self.nodes.arr.iter().map(|n| n.edges.capacity()).sum();
+        let free_lists = self.nodes.arr_idx.capacity() + self.edges.arr_idx.capacity();
+        let generations = self.nodes.generations.capacity() + self.edges.generations.capacity();
+        MemoryUsage {
+            node_capacity: self.nodes.arr.capacity(),
+            edge_capacity: self.edges.arr.capacity(),
+            free_nodes: self.nodes.arr_idx.len(),
+            free_edges: self.edges.arr_idx.len(),
+            label_bytes,
+            node_size,
+            edge_size,
+            total: std::mem::size_of::<Self>()
+                + self.nodes.arr.capacity() * node_size
+                + self.edges.arr.capacity() * edge_size
+                + label_bytes
+                + edge_lists * index_size
+                + free_lists * std::mem::size_of::<usize>()
+                + generations * std::mem::size_of::<u32>(),
+        }
+    }
+
+    // Room for at least `nodes` more nodes and `edges` more edges, freed
+    // slots count as room
+    pub fn reserve(&mut self, nodes: usize, edges: usize) {
+        self.nodes.reserve(nodes);
+        self.edges.reserve(edges);
+    }
+
+    // Number of keys
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Remove the key and return its value, `None` if it wasn't there
+    pub fn remove(&mut self, key: String) -> Option<T> {
+        trace_span!("radix.remove", key_len = key.len());
+        let ans = self.lookup(&self.fold(&key));
+        if !ans.exists {
+            return None;
+        }
+        // The freed slot keeps its node until it's reused, so the value is
+        // moved out of it
+        let value = self.nodes[ans.node].value.take();
+        self.len -= 1;
+        self.edges.delete(ans.edge);
+        self.nodes.delete(ans.node);
+        self.nodes[ans.parent].edges.retain(|&x| x != ans.edge);
+        self.merge_single_edge(ans.parent, ans.parent_edge);
+        value
+    }
+
+    // Inner nodes other than the root have at least two edges, when a node
+    // is left with one it merges into the edge leading to it.
+    // Deleting "team":
+    //      "te"            "test"
+    //     /    \      =>     |
+    //  "st"    "am"
+    fn merge_single_edge(&mut self, node_idx: Idx, in_edge: Idx) {
+        if node_idx != ROOT && self.nodes[node_idx].edges.len() == 1 {
+            let child_edge = self.nodes[node_idx].edges[0];
+            let label = self.edges[child_edge].label.clone();
+            self.edges[in_edge].label.push_str(&label);
+            self.edges[in_edge].target_node = self.edges[child_edge].target_node;
+            self.edges.delete(child_edge);
+            self.nodes.delete(node_idx);
+        }
+    }
+
+    // Remove all keys starting with `prefix`, returns how many there were.
+    // The edge the prefix ends on is cut off and every slot below it is
+    // freed
+    pub fn remove_prefix(&mut self, prefix: &str) -> usize {
+        let prefix = self.fold(prefix).into_owned();
+        if prefix.is_empty() {
+            let count = self.len;
+            for e_idx in std::mem::take(&mut self.nodes[ROOT].edges) {
+                self.free_subtree(e_idx);
+            }
+            return count;
+        }
+        let mut node_idx = ROOT;
+        let mut in_edge = ROOT;
+        let mut len = 0;
+        loop {
+            let rest = &prefix[len..];
+            let e_idx = match self.next_edge(node_idx, rest) {
+                Some(e_idx) => e_idx,
+                None => return 0,
+            };
+            let label = &self.edges[e_idx].label;
+            let common = self.common_prefix(rest, label);
+            if common == rest.len() {
+                // All keys below the edge start with the prefix
+                self.nodes[node_idx].edges.retain(|&x| x != e_idx);
+                let count = self.free_subtree(e_idx);
+                self.merge_single_edge(node_idx, in_edge);
+                return count;
+            }
+            if common < label.len() {
+                return 0;
+            }
+            len += label.len();
+            in_edge = e_idx;
+            node_idx = self.edges[e_idx].target_node;
+        }
+    }
+
+    // Free the edge with everything below it, returns the number of keys
+    fn free_subtree(&mut self, e_idx: Idx) -> usize {
+        let mut count = 0;
+        let mut stack = vec![e_idx];
+        while let Some(e_idx) = stack.pop() {
+            let node_idx = self.edges[e_idx].target_node;
+            let node = &mut self.nodes[node_idx];
+            if node.value.take().is_some() {
+                count += 1;
+            }
+            stack.append(&mut node.edges);
+            node.key = None;
+            self.nodes.delete(node_idx);
+            self.edges.delete(e_idx);
+        }
+        self.len -= count;
+        count
+    }
+
+    // Edges of a node are sorted by the first unit of their labels, which
+    // stays the same when a label is split or merged
+    fn add_edge(&mut self, node_idx: Idx, label: String, target_node_idx: Idx) {
+        let first = self.units(&label).next();
+        let pos = match self.nodes[node_idx]
+            .edges
+            .binary_search_by(|&e_idx| self.units(&self.edges[e_idx].label).next().cmp(&first))
+        {
+            Ok(pos) | Err(pos) => pos,
+        };
+        let edge_idx = self.edges.insert(Edge::new(target_node_idx, label));
+        self.nodes[node_idx].edges.insert(pos, edge_idx);
+    }
+
+    fn add_leaf(&mut self, node_idx: Idx, label: String, val: T) -> Idx {
+        let leaf_idx = self.nodes.insert(Node::new(val));
+        self.add_edge(node_idx, label, leaf_idx);
+        leaf_idx
+    }
+
+    // Inserting an existing key replaces its value and returns the old
+    // one, the key keeps the case it was first inserted with
+    pub fn insert(&mut self, key: String, val: T) -> Option<T> {
+        trace_span!("radix.insert", key_len = key.len());
+        let folded = self.fold(&key);
+        let ans = self.lookup(&folded);
         if ans.exists {
-            return Some(&self.nodes[self.edges[idx].target_node].value);
+            return self.nodes[ans.node].value.replace(val);
+        }
+        self.len += 1;
+        let leaf_idx = self.insert_at(&ans, &folded, val);
+        if folded != key {
+            self.nodes[leaf_idx].key = Some(key);
         }
         None
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.edges.is_empty()
+    // Add the leaf of a new key where its lookup stopped
+    fn insert_at(&mut self, ans: &Ans, key: &str, val: T) -> Idx {
+        if let Some((e_idx, prefix_count)) = ans.split {
+            // case when we have to split the edge using common prefix
+            let label = self.edges[e_idx].label.clone();
+            trace_event!(
+                node = "edge",
+                depth = ans.count,
+                matched = prefix_count,
+                "split"
+            );
+            let mut split_node = Node::inner();
+            let target_node_idx = self.edges[e_idx].target_node;
+            let edge_left_idx = self.edges.insert(Edge::new(
+                target_node_idx,
+                label[prefix_count..].to_string(),
+            ));
+            split_node.edges.push(edge_left_idx);
+            let split_node_idx = self.nodes.insert(split_node);
+            self.edges[e_idx].label = label[..prefix_count].to_string();
+            self.edges[e_idx].target_node = split_node_idx;
+            let suffix = key[ans.count + prefix_count..].to_string();
+            return self.add_leaf(split_node_idx, suffix, val);
+        }
+        let mut target_node_idx = ans.node;
+        if self.nodes[target_node_idx].is_leaf() {
+            // case when the key goes past a leaf, the leaf moves down to
+            // the end of an empty edge from a new inner node
+            let inner_idx = self.nodes.insert(Node::inner());
+            self.edges[ans.edge].target_node = inner_idx;
+            self.add_edge(inner_idx, "".to_string(), target_node_idx);
+            target_node_idx = inner_idx;
+        }
+        self.add_leaf(target_node_idx, key[ans.count..].to_string(), val)
+    }
+
+    // Same as `insert`, `find` and `remove` with keys of any type the codec
+    // encodes. The tree holds strings, a key the codec doesn't write as
+    // UTF-8 can't be inserted and is never found
+    pub fn try_insert_with<Q: ?Sized, C: KeyCodec<Q>>(
+        &mut self,
+        codec: &C,
+        key: &Q,
+        val: T,
+    ) -> Result<Option<T>, Error> {
+        Ok(self.insert(String::from_utf8(codec.to_bytes(key))?, val))
+    }
+
+    pub fn find_with<Q: ?Sized, C: KeyCodec<Q>>(&self, codec: &C, key: &Q) -> Option<&T> {
+        self.find(String::from_utf8(codec.to_bytes(key)).ok()?)
+    }
+
+    pub fn remove_with<Q: ?Sized, C: KeyCodec<Q>>(&mut self, codec: &C, key: &Q) -> Option<T> {
+        self.remove(String::from_utf8(codec.to_bytes(key)).ok()?)
+    }
+}
+
+impl<V: Clone> RadixTree<V> {
+    // Build the tree straight from the nodes of a trie of chars. Chains of
+    // trie nodes without a value and with a single child become one edge
+    pub fn from_trie(trie: &TrieMap<char, V>) -> Self {
+        let mut tree = Self::new();
+        if let Some(value) = &trie.value {
+            tree.len += 1;
+            tree.add_leaf(ROOT, String::new(), value.clone());
+        }
+        let mut stack = vec![(trie, ROOT)];
+        while let Some((trie_node, node_idx)) = stack.pop() {
+            for (&c, mut child) in trie_node.next.iter() {
+                let mut label = c.to_string();
+                while child.value.is_none() && child.next.len() == 1 {
+                    let (&c, next) = child.next.iter().next().unwrap();
+                    label.push(c);
+                    child = next;
+                }
+                if child.next.is_empty() {
+                    if let Some(value) = &child.value {
+                        tree.len += 1;
+                        tree.add_leaf(node_idx, label, value.clone());
+                    }
+                    continue;
+                }
+                let inner_idx = tree.nodes.insert(Node::inner());
+                tree.add_edge(node_idx, label, inner_idx);
+                if let Some(value) = &child.value {
+                    tree.len += 1;
+                    tree.add_leaf(inner_idx, String::new(), value.clone());
+                }
+                stack.push((child, inner_idx));
+            }
+        }
+        tree
+    }
+
+    // Trie of the keys as inserted, one node per char
+    pub fn to_trie(&self) -> TrieMap<char, V> {
+        let mut trie = TrieMap::new();
+        for (key, value) in self.iter() {
+            trie.insert(&mut key.chars(), value.clone());
+        }
+        trie
+    }
+}
+
+impl<V: Clone> From<&TrieMap<char, V>> for RadixTree<V> {
+    fn from(trie: &TrieMap<char, V>) -> Self {
+        Self::from_trie(trie)
+    }
+}
+
+impl<V: Clone> From<&RadixTree<V>> for TrieMap<char, V> {
+    fn from(tree: &RadixTree<V>) -> Self {
+        tree.to_trie()
+    }
+}
+
+// Binary dump of a tree, the live nodes in preorder without any indices:
+//     MAGIC | u8 boundary | u8 case | node
+// where a node is
+//     u8 flags | value? | key? | varint edge_count | (label, node)*
+// Bit 0 of the flags says there is a value, written with its codec as
+//     varint len | bytes
+// bit 1 that there is a key as inserted, and labels and keys are written
+// the same way. Loading numbers the nodes and edges in the file order
+const MAGIC: &[u8; 8] = b"radixtr1";
+
+fn corrupt(msg: &str) -> Error {
+    Error::Corrupt(msg.to_string())
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn read_bytes<'a>(buf: &'a [u8], pos: &mut usize) -> Result<&'a [u8], Error> {
+    let truncated = || corrupt("truncated radix tree");
+    let len = read_varint(buf, pos).ok_or_else(truncated)? as usize;
+    let end = pos.checked_add(len).ok_or_else(truncated)?;
+    let bytes = buf.get(*pos..end).ok_or_else(truncated)?;
+    *pos = end;
+    Ok(bytes)
+}
+
+fn read_string(buf: &[u8], pos: &mut usize) -> Result<String, Error> {
+    Ok(String::from_utf8(read_bytes(buf, pos)?.to_vec())?)
+}
+
+// Node record of a dump with the number of its edges
+fn read_node<T: Codec>(buf: &[u8], pos: &mut usize) -> Result<(Node<T>, usize), Error> {
+    let bad_node = || corrupt("bad radix tree node");
+    let flags = *buf.get(*pos).ok_or_else(bad_node)?;
+    *pos += 1;
+    if flags > 3 {
+        return Err(bad_node());
+    }
+    let mut node = Node::inner();
+    if flags & 1 != 0 {
+        let value = T::decode(read_bytes(buf, pos)?).ok_or_else(|| corrupt("bad value"))?;
+        node.value = Some(value);
+    }
+    if flags & 2 != 0 {
+        node.key = Some(read_string(buf, pos)?);
+    }
+    let edge_count = read_varint(buf, pos).ok_or_else(bad_node)? as usize;
+    if node.value.is_some() && edge_count > 0 {
+        return Err(bad_node());
+    }
+    Ok((node, edge_count))
+}
+
+impl<T: Codec> RadixTree<T> {
+    pub fn save<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut buf = MAGIC.to_vec();
+        buf.push(match self.boundary {
+            Boundary::Char => 0,
+            #[cfg(feature = "graphemes")]
+            Boundary::Grapheme => 1,
+        });
+        buf.push(match self.case {
+            Case::Sensitive => 0,
+            Case::Ascii => 1,
+            #[cfg(feature = "unicode-case")]
+            Case::Unicode => 2,
+        });
+        let mut stack = vec![(None, ROOT)];
+        while let Some((label, node_idx)) = stack.pop() {
+            if let Some(label) = label {
+                write_bytes(&mut buf, label);
+            }
+            let node = &self.nodes[node_idx];
+            let flags = node.value.is_some() as u8 | (node.key.is_some() as u8) << 1;
+            buf.push(flags);
+            if let Some(value) = &node.value {
+                let mut bytes = vec![];
+                value.encode(&mut bytes);
+                write_bytes(&mut buf, &bytes);
+            }
+            if let Some(key) = &node.key {
+                write_bytes(&mut buf, key.as_bytes());
+            }
+            write_varint(&mut buf, node.edges.len() as u64);
+            for &e_idx in node.edges.iter().rev() {
+                let edge = &self.edges[e_idx];
+                stack.push((Some(edge.label.as_bytes()), edge.target_node));
+            }
+        }
+        writer.write_all(&buf)?;
+        writer.flush()
+    }
+
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.save(io::BufWriter::new(File::create(path)?))
+    }
+
+    // Load a tree written by `save`, its arenas have no free slots
+    pub fn load<R: Read>(mut reader: R) -> Result<Self, Error> {
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf)?;
+        if buf.len() < MAGIC.len() + 2 || &buf[..MAGIC.len()] != MAGIC {
+            return Err(corrupt("bad radix tree magic"));
+        }
+        let boundary = match buf[MAGIC.len()] {
+            0 => Boundary::Char,
+            #[cfg(feature = "graphemes")]
+            1 => Boundary::Grapheme,
+            _ => return Err(corrupt("unsupported radix tree boundary")),
+        };
+        let case = match buf[MAGIC.len() + 1] {
+            0 => Case::Sensitive,
+            1 => Case::Ascii,
+            #[cfg(feature = "unicode-case")]
+            2 => Case::Unicode,
+            _ => return Err(corrupt("unsupported radix tree case")),
+        };
+        let mut tree = Self::with_options(boundary, case);
+        let mut pos = MAGIC.len() + 2;
+        let bad_node = || corrupt("bad radix tree node");
+        let (root, edge_count) = read_node::<T>(&buf, &mut pos)?;
+        if root.value.is_some() {
+            return Err(bad_node());
+        }
+        // Nodes with the number of their edges still to read
+        let mut stack = vec![(ROOT, edge_count)];
+        while let Some((parent, left)) = stack.last_mut() {
+            if *left == 0 {
+                stack.pop();
+                continue;
+            }
+            *left -= 1;
+            let parent = *parent;
+            let label = read_string(&buf, &mut pos)?;
+            let (node, edge_count) = read_node(&buf, &mut pos)?;
+            if node.value.is_none() && edge_count == 0 {
+                return Err(bad_node());
+            }
+            tree.len += node.value.is_some() as usize;
+            let node_idx = tree.nodes.insert(node);
+            let e_idx = tree.edges.insert(Edge::new(node_idx, label));
+            tree.nodes[parent].edges.push(e_idx);
+            stack.push((node_idx, edge_count));
+        }
+        if pos != buf.len() {
+            return Err(corrupt("trailing bytes after the radix tree"));
+        }
+        Ok(tree)
+    }
+
+    pub fn load_from<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::load(io::BufReader::new(File::open(path)?))
     }
+}
 
+impl<T: std::fmt::Debug> RadixTree<T> {
+    // Write the tree in the Graphviz DOT format, e.g. for `dot -Tsvg`.
+    // Nodes with a value show it, inner nodes are points and the edges
+    // carry their labels, the empty one shows as ""
+    pub fn to_dot<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "digraph radix {{")?;
+        writeln!(writer, "    node [shape=box];")?;
+        let mut stack = vec![ROOT];
+        while let Some(node_idx) = stack.pop() {
+            let node = &self.nodes[node_idx];
+            match &node.value {
+                Some(value) => {
+                    let value = format!("{:?}", value);
+                    writeln!(writer, "    n{} [label={:?}];", node_idx.slot, value)?;
+                }
+                None => writeln!(writer, "    n{} [shape=point];", node_idx.slot)?,
+            }
+            for &e_idx in node.edges.iter() {
+                let edge = &self.edges[e_idx];
+                let label = format!("{:?}", edge.label);
+                writeln!(
+                    writer,
+                    "    n{} -> n{} [label={:?}];",
+                    node_idx.slot, edge.target_node.slot, label
+                )?;
+                stack.push(edge.target_node);
+            }
+        }
+        writeln!(writer, "}}")
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn print_nodes(&self) {
         let mut q = VecDeque::new();
-        q.push_front(0);
+        q.push_front(ROOT);
         while !q.is_empty() {
             let mut level_size = q.len();
             while level_size > 0 {
@@ -232,9 +1162,10 @@ impl<T: std::default::Default + std::fmt::Debug + std::clone::Clone> RadixTree<T
         }
     }
 
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn print_edges(&self) {
         let mut q = VecDeque::new();
-        q.push_front(0);
+        q.push_front(ROOT);
         while !q.is_empty() {
             let mut level_size = q.len();
             while level_size > 0 {
@@ -249,67 +1180,283 @@ impl<T: std::default::Default + std::fmt::Debug + std::clone::Clone> RadixTree<T
             println!();
         }
     }
+}
 
-    pub fn delete(&mut self, key: String) {
-        let (ans, idx, node_idx) = self.lookup(key);
-        if ans.exists {
-            let target_node_idx = self.edges[idx].target_node;
-            self.edges.delete(idx);
-            self.nodes.delete(target_node_idx);
-            self.nodes[node_idx].edges.retain(|&x| x != idx);
+// Iterator over the pairs with keys in a range, in the order of `Iter`.
+// Subtrees all of whose keys are out of the range are skipped
+pub struct Range<'a, T> {
+    tree: &'a RadixTree<T>,
+    stack: Vec<(Idx, String)>,
+    start: Bound<String>,
+    end: Bound<String>,
+}
+
+impl<'a, T> Range<'a, T> {
+    // Keys below start with `prefix`, they are all smaller than the start
+    // when `prefix` is smaller without being a prefix of it
+    fn before_start(&self, prefix: &str) -> bool {
+        match &self.start {
+            Bound::Included(start) | Bound::Excluded(start) => {
+                prefix < start.as_str() && !start.starts_with(prefix)
+            }
+            Bound::Unbounded => false,
         }
     }
 
-    pub fn insert(&mut self, key: String, val: T) {
-        let (ans, idx, _) = self.lookup(key.clone());
-        let target_node_idx = self.edges[idx].target_node;
-        if !ans.exists {
-            if ans.count < key.len() {
-                // case when we have to add new node with suffix
-                let node_idx = self.nodes.insert(Node::new(val));
-                let edge_idx = self
-                    .edges
-                    .insert(Edge::new(node_idx, key[ans.count..].to_string()));
-                if self.nodes[target_node_idx].is_leaf {
-                    let node_idx = self
-                        .nodes
-                        .insert(Node::new(self.nodes[target_node_idx].value.clone()));
-                    self.nodes[target_node_idx].value = T::default();
-                    let edge_idx = self.edges.insert(Edge::new(node_idx, "".to_string()));
-                    self.nodes[target_node_idx].edges.push(edge_idx);
+    fn after_end(&self, prefix: &str) -> bool {
+        match &self.end {
+            Bound::Included(end) => prefix > end.as_str(),
+            Bound::Excluded(end) => prefix >= end.as_str(),
+            Bound::Unbounded => false,
+        }
+    }
+}
+
+impl<'a, T> Iterator for Range<'a, T> {
+    type Item = (String, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node_idx, key)) = self.stack.pop() {
+            if self.before_start(&key) || self.after_end(&key) {
+                continue;
+            }
+            let node = &self.tree.nodes[node_idx];
+            if let Some(value) = &node.value {
+                let below_start = match &self.start {
+                    Bound::Included(start) => key < *start,
+                    Bound::Excluded(start) => key <= *start,
+                    Bound::Unbounded => false,
+                };
+                if below_start {
+                    continue;
                 }
-                self.nodes[target_node_idx].edges.push(edge_idx);
-                self.nodes[target_node_idx].is_leaf = false;
-            } else {
-                // case when we have to split node using common prefix
-                //let split_node = self.nodes[target_node_idx].clone();
-                let mut split_node = Node::new(T::default());
-                split_node.is_leaf = false;
-                let label = self.edges[idx].label.clone();
-                let count = ans.count - key.len();
-                let prefix_count = self
-                    .common_prefix(&key[count..], &self.edges[idx].label)
-                    .unwrap()
-                    .len();
-                println!(
-                    "{}, {}",
-                    count,
-                    key[count..count + prefix_count].to_string()
-                );
+                return Some((node.key.clone().unwrap_or(key), value));
+            }
+            for &e_idx in node.edges.iter().rev() {
+                let edge = &self.tree.edges[e_idx];
+                self.stack
+                    .push((edge.target_node, key.clone() + &edge.label));
+            }
+        }
+        None
+    }
+}
+
+// A tree is written as a map of its keys to their values, so nothing of the
+// arenas gets saved and loading builds a compact tree. The keys are the
+// ones inserted, the boundary and the case aren't saved, a loaded tree
+// splits at chars and is case sensitive
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> serde::Serialize for RadixTree<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for RadixTree<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TreeVisitor<T>(std::marker::PhantomData<T>);
+
+        impl<'de, T: serde::Deserialize<'de>> serde::de::Visitor<'de> for TreeVisitor<T> {
+            type Value = RadixTree<T>;
 
-                self.edges[idx].label = key[count..count + prefix_count].to_string();
-                let edge_left = Edge::new(target_node_idx, label[prefix_count..].to_string());
-                let edge_left_idx = self.edges.insert(edge_left);
-                let new_node = Node::new(val);
-                let new_node_idx = self.nodes.insert(new_node);
-                let edge_right = Edge::new(new_node_idx, key[count + prefix_count..].to_string());
-                let edge_right_idx = self.edges.insert(edge_right);
-                split_node.edges.push(edge_left_idx);
-                split_node.edges.push(edge_right_idx);
-                let split_node_idx = self.nodes.insert(split_node);
-                self.edges[idx].target_node = split_node_idx;
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a map of strings to values")
             }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<Self::Value, A::Error> {
+                let mut tree = RadixTree::new();
+                while let Some((key, value)) = map.next_entry()? {
+                    tree.insert(key, value);
+                }
+                Ok(tree)
+            }
+        }
+
+        deserializer.deserialize_map(TreeVisitor(std::marker::PhantomData))
+    }
+}
+
+// Tree shared between threads. Keys are spread over shards by the hash of
+// the folded key, every shard holds a snapshot of its tree behind an `Arc`.
+// A read clones the `Arc` of one shard and looks the key up in it without
+// any lock held, so it never waits for a write, at most for another
+// thread swapping a pointer. A write copies the tree of its shard, changes
+// the copy and swaps it in, writers of one shard go one after another.
+// Writes cost a copy of the shard, more shards make them cheaper. A
+// writer that panics, in the tree or in the closure of `update`, only
+// loses its copy, the snapshot stays as it was
+pub struct SyncRadixTree<T> {
+    shards: Vec<Shard<T>>,
+    case: Case,
+}
+
+struct Shard<T> {
+    current: RwLock<Arc<RadixTree<T>>>,
+    writer: Mutex<()>,
+}
+
+impl<T> Default for SyncRadixTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> SyncRadixTree<T> {
+    pub fn new() -> Self {
+        Self::with_options(16, Boundary::Char, Case::Sensitive)
+    }
+
+    pub fn with_options(shards: usize, boundary: Boundary, case: Case) -> Self {
+        Self {
+            shards: (0..shards.max(1))
+                .map(|_| Shard {
+                    current: RwLock::new(Arc::new(RadixTree::with_options(boundary, case))),
+                    writer: Mutex::new(()),
+                })
+                .collect(),
+            case,
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Shard<T> {
+        let mut hasher = DefaultHasher::new();
+        self.case.fold(key).hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    // Snapshot of the shard, the lock is only held to clone the `Arc`. A
+    // panic can't happen while it's held, so a poisoned lock is still fine
+    fn load(shard: &Shard<T>) -> Arc<RadixTree<T>> {
+        shard
+            .current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        Self::load(self.shard(key)).get_key_value(key).is_some()
+    }
+
+    // Call `f` with the value of the key in the current snapshot
+    pub fn with<R>(&self, key: &str, f: impl FnOnce(&T) -> R) -> Option<R> {
+        Self::load(self.shard(key))
+            .get_key_value(key)
+            .map(|(_, value)| f(value))
+    }
+
+    // Number of keys, shards are counted one after another, so it's exact
+    // only while no one writes
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| Self::load(shard).len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T: Clone> SyncRadixTree<T> {
+    // Change a copy of the shard's tree and swap it in
+    fn write<R>(shard: &Shard<T>, f: impl FnOnce(&mut RadixTree<T>) -> R) -> R {
+        let _writer = shard.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut tree = Self::load(shard);
+        let result = f(Arc::make_mut(&mut tree));
+        *shard
+            .current
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = tree;
+        result
+    }
+
+    pub fn insert(&self, key: String, val: T) -> Option<T> {
+        Self::write(self.shard(&key), |tree| tree.insert(key, val))
+    }
+
+    pub fn remove(&self, key: &str) -> Option<T> {
+        Self::write(self.shard(key), |tree| tree.remove(key.to_string()))
+    }
+
+    // Call `f` with the value of the key in the copy a write swaps in
+    pub fn update<R>(&self, key: &str, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        Self::write(self.shard(key), |tree| tree.get_mut(key).map(f))
+    }
+
+    // Give the freed arena slots of every shard back
+    pub fn shrink_to_fit(&self) {
+        for shard in self.shards.iter() {
+            Self::write(shard, RadixTree::shrink_to_fit);
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<T> {
+        self.with(key, T::clone)
+    }
+
+    // Pairs with keys starting with `prefix` from all shards, in the order
+    // of the keys
+    pub fn find_prefix(&self, prefix: &str) -> Vec<(String, T)> {
+        let mut found = vec![];
+        for shard in self.shards.iter() {
+            let tree = Self::load(shard);
+            found.extend(
+                tree.find_prefix(prefix)
+                    .map(|(key, value)| (key, value.clone())),
+            );
         }
+        found.sort_by(|a, b| self.case.fold(&a.0).cmp(&self.case.fold(&b.0)));
+        found
+    }
+}
+
+// Assigns stable u32 symbols to strings, interning an equal string again
+// gives the same symbol. The tree finds the symbol of a string, the table
+// of strings resolves a symbol back
+#[derive(Default)]
+pub struct Interner {
+    symbols: RadixTree<u32>,
+    strings: Vec<String>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn intern(&mut self, s: &str) -> u32 {
+        if let Some(symbol) = self.symbols.find(s.to_string()) {
+            return *symbol;
+        }
+        let symbol = self.strings.len() as u32;
+        self.symbols.insert(s.to_string(), symbol);
+        self.strings.push(s.to_string());
+        symbol
+    }
+
+    // Symbol of an already interned string
+    pub fn get(&self, s: &str) -> Option<u32> {
+        self.symbols.find(s.to_string()).copied()
+    }
+
+    pub fn resolve(&self, symbol: u32) -> Option<&str> {
+        self.strings.get(symbol as usize).map(|s| s.as_str())
+    }
+
+    // Number of interned strings
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
     }
 }
 
@@ -334,11 +1481,648 @@ mod test {
         }
 
         for (elem0, elem1) in &data {
-            art.insert(elem0.clone(), elem1.clone());
+            art.insert(elem0.clone(), *elem1);
         }
 
         for (elem0, elem1) in &data {
             assert_eq!(elem1.clone(), *art.find(elem0.clone()).unwrap());
         }
     }
+
+    #[test]
+    fn test_shared_prefixes() {
+        let mut tree = RadixTree::<u32>::new();
+        let mut data = std::collections::BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for _i in 0..10_000 {
+            // Short keys over a few chars to split labels and go past leaves
+            let len = rng.gen_range(0, 8);
+            let key: String = (0..len)
+                .map(|_| ['a', 'b', 'é', '~'][rng.gen_range(0, 4)])
+                .collect();
+            let value = rng.gen::<u32>();
+            tree.insert(key.clone(), value);
+            data.insert(key, value);
+        }
+        for (key, value) in &data {
+            assert_eq!(Some(value), tree.find(key.clone()));
+        }
+        assert_eq!(None, tree.find("aaaaaaaaa".to_string()));
+
+        // Values don't need a default
+        let mut tree = RadixTree::new();
+        tree.insert("ab".to_string(), std::num::NonZeroU32::new(1).unwrap());
+        tree.insert("a".to_string(), std::num::NonZeroU32::new(2).unwrap());
+        assert_eq!(2, tree.remove("a".to_string()).unwrap().get());
+        assert_eq!(1, tree.find("ab".to_string()).unwrap().get());
+    }
+
+    #[test]
+    fn test_interner() {
+        let mut interner = Interner::new();
+        let words = ["test", "tests", "te", "", "testing", "team", "test"];
+        let symbols: Vec<_> = words.iter().map(|word| interner.intern(word)).collect();
+        assert_eq!(vec![0, 1, 2, 3, 4, 5, 0], symbols);
+        assert_eq!(6, interner.len());
+        for (word, symbol) in words.iter().zip(symbols) {
+            assert_eq!(Some(*word), interner.resolve(symbol));
+        }
+        assert_eq!(Some(4), interner.get("testing"));
+        assert_eq!(None, interner.get("tes"));
+        assert_eq!(None, interner.resolve(6));
+    }
+
+    #[test]
+    fn test_add_and_delete() {
+        let mut tree = RadixTree::<u32>::new();
+        let mut data = std::collections::BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        let key = |rng: &mut rand::rngs::ThreadRng| -> String {
+            let len = rng.gen_range(0, 6);
+            (0..len)
+                .map(|_| ['a', 'b', 'c'][rng.gen_range(0, 3)])
+                .collect()
+        };
+        for _i in 0..20_000 {
+            let k = key(&mut rng);
+            if rng.gen::<bool>() {
+                let value = rng.gen::<u32>();
+                assert_eq!(data.insert(k.clone(), value), tree.insert(k, value));
+            } else {
+                assert_eq!(data.remove(&k), tree.remove(k.clone()));
+            }
+            let k = key(&mut rng);
+            assert_eq!(data.get(&k), tree.find(k.clone()));
+            assert_eq!(data.len(), tree.len());
+            if let Some(value) = tree.get_mut(&k) {
+                *value = value.wrapping_add(1);
+                assert_eq!(
+                    Some(*value),
+                    data.insert(k, *value).map(|v| v.wrapping_add(1))
+                );
+            }
+        }
+        for (key, value) in &data {
+            assert_eq!(Some(value), tree.find(key.clone()));
+        }
+        for prefix in ["", "a", "ab", "bca", "ccccc", "cccccc"].iter() {
+            let mut found: Vec<_> = tree.find_prefix(prefix).map(|(key, _)| key).collect();
+            found.sort_unstable();
+            let expected: Vec<_> = data
+                .keys()
+                .filter(|key| key.starts_with(prefix))
+                .cloned()
+                .collect();
+            assert_eq!(expected, found);
+        }
+        for _i in 0..100 {
+            let query = key(&mut rng) + &key(&mut rng);
+            let expected = data
+                .iter()
+                .filter(|(key, _)| query.starts_with(key.as_str()))
+                .max_by_key(|(key, _)| key.len())
+                .map(|(key, value)| (key.clone(), value));
+            assert_eq!(expected, tree.longest_match(&query));
+        }
+        assert!(tree
+            .iter()
+            .map(|(key, value)| (key, *value))
+            .eq(data.iter().map(|(key, value)| (key.clone(), *value))));
+        for _i in 0..100 {
+            let (start, end) = (key(&mut rng), key(&mut rng));
+            if start > end {
+                continue;
+            }
+            let found: Vec<_> = tree.range(start.as_str()..end.as_str()).collect();
+            let expected: Vec<_> = data
+                .range(start.clone()..end.clone())
+                .map(|(key, value)| (key.clone(), value))
+                .collect();
+            assert_eq!(expected, found);
+            let found: Vec<_> = tree.range(start.as_str()..=end.as_str()).collect();
+            let expected: Vec<_> = data
+                .range(start.clone()..=end.clone())
+                .map(|(key, value)| (key.clone(), value))
+                .collect();
+            assert_eq!(expected, found);
+            assert_eq!(
+                data.range(start.clone()..).count(),
+                tree.range(start.as_str()..).count()
+            );
+        }
+        // Removes keep the tree compressed, there's nothing left to merge
+        let live = tree.nodes.arr.len() - tree.nodes.arr_idx.len();
+        tree.compress();
+        assert_eq!(live, tree.nodes.arr.len() - tree.nodes.arr_idx.len());
+        let before = tree.memory_usage();
+        tree.shrink_to_fit();
+        assert!(tree.nodes.arr_idx.is_empty() && tree.edges.arr_idx.is_empty());
+        let after = tree.memory_usage();
+        assert_eq!((0, 0), (after.free_nodes, after.free_edges));
+        assert!(after.total <= before.total);
+        assert!(after.label_bytes >= data.keys().map(|key| key.len()).max().unwrap_or(0));
+        for (key, value) in &data {
+            assert_eq!(Some(*value), tree.remove(key.clone()));
+        }
+        assert_eq!(0, tree.iter().count());
+        assert!(tree.is_empty());
+        // Only the root and the edge to it are left
+        assert_eq!(1, tree.nodes.arr.len() - tree.nodes.arr_idx.len());
+        assert_eq!(1, tree.edges.arr.len() - tree.edges.arr_idx.len());
+    }
+
+    #[cfg(feature = "graphemes")]
+    #[test]
+    fn test_grapheme_boundary() {
+        // "e" and "e" with a combining acute accent share a char, but not
+        // a grapheme
+        let keys = ["e", "e\u{301}", "e\u{301}t", "et"];
+        let mut chars = RadixTree::new();
+        let mut graphemes = RadixTree::with_boundary(Boundary::Grapheme);
+        for (i, key) in keys.iter().enumerate() {
+            chars.insert(key.to_string(), i);
+            graphemes.insert(key.to_string(), i);
+        }
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(Some(&i), chars.find(key.to_string()));
+            assert_eq!(Some(&i), graphemes.find(key.to_string()));
+        }
+        assert_eq!(4, chars.find_prefix("e").count());
+        let mut found: Vec<_> = graphemes.find_prefix("e").map(|(key, _)| key).collect();
+        found.sort_unstable();
+        assert_eq!(vec!["e", "et"], found);
+        assert_eq!(Some(("e".to_string(), &0)), chars.longest_match("e\u{302}"));
+        assert_eq!(None, graphemes.longest_match("e\u{302}"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let mut tree = RadixTree::new();
+        let mut rng = rand::thread_rng();
+        for i in 0..1000 {
+            let len = rng.gen_range(0, 8);
+            let key: String = (0..len)
+                .map(|_| ['a', 'b', 'é'][rng.gen_range(0, 3)])
+                .collect();
+            tree.insert(key, i);
+        }
+        let keys: Vec<_> = tree.iter().map(|(key, _)| key).collect();
+        for key in keys.iter().step_by(2) {
+            tree.remove(key.clone());
+        }
+        let json = serde_json::to_string(&tree).unwrap();
+        let loaded: RadixTree<u32> = serde_json::from_str(&json).unwrap();
+        let mut expected: Vec<_> = tree.iter().map(|(key, value)| (key, *value)).collect();
+        let mut found: Vec<_> = loaded.iter().map(|(key, value)| (key, *value)).collect();
+        expected.sort_unstable();
+        found.sort_unstable();
+        assert_eq!(expected, found);
+        assert!(loaded.nodes.arr_idx.is_empty());
+        assert!(serde_json::from_str::<RadixTree<u32>>("[1, 2]").is_err());
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let mut tree = RadixTree::new();
+        for (i, key) in ["test", "team", "te"].iter().enumerate() {
+            tree.insert(key.to_string(), i);
+        }
+        let mut dot = vec![];
+        tree.to_dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.starts_with("digraph radix {\n"));
+        assert!(dot.ends_with("}\n"));
+        // The root and the split node at "te" are inner nodes
+        assert_eq!(2, dot.matches("[shape=point];").count());
+        assert_eq!(4, dot.matches(" -> ").count());
+        for label in ["te", "st", "am", ""].iter() {
+            assert!(dot.contains(&format!("[label=\"\\\"{}\\\"\"];", label)));
+        }
+        for value in 0..3 {
+            assert!(dot.contains(&format!("[label=\"{}\"];", value)));
+        }
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let mut tree = RadixTree::with_case(Case::Ascii);
+        tree.insert("Example.COM".to_string(), 0);
+        tree.insert("example.org".to_string(), 1);
+        assert_eq!(Some(0), tree.insert("EXAMPLE.com".to_string(), 2));
+        assert_eq!(2, tree.len());
+        *tree.get_mut("EXAMPLE.COM").unwrap() = 0;
+        assert_eq!(Some(&0), tree.find("example.com".to_string()));
+        assert_eq!(
+            Some(("Example.COM".to_string(), &0)),
+            tree.get_key_value("EXAMPLE.COM")
+        );
+        let keys: Vec<_> = tree.find_prefix("EXAMPLE.").map(|(key, _)| key).collect();
+        assert_eq!(vec!["Example.COM", "example.org"], keys);
+        assert_eq!(
+            Some(("Example.COM".to_string(), &0)),
+            tree.longest_match("example.Com.evil")
+        );
+        assert_eq!(1, tree.range("EXAMPLE.D"..).count());
+        assert_eq!(Some(0), tree.remove("eXaMpLe.CoM".to_string()));
+        assert_eq!(None, tree.find("Example.COM".to_string()));
+
+        let mut tree = RadixTree::new();
+        tree.insert("Example.COM".to_string(), 0);
+        assert_eq!(None, tree.find("example.com".to_string()));
+    }
+
+    #[cfg(feature = "unicode-case")]
+    #[test]
+    fn test_unicode_case() {
+        let mut tree = RadixTree::with_case(Case::Unicode);
+        tree.insert("Straße".to_string(), 0);
+        tree.insert("ΣΊΣΥΦΟΣ".to_string(), 1);
+        assert_eq!(Some(&0), tree.find("STRASSE".to_string()));
+        assert_eq!(
+            Some(("ΣΊΣΥΦΟΣ".to_string(), &1)),
+            tree.get_key_value("σίσυφος")
+        );
+        assert_eq!(
+            Some(("Straße".to_string(), &0)),
+            tree.longest_match("strasse 5")
+        );
+    }
+
+    #[test]
+    fn test_sync_radix_tree() {
+        let tree = SyncRadixTree::<usize>::with_options(4, Boundary::Char, Case::Ascii);
+        let words: Vec<String> = (0..2_000).map(|i| format!("Key{}", i)).collect();
+        std::thread::scope(|scope| {
+            for part in words.chunks(500) {
+                let tree = &tree;
+                scope.spawn(move || {
+                    for word in part {
+                        let value = word[3..].parse().unwrap();
+                        assert_eq!(None, tree.insert(word.clone(), value));
+                        assert_eq!(Some(value), tree.get(&word.to_uppercase()));
+                    }
+                });
+            }
+            // Readers go on while the writers insert
+            scope.spawn(|| {
+                for word in words.iter().cycle().take(10_000) {
+                    if let Some(value) = tree.get(word) {
+                        assert_eq!(word[3..].parse::<usize>().unwrap(), value);
+                    }
+                }
+            });
+        });
+        assert_eq!(2_000, tree.len());
+        assert_eq!(
+            Some(2),
+            tree.update("key7", |value| {
+                *value *= 2;
+                *value / 7
+            })
+        );
+        assert_eq!(Some(14), tree.get("KEY7"));
+        let found: Vec<_> = tree
+            .find_prefix("key19")
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        let mut expected: Vec<_> = words
+            .iter()
+            .filter(|w| w.starts_with("Key19"))
+            .cloned()
+            .collect();
+        expected.sort_unstable();
+        assert_eq!(expected, found);
+        assert_eq!(Some(14), tree.remove("kEy7"));
+        assert!(!tree.contains_key("Key7"));
+        assert_eq!(1_999, tree.len());
+        // A writer that panics leaves the snapshot as it was
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        let tree = SyncRadixTree::<usize>::with_options(1, Boundary::Char, Case::Sensitive);
+        tree.insert("a".to_string(), 1);
+        let update = catch_unwind(AssertUnwindSafe(|| {
+            tree.update("a", |value| {
+                *value = 2;
+                panic!("in update")
+            })
+        }));
+        assert!(update.is_err());
+        assert_eq!(Some(1), tree.get("a"));
+        assert_eq!(None, tree.insert("b".to_string(), 3));
+
+        // Readers keep their snapshot while writes go on
+        let snapshot = SyncRadixTree::load(tree.shard("a"));
+        tree.remove("a");
+        assert_eq!(Some(&1), snapshot.find("a".to_string()));
+        assert!(!tree.contains_key("a"));
+    }
+
+    #[test]
+    #[should_panic(expected = "stale arena index")]
+    fn test_stale_index() {
+        let mut arena = Arena::new();
+        let old = arena.insert(1);
+        arena.delete(old);
+        let new = arena.insert(2);
+        assert_eq!(old.slot, new.slot);
+        assert_eq!(2, arena[new]);
+        let _ = arena[old];
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut tree = RadixTree::with_capacity(200, 200);
+        let capacity = tree.memory_usage();
+        assert!(capacity.node_capacity >= 200 && capacity.edge_capacity >= 200);
+        for i in 0..99 {
+            tree.insert(format!("{:02}", i), i);
+        }
+        // No reallocation, the arenas still have the capacity they started with
+        let after = tree.memory_usage();
+        assert_eq!(
+            (capacity.node_capacity, capacity.edge_capacity),
+            (after.node_capacity, after.edge_capacity)
+        );
+        tree.reserve(1000, 1000);
+        let reserved = tree.memory_usage();
+        assert!(reserved.node_capacity >= tree.nodes.arr.len() + 1000);
+        assert!(reserved.edge_capacity >= tree.edges.arr.len() + 1000);
+        assert_eq!(Some(&42), tree.find("42".to_string()));
+    }
+
+    #[test]
+    fn test_remove_prefix() {
+        let mut tree = RadixTree::new();
+        let mut data = std::collections::BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        let key = |rng: &mut rand::rngs::ThreadRng, max_len| -> String {
+            (0..rng.gen_range(0, max_len))
+                .map(|_| ['a', 'b', 'é'][rng.gen_range(0, 3)])
+                .collect()
+        };
+        for round in 0..50 {
+            for i in 0..200 {
+                let k = key(&mut rng, 7);
+                tree.insert(k.clone(), i);
+                data.insert(k, i);
+            }
+            let prefix = key(&mut rng, 4);
+            let removed: Vec<_> = data
+                .keys()
+                .filter(|k| k.starts_with(&prefix))
+                .cloned()
+                .collect();
+            for k in removed.iter() {
+                data.remove(k);
+            }
+            assert_eq!(
+                removed.len(),
+                tree.remove_prefix(&prefix),
+                "round {}",
+                round
+            );
+            assert_eq!(data.len(), tree.len());
+            assert!(tree
+                .iter()
+                .map(|(key, value)| (key, *value))
+                .eq(data.iter().map(|(key, value)| (key.clone(), *value))));
+            // Still compressed
+            let live = tree.nodes.arr.len() - tree.nodes.arr_idx.len();
+            tree.compress();
+            assert_eq!(live, tree.nodes.arr.len() - tree.nodes.arr_idx.len());
+        }
+        assert_eq!(data.len(), tree.remove_prefix(""));
+        assert!(tree.is_empty());
+        assert_eq!(1, tree.nodes.arr.len() - tree.nodes.arr_idx.len());
+        assert_eq!(1, tree.edges.arr.len() - tree.edges.arr_idx.len());
+        assert_eq!(0, tree.remove_prefix("a"));
+    }
+
+    #[test]
+    fn test_walk() {
+        let mut tree = RadixTree::new();
+        for (i, key) in ["test", "team", "te", "toast", "slow"].iter().enumerate() {
+            tree.insert(key.to_string(), i);
+        }
+        let mut visited = vec![];
+        tree.walk(|depth, key, label, value| {
+            visited.push((depth, key.to_string(), label.to_string(), value.copied()));
+            true
+        });
+        let expected = vec![
+            (1, "slow", "slow", Some(4)),
+            (1, "t", "t", None),
+            (2, "te", "e", None),
+            (3, "te", "", Some(2)),
+            (3, "team", "am", Some(1)),
+            (3, "test", "st", Some(0)),
+            (2, "toast", "oast", Some(3)),
+        ];
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(depth, key, label, value)| (depth, key.to_string(), label.to_string(), value))
+            .collect();
+        assert_eq!(expected, visited);
+
+        // Pruning "te" skips its three keys
+        let mut count = 0;
+        tree.walk(|_, key, _, value| {
+            count += value.is_some() as usize;
+            key != "te"
+        });
+        assert_eq!(2, count);
+    }
+
+    #[test]
+    fn test_save_load() {
+        let mut tree = RadixTree::with_case(Case::Ascii);
+        let mut rng = rand::thread_rng();
+        for i in 0..2_000u32 {
+            let key: String = (0..rng.gen_range(0, 8))
+                .map(|_| ['a', 'B', 'é'][rng.gen_range(0, 3)])
+                .collect();
+            tree.insert(key, i);
+        }
+        let keys: Vec<_> = tree.iter().map(|(key, _)| key).collect();
+        for key in keys.iter().step_by(3) {
+            tree.remove(key.clone());
+        }
+        let mut file = vec![];
+        tree.save(&mut file).unwrap();
+        let mut loaded = RadixTree::<u32>::load(&file[..]).unwrap();
+        assert!(loaded.iter().eq(tree.iter()));
+        assert_eq!(tree.len(), loaded.len());
+        assert!(loaded.nodes.arr_idx.is_empty() && loaded.edges.arr_idx.is_empty());
+        assert_eq!(tree.find("BAB".to_string()), loaded.find("bab".to_string()));
+        loaded.insert("abcabc".to_string(), 7);
+        assert_eq!(Some(7), loaded.remove("ABCABC".to_string()));
+
+        for len in [0, 8, 10, file.len() - 1].iter() {
+            let loaded = RadixTree::<u32>::load(&file[..*len]);
+            assert!(matches!(loaded, Err(Error::Corrupt(_))));
+        }
+        let mut longer = file.clone();
+        longer.push(0);
+        assert!(RadixTree::<u32>::load(&longer[..]).is_err());
+        assert!(RadixTree::<u8>::load(&file[..]).is_err());
+
+        let path = std::env::temp_dir().join(format!("radix-{}.bin", std::process::id()));
+        tree.save_to(&path).unwrap();
+        let loaded = RadixTree::<u32>::load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.iter().eq(tree.iter()));
+        assert!(matches!(
+            RadixTree::<u32>::load_from(&path),
+            Err(Error::Io(_))
+        ));
+
+        // A label that isn't UTF-8 anymore
+        let mut tree = RadixTree::new();
+        tree.insert("é".to_string(), 1u32);
+        let mut file = vec![];
+        tree.save(&mut file).unwrap();
+        let pos = file.iter().position(|&byte| byte == 0xa9).unwrap();
+        file[pos] = 0xff;
+        assert!(matches!(
+            RadixTree::<u32>::load(&file[..]),
+            Err(Error::Utf8(_))
+        ));
+    }
+
+    #[test]
+    fn test_key_codec() {
+        use crate::keys::{AsciiLowercase, BigEndian, NullTerminated, Raw, Reversed};
+        let codec = NullTerminated(AsciiLowercase(Reversed(Raw)));
+        let mut tree = RadixTree::new();
+        for (i, host) in ["mail.example.com", "Example.COM", "example.org"]
+            .iter()
+            .enumerate()
+        {
+            tree.try_insert_with(&codec, *host, i).unwrap();
+        }
+        assert_eq!(Some(&1), tree.find_with(&codec, "EXAMPLE.com"));
+        let mut found: Vec<_> = tree.find_prefix("moc.elpmaxe").map(|(_, v)| *v).collect();
+        found.sort_unstable();
+        assert_eq!(vec![0, 1], found);
+        assert_eq!(Some(2), tree.remove_with(&codec, "example.org"));
+        assert_eq!(2, tree.len());
+
+        // Big-endian 255 isn't UTF-8
+        let err = tree.try_insert_with(&BigEndian, &255u16, 3).unwrap_err();
+        assert!(matches!(err, Error::Utf8(_)));
+        assert_eq!(None, tree.find_with(&BigEndian, &255u16));
+    }
+
+    #[test]
+    fn test_from_trie() {
+        let mut trie = TrieMap::new();
+        let mut data = std::collections::BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for i in 0..2_000 {
+            let key: String = (0..rng.gen_range(0, 8))
+                .map(|_| ['a', 'b', 'é'][rng.gen_range(0, 3)])
+                .collect();
+            trie.insert(&mut key.chars(), i);
+            data.insert(key, i);
+        }
+        let mut tree = RadixTree::from_trie(&trie);
+        assert_eq!(data.len(), tree.len());
+        assert!(tree
+            .iter()
+            .map(|(key, value)| (key, *value))
+            .eq(data.iter().map(|(key, value)| (key.clone(), *value))));
+        // Compressed already
+        let live = tree.nodes.arr.len() - tree.nodes.arr_idx.len();
+        tree.compress();
+        assert_eq!(live, tree.nodes.arr.len() - tree.nodes.arr_idx.len());
+        tree.insert("abab".to_string(), 7);
+        assert_eq!(Some(&7), tree.find("abab".to_string()));
+
+        let trie = tree.to_trie();
+        for (key, value) in tree.iter() {
+            assert_eq!(Some(value), trie.get(&mut key.chars()));
+        }
+    }
+
+    #[test]
+    fn test_complete() {
+        let mut tree = RadixTree::new();
+        let mut data = std::collections::BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for i in 0..2_000 {
+            let key: String = (0..rng.gen_range(0, 8))
+                .map(|_| ['a', 'b', 'é'][rng.gen_range(0, 3)])
+                .collect();
+            tree.insert(key.clone(), i);
+            data.insert(key, i);
+        }
+        for prefix in ["", "a", "ab", "éa", "bbbbbbbbb"].iter() {
+            for k in [0, 1, 5, 10_000].iter() {
+                let expected: Vec<_> = data
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(prefix))
+                    .take(*k)
+                    .map(|(key, value)| (key.clone(), value))
+                    .collect();
+                assert_eq!(expected, tree.complete(prefix, *k));
+            }
+        }
+    }
+
+    fn distance(a: &[char], b: &[char]) -> usize {
+        let mut row: Vec<_> = (0..=b.len()).collect();
+        for (i, x) in a.iter().enumerate() {
+            let mut next = vec![i + 1];
+            for (j, y) in b.iter().enumerate() {
+                next.push(
+                    (row[j] + (x != y) as usize)
+                        .min(row[j + 1] + 1)
+                        .min(next[j] + 1),
+                );
+            }
+            row = next;
+        }
+        row[b.len()]
+    }
+
+    #[test]
+    fn test_approximate_matches() {
+        let mut tree = RadixTree::new();
+        let mut data = std::collections::BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        let word = |rng: &mut rand::rngs::ThreadRng| -> String {
+            (0..rng.gen_range(0, 8))
+                .map(|_| ['a', 'b', 'c', 'é'][rng.gen_range(0, 4)])
+                .collect()
+        };
+        for i in 0..500 {
+            let key = word(&mut rng);
+            tree.insert(key.clone(), i);
+            data.insert(key, i);
+        }
+        for _i in 0..100 {
+            let query = word(&mut rng);
+            let chars: Vec<char> = query.chars().collect();
+            let mut expected: Vec<_> = data
+                .iter()
+                .map(|(key, value)| {
+                    let key_chars: Vec<char> = key.chars().collect();
+                    (key.clone(), value, distance(&key_chars, &chars))
+                })
+                .filter(|&(_, _, distance)| distance <= 2)
+                .collect();
+            expected.sort_by_key(|&(_, _, distance)| distance);
+            assert_eq!(expected, tree.approximate_matches(&query, 2));
+        }
+
+        // Distances are over the folded keys
+        let mut tree = RadixTree::with_case(Case::Ascii);
+        tree.insert("Hello".to_string(), 1);
+        tree.insert("help".to_string(), 2);
+        assert_eq!(
+            vec![("Hello".to_string(), &1, 0), ("help".to_string(), &2, 2)],
+            tree.approximate_matches("HELLO", 2)
+        );
+    }
 }
@@ -0,0 +1,334 @@
+// URL router. Routes are split into segments at '/' and kept in a tree of
+// segments, a segment is either static text, a named parameter `:name`
+// matching any single non-empty segment, or a catch-all `*name` at the end
+// of the route matching the rest of the path. Static segments win over a
+// parameter and a parameter wins over a catch-all; when the more specific
+// branch doesn't lead to a route the next one is tried. Every route has
+// handlers per HTTP method, a handler added without one serves any method.
+// The static segments of all nodes are in one `RadixTree`, the parameters
+// and catch-alls are the fallbacks kept in the nodes around it
+use crate::error::Error;
+use crate::radix::RadixTree;
+
+// Method of the handlers serving any method
+const ANY: &str = "*";
+
+// Why `match_route` found no handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NoMatch {
+    // No route matches the path, 404
+    NotFound,
+    // The route has no handler for the method, 405 with the allowed ones
+    MethodNotAllowed(Vec<String>),
+}
+
+// Handlers of a route by their method
+type Handlers<H> = Vec<(String, H)>;
+
+fn set_handler<H>(handlers: &mut Handlers<H>, method: &str, handler: H) {
+    match handlers.iter_mut().find(|(m, _)| m == method) {
+        Some(old) => old.1 = handler,
+        None => handlers.push((method.to_string(), handler)),
+    }
+}
+
+// Node of the segment tree, its static children are in `Router::statics`
+#[derive(Debug)]
+struct RouteNode<H> {
+    // Name of the parameter and the index of its node
+    param: Option<(String, usize)>,
+    catch_all: Option<(String, Handlers<H>)>,
+    handlers: Handlers<H>,
+}
+
+impl<H> RouteNode<H> {
+    fn new() -> Self {
+        Self {
+            param: None,
+            catch_all: None,
+            handlers: vec![],
+        }
+    }
+}
+
+// Key of a static child, the node index can't contain the '/' after it,
+// so the segment may be anything
+fn static_key(node: usize, segment: &str) -> String {
+    format!("{}/{}", node, segment)
+}
+
+// Handler of a matched route with the values of its parameters
+#[derive(Debug)]
+pub struct Match<'a, H> {
+    pub handler: &'a H,
+    pub params: Vec<(String, String)>,
+}
+
+impl<'a, H> Match<'a, H> {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn segments(path: &str) -> Vec<&str> {
+    path.strip_prefix('/').unwrap_or(path).split('/').collect()
+}
+
+pub struct Router<H> {
+    // Nodes of the segment tree, the root first
+    nodes: Vec<RouteNode<H>>,
+    // Index of the static child of a node by `static_key`
+    statics: RadixTree<usize>,
+}
+
+impl<H: std::fmt::Debug> std::fmt::Debug for Router<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let statics: Vec<_> = self.statics.iter().collect();
+        f.debug_struct("Router")
+            .field("nodes", &self.nodes)
+            .field("statics", &statics)
+            .finish()
+    }
+}
+
+impl<H> Default for Router<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> Router<H> {
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![RouteNode::new()],
+            statics: RadixTree::new(),
+        }
+    }
+
+    // Add a route like "/users/:id" or "/static/*path" serving any method,
+    // adding the same route again replaces its handler. A route that
+    // can't be parsed or conflicts with the names of the others gives
+    // `Error::InvalidArgument`
+    pub fn insert(&mut self, route: &str, handler: H) -> Result<(), Error> {
+        self.insert_method(ANY, route, handler)
+    }
+
+    // Add a route serving only the method, like "GET"
+    pub fn insert_method(&mut self, method: &str, route: &str, handler: H) -> Result<(), Error> {
+        let invalid = |reason| Error::InvalidArgument(format!("route {}: {}", route, reason));
+        let segments = segments(route);
+        // Check the whole route first, so a bad one adds no nodes
+        for (i, segment) in segments.iter().enumerate() {
+            if segment.starts_with('*') && i + 1 != segments.len() {
+                return Err(invalid("catch-all must be the last segment"));
+            }
+            if *segment == ":" {
+                return Err(invalid("parameter without a name"));
+            }
+        }
+        let mut node = 0;
+        for segment in segments.iter() {
+            if let Some(name) = segment.strip_prefix('*') {
+                let (other, handlers) = self.nodes[node]
+                    .catch_all
+                    .get_or_insert_with(|| (name.to_string(), vec![]));
+                if other != name {
+                    return Err(invalid("conflicting catch-all name"));
+                }
+                set_handler(handlers, method, handler);
+                return Ok(());
+            }
+            node = if let Some(name) = segment.strip_prefix(':') {
+                match &self.nodes[node].param {
+                    Some((other, _)) if other != name => {
+                        return Err(invalid("conflicting parameter name"));
+                    }
+                    Some((_, child)) => *child,
+                    None => {
+                        let child = self.nodes.len();
+                        self.nodes.push(RouteNode::new());
+                        self.nodes[node].param = Some((name.to_string(), child));
+                        child
+                    }
+                }
+            } else {
+                let key = static_key(node, segment);
+                match self.statics.find(key.clone()) {
+                    Some(&child) => child,
+                    None => {
+                        let child = self.nodes.len();
+                        self.nodes.push(RouteNode::new());
+                        self.statics.insert(key, child);
+                        child
+                    }
+                }
+            };
+        }
+        set_handler(&mut self.nodes[node].handlers, method, handler);
+        Ok(())
+    }
+
+    fn find(
+        &self,
+        node_idx: usize,
+        segments: &[&str],
+        params: &mut Vec<(String, String)>,
+    ) -> Option<&Handlers<H>> {
+        let node = &self.nodes[node_idx];
+        let (segment, rest) = match segments.split_first() {
+            Some(split) => split,
+            None if node.handlers.is_empty() => return None,
+            None => return Some(&node.handlers),
+        };
+        if let Some(&child) = self.statics.find(static_key(node_idx, segment)) {
+            if let Some(handlers) = self.find(child, rest, params) {
+                return Some(handlers);
+            }
+        }
+        if let Some((name, child)) = &node.param {
+            if !segment.is_empty() {
+                params.push((name.clone(), segment.to_string()));
+                if let Some(handlers) = self.find(*child, rest, params) {
+                    return Some(handlers);
+                }
+                params.pop();
+            }
+        }
+        let (name, handlers) = node.catch_all.as_ref()?;
+        if handlers.is_empty() {
+            return None;
+        }
+        params.push((name.clone(), segments.join("/")));
+        Some(handlers)
+    }
+
+    // Handler serving any method
+    pub fn lookup(&self, path: &str) -> Option<Match<'_, H>> {
+        self.match_route(ANY, path).ok()
+    }
+
+    // Handler for the method, falls back to the one serving any method
+    pub fn match_route(&self, method: &str, path: &str) -> Result<Match<'_, H>, NoMatch> {
+        let mut params = vec![];
+        let handlers = self
+            .find(0, &segments(path), &mut params)
+            .ok_or(NoMatch::NotFound)?;
+        let handler = handlers
+            .iter()
+            .find(|(m, _)| m == method)
+            .or_else(|| handlers.iter().find(|(m, _)| m == ANY));
+        match handler {
+            Some((_, handler)) => Ok(Match { handler, params }),
+            None => {
+                let mut allowed: Vec<_> = handlers.iter().map(|(m, _)| m.clone()).collect();
+                allowed.sort_unstable();
+                Err(NoMatch::MethodNotAllowed(allowed))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let mut router = Router::new();
+        for (i, route) in [
+            "/",
+            "/users",
+            "/users/new",
+            "/users/:id",
+            "/users/:id/posts/:post",
+            "/static/*path",
+            "/static/favicon.ico",
+        ]
+        .iter()
+        .enumerate()
+        {
+            router.insert(route, i).unwrap();
+        }
+        assert_eq!(0, *router.lookup("/").unwrap().handler);
+        assert_eq!(1, *router.lookup("/users").unwrap().handler);
+        assert_eq!(2, *router.lookup("/users/new").unwrap().handler);
+        let found = router.lookup("/users/42").unwrap();
+        assert_eq!((3, Some("42")), (*found.handler, found.param("id")));
+        // "new" is static, but only the parameter leads to a route
+        let found = router.lookup("/users/new/posts/7").unwrap();
+        assert_eq!(4, *found.handler);
+        assert_eq!(Some("new"), found.param("id"));
+        assert_eq!(Some("7"), found.param("post"));
+        let found = router.lookup("/static/css/site.css").unwrap();
+        assert_eq!(
+            (5, Some("css/site.css")),
+            (*found.handler, found.param("path"))
+        );
+        assert_eq!(6, *router.lookup("/static/favicon.ico").unwrap().handler);
+        assert!(router.lookup("/users/42/comments").is_none());
+        assert!(router.lookup("/users//posts/7").is_none());
+
+        assert!(matches!(
+            router.insert("/users/:name/likes", 7),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(router.insert("/files/*path/info", 7).is_err());
+        assert!(router.insert("/files/:", 7).is_err());
+        assert!(router.lookup("/files").is_none());
+    }
+
+    #[test]
+    fn test_static_segments() {
+        // Segments sharing prefixes split the labels of the radix tree,
+        // the same segment under different nodes stays apart
+        let mut router = Router::new();
+        let routes = [
+            "/api/users",
+            "/api/user",
+            "/api/user-settings",
+            "/api/users/:id/user",
+            "/user/api",
+            "/:x/users",
+            "/api/:x",
+        ];
+        for (i, route) in routes.iter().enumerate() {
+            router.insert(route, i).unwrap();
+        }
+        for (i, route) in routes[..5].iter().enumerate() {
+            assert_eq!(i, *router.lookup(route).unwrap().handler);
+        }
+        assert_eq!(5, *router.lookup("/user/users").unwrap().handler);
+        assert_eq!(6, *router.lookup("/api/use").unwrap().handler);
+        assert!(router.lookup("/1/api").is_none());
+        assert!(router.lookup("/api/users/7/use").is_none());
+        assert!(router.lookup("/0/api").is_none());
+    }
+
+    #[test]
+    fn test_match_route() {
+        let mut router = Router::new();
+        router.insert_method("GET", "/users/:id", 0).unwrap();
+        router.insert_method("PUT", "/users/:id", 1).unwrap();
+        router.insert_method("GET", "/files/*path", 2).unwrap();
+        router.insert("/health", 3).unwrap();
+        let found = router.match_route("PUT", "/users/7").unwrap();
+        assert_eq!((1, Some("7")), (*found.handler, found.param("id")));
+        assert_eq!(
+            NoMatch::MethodNotAllowed(vec!["GET".to_string(), "PUT".to_string()]),
+            router.match_route("DELETE", "/users/7").unwrap_err()
+        );
+        assert_eq!(
+            NoMatch::MethodNotAllowed(vec!["GET".to_string()]),
+            router.match_route("POST", "/files/a/b").unwrap_err()
+        );
+        assert_eq!(
+            NoMatch::NotFound,
+            router.match_route("GET", "/users").unwrap_err()
+        );
+        assert_eq!(3, *router.match_route("POST", "/health").unwrap().handler);
+        assert!(router.lookup("/users/7").is_none());
+    }
+}
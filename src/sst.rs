@@ -0,0 +1,439 @@
+use crate::codec::{read_varint, write_varint};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+// Sorted string table: a file of key/value pairs in the key order.
+//
+// The layout follows the tables of LevelDB, but the format is this crate's
+// own and the files can't be read by LevelDB or RocksDB, other tools have
+// to go through `SstReader`.
+//
+// The pairs are grouped into data blocks of about `BLOCK_SIZE` bytes.
+// Inside a block every key is stored as the number of bytes shared with
+// the previous key and the rest of it:
+//     varint shared | varint unshared | varint value_len | key[shared..] | value
+// The first key of a block is always stored in full.
+//
+// Every block is written with a trailer of its compression and the CRC-32C
+// of the block and that byte, the block is stored compressed only if that
+// saves an eighth of it:
+//     block | u8 compression | u32 crc (little endian)
+//
+// The data blocks are followed by the index block, which has one entry
+// per data block with its last key, offset and stored size:
+//     varint key_len | key | varint offset | varint size
+// and the fixed size footer:
+//     u64 index_offset | u64 index_size | u64 MAGIC (little endian)
+
+const BLOCK_SIZE: usize = 4096;
+const TRAILER_SIZE: usize = 5;
+const FOOTER_SIZE: usize = 24;
+const MAGIC: u64 = 0x7473_7378_6964_6173;
+
+// How the blocks of a table are compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Default for Compression {
+    // LZ4 if the crate is built with it
+    fn default() -> Self {
+        #[cfg(feature = "lz4")]
+        return Compression::Lz4;
+        #[cfg(not(feature = "lz4"))]
+        return Compression::None;
+    }
+}
+
+// Byte of the compression in the block trailers
+const RAW_BLOCK: u8 = 0;
+const LZ4_BLOCK: u8 = 1;
+
+// Table of CRC-32C (Castagnoli) for the byte at a time computation
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32c(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for part in parts {
+        for &byte in part.iter() {
+            crc = CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+        }
+    }
+    !crc
+}
+
+fn corrupt(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+struct IndexEntry {
+    last_key: Vec<u8>,
+    offset: u64,
+    size: u64,
+}
+
+pub struct SstWriter<W: Write> {
+    writer: W,
+    compression: Compression,
+    block: Vec<u8>,
+    last_key: Vec<u8>,
+    index: Vec<IndexEntry>,
+    offset: u64,
+}
+
+impl<W: Write> SstWriter<W> {
+    // Blocks are compressed with `Compression::default()`
+    pub fn new(writer: W) -> Self {
+        Self::with_compression(writer, Compression::default())
+    }
+
+    pub fn with_compression(writer: W, compression: Compression) -> Self {
+        Self {
+            writer,
+            compression,
+            block: vec![],
+            last_key: vec![],
+            index: vec![],
+            offset: 0,
+        }
+    }
+
+    // Keys have to be added in the increasing order
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let shared = if self.block.is_empty() {
+            0
+        } else {
+            key.iter()
+                .zip(self.last_key.iter())
+                .take_while(|&(a, b)| a == b)
+                .count()
+        };
+        write_varint(&mut self.block, shared as u64);
+        write_varint(&mut self.block, (key.len() - shared) as u64);
+        write_varint(&mut self.block, value.len() as u64);
+        self.block.extend_from_slice(&key[shared..]);
+        self.block.extend_from_slice(value);
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        if self.block.len() >= BLOCK_SIZE {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let block = std::mem::take(&mut self.block);
+        let size = self.write_block(&block)?;
+        self.index.push(IndexEntry {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            size,
+        });
+        self.offset += size + TRAILER_SIZE as u64;
+        self.block = block;
+        self.block.clear();
+        Ok(())
+    }
+
+    // Write the block with its trailer, returns the stored size without it
+    fn write_block(&mut self, block: &[u8]) -> io::Result<u64> {
+        let compressed: Option<Vec<u8>> = match self.compression {
+            Compression::None => None,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some(lz4_flex::block::compress_prepend_size(block))
+                .filter(|data| data.len() < block.len() - block.len() / 8),
+        };
+        let (data, kind) = match compressed.as_ref() {
+            Some(data) => (&data[..], LZ4_BLOCK),
+            None => (block, RAW_BLOCK),
+        };
+        self.writer.write_all(data)?;
+        self.writer.write_all(&[kind])?;
+        self.writer
+            .write_all(&crc32c(&[data, &[kind]]).to_le_bytes())?;
+        Ok(data.len() as u64)
+    }
+
+    // Write the index and the footer, returns the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_block()?;
+        let mut index = vec![];
+        for entry in &self.index {
+            write_varint(&mut index, entry.last_key.len() as u64);
+            index.extend_from_slice(&entry.last_key);
+            write_varint(&mut index, entry.offset);
+            write_varint(&mut index, entry.size);
+        }
+        let compression = std::mem::replace(&mut self.compression, Compression::None);
+        let size = self.write_block(&index)?;
+        self.compression = compression;
+        self.writer.write_all(&self.offset.to_le_bytes())?;
+        self.writer.write_all(&size.to_le_bytes())?;
+        self.writer.write_all(&MAGIC.to_le_bytes())?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+pub struct SstReader<R: Read + Seek> {
+    reader: R,
+    index: Vec<IndexEntry>,
+}
+
+impl<R: Read + Seek> SstReader<R> {
+    // Read the footer and the index, data blocks are read on demand
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        if len < FOOTER_SIZE as u64 {
+            return Err(corrupt("file is too short for an sst footer"));
+        }
+        reader.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+        let mut footer = [0; FOOTER_SIZE];
+        reader.read_exact(&mut footer)?;
+        let field = |i: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&footer[i * 8..i * 8 + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        let (index_offset, index_size) = (field(0), field(1));
+        if field(2) != MAGIC {
+            return Err(corrupt("bad sst magic"));
+        }
+        let index_end = index_offset
+            .checked_add(index_size)
+            .and_then(|end| end.checked_add(TRAILER_SIZE as u64));
+        if index_end != Some(len - FOOTER_SIZE as u64) {
+            return Err(corrupt("bad sst index position"));
+        }
+        let buf = read_block(&mut reader, index_offset, index_size)?;
+
+        let mut index = vec![];
+        let mut pos = 0;
+        while pos < buf.len() {
+            let entry = (|| {
+                let key_len = read_varint(&buf, &mut pos)? as usize;
+                let last_key = buf.get(pos..pos.checked_add(key_len)?)?.to_vec();
+                pos += key_len;
+                let offset = read_varint(&buf, &mut pos)?;
+                let size = read_varint(&buf, &mut pos)?;
+                if offset.checked_add(size)?.checked_add(TRAILER_SIZE as u64)? > index_offset {
+                    return None;
+                }
+                Some(IndexEntry {
+                    last_key,
+                    offset,
+                    size,
+                })
+            })();
+            index.push(entry.ok_or_else(|| corrupt("bad sst index entry"))?);
+        }
+        Ok(Self { reader, index })
+    }
+
+    fn read_block(&mut self, i: usize) -> io::Result<Vec<u8>> {
+        let entry = &self.index[i];
+        read_block(&mut self.reader, entry.offset, entry.size)
+    }
+
+    // Look up a single key, reads at most one data block
+    pub fn get(&mut self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        // First block whose last key isn't less than the searched one
+        let i = self
+            .index
+            .partition_point(|entry| entry.last_key.as_slice() < key);
+        if i == self.index.len() {
+            return Ok(None);
+        }
+        let block = self.read_block(i)?;
+        let mut found = None;
+        decode_block(&block, |k, v| {
+            if k == key {
+                found = Some(v.to_vec());
+            }
+            k < key
+        })?;
+        Ok(found)
+    }
+
+    // Call `f` for every pair in the key order
+    pub fn for_each<F: FnMut(&[u8], &[u8])>(&mut self, mut f: F) -> io::Result<()> {
+        for i in 0..self.index.len() {
+            let block = self.read_block(i)?;
+            decode_block(&block, |k, v| {
+                f(k, v);
+                true
+            })?;
+        }
+        Ok(())
+    }
+}
+
+// Read the block stored at `offset` with `size` bytes before its trailer,
+// check its checksum and decompress it
+fn read_block<R: Read + Seek>(reader: &mut R, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut data = vec![0; size as usize + TRAILER_SIZE];
+    reader.read_exact(&mut data)?;
+    let trailer = data.split_off(size as usize);
+    let mut crc = [0; 4];
+    crc.copy_from_slice(&trailer[1..]);
+    if crc32c(&[&data, &trailer[..1]]) != u32::from_le_bytes(crc) {
+        return Err(corrupt("sst block checksum mismatch"));
+    }
+    match trailer[0] {
+        RAW_BLOCK => Ok(data),
+        #[cfg(feature = "lz4")]
+        LZ4_BLOCK => lz4_flex::block::decompress_size_prepended(&data)
+            .map_err(|_| corrupt("bad lz4 sst block")),
+        #[cfg(not(feature = "lz4"))]
+        LZ4_BLOCK => Err(corrupt("lz4 sst block, the crate is built without lz4")),
+        _ => Err(corrupt("unknown sst block compression")),
+    }
+}
+
+// Decode pairs of a data block while `f` returns true
+fn decode_block<F: FnMut(&[u8], &[u8]) -> bool>(block: &[u8], mut f: F) -> io::Result<()> {
+    let mut key = vec![];
+    let mut pos = 0;
+    while pos < block.len() {
+        let (shared, unshared, value_len) = (|| {
+            let shared = read_varint(block, &mut pos)? as usize;
+            let unshared = read_varint(block, &mut pos)? as usize;
+            let value_len = read_varint(block, &mut pos)? as usize;
+            Some((shared, unshared, value_len))
+        })()
+        .ok_or_else(|| corrupt("bad sst block entry"))?;
+        let end = pos
+            .checked_add(unshared)
+            .and_then(|end| end.checked_add(value_len))
+            .filter(|&end| end <= block.len() && shared <= key.len())
+            .ok_or_else(|| corrupt("bad sst block entry"))?;
+        key.truncate(shared);
+        key.extend_from_slice(&block[pos..pos + unshared]);
+        if !f(&key, &block[pos + unshared..end]) {
+            break;
+        }
+        pos = end;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn write(compression: Compression) -> Vec<u8> {
+        let mut writer = SstWriter::with_compression(vec![], compression);
+        for i in 0..10_000u32 {
+            let key = format!("key{:08}", i);
+            writer.add(key.as_bytes(), &i.to_be_bytes()).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(0xE306_9283, crc32c(&[b"123456789"]));
+        assert_eq!(crc32c(&[b"123456789"]), crc32c(&[b"1234", b"", b"56789"]));
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let file = write(Compression::None);
+        // Shared prefixes are not repeated
+        assert!(file.len() < 10_000 * 15);
+        #[cfg(feature = "lz4")]
+        {
+            let compressed = write(Compression::Lz4);
+            assert!(compressed.len() < file.len() * 7 / 8);
+            let mut pairs = vec![];
+            SstReader::new(Cursor::new(compressed))
+                .unwrap()
+                .for_each(|k, v| pairs.push((k.to_vec(), v.to_vec())))
+                .unwrap();
+            let mut expected = vec![];
+            SstReader::new(Cursor::new(&file))
+                .unwrap()
+                .for_each(|k, v| expected.push((k.to_vec(), v.to_vec())))
+                .unwrap();
+            assert_eq!(expected, pairs);
+        }
+
+        let mut reader = SstReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(
+            Some(1234u32.to_be_bytes().to_vec()),
+            reader.get(b"key00001234").unwrap()
+        );
+        assert_eq!(None, reader.get(b"key0000123").unwrap());
+        assert_eq!(None, reader.get(b"zzz").unwrap());
+
+        let mut count = 0u32;
+        reader
+            .for_each(|k, v| {
+                assert_eq!(format!("key{:08}", count).as_bytes(), k);
+                assert_eq!(&count.to_be_bytes(), v);
+                count += 1;
+            })
+            .unwrap();
+        assert_eq!(10_000, count);
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let mut writer = SstWriter::new(vec![]);
+        writer.add(b"key", b"value").unwrap();
+        let mut file = writer.finish().unwrap();
+        assert!(SstReader::new(Cursor::new(&file[1..])).is_err());
+        let len = file.len();
+        file[len - 1] ^= 1;
+        assert!(SstReader::new(Cursor::new(file)).is_err());
+        // A flipped bit anywhere in a block or its trailer fails its checksum
+        let file = write(Compression::default());
+        let index_offset = {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&file[file.len() - FOOTER_SIZE..][..8]);
+            u64::from_le_bytes(bytes) as usize
+        };
+        for pos in [
+            0,
+            100,
+            index_offset - 1,
+            index_offset,
+            file.len() - FOOTER_SIZE - 1,
+        ]
+        .iter()
+        {
+            let mut file = file.clone();
+            file[*pos] ^= 4;
+            let err = SstReader::new(Cursor::new(file)).and_then(|mut reader| {
+                reader.get(b"key00000000")?;
+                reader.for_each(|_, _| ())
+            });
+            assert!(err.unwrap_err().to_string().contains("checksum"));
+        }
+    }
+}
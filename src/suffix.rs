@@ -0,0 +1,154 @@
+// Generalized suffix trie. Every suffix of every document is inserted into
+// a path compressed trie, the node a suffix ends at keeps the id of its
+// document. A substring is a prefix of some suffix, so it occurs in the
+// documents found below the place its bytes lead to
+
+#[derive(Debug, Default)]
+struct SuffixNode {
+    // Edges sorted by the first byte of the label
+    children: Vec<(Vec<u8>, SuffixNode)>,
+    // Documents having a suffix ending at the node
+    docs: Vec<usize>,
+}
+
+impl SuffixNode {
+    fn insert(&mut self, suffix: &[u8], doc: usize) {
+        if suffix.is_empty() {
+            if self.docs.last() != Some(&doc) {
+                self.docs.push(doc);
+            }
+            return;
+        }
+        let pos = match self
+            .children
+            .binary_search_by_key(&suffix[0], |(label, _)| label[0])
+        {
+            Ok(pos) => pos,
+            Err(pos) => {
+                let leaf = SuffixNode {
+                    children: vec![],
+                    docs: vec![doc],
+                };
+                self.children.insert(pos, (suffix.to_vec(), leaf));
+                return;
+            }
+        };
+        let (label, child) = &mut self.children[pos];
+        let common = label.iter().zip(suffix).take_while(|(a, b)| a == b).count();
+        if common < label.len() {
+            // Split the edge at the end of the common part
+            let rest = label.split_off(common);
+            let old = std::mem::take(child);
+            child.children.push((rest, old));
+        }
+        child.insert(&suffix[common..], doc);
+    }
+
+    // Node below the end of the pattern
+    fn locate(&self, pattern: &[u8]) -> Option<&SuffixNode> {
+        let mut node = self;
+        let mut rest = pattern;
+        while !rest.is_empty() {
+            let pos = node
+                .children
+                .binary_search_by_key(&rest[0], |(label, _)| label[0])
+                .ok()?;
+            let (label, child) = &node.children[pos];
+            if rest.len() <= label.len() {
+                return if label.starts_with(rest) {
+                    Some(child)
+                } else {
+                    None
+                };
+            }
+            if !rest.starts_with(label) {
+                return None;
+            }
+            rest = &rest[label.len()..];
+            node = child;
+        }
+        Some(node)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SuffixIndex {
+    root: SuffixNode,
+    len: usize,
+}
+
+impl SuffixIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Index the document and return its id, ids go up from 0
+    pub fn add(&mut self, document: &str) -> usize {
+        let doc = self.len;
+        let bytes = document.as_bytes();
+        for start in 0..=bytes.len() {
+            self.root.insert(&bytes[start..], doc);
+        }
+        self.len += 1;
+        doc
+    }
+
+    // Number of documents
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains_substring(&self, substring: &str) -> bool {
+        self.root.locate(substring.as_bytes()).is_some()
+    }
+
+    // Ids of the documents containing the substring in the increasing order
+    pub fn find_documents(&self, substring: &str) -> Vec<usize> {
+        let mut docs = vec![];
+        let mut stack: Vec<_> = self.root.locate(substring.as_bytes()).into_iter().collect();
+        while let Some(node) = stack.pop() {
+            docs.extend_from_slice(&node.docs);
+            stack.extend(node.children.iter().map(|(_, child)| child));
+        }
+        docs.sort_unstable();
+        docs.dedup();
+        docs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_find_documents() {
+        let mut index = SuffixIndex::new();
+        let mut documents = vec![];
+        let mut rng = rand::thread_rng();
+        for _i in 0..100 {
+            let len = rng.gen_range(0, 50);
+            let document: String = (0..len)
+                .map(|_| ['a', 'b', 'c', 'é'][rng.gen_range(0, 4)])
+                .collect();
+            assert_eq!(documents.len(), index.add(&document));
+            documents.push(document);
+        }
+        for _i in 0..1000 {
+            let len = rng.gen_range(0, 6);
+            let substring: String = (0..len)
+                .map(|_| ['a', 'b', 'c', 'é'][rng.gen_range(0, 4)])
+                .collect();
+            let expected: Vec<_> = (0..documents.len())
+                .filter(|&doc| documents[doc].contains(&substring))
+                .collect();
+            assert_eq!(expected, index.find_documents(&substring));
+            assert_eq!(!expected.is_empty(), index.contains_substring(&substring));
+        }
+        assert!(!index.contains_substring("abcabcabcabcabcabcabc"));
+    }
+}
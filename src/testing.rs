@@ -0,0 +1,194 @@
+use crate::art::{Art, ArtKey};
+use crate::burst::BurstTrie;
+use crate::critbit::CritBit;
+use crate::hot::Hot;
+use crate::qptrie::QpTrie;
+use crate::radix::RadixTree;
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+
+// Common interface of the maps in the crate, so the same differential
+// tests and benchmarks can run against any of them. Keys are compared by
+// their byte representation, `pairs` lists them in that order whatever
+// order the structure iterates in
+pub trait OrderedMap<K, T> {
+    fn insert(&mut self, key: K, value: T);
+    fn find(&self, key: K) -> Option<&T>;
+    fn delete(&mut self, key: K);
+    fn pairs(&self) -> Vec<(Vec<u8>, &T)>;
+
+    fn len(&self) -> usize {
+        self.pairs().len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Reference implementation on top of BTreeMap, the baseline the other maps
+// are checked against
+pub struct OracleMap<K, T> {
+    pub map: BTreeMap<Vec<u8>, T>,
+    key: PhantomData<K>,
+}
+
+impl<K: ArtKey, T> Default for OracleMap<K, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: ArtKey, T> OracleMap<K, T> {
+    pub fn new() -> Self {
+        Self {
+            map: BTreeMap::new(),
+            key: PhantomData,
+        }
+    }
+}
+
+impl<K: ArtKey, T> OrderedMap<K, T> for OracleMap<K, T> {
+    fn insert(&mut self, key: K, value: T) {
+        self.map.insert(key.bytes(), value);
+    }
+
+    fn find(&self, key: K) -> Option<&T> {
+        self.map.get(&key.bytes())
+    }
+
+    fn delete(&mut self, key: K) {
+        self.map.remove(&key.bytes());
+    }
+
+    fn pairs(&self) -> Vec<(Vec<u8>, &T)> {
+        self.map
+            .iter()
+            .map(|(key, value)| (key.clone(), value))
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+}
+
+macro_rules! ordered_map {
+    ($($map:ident)*) => ($(impl<K: ArtKey, T> OrderedMap<K, T> for $map<K, T> {
+        fn insert(&mut self, key: K, value: T) {
+            $map::insert(self, key, value)
+        }
+
+        fn find(&self, key: K) -> Option<&T> {
+            $map::find(self, key)
+        }
+
+        fn delete(&mut self, key: K) {
+            $map::delete(self, key)
+        }
+
+        fn pairs(&self) -> Vec<(Vec<u8>, &T)> {
+            let mut pairs: Vec<_> = self.iter().map(|(key, value)| (key.to_vec(), value)).collect();
+            pairs.sort_by(|a, b| a.0.cmp(&b.0));
+            pairs
+        }
+    })*)
+}
+ordered_map! { BurstTrie CritBit Hot QpTrie }
+
+impl<K, T> OrderedMap<K, T> for Art<K, T>
+where
+    K: ArtKey + std::fmt::Debug,
+    T: 'static + Clone + std::fmt::Debug,
+{
+    fn insert(&mut self, key: K, value: T) {
+        Art::insert(self, key, value);
+    }
+
+    fn find(&self, key: K) -> Option<&T> {
+        Art::find(self, key)
+    }
+
+    fn delete(&mut self, key: K) {
+        Art::delete(self, key)
+    }
+
+    fn pairs(&self) -> Vec<(Vec<u8>, &T)> {
+        let mut pairs: Vec<_> = self
+            .iter()
+            .map(|(key, value)| (key.to_vec(), value))
+            .collect();
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        pairs
+    }
+}
+
+impl<T> OrderedMap<String, T> for RadixTree<T> {
+    fn insert(&mut self, key: String, value: T) {
+        RadixTree::insert(self, key, value);
+    }
+
+    fn find(&self, key: String) -> Option<&T> {
+        RadixTree::find(self, key)
+    }
+
+    fn delete(&mut self, key: String) {
+        RadixTree::remove(self, key);
+    }
+
+    fn pairs(&self) -> Vec<(Vec<u8>, &T)> {
+        let mut pairs: Vec<_> = self
+            .iter()
+            .map(|(key, value)| (key.into_bytes(), value))
+            .collect();
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        pairs
+    }
+
+    fn len(&self) -> usize {
+        RadixTree::len(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+
+    // Random inserts and deletes of short keys over a small alphabet, so
+    // they share prefixes and hit existing keys. With `terminate` the keys
+    // end with \0, for the maps where no key may be a prefix of another
+    fn check<M: OrderedMap<String, u32>>(mut map: M, terminate: bool) {
+        let mut oracle = OracleMap::new();
+        let mut rng = rand::thread_rng();
+        for i in 0..5_000 {
+            let len = rng.gen_range(0, 8);
+            let mut key: String = (0..len)
+                .map(|_| ['a', 'b', 'c'][rng.gen_range(0, 3)])
+                .collect();
+            if terminate {
+                key.push('\0');
+            }
+            if rng.gen_range(0, 3) == 0 {
+                map.delete(key.clone());
+                oracle.delete(key.clone());
+            } else {
+                map.insert(key.clone(), i);
+                oracle.insert(key.clone(), i);
+            }
+            assert_eq!(oracle.find(key.clone()), map.find(key));
+        }
+        assert_eq!(oracle.len(), map.len());
+        assert_eq!(oracle.pairs(), map.pairs());
+    }
+
+    #[test]
+    fn test_against_oracle() {
+        check(Art::new(), true);
+        check(BurstTrie::new(), false);
+        check(CritBit::new(), false);
+        check(Hot::new(), false);
+        check(QpTrie::new(), false);
+        check(RadixTree::new(), false);
+    }
+}
@@ -0,0 +1,114 @@
+use crate::art::Art;
+use crate::keys::{BigEndian, KeyCodec, Raw};
+use std::convert::TryInto;
+use std::ops::Bound::{Excluded, Included};
+
+// Points of many series in one ART, keyed by the series id followed by the
+// timestamp in big-endian, so the points of a series sort next to each
+// other in time order and a time window is a range of keys
+pub struct TimeSeries<T: 'static + std::fmt::Debug> {
+    art: Art<u64, T>,
+}
+
+impl<T: 'static + Clone + std::fmt::Debug> Default for TimeSeries<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: 'static + Clone + std::fmt::Debug> TimeSeries<T> {
+    pub fn new() -> Self {
+        Self { art: Art::new() }
+    }
+
+    fn key(series: u64, timestamp: i64) -> Vec<u8> {
+        (BigEndian, BigEndian).to_bytes(&(series, timestamp))
+    }
+
+    // Timestamp of a key, undoing the sign bit flip of `BigEndian`
+    fn timestamp(key: &[u8]) -> i64 {
+        i64::from_be_bytes(key[8..16].try_into().unwrap()) ^ i64::MIN
+    }
+
+    // Replaces the value of a point with the same timestamp
+    pub fn insert(&mut self, series: u64, timestamp: i64, value: T) {
+        self.art
+            .insert_with(&Raw, &Self::key(series, timestamp)[..], value);
+    }
+
+    pub fn get(&self, series: u64, timestamp: i64) -> Option<&T> {
+        self.art.find_with(&Raw, &Self::key(series, timestamp)[..])
+    }
+
+    pub fn remove(&mut self, series: u64, timestamp: i64) {
+        self.art
+            .delete_with(&Raw, &Self::key(series, timestamp)[..]);
+    }
+
+    // Points of the series with `from <= timestamp < to` in time order
+    pub fn scan_window(&self, series: u64, from: i64, to: i64) -> Vec<(i64, &T)> {
+        let (from, to) = (Self::key(series, from), Self::key(series, to));
+        self.art
+            .range((Included(&from[..]), Excluded(&to[..])))
+            .map(|(key, value)| (Self::timestamp(key), value))
+            .collect()
+    }
+
+    // Remove the points of the series older than `timestamp`, returns how
+    // many were removed
+    pub fn trim_before(&mut self, series: u64, timestamp: i64) -> usize {
+        let (from, to) = (Self::key(series, i64::MIN), Self::key(series, timestamp));
+        let old: Vec<_> = self
+            .art
+            .range((Included(&from[..]), Excluded(&to[..])))
+            .map(|(key, _)| key.to_vec())
+            .collect();
+        for key in old.iter() {
+            self.art.delete_with(&Raw, &key[..]);
+        }
+        old.len()
+    }
+
+    // The tree underneath, for the queries of `Art`
+    pub fn art(&self) -> &Art<u64, T> {
+        &self.art
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_time_series() {
+        let mut series = TimeSeries::new();
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for i in 0..3_000 {
+            let (id, ts) = (rng.gen_range(0, 3), rng.gen_range(-500, 500));
+            series.insert(id, ts, i);
+            data.insert((id, ts), i);
+        }
+        for _ in 0..100 {
+            let id = rng.gen_range(0, 3);
+            let (a, b) = (rng.gen_range(-600, 600), rng.gen_range(-600, 600));
+            let (from, to) = (a.min(b), a.max(b));
+            let expected: Vec<_> = data
+                .range((id, from)..(id, to))
+                .map(|(&(_, ts), value)| (ts, value))
+                .collect();
+            assert_eq!(expected, series.scan_window(id, from, to));
+        }
+        let old = data.range((1, i64::MIN)..(1, 0)).count();
+        assert_eq!(old, series.trim_before(1, 0));
+        assert!(series.scan_window(1, i64::MIN, 0).is_empty());
+        assert_eq!(
+            data.range((0, -500)..(0, 500)).count(),
+            series.scan_window(0, -500, 500).len()
+        );
+        assert_eq!(data.get(&(2, 7)), series.get(2, 7));
+        assert_eq!(0, series.trim_before(1, 0));
+    }
+}
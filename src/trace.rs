@@ -0,0 +1,17 @@
+// Spans and events of the `tracing` feature, without the feature they
+// expand to nothing. `trace_span!` stays entered until the end of the
+// block it's used in
+
+macro_rules! trace_span {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!($($arg)*).entered();
+    };
+}
+
+macro_rules! trace_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::trace!($($arg)*);
+    };
+}
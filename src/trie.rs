@@ -1,11 +1,39 @@
-use std::cmp::Eq;
-use std::collections::HashMap;
+use std::cmp::{Eq, Reverse};
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
 use std::default::Default;
 use std::hash::Hash;
+use std::iter::Peekable;
 
+// Shape of a trie, see `TrieNode::stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrieStats {
+    // Nodes, the root included, and the ones a sequence ends at
+    pub nodes: usize,
+    pub terminals: usize,
+    // Length of the longest sequence and the average one
+    pub max_depth: usize,
+    pub avg_depth: f64,
+    // Number of nodes by their number of children, `branching[2]` nodes
+    // have two children. A lot of nodes with one child means long chains a
+    // radix tree would compress into single edges
+    pub branching: Vec<usize>,
+    // Share of the nodes a sequence ends at
+    pub terminal_density: f64,
+}
+
+#[derive(Clone)]
 pub struct TrieNode<T> {
     next: HashMap<T, TrieNode<T>>,
     end: bool,
+    // Number of stored sequences ending here or below
+    count: usize,
+}
+
+impl<T: Default + Eq + Hash + Clone> Default for TrieNode<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T: Default + Eq + Hash + Clone> TrieNode<T> {
@@ -13,27 +41,1166 @@ impl<T: Default + Eq + Hash + Clone> TrieNode<T> {
         Self {
             next: HashMap::new(),
             end: false,
+            count: 0,
         }
     }
 
     pub fn add(&mut self, s: &mut dyn Iterator<Item = T>) {
+        let path: Vec<T> = s.collect();
+        trace_span!("trie.add", key_len = path.len());
+        self.add_path(&path);
+    }
+
+    // Returns whether a new sequence got stored, the counts on the path
+    // go up only then
+    fn add_path(&mut self, path: &[T]) -> bool {
+        let added = match path.split_first() {
+            Some((c, rest)) => self.next.entry(c.clone()).or_default().add_path(rest),
+            None => !std::mem::replace(&mut self.end, true),
+        };
+        if added {
+            self.count += 1;
+        }
+        added
+    }
+    pub fn find(&self, s: &mut dyn Iterator<Item = T>) -> bool {
+        trace_span!("trie.find");
         let mut n = self;
         for c in s {
+            match n.next.get(&c) {
+                Some(node) => n = node,
+                None => return false,
+            }
+        }
+        n.end
+    }
+
+    // Returns whether the sequence was stored. Nodes left with nothing
+    // below them and not ending a sequence are dropped up the path
+    pub fn remove(&mut self, s: &mut dyn Iterator<Item = T>) -> bool {
+        let path: Vec<T> = s.collect();
+        trace_span!("trie.remove", key_len = path.len());
+        self.remove_path(&path)
+    }
+
+    fn remove_path(&mut self, path: &[T]) -> bool {
+        let removed = match path.split_first() {
+            None => std::mem::replace(&mut self.end, false),
+            Some((c, rest)) => match self.next.get_mut(c) {
+                Some(child) => {
+                    let removed = child.remove_path(rest);
+                    if child.count == 0 {
+                        self.next.remove(c);
+                    }
+                    removed
+                }
+                None => false,
+            },
+        };
+        if removed {
+            self.count -= 1;
+        }
+        removed
+    }
+
+    // Number of stored sequences
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    // Number of nodes, the root included
+    pub fn node_count(&self) -> usize {
+        1 + self
+            .next
+            .values()
+            .map(|child| child.node_count())
+            .sum::<usize>()
+    }
+
+    pub fn stats(&self) -> TrieStats {
+        let mut nodes = 0;
+        let mut terminals = 0;
+        let mut max_depth = 0;
+        let mut depths = 0;
+        let mut branching = vec![];
+        self.walk(|path, node, end| {
+            nodes += 1;
+            if end {
+                terminals += 1;
+                depths += path.len();
+            }
+            max_depth = max_depth.max(path.len());
+            let children = node.next.len();
+            if branching.len() <= children {
+                branching.resize(children + 1, 0);
+            }
+            branching[children] += 1;
+            true
+        });
+        TrieStats {
+            nodes,
+            terminals,
+            max_depth,
+            avg_depth: if terminals == 0 {
+                0.0
+            } else {
+                depths as f64 / terminals as f64
+            },
+            branching,
+            terminal_density: terminals as f64 / nodes as f64,
+        }
+    }
+
+    // Approximate number of bytes held by the trie. Every node holds a
+    // HashMap with its children in place, the map allocates a bucket and
+    // a control byte per slot of its capacity
+    pub fn memory(&self) -> usize {
+        let bucket = std::mem::size_of::<(T, TrieNode<T>)>() + 1;
+        let mut memory = std::mem::size_of::<Self>();
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            memory += node.next.capacity() * bucket;
+            stack.extend(node.next.values());
+        }
+        memory
+    }
+
+    // Follow the input as far as the trie goes, returns the number of
+    // matched elements and whether they are a stored sequence. The input
+    // is peeked first, so the element that didn't match is left in it
+    pub fn find_longest<I: Iterator<Item = T>>(&self, s: &mut Peekable<I>) -> (usize, bool) {
+        let mut n = self;
+        let mut matched = 0;
+        while let Some(node) = s.peek().and_then(|c| n.next.get(c)) {
+            n = node;
+            matched += 1;
+            s.next();
+        }
+        (matched, n.end)
+    }
+
+    // Length of the longest stored sequence the input starts with, 0 when
+    // there is none. Only as much of the input as the trie has is read
+    pub fn longest_prefix(&self, s: &mut dyn Iterator<Item = T>) -> usize {
+        let mut n = self;
+        let mut longest = 0;
+        for (len, c) in s.enumerate() {
+            match n.next.get(&c) {
+                Some(node) => n = node,
+                None => break,
+            }
             if n.end {
-                break;
+                longest = len + 1;
             }
-            n = n.next.entry(c).or_insert_with(TrieNode::new);
         }
-        n.end = true;
+        longest
     }
-    pub fn find(&self, s: &mut dyn Iterator<Item = T>) -> bool {
+
+    // Add all sequences of the other trie. Subtrees only the other trie
+    // has are moved over as they are, shared ones are merged
+    pub fn merge(&mut self, other: TrieNode<T>) {
+        self.end |= other.end;
+        for (c, child) in other.next {
+            match self.next.entry(c) {
+                Entry::Occupied(mut entry) => entry.get_mut().merge(child),
+                Entry::Vacant(entry) => {
+                    entry.insert(child);
+                }
+            }
+        }
+        self.count = self.end as usize + self.next.values().map(|child| child.count).sum::<usize>();
+    }
+
+    // Sequences stored in both tries. Both are walked together along the
+    // children they share
+    pub fn intersection(&self, other: &TrieNode<T>) -> TrieNode<T> {
+        let mut node = TrieNode::new();
+        node.end = self.end && other.end;
+        node.count = node.end as usize;
+        for (c, child) in self.next.iter() {
+            if let Some(other_child) = other.next.get(c) {
+                node.add_child(c, child.intersection(other_child));
+            }
+        }
+        node
+    }
+
+    // Sequences stored in this trie and not in the other one. Subtrees the
+    // other trie doesn't have are copied whole
+    pub fn difference(&self, other: &TrieNode<T>) -> TrieNode<T> {
+        let mut node = TrieNode::new();
+        node.end = self.end && !other.end;
+        node.count = node.end as usize;
+        for (c, child) in self.next.iter() {
+            let child = match other.next.get(c) {
+                Some(other_child) => child.difference(other_child),
+                None => child.clone(),
+            };
+            node.add_child(c, child);
+        }
+        node
+    }
+
+    // Keep the child if a sequence ends in it and count it in
+    fn add_child(&mut self, c: &T, child: TrieNode<T>) {
+        if child.count > 0 {
+            self.count += child.count;
+            self.next.insert(c.clone(), child);
+        }
+    }
+
+    // Whether some stored sequence starts with the prefix. Nodes are only
+    // kept while a sequence ends at or below them, so reaching the end of
+    // the prefix is enough
+    pub fn contains_prefix(&self, prefix: &mut dyn Iterator<Item = T>) -> bool {
         let mut n = self;
-        for c in s {
+        for c in prefix {
             match n.next.get(&c) {
                 Some(node) => n = node,
                 None => return false,
             }
         }
-        n.end
+        n.count > 0
+    }
+
+    // Number of stored sequences starting with the prefix
+    pub fn count_prefix(&self, prefix: &mut dyn Iterator<Item = T>) -> usize {
+        let mut n = self;
+        for c in prefix {
+            match n.next.get(&c) {
+                Some(node) => n = node,
+                None => return 0,
+            }
+        }
+        n.count
+    }
+
+    // Whether a stored sequence matches the pattern, where the wildcard
+    // stands for any single element, like "b.d" with '.'
+    pub fn matches(&self, pattern: &mut dyn Iterator<Item = T>, wildcard: &T) -> bool {
+        let pattern: Vec<T> = pattern.collect();
+        !self.match_pattern(&pattern, wildcard, true).is_empty()
+    }
+
+    // All stored sequences matching the pattern, in no particular order
+    pub fn find_matches(&self, pattern: &mut dyn Iterator<Item = T>, wildcard: &T) -> Vec<Vec<T>> {
+        let pattern: Vec<T> = pattern.collect();
+        self.match_pattern(&pattern, wildcard, false)
+    }
+
+    // A wildcard branches into every child, any other element follows at
+    // most one, a path ends when it's as long as the pattern
+    fn match_pattern(&self, pattern: &[T], wildcard: &T, first_only: bool) -> Vec<Vec<T>> {
+        let mut found = vec![];
+        let mut stack = vec![(self, vec![])];
+        while let Some((node, path)) = stack.pop() {
+            let c = match pattern.get(path.len()) {
+                Some(c) => c,
+                None => {
+                    if node.end {
+                        found.push(path);
+                        if first_only {
+                            break;
+                        }
+                    }
+                    continue;
+                }
+            };
+            if c == wildcard {
+                for (c, child) in node.next.iter() {
+                    let mut path = path.clone();
+                    path.push(c.clone());
+                    stack.push((child, path));
+                }
+            } else if let Some(child) = node.next.get(c) {
+                let mut path = path;
+                path.push(c.clone());
+                stack.push((child, path));
+            }
+        }
+        found
+    }
+
+    // Visit the nodes depth first, the root included. The visitor gets the
+    // path to the node, the node and whether a sequence ends there, when it
+    // returns false nothing below the node is visited
+    pub fn walk<F>(&self, visitor: F)
+    where
+        F: FnMut(&[T], &TrieNode<T>, bool) -> bool,
+    {
+        self.traverse(visitor, false);
+    }
+
+    // Same as `walk`, level by level
+    pub fn walk_breadth_first<F>(&self, visitor: F)
+    where
+        F: FnMut(&[T], &TrieNode<T>, bool) -> bool,
+    {
+        self.traverse(visitor, true);
+    }
+
+    // The queue works as a stack going depth first
+    fn traverse<F>(&self, mut visitor: F, breadth_first: bool)
+    where
+        F: FnMut(&[T], &TrieNode<T>, bool) -> bool,
+    {
+        let mut queue = VecDeque::new();
+        queue.push_back((self, vec![]));
+        loop {
+            let next = if breadth_first {
+                queue.pop_front()
+            } else {
+                queue.pop_back()
+            };
+            let (node, path) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            if !visitor(&path, node, node.end) {
+                continue;
+            }
+            for (c, child) in node.next.iter() {
+                let mut path = path.clone();
+                path.push(c.clone());
+                queue.push_back((child, path));
+            }
+        }
+    }
+
+    // All stored sequences, in no particular order
+    pub fn iter(&self) -> PrefixIter<'_, T> {
+        PrefixIter {
+            stack: vec![(self, vec![])],
+        }
+    }
+
+    // Stored sequences starting with the prefix, in no particular order
+    pub fn iter_prefix(&self, prefix: &mut dyn Iterator<Item = T>) -> PrefixIter<'_, T> {
+        let mut n = self;
+        let mut path = vec![];
+        for c in prefix {
+            match n.next.get(&c) {
+                Some(node) => n = node,
+                None => return PrefixIter { stack: vec![] },
+            }
+            path.push(c);
+        }
+        PrefixIter {
+            stack: vec![(n, path)],
+        }
+    }
+}
+
+pub struct PrefixIter<'a, T> {
+    stack: Vec<(&'a TrieNode<T>, Vec<T>)>,
+}
+
+impl<'a, T: Clone> Iterator for PrefixIter<'a, T> {
+    type Item = Vec<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, path)) = self.stack.pop() {
+            for (c, child) in node.next.iter() {
+                let mut path = path.clone();
+                path.push(c.clone());
+                self.stack.push((child, path));
+            }
+            if node.end {
+                return Some(path);
+            }
+        }
+        None
+    }
+}
+
+// Trie with a value at the end of every stored sequence
+pub struct TrieMap<K, V> {
+    pub(crate) next: HashMap<K, TrieMap<K, V>>,
+    pub(crate) value: Option<V>,
+}
+
+impl<K: Eq + Hash, V> Default for TrieMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash, V> TrieMap<K, V> {
+    pub fn new() -> Self {
+        Self {
+            next: HashMap::new(),
+            value: None,
+        }
+    }
+
+    // Returns the old value of the sequence
+    pub fn insert(&mut self, s: &mut dyn Iterator<Item = K>, value: V) -> Option<V> {
+        let mut n = self;
+        for c in s {
+            n = n.next.entry(c).or_default();
+        }
+        n.value.replace(value)
+    }
+
+    pub fn get(&self, s: &mut dyn Iterator<Item = K>) -> Option<&V> {
+        let mut n = self;
+        for c in s {
+            n = n.next.get(&c)?;
+        }
+        n.value.as_ref()
+    }
+
+    pub fn get_mut(&mut self, s: &mut dyn Iterator<Item = K>) -> Option<&mut V> {
+        let mut n = self;
+        for c in s {
+            n = n.next.get_mut(&c)?;
+        }
+        n.value.as_mut()
+    }
+
+    // Stored sequences with their values, in no particular order
+    pub fn iter(&self) -> MapIter<'_, K, V> {
+        MapIter {
+            stack: vec![(self, vec![])],
+        }
+    }
+}
+
+pub struct MapIter<'a, K, V> {
+    stack: Vec<(&'a TrieMap<K, V>, Vec<K>)>,
+}
+
+impl<'a, K: Clone, V> Iterator for MapIter<'a, K, V> {
+    type Item = (Vec<K>, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, path)) = self.stack.pop() {
+            for (c, child) in node.next.iter() {
+                let mut path = path.clone();
+                path.push(c.clone());
+                self.stack.push((child, path));
+            }
+            if let Some(value) = &node.value {
+                return Some((path, value));
+            }
+        }
+        None
+    }
+}
+
+// Trie counting how many times every sequence was added, e.g. n-grams
+pub struct FrequencyTrie<T> {
+    counts: TrieMap<T, u64>,
+}
+
+impl<T: Eq + Hash> Default for FrequencyTrie<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Eq + Hash> FrequencyTrie<T> {
+    pub fn new() -> Self {
+        Self {
+            counts: TrieMap::new(),
+        }
+    }
+
+    // Count one more occurrence, returns the new count
+    pub fn add(&mut self, s: &mut dyn Iterator<Item = T>) -> u64 {
+        let mut n = &mut self.counts;
+        for c in s {
+            n = n.next.entry(c).or_default();
+        }
+        let count = n.value.get_or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    // Number of times the sequence was added
+    pub fn count(&self, s: &mut dyn Iterator<Item = T>) -> u64 {
+        self.counts.get(s).copied().unwrap_or(0)
+    }
+}
+
+impl<T: Eq + Hash + Ord + Clone> FrequencyTrie<T> {
+    // Up to `k` most frequent sequences, the smaller sequence first for
+    // equal counts
+    pub fn top_k(&self, k: usize) -> Vec<(Vec<T>, u64)> {
+        let mut found: Vec<_> = self.counts.iter().map(|(s, count)| (s, *count)).collect();
+        found.sort_by(|a, b| (Reverse(a.1), &a.0).cmp(&(Reverse(b.1), &b.0)));
+        found.truncate(k);
+        found
+    }
+}
+
+// State of the Aho-Corasick automaton, one per trie node
+struct AcState<T> {
+    next: HashMap<T, usize>,
+    // Longest proper suffix of the path that is also a path of the trie
+    fail: usize,
+    // Longest proper suffix of the path that is a stored sequence
+    output: Option<usize>,
+    depth: usize,
+    end: bool,
+}
+
+// Multi-pattern matcher over the sequences of a trie
+pub struct AhoCorasick<T> {
+    states: Vec<AcState<T>>,
+}
+
+impl<T: Default + Eq + Hash + Clone> TrieNode<T> {
+    pub fn matcher(&self) -> AhoCorasick<T> {
+        // Number the nodes breadth first, so a failure link always points
+        // to a state that is already done
+        let mut states = vec![];
+        let mut queue = VecDeque::new();
+        queue.push_back((self, 0));
+        while let Some((node, depth)) = queue.pop_front() {
+            let mut next = HashMap::new();
+            for (c, child) in node.next.iter() {
+                next.insert(c.clone(), states.len() + queue.len() + 1);
+                queue.push_back((child, depth + 1));
+            }
+            states.push(AcState {
+                next,
+                fail: 0,
+                output: None,
+                depth,
+                end: node.end,
+            });
+        }
+        for u in 0..states.len() {
+            let next: Vec<_> = states[u]
+                .next
+                .iter()
+                .map(|(c, &v)| (c.clone(), v))
+                .collect();
+            for (c, v) in next {
+                let fail = if u == 0 {
+                    0
+                } else {
+                    AhoCorasick::step(&states, states[u].fail, &c)
+                };
+                states[v].fail = fail;
+                states[v].output = if states[fail].end {
+                    Some(fail)
+                } else {
+                    states[fail].output
+                };
+            }
+        }
+        AhoCorasick { states }
+    }
+}
+
+impl<T: Eq + Hash> AhoCorasick<T> {
+    fn step(states: &[AcState<T>], mut state: usize, c: &T) -> usize {
+        loop {
+            if let Some(&next) = states[state].next.get(c) {
+                return next;
+            }
+            if state == 0 {
+                return 0;
+            }
+            state = states[state].fail;
+        }
+    }
+
+    // Spans `start..end` of all occurrences of the stored sequences in the
+    // haystack, ordered by their end and longest first for the same end
+    pub fn find_all<I: IntoIterator<Item = T>>(&self, haystack: I) -> FindAll<'_, T, I::IntoIter> {
+        FindAll {
+            matcher: self,
+            haystack: haystack.into_iter(),
+            state: 0,
+            pos: 0,
+            pending: None,
+        }
+    }
+}
+
+pub struct FindAll<'a, T, I> {
+    matcher: &'a AhoCorasick<T>,
+    haystack: I,
+    state: usize,
+    pos: usize,
+    // Next state along the output links to report at `pos`
+    pending: Option<usize>,
+}
+
+impl<'a, T: Eq + Hash, I: Iterator<Item = T>> Iterator for FindAll<'a, T, I> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let states = &self.matcher.states;
+        loop {
+            if let Some(state) = self.pending {
+                self.pending = states[state].output;
+                return Some((self.pos - states[state].depth, self.pos));
+            }
+            let c = self.haystack.next()?;
+            self.state = AhoCorasick::step(states, self.state, &c);
+            self.pos += 1;
+            self.pending = if states[self.state].end {
+                Some(self.state)
+            } else {
+                states[self.state].output
+            };
+        }
+    }
+}
+
+// Step of the fuzzy search, a node with the edit distances of the query
+// prefixes to its path, or a stored sequence found within the distance
+enum Candidate<'a, T> {
+    Node(&'a TrieNode<T>, Vec<T>, Vec<usize>),
+    Match(Vec<T>, usize),
+}
+
+// Sequences within the edit distance of the query, closest first
+pub struct ApproximateMatches<'a, T> {
+    query: &'a [T],
+    max_distance: usize,
+    // (cost, index of the candidate), ties go in the insertion order
+    heap: BinaryHeap<Reverse<(usize, usize)>>,
+    candidates: Vec<Option<Candidate<'a, T>>>,
+}
+
+impl<'a, T> ApproximateMatches<'a, T> {
+    fn push(&mut self, cost: usize, candidate: Candidate<'a, T>) {
+        let index = self.candidates.len();
+        self.heap.push(Reverse((cost, index)));
+        self.candidates.push(Some(candidate));
+    }
+}
+
+impl<'a, T: Default + Eq + Hash + Clone> Iterator for ApproximateMatches<'a, T> {
+    type Item = (Vec<T>, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(Reverse((_, index))) = self.heap.pop() {
+            let (node, path, row) = match self.candidates[index].take()? {
+                Candidate::Match(path, distance) => return Some((path, distance)),
+                Candidate::Node(node, path, row) => (node, path, row),
+            };
+            let distance = row[self.query.len()];
+            if node.end && distance <= self.max_distance {
+                self.push(distance, Candidate::Match(path.clone(), distance));
+            }
+            for (c, child) in node.next.iter() {
+                // Next row of the Levenshtein table, the state of the
+                // automaton after reading `c`
+                let mut next = vec![row[0] + 1];
+                for (i, q) in self.query.iter().enumerate() {
+                    let replace = row[i] + (q != c) as usize;
+                    next.push(replace.min(row[i + 1] + 1).min(next[i] + 1));
+                }
+                // Distances never go down deeper in the trie, so the
+                // smallest one bounds every match below
+                let cost = *next.iter().min().unwrap();
+                if cost <= self.max_distance {
+                    let mut path = path.clone();
+                    path.push(c.clone());
+                    self.push(cost, Candidate::Node(child, path, next));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<T: Default + Eq + Hash + Clone> TrieNode<T> {
+    // Stored sequences within the Levenshtein distance `max_distance` of
+    // the query with their distances, in the increasing distance order.
+    // Subtrees no match can be in are never visited
+    pub fn approximate_matches<'a>(
+        &'a self,
+        query: &'a [T],
+        max_distance: usize,
+    ) -> ApproximateMatches<'a, T> {
+        let mut matches = ApproximateMatches {
+            query,
+            max_distance,
+            heap: BinaryHeap::new(),
+            candidates: vec![],
+        };
+        let row: Vec<_> = (0..=query.len()).collect();
+        let cost = *row.iter().min().unwrap();
+        matches.push(cost, Candidate::Node(self, vec![], row));
+        matches
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_find_all() {
+        let mut trie = TrieNode::new();
+        for pattern in ["hers", "he", "she", "his"].iter() {
+            trie.add(&mut pattern.chars());
+        }
+        let matcher = trie.matcher();
+        let found: Vec<_> = matcher.find_all("ushers".chars()).collect();
+        assert_eq!(vec![(1, 4), (2, 4), (2, 6)], found);
+
+        let mut rng = rand::thread_rng();
+        let patterns: Vec<Vec<u8>> = (0..20)
+            .map(|_| {
+                (0..rng.gen_range(1, 4))
+                    .map(|_| rng.gen_range(0, 3))
+                    .collect()
+            })
+            .collect();
+        let mut trie = TrieNode::new();
+        for pattern in patterns.iter() {
+            trie.add(&mut pattern.iter().cloned());
+        }
+        let haystack: Vec<u8> = (0..1000).map(|_| rng.gen_range(0, 3)).collect();
+        let mut found: Vec<_> = trie.matcher().find_all(haystack.iter().cloned()).collect();
+        let mut expected = vec![];
+        for end in 1..=haystack.len() {
+            for start in 0..end {
+                if patterns.contains(&haystack[start..end].to_vec()) {
+                    expected.push((start, end));
+                }
+            }
+        }
+        found.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(expected, found);
+    }
+
+    #[test]
+    fn test_trie_map() {
+        let mut trie = TrieMap::new();
+        let mut data = HashMap::new();
+        let mut rng = rand::thread_rng();
+        for i in 0..5_000 {
+            let key: Vec<u8> = (0..rng.gen_range(0, 6))
+                .map(|_| rng.gen_range(0, 3))
+                .collect();
+            assert_eq!(
+                data.insert(key.clone(), i),
+                trie.insert(&mut key.iter().cloned(), i)
+            );
+        }
+        for (key, value) in data.iter_mut() {
+            assert_eq!(Some(&*value), trie.get(&mut key.iter().cloned()));
+            *trie.get_mut(&mut key.iter().cloned()).unwrap() += 1;
+            *value += 1;
+        }
+        for key in data.keys() {
+            assert_eq!(data.get(key), trie.get(&mut key.iter().cloned()));
+        }
+        let mut pairs: Vec<_> = trie.iter().map(|(key, value)| (key, *value)).collect();
+        let mut expected: Vec<_> = data.into_iter().collect();
+        pairs.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(expected, pairs);
+        assert_eq!(None, trie.get(&mut [0, 1, 2, 0, 1, 2, 0].iter().cloned()));
+        assert_eq!(None, trie.get_mut(&mut [3].iter().cloned()));
+    }
+
+    #[test]
+    fn test_frequency_trie() {
+        let text = "the cat and the dog and the bird";
+        let words: Vec<_> = text.split(' ').collect();
+        let mut bigrams = FrequencyTrie::new();
+        for pair in words.windows(2) {
+            bigrams.add(&mut pair.iter().cloned());
+        }
+        assert_eq!(2, bigrams.count(&mut ["and", "the"].iter().cloned()));
+        assert_eq!(1, bigrams.count(&mut ["the", "cat"].iter().cloned()));
+        assert_eq!(0, bigrams.count(&mut ["the"].iter().cloned()));
+        assert_eq!(3, bigrams.add(&mut ["and", "the"].iter().cloned()));
+        let top = bigrams.top_k(3);
+        assert_eq!((vec!["and", "the"], 3), top[0]);
+        // Ties go in the order of the sequences
+        assert_eq!(
+            vec![(vec!["cat", "and"], 1), (vec!["dog", "and"], 1)],
+            top[1..]
+        );
+        assert_eq!(6, bigrams.top_k(10).len());
+    }
+
+    #[test]
+    fn test_nested_prefixes() {
+        // Shorter keys first used to hide the longer ones
+        let words = ["", "a", "app", "apple", "applesauce", "b"];
+        let mut trie = TrieNode::new();
+        for word in words.iter() {
+            trie.add(&mut word.chars());
+        }
+        for word in words.iter() {
+            assert!(trie.find(&mut word.chars()), "{}", word);
+        }
+        assert!(!trie.find(&mut "appl".chars()));
+        assert_eq!(words.len(), trie.len());
+        assert_eq!(4, trie.count_prefix(&mut "a".chars()));
+        trie.add(&mut "apple".chars());
+        assert_eq!(words.len(), trie.len());
+        assert!(trie.remove(&mut "app".chars()));
+        assert!(trie.find(&mut "apple".chars()));
+        assert!(trie.remove(&mut "".chars()));
+        assert!(trie.find(&mut "a".chars()));
+        assert_eq!(words.len() - 2, trie.len());
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut rng = rand::thread_rng();
+        let words: Vec<Vec<u8>> = (0..1000)
+            .map(|_| {
+                (0..rng.gen_range(0, 6))
+                    .map(|_| rng.gen_range(0, 3))
+                    .collect()
+            })
+            .collect();
+        let mut trie = TrieNode::new();
+        for word in words.iter() {
+            trie.add(&mut word.iter().cloned());
+        }
+        let mut stored: std::collections::HashSet<_> = words.into_iter().collect();
+        for _i in 0..1000 {
+            let word: Vec<u8> = (0..rng.gen_range(0, 6))
+                .map(|_| rng.gen_range(0, 3))
+                .collect();
+            assert_eq!(stored.remove(&word), trie.remove(&mut word.iter().cloned()));
+            assert!(!trie.find(&mut word.iter().cloned()));
+        }
+        for word in stored.iter() {
+            assert!(trie.find(&mut word.iter().cloned()));
+        }
+        assert_eq!(stored.len(), trie.count_prefix(&mut std::iter::empty()));
+        assert_eq!(stored.len(), trie.len());
+        let mut all: Vec<_> = trie.iter().collect();
+        let mut expected: Vec<_> = stored.iter().cloned().collect();
+        all.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(expected, all);
+        let mut prefixes: std::collections::HashSet<_> = stored
+            .iter()
+            .flat_map(|word| (1..=word.len()).map(move |len| &word[..len]))
+            .collect();
+        prefixes.insert(&[]);
+        assert_eq!(prefixes.len(), trie.node_count());
+        assert!(trie.memory() >= trie.node_count() * std::mem::size_of::<TrieNode<u8>>());
+        let with_zero = stored
+            .iter()
+            .filter(|word| word.first() == Some(&0))
+            .count();
+        assert_eq!(with_zero, trie.count_prefix(&mut std::iter::once(0)));
+        for word in stored.iter() {
+            assert!(trie.remove(&mut word.iter().cloned()));
+        }
+        // Every node is gone with the last sequence below it
+        assert!(trie.next.is_empty());
+    }
+
+    #[test]
+    fn test_matches() {
+        let mut rng = rand::thread_rng();
+        let mut words: Vec<Vec<u8>> = (0..300)
+            .map(|_| {
+                (0..rng.gen_range(0, 6))
+                    .map(|_| rng.gen_range(b'a', b'd'))
+                    .collect()
+            })
+            .collect();
+        let mut trie = TrieNode::new();
+        for word in words.iter() {
+            trie.add(&mut word.iter().cloned());
+        }
+        words.sort_unstable();
+        words.dedup();
+        for _i in 0..200 {
+            let pattern: Vec<u8> = (0..rng.gen_range(0, 6))
+                .map(|_| b"abcd.."[rng.gen_range(0, 6)])
+                .collect();
+            let expected: Vec<_> = words
+                .iter()
+                .filter(|word| {
+                    word.len() == pattern.len()
+                        && word
+                            .iter()
+                            .zip(pattern.iter())
+                            .all(|(w, p)| p == &b'.' || w == p)
+                })
+                .cloned()
+                .collect();
+            let mut found = trie.find_matches(&mut pattern.iter().cloned(), &b'.');
+            found.sort_unstable();
+            assert_eq!(expected, found);
+            assert_eq!(
+                !expected.is_empty(),
+                trie.matches(&mut pattern.iter().cloned(), &b'.')
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut rng = rand::thread_rng();
+        let mut all = std::collections::HashSet::new();
+        let mut merged = TrieNode::new();
+        for _part in 0..4 {
+            let words: Vec<Vec<u8>> = (0..200)
+                .map(|_| {
+                    (0..rng.gen_range(0, 5))
+                        .map(|_| rng.gen_range(0, 3))
+                        .collect()
+                })
+                .collect();
+            let mut trie = TrieNode::new();
+            for word in words.iter() {
+                trie.add(&mut word.iter().cloned());
+            }
+            all.extend(words);
+            merged.merge(trie);
+        }
+        let mut found: Vec<_> = merged.iter().collect();
+        let mut expected: Vec<_> = all.into_iter().collect();
+        found.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(expected, found);
+        assert_eq!(expected.len(), merged.len());
+        let with_one = expected
+            .iter()
+            .filter(|word| word.first() == Some(&1))
+            .count();
+        assert_eq!(with_one, merged.count_prefix(&mut std::iter::once(1)));
+    }
+
+    #[test]
+    fn test_intersection_difference() {
+        let mut rng = rand::thread_rng();
+        let mut sets = vec![];
+        let mut tries = vec![];
+        for _i in 0..2 {
+            let words: Vec<Vec<u8>> = (0..300)
+                .map(|_| {
+                    (0..rng.gen_range(0, 5))
+                        .map(|_| rng.gen_range(0, 3))
+                        .collect()
+                })
+                .collect();
+            let mut trie = TrieNode::new();
+            for word in words.iter() {
+                trie.add(&mut word.iter().cloned());
+            }
+            sets.push(words.into_iter().collect::<std::collections::HashSet<_>>());
+            tries.push(trie);
+        }
+        let check = |trie: TrieNode<u8>, expected: Vec<&Vec<u8>>| {
+            let mut found: Vec<_> = trie.iter().collect();
+            let mut expected: Vec<_> = expected.into_iter().cloned().collect();
+            found.sort_unstable();
+            expected.sort_unstable();
+            assert_eq!(expected, found);
+            assert_eq!(expected.len(), trie.len());
+            // Nothing is left of the dropped sequences
+            assert!(trie.node_count() <= 1 + expected.iter().map(|w| w.len()).sum::<usize>());
+        };
+        check(
+            tries[0].intersection(&tries[1]),
+            sets[0].intersection(&sets[1]).collect(),
+        );
+        check(
+            tries[0].difference(&tries[1]),
+            sets[0].difference(&sets[1]).collect(),
+        );
+        check(
+            tries[1].difference(&tries[0]),
+            sets[1].difference(&sets[0]).collect(),
+        );
+    }
+
+    #[test]
+    fn test_longest_prefix() {
+        let mut trie = TrieNode::new();
+        for word in ["abcd", "ab", "b"].iter() {
+            trie.add(&mut word.chars());
+        }
+        // Greedy tokenization
+        let text: Vec<char> = "abcdabbab".chars().collect();
+        let mut tokens = vec![];
+        let mut pos = 0;
+        while pos < text.len() {
+            let len = trie.longest_prefix(&mut text[pos..].iter().cloned());
+            assert!(len > 0);
+            tokens.push(text[pos..pos + len].iter().collect::<String>());
+            pos += len;
+        }
+        assert_eq!(vec!["abcd", "ab", "b", "ab"], tokens);
+        assert_eq!(2, trie.longest_prefix(&mut "abc".chars()));
+        assert_eq!(0, trie.longest_prefix(&mut "a".chars()));
+        assert_eq!(0, trie.longest_prefix(&mut "".chars()));
+    }
+
+    #[test]
+    fn test_walk() {
+        let mut trie = TrieNode::new();
+        for word in ["tea", "ten", "to", "in", "inn"].iter() {
+            trie.add(&mut word.chars());
+        }
+        let mut nodes = 0;
+        let mut found = vec![];
+        trie.walk(|path, node, end| {
+            nodes += 1;
+            if end {
+                found.push(path.iter().collect::<String>());
+            }
+            // Skip everything under "te", but not "te" itself
+            assert_eq!(node.len(), trie.count_prefix(&mut path.iter().cloned()));
+            path != ['t', 'e']
+        });
+        found.sort_unstable();
+        assert_eq!(vec!["in", "inn", "to"], found);
+        assert_eq!(trie.node_count() - 2, nodes);
+
+        let mut depths = vec![];
+        trie.walk_breadth_first(|path, _, _| {
+            depths.push(path.len());
+            true
+        });
+        assert_eq!(trie.node_count(), depths.len());
+        assert!(depths.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut trie = TrieNode::new();
+        let stats = trie.stats();
+        assert_eq!(
+            (1, 0, 0, vec![1]),
+            (
+                stats.nodes,
+                stats.terminals,
+                stats.max_depth,
+                stats.branching
+            )
+        );
+        assert_eq!(0.0, stats.avg_depth);
+
+        for word in ["tea", "ten", "to", "inn"].iter() {
+            trie.add(&mut word.chars());
+        }
+        let stats = trie.stats();
+        assert_eq!(trie.node_count(), stats.nodes);
+        assert_eq!(trie.len(), stats.terminals);
+        assert_eq!(3, stats.max_depth);
+        assert_eq!(11.0 / 4.0, stats.avg_depth);
+        // root, t and te have two children, i and in one
+        assert_eq!(vec![4, 2, 3], stats.branching);
+        assert_eq!(4.0 / 9.0, stats.terminal_density);
+    }
+
+    #[test]
+    fn test_find_longest() {
+        let mut trie = TrieNode::new();
+        for word in ["tea", "ten", "to"].iter() {
+            trie.add(&mut word.chars());
+        }
+        let mut input = "teapot".chars().peekable();
+        assert_eq!((3, true), trie.find_longest(&mut input));
+        assert_eq!(Some('p'), input.next());
+        let mut input = "text".chars().peekable();
+        assert_eq!((2, false), trie.find_longest(&mut input));
+        assert_eq!("xt", input.collect::<String>());
+        let mut input = "".chars().peekable();
+        assert_eq!((0, false), trie.find_longest(&mut input));
+    }
+
+    #[test]
+    fn test_iter_prefix() {
+        let mut trie = TrieNode::new();
+        for word in ["tea", "ten", "inn", "to", "in"].iter() {
+            trie.add(&mut word.chars());
+        }
+        let collect = |prefix: &str| {
+            let mut found: Vec<String> = trie
+                .iter_prefix(&mut prefix.chars())
+                .map(|word| word.into_iter().collect())
+                .collect();
+            found.sort_unstable();
+            found
+        };
+        assert_eq!(vec!["tea", "ten", "to"], collect("t"));
+        assert_eq!(vec!["tea", "ten"], collect("te"));
+        assert_eq!(vec!["in", "inn"], collect("in"));
+        assert_eq!(5, collect("").len());
+        assert!(collect("x").is_empty());
+        assert!(collect("tent").is_empty());
+        for prefix in ["t", "te", "in", "", "x", "tent"].iter() {
+            assert_eq!(
+                collect(prefix).len(),
+                trie.count_prefix(&mut prefix.chars())
+            );
+            assert_eq!(
+                !collect(prefix).is_empty(),
+                trie.contains_prefix(&mut prefix.chars())
+            );
+        }
+        // "te" isn't stored, but starts some sequences
+        assert!(!trie.find(&mut "te".chars()));
+        assert!(trie.contains_prefix(&mut "te".chars()));
+    }
+
+    fn distance(a: &[u8], b: &[u8]) -> usize {
+        let mut row: Vec<_> = (0..=b.len()).collect();
+        for (i, x) in a.iter().enumerate() {
+            let mut next = vec![i + 1];
+            for (j, y) in b.iter().enumerate() {
+                next.push(
+                    (row[j] + (x != y) as usize)
+                        .min(row[j + 1] + 1)
+                        .min(next[j] + 1),
+                );
+            }
+            row = next;
+        }
+        row[b.len()]
+    }
+
+    #[test]
+    fn test_approximate_matches() {
+        let mut rng = rand::thread_rng();
+        let mut words: Vec<Vec<u8>> = (0..500)
+            .map(|_| {
+                (0..rng.gen_range(1, 8))
+                    .map(|_| rng.gen_range(b'a', b'e'))
+                    .collect()
+            })
+            .collect();
+        let mut trie = TrieNode::new();
+        for word in words.iter() {
+            trie.add(&mut word.iter().cloned());
+        }
+        words.sort_unstable();
+        words.dedup();
+        for _i in 0..100 {
+            let query: Vec<u8> = (0..rng.gen_range(0, 8))
+                .map(|_| rng.gen_range(b'a', b'e'))
+                .collect();
+            let mut found: Vec<_> = trie.approximate_matches(&query, 2).collect();
+            assert!(found.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+            let mut expected: Vec<_> = words
+                .iter()
+                .map(|word| (word.clone(), distance(word, &query)))
+                .filter(|(_, distance)| *distance <= 2)
+                .collect();
+            found.sort_unstable();
+            expected.sort_unstable();
+            assert_eq!(expected, found);
+        }
     }
 }
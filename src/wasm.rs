@@ -0,0 +1,118 @@
+use crate::art::Art;
+use crate::keys::{KeyCodec, NullTerminated, Raw};
+use crate::Error;
+use wasm_bindgen::prelude::*;
+
+// String map on top of the ART for JS, e.g. for autocomplete in the
+// browser. Keys go in with a \0 at the end, so one key can be a prefix of
+// another, and come out without it. Keys can't contain \0 themselves,
+// calls with one throw
+#[wasm_bindgen]
+pub struct ArtMap {
+    art: Art<String, String>,
+    len: usize,
+}
+
+impl Default for ArtMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+impl ArtMap {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self {
+            art: Art::new(),
+            len: 0,
+        }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn size(&self) -> usize {
+        self.len
+    }
+
+    pub fn set(&mut self, key: &str, value: String) -> Result<(), JsValue> {
+        let key = key_bytes(key).map_err(js_error)?;
+        if self.art.insert_with(&Raw, &key[..], value).is_none() {
+            self.len += 1;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>, JsValue> {
+        let key = key_bytes(key).map_err(js_error)?;
+        Ok(self.art.find_with(&Raw, &key[..]).cloned())
+    }
+
+    pub fn has(&self, key: &str) -> Result<bool, JsValue> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    pub fn delete(&mut self, key: &str) -> Result<bool, JsValue> {
+        let key = key_bytes(key).map_err(js_error)?;
+        let found = self.art.remove_with(&Raw, &key[..]).is_some();
+        if found {
+            self.len -= 1;
+        }
+        Ok(found)
+    }
+
+    // Up to `limit` keys starting with `prefix` in the key order
+    pub fn complete(&self, prefix: &str, limit: usize) -> Vec<String> {
+        self.art
+            .complete(prefix.as_bytes(), limit)
+            .into_iter()
+            .map(|(key, _)| String::from_utf8_lossy(&key[..key.len() - 1]).into_owned())
+            .collect()
+    }
+}
+
+// Bytes of the key in the tree. `NullTerminated` asserts there is no \0
+// in the key, which would trap, so it's checked here first
+fn key_bytes(key: &str) -> Result<Vec<u8>, Error> {
+    if key.contains('\0') {
+        return Err(Error::InvalidArgument(format!(
+            "key {:?} contains a null byte",
+            key
+        )));
+    }
+    Ok(NullTerminated(Raw).to_bytes(key))
+}
+
+fn js_error(err: Error) -> JsValue {
+    JsValue::from_str(&err.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_art_map() {
+        let mut map = ArtMap::new();
+        for word in ["tree", "trie", "tr", "treat", "radix"].iter() {
+            map.set(word, word.to_uppercase()).unwrap();
+        }
+        map.set("tr", "TR".to_string()).unwrap();
+        assert_eq!(5, map.size());
+        assert_eq!(Some("TRIE".to_string()), map.get("trie").unwrap());
+        assert_eq!(None, map.get("t").unwrap());
+        assert!(map.has("tr").unwrap());
+        assert_eq!(vec!["tr", "treat", "tree"], map.complete("tr", 3));
+        assert!(map.delete("tree").unwrap());
+        assert!(!map.delete("tree").unwrap());
+        assert_eq!(vec!["tr", "treat", "trie"], map.complete("tr", 10));
+        assert_eq!(4, map.size());
+        // JS strings can hold \0, it's an error rather than a trap. The
+        // `JsValue` of the error only exists in wasm, so the check is
+        // tested on its own
+        assert!(matches!(
+            key_bytes("tr\0ee"),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert_eq!(b"tr\0".to_vec(), key_bytes("tr").unwrap());
+    }
+}
@@ -0,0 +1,365 @@
+use crate::art::ArtKey;
+use core::marker::PhantomData;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+// Trie of scored keys for ranked completion. Every node caches the highest
+// score in its subtree, so a best-first search can always go on with the
+// most promising subtree and stop after `k` results without looking at the
+// rest. Scores are plain weights, e.g. query frequencies
+
+#[derive(Debug)]
+struct WeightedNode<T> {
+    // Children sorted by their byte
+    children: Vec<(u8, WeightedNode<T>)>,
+    value: Option<(u64, T)>,
+    // Highest score in the subtree
+    max_score: u64,
+}
+
+impl<T> WeightedNode<T> {
+    fn new() -> Self {
+        Self {
+            children: vec![],
+            value: None,
+            max_score: 0,
+        }
+    }
+
+    fn child(&self, byte: u8) -> Option<&WeightedNode<T>> {
+        self.children
+            .binary_search_by_key(&byte, |(b, _)| *b)
+            .ok()
+            .map(|pos| &self.children[pos].1)
+    }
+
+    fn update_max_score(&mut self) {
+        let own = self.value.as_ref().map_or(0, |(score, _)| *score);
+        let below = self.children.iter().map(|(_, child)| child.max_score);
+        self.max_score = below.fold(own, std::cmp::max);
+    }
+
+    fn insert(&mut self, key: &[u8], score: u64, value: T) -> Option<(u64, T)> {
+        let old = match key.split_first() {
+            None => self.value.replace((score, value)),
+            Some((&byte, rest)) => {
+                let pos = match self.children.binary_search_by_key(&byte, |(b, _)| *b) {
+                    Ok(pos) => pos,
+                    Err(pos) => {
+                        self.children.insert(pos, (byte, WeightedNode::new()));
+                        pos
+                    }
+                };
+                self.children[pos].1.insert(rest, score, value)
+            }
+        };
+        self.update_max_score();
+        old
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<(u64, T)> {
+        let old = match key.split_first() {
+            None => self.value.take(),
+            Some((&byte, rest)) => {
+                let pos = self
+                    .children
+                    .binary_search_by_key(&byte, |(b, _)| *b)
+                    .ok()?;
+                let old = self.children[pos].1.remove(rest);
+                let child = &self.children[pos].1;
+                if child.value.is_none() && child.children.is_empty() {
+                    self.children.remove(pos);
+                }
+                old
+            }
+        };
+        self.update_max_score();
+        old
+    }
+}
+
+// Key of a phone keypad the letter is on, 2 to 9
+fn keypad_digit(byte: u8) -> Option<u8> {
+    const KEYS: &[u8; 26] = b"22233344455566677778889999";
+    let letter = byte.to_ascii_lowercase();
+    if letter.is_ascii_lowercase() {
+        Some(KEYS[(letter - b'a') as usize])
+    } else {
+        None
+    }
+}
+
+// Step of the best-first search, a subtree or a key found in it
+enum Candidate<'a, T> {
+    Node(&'a WeightedNode<T>),
+    Key(u64, &'a T),
+}
+
+pub struct WeightedTrie<K, T> {
+    root: WeightedNode<T>,
+    len: usize,
+    key: PhantomData<K>,
+}
+
+impl<K: ArtKey, T> Default for WeightedTrie<K, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: ArtKey, T> WeightedTrie<K, T> {
+    pub fn new() -> Self {
+        Self {
+            root: WeightedNode::new(),
+            len: 0,
+            key: PhantomData,
+        }
+    }
+
+    // Number of keys
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Insert the key with its score, returns the old score and value
+    pub fn insert(&mut self, key: K, score: u64, value: T) -> Option<(u64, T)> {
+        let key_bytes = key.bytes();
+        trace_span!("weighted.insert", key_len = key_bytes.len());
+        let old = self.root.insert(&key_bytes, score, value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn remove(&mut self, key: K) -> Option<(u64, T)> {
+        let key_bytes = key.bytes();
+        trace_span!("weighted.remove", key_len = key_bytes.len());
+        let old = self.root.remove(&key_bytes);
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+
+    // Score and value of the key
+    pub fn find(&self, key: K) -> Option<(u64, &T)> {
+        let key_bytes = key.bytes();
+        trace_span!("weighted.find", key_len = key_bytes.len());
+        let mut node = &self.root;
+        for byte in key_bytes {
+            node = node.child(byte)?;
+        }
+        node.value.as_ref().map(|(score, value)| (*score, value))
+    }
+
+    // Up to `k` keys starting with `prefix` with the highest scores, best
+    // first and in the key order for equal scores
+    pub fn top_k(&self, prefix: &[u8], k: usize) -> Vec<(Vec<u8>, u64, &T)> {
+        let mut found = vec![];
+        let mut node = &self.root;
+        for byte in prefix {
+            match node.child(*byte) {
+                Some(child) => node = child,
+                None => return found,
+            }
+        }
+        // The heap holds the scores and keys of the candidates with their
+        // indices, a smaller key wins a tie
+        let mut candidates = vec![Some(Candidate::Node(node))];
+        let mut heap = BinaryHeap::new();
+        heap.push((node.max_score, Reverse(prefix.to_vec()), 0));
+        while let Some((_, Reverse(path), index)) = heap.pop() {
+            if found.len() == k {
+                break;
+            }
+            match candidates[index].take() {
+                Some(Candidate::Key(score, value)) => found.push((path, score, value)),
+                Some(Candidate::Node(node)) => {
+                    if let Some((score, value)) = &node.value {
+                        heap.push((*score, Reverse(path.clone()), candidates.len()));
+                        candidates.push(Some(Candidate::Key(*score, value)));
+                    }
+                    for (byte, child) in node.children.iter() {
+                        let mut key = path.clone();
+                        key.push(*byte);
+                        heap.push((child.max_score, Reverse(key), candidates.len()));
+                        candidates.push(Some(Candidate::Node(child)));
+                    }
+                }
+                None => {}
+            }
+        }
+        found
+    }
+
+    // Corrections of the word within the edit distance `max_distance`,
+    // closest first and the higher score first for the same distance.
+    // Subtrees whose Levenshtein table row is past the distance are skipped
+    pub fn suggest(
+        &self,
+        word: &[u8],
+        max_distance: usize,
+        limit: usize,
+    ) -> Vec<(Vec<u8>, usize, u64, &T)> {
+        let mut found = vec![];
+        let mut stack = vec![(&self.root, vec![], (0..=word.len()).collect::<Vec<_>>())];
+        while let Some((node, path, row)) = stack.pop() {
+            if let Some((score, value)) = &node.value {
+                if row[word.len()] <= max_distance {
+                    found.push((path.clone(), row[word.len()], *score, value));
+                }
+            }
+            for (byte, child) in node.children.iter() {
+                let mut next = vec![row[0] + 1];
+                for (i, w) in word.iter().enumerate() {
+                    let replace = row[i] + (w != byte) as usize;
+                    next.push(replace.min(row[i + 1] + 1).min(next[i] + 1));
+                }
+                if next.iter().min().unwrap() <= &max_distance {
+                    let mut key = path.clone();
+                    key.push(*byte);
+                    stack.push((child, key, next));
+                }
+            }
+        }
+        found.sort_by(|a, b| (a.1, Reverse(a.2), &a.0).cmp(&(b.1, Reverse(b.2), &b.0)));
+        found.truncate(limit);
+        found
+    }
+
+    // Words typed with the digits on a phone keypad, like "4663" for
+    // "good" and "home", the higher score first. Only children on the key
+    // of the next digit are visited
+    pub fn keypad(&self, digits: &[u8]) -> Vec<(Vec<u8>, u64, &T)> {
+        let mut found = vec![];
+        let mut stack = vec![(&self.root, vec![])];
+        while let Some((node, path)) = stack.pop() {
+            let digit = match digits.get(path.len()) {
+                Some(digit) => *digit,
+                None => {
+                    if let Some((score, value)) = &node.value {
+                        found.push((path, *score, value));
+                    }
+                    continue;
+                }
+            };
+            for (byte, child) in node.children.iter() {
+                if keypad_digit(*byte) == Some(digit) {
+                    let mut key = path.clone();
+                    key.push(*byte);
+                    stack.push((child, key));
+                }
+            }
+        }
+        found.sort_by(|a, b| (Reverse(a.1), &a.0).cmp(&(Reverse(b.1), &b.0)));
+        found
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_top_k() {
+        let mut trie = WeightedTrie::<String, u32>::new();
+        let mut data = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for i in 0..5_000 {
+            let len = rng.gen_range(0, 7);
+            let key: String = (0..len)
+                .map(|_| ['a', 'b', 'c'][rng.gen_range(0, 3)])
+                .collect();
+            let score = rng.gen_range(0, 100);
+            trie.insert(key.clone(), score, i);
+            data.insert(key, (score, i));
+        }
+        let keys: Vec<_> = data.keys().cloned().collect();
+        for key in keys.iter().step_by(3) {
+            assert_eq!(data.remove(key), trie.remove(key.clone()));
+        }
+        assert_eq!(data.len(), trie.len());
+        for prefix in ["", "a", "bc", "cab", "abcabcabc"].iter() {
+            let found: Vec<_> = trie
+                .top_k(prefix.as_bytes(), 20)
+                .into_iter()
+                .map(|(key, score, value)| (key, score, *value))
+                .collect();
+            let mut expected: Vec<_> = data
+                .iter()
+                .filter(|(key, _)| key.starts_with(prefix))
+                .map(|(key, (score, value))| (key.as_bytes().to_vec(), *score, *value))
+                .collect();
+            expected.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            expected.truncate(20);
+            assert_eq!(expected, found);
+        }
+    }
+
+    #[test]
+    fn test_suggest() {
+        let mut trie = WeightedTrie::<String, ()>::new();
+        for (word, score) in [
+            ("hello", 10),
+            ("help", 30),
+            ("hell", 5),
+            ("yellow", 50),
+            ("world", 99),
+        ]
+        .iter()
+        {
+            trie.insert(word.to_string(), *score, ());
+        }
+        let found: Vec<_> = trie
+            .suggest(b"helo", 1, 10)
+            .into_iter()
+            .map(|(word, distance, score, _)| (String::from_utf8(word).unwrap(), distance, score))
+            .collect();
+        let expected = vec![
+            ("help".to_string(), 1, 30),
+            ("hello".to_string(), 1, 10),
+            ("hell".to_string(), 1, 5),
+        ];
+        assert_eq!(expected, found);
+        assert_eq!(1, trie.suggest(b"helo", 2, 1).len());
+        assert_eq!(4, trie.suggest(b"helo", 3, 10).len());
+    }
+
+    #[test]
+    fn test_keypad() {
+        let mut trie = WeightedTrie::<String, ()>::new();
+        for (word, score) in [
+            ("good", 10),
+            ("home", 30),
+            ("gone", 20),
+            ("Hood", 5),
+            ("goods", 50),
+        ]
+        .iter()
+        {
+            trie.insert(word.to_string(), *score, ());
+        }
+        let found: Vec<_> = trie
+            .keypad(b"4663")
+            .into_iter()
+            .map(|(word, score, _)| (word, score))
+            .collect();
+        assert_eq!(
+            vec![
+                (b"home".to_vec(), 30),
+                (b"gone".to_vec(), 20),
+                (b"good".to_vec(), 10),
+                (b"Hood".to_vec(), 5)
+            ],
+            found
+        );
+        assert!(trie.keypad(b"1").is_empty());
+    }
+}